@@ -0,0 +1,162 @@
+//! Captures whoever uses the device while its VT is locked, following the server's policy.
+//! The policy isn't signed, so we clamp it and keep our own upload quota on top of it.
+
+use crate::event::ClientEvent;
+use crate::lock::{self, get_screenshot};
+use crate::profile::every;
+use crate::webcam::capture_webcam_jpeg;
+use aegislib::command::device::CaptureTrigger;
+use aegislib::command::server::CapturePolicy;
+use anyhow::Result;
+use image::codecs::jpeg::JpegEncoder;
+use image::{ColorType, Rgb, RgbImage};
+use lazy_static::lazy_static;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Mutex;
+use tokio::task::{spawn_blocking, JoinHandle};
+use tracing::{info, warn};
+
+const QUOTA_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+lazy_static! {
+    static ref POLICY: Mutex<CapturePolicy> = Mutex::new(CapturePolicy::default());
+    /// Tasks of the capture schedule, None while the VT isn't locked
+    static ref SCHEDULE: Mutex<Option<Vec<JoinHandle<()>>>> = Mutex::new(None);
+    static ref EVENT_TX: Mutex<Option<Sender<ClientEvent>>> = Mutex::new(None);
+}
+
+pub async fn register_event_tx(sender: Sender<ClientEvent>) {
+    EVENT_TX.lock().await.replace(sender);
+}
+
+pub async fn send_event(event: ClientEvent) {
+    if let Some(tx) = EVENT_TX.lock().await.as_ref() {
+        let _ = tx.send(event).await;
+    }
+}
+
+pub async fn policy() -> CapturePolicy {
+    *POLICY.lock().await
+}
+
+/// Replaces the policy, and restarts the schedule with it if it's running
+pub async fn set_policy(policy: CapturePolicy) {
+    let policy = policy.clamped();
+    info!("Applying capture policy: {policy:?}");
+    *POLICY.lock().await = policy;
+    let mut schedule = SCHEDULE.lock().await;
+    if let Some(tasks) = schedule.as_mut() {
+        for task in tasks.drain(..) {
+            task.abort();
+        }
+        *tasks = spawn_schedule(policy).await;
+    }
+}
+
+/// Starts the periodic captures of the policy, if they aren't running already
+pub async fn start_schedule() {
+    let policy = policy().await;
+    let mut schedule = SCHEDULE.lock().await;
+    if schedule.is_none() {
+        *schedule = Some(spawn_schedule(policy).await);
+    }
+}
+
+pub async fn stop_schedule() {
+    if let Some(tasks) = SCHEDULE.lock().await.take() {
+        for task in tasks {
+            task.abort();
+        }
+    }
+}
+
+async fn spawn_schedule(policy: CapturePolicy) -> Vec<JoinHandle<()>> {
+    let Some(event_tx) = EVENT_TX.lock().await.clone() else {
+        return Vec::new();
+    };
+    let mut tasks = Vec::new();
+    if let Some(secs) = policy.webcam_interval_secs {
+        tasks.push(every(
+            secs,
+            event_tx.clone(),
+            || match capture_webcam_jpeg() {
+                Ok(data) => Some(ClientEvent::Picture(data, CaptureTrigger::Scheduled)),
+                Err(e) => {
+                    warn!("Failed to capture scheduled webcam picture: {e}");
+                    None
+                }
+            },
+        ));
+    }
+    if let Some(secs) = policy.screenshot_interval_secs {
+        tasks.push(every(secs, event_tx, || match capture_screenshot_jpeg() {
+            Ok(data) => Some(ClientEvent::Picture(data, CaptureTrigger::Screenshot)),
+            Err(e) => {
+                warn!("Failed to capture scheduled screenshot: {e}");
+                None
+            }
+        }));
+    }
+    tasks
+}
+
+/// Takes a webcam picture for the server, only while our VT is locked
+pub async fn capture_requested() {
+    if !lock::current_status().await.vt_locked {
+        info!("Ignoring capture request, the VT isn't locked");
+        return;
+    }
+    match spawn_blocking(capture_webcam_jpeg).await {
+        Ok(Ok(data)) => send_event(ClientEvent::Picture(data, CaptureTrigger::Requested)).await,
+        Ok(Err(e)) => warn!("Failed to capture requested webcam picture: {e}"),
+        Err(e) => warn!("Requested webcam capture panicked: {e}"),
+    }
+}
+
+fn capture_screenshot_jpeg() -> Result<Vec<u8>> {
+    let screen = get_screenshot()?;
+    let rgb = RgbImage::from_fn(screen.width(), screen.height(), |x, y| {
+        let [b, g, r, _] = screen.get_pixel(x, y).0;
+        Rgb([r, g, b])
+    });
+    let mut jpeg_data = Vec::new();
+    JpegEncoder::new(&mut jpeg_data).encode(&rgb, rgb.width(), rgb.height(), ColorType::Rgb8)?;
+    Ok(jpeg_data)
+}
+
+/// Counts our uploads over the last hour, so we stop before the server has to refuse them
+pub struct UploadQuota {
+    /// From our own config, applies even if the server's policy allows more
+    local_max: Option<u32>,
+    recent: VecDeque<Instant>,
+}
+
+impl UploadQuota {
+    pub fn new(local_max: Option<u32>) -> Self {
+        Self {
+            local_max,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Records an upload if both our quota and the policy's allow one more
+    pub fn try_take(&mut self, policy_max: u32) -> bool {
+        let now = Instant::now();
+        while let Some(&oldest) = self.recent.front() {
+            if now.duration_since(oldest) < QUOTA_PERIOD {
+                break;
+            }
+            self.recent.pop_front();
+        }
+        let max = self
+            .local_max
+            .map_or(policy_max, |local| local.min(policy_max));
+        if self.recent.len() >= max as usize {
+            return false;
+        }
+        self.recent.push_back(now);
+        true
+    }
+}
@@ -1,11 +1,13 @@
+use crate::device_key;
 use crate::Config;
 use aegislib::client::{register_device, ClientError, DeviceClient, StatusCode};
 use aegislib::command::server::ServerCommand;
-use aegislib::crypto::SigningKey;
+use aegislib::crypto::{SigningKey, VerifyingKey};
 use anyhow::{bail, Result};
 use std::time::Duration;
 use tokio::sync::mpsc::Sender;
 use tokio::time::sleep;
+use tracing::{info, warn};
 
 /// If we get 403 Forbidden when connecting to the server, the device hasn't been approved by an admin
 /// This is the cooldown before we periodically retry connecting to the server websocket
@@ -15,26 +17,62 @@ async fn register(config: &Config, key: &SigningKey) -> Result<()> {
     // register_device considers CONFLICT as an error, but for our purpose it means the device
     // is already known by the server, we just need admin approval before we can connect
     // If register_device returns anything else, we actually failed to register and should exit
-    match register_device(&config.into(), &config.device_name, &key.verifying_key()).await {
+    match register_device(
+        &config.try_into()?,
+        &config.device_name,
+        &key.verifying_key(),
+        config.enrollment_token.as_deref(),
+    )
+    .await
+    {
         Ok(_) => Ok(()),
         Err(ClientError::Http(e)) if e.code == StatusCode::CONFLICT => Ok(()),
         Err(e) => Err(e.into()),
     }
 }
 
+/// Returns the connected client, and the public key it ended up using
 pub async fn connect(
     config: &Config,
     mut key: SigningKey,
     event_tx: Sender<ServerCommand>,
-) -> Result<DeviceClient> {
+) -> Result<(DeviceClient, VerifyingKey)> {
+    let client_config = config.try_into()?;
     let mut has_registered = false;
+    let mut pending_key = device_key::read_pending_key(&config.device_key_path);
     loop {
-        match DeviceClient::new(&config.into(), key, Some(event_tx.clone())).await {
-            Ok(c) => return Ok(c),
+        let verifying_key = key.verifying_key();
+        match DeviceClient::new(&client_config, key, Some(event_tx.clone())).await {
+            Ok(c) => return Ok((c, verifying_key)),
             Err((_, ClientError::Other(err))) => return Err(err),
             Err((_, e @ ClientError::WebsocketDisconnected(_))) => bail!(e),
             Err((err_key, ClientError::Http(err))) => {
                 if err.code == StatusCode::FORBIDDEN {
+                    // We may have been stopped after the server accepted our new key,
+                    // but before we saved it
+                    if let Some(new_key) = pending_key.take() {
+                        match DeviceClient::new(&client_config, new_key, Some(event_tx.clone()))
+                            .await
+                        {
+                            Ok(c) => {
+                                info!("Finishing an interrupted device key rotation");
+                                device_key::promote_pending_key(&config.device_key_path)?;
+                                let verifying_key =
+                                    device_key::get_or_create_keys(&config.device_key_path)?
+                                        .verifying_key();
+                                return Ok((c, verifying_key));
+                            }
+                            Err((_, e)) if e.is_forbidden() => {
+                                warn!("Discarding a device key the server never accepted");
+                                device_key::discard_pending_key(&config.device_key_path);
+                            }
+                            // The server may have our new key, we just couldn't check
+                            Err((new_key, e)) => {
+                                warn!("Failed to connect with the pending device key, will retry: {e}");
+                                pending_key = Some(new_key);
+                            }
+                        }
+                    }
                     if !has_registered {
                         tracing::warn!("Device rejected by server, please authorize the device with the admin interface");
                         register(config, &err_key).await?;
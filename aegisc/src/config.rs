@@ -1,3 +1,4 @@
+use aegislib::client::read_pem;
 use anyhow::{anyhow, Context, Result};
 use serde::Deserialize;
 use std::path::{Path, PathBuf};
@@ -8,6 +9,26 @@ pub struct Config {
     pub use_tls: bool,
     pub server_addr: String,
     pub device_key_path: PathBuf,
+    /// PEM files of CAs trusted in addition to the webpki roots
+    #[serde(default)]
+    pub extra_ca_certs: Vec<PathBuf>,
+    /// PEM file of the only server certificate we accept
+    pub pinned_server_cert: Option<PathBuf>,
+    /// Base64 SHA-256 of the server's DER SubjectPublicKeyInfo
+    pub pinned_spki_sha256: Option<String>,
+    /// aegisd's channel public key (URL-safe base64), set to encrypt our websocket end-to-end
+    pub server_public_key: Option<String>,
+    /// Admin root public key (URL-safe base64). When set, admin commands must be signed by it
+    pub root_public_signature_key: Option<String>,
+    /// Token from an admin to register with, so the server knows to expect this device
+    pub enrollment_token: Option<String>,
+    /// Replace the device key once it gets this old. Unset keeps it until an admin asks.
+    pub key_rotation_days: Option<u32>,
+    /// Most pictures we upload in an hour, even if the server's capture policy allows more
+    pub max_uploads_per_hour: Option<u32>,
+    /// Admins can only fetch files under these paths, none when empty
+    #[serde(default)]
+    pub fetch_allowed_prefixes: Vec<PathBuf>,
 }
 
 impl Config {
@@ -33,6 +54,15 @@ impl Default for Config {
             use_tls: true,
             server_addr: "alacrem.net/aegis".to_string(),
             device_key_path: "/var/lib/aegisc/device.key".into(),
+            extra_ca_certs: Vec::new(),
+            pinned_server_cert: None,
+            pinned_spki_sha256: None,
+            server_public_key: None,
+            root_public_signature_key: None,
+            enrollment_token: None,
+            key_rotation_days: None,
+            max_uploads_per_hour: None,
+            fetch_allowed_prefixes: Vec::new(),
         }
     }
 }
@@ -41,12 +71,26 @@ pub fn default_path() -> PathBuf {
     "/etc/aegisc.toml".into()
 }
 
-impl From<&Config> for aegislib::client::ClientConfig {
-    fn from(config: &Config) -> Self {
-        Self {
+impl TryFrom<&Config> for aegislib::client::ClientConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(config: &Config) -> Result<Self> {
+        Ok(Self {
             server_addr: config.server_addr.clone(),
             use_tls: config.use_tls,
             use_rest: false,
-        }
+            extra_ca_certs_pem: config
+                .extra_ca_certs
+                .iter()
+                .map(|path| read_pem(path))
+                .collect::<Result<_>>()?,
+            pinned_server_cert_pem: config
+                .pinned_server_cert
+                .as_deref()
+                .map(read_pem)
+                .transpose()?,
+            pinned_spki_sha256: config.pinned_spki_sha256.clone(),
+            server_public_key: config.server_public_key.clone(),
+        })
     }
 }
@@ -1,6 +1,15 @@
-use aegislib::crypto::{sign_keypair_from_file, SigningKey};
+use crate::event::ClientEvent;
+use aegislib::client::DeviceClient;
+use aegislib::crypto::{sign_keypair_from_file, SigningKey, VerifyingKey};
 use anyhow::{Context, Result};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::Sender;
+use tokio::time::sleep;
+use tracing::{error, info};
+
+/// How often we check whether the key is due for a scheduled rotation
+const KEY_AGE_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 pub fn get_or_create_keys(path: &Path) -> Result<SigningKey> {
     Ok(if path.exists() {
@@ -19,3 +28,68 @@ pub fn get_or_create_keys(path: &Path) -> Result<SigningKey> {
         sign_kp
     })
 }
+
+/// Where a new key waits until the server acknowledged it
+fn pending_key_path(path: &Path) -> PathBuf {
+    let mut pending = path.as_os_str().to_owned();
+    pending.push(".new");
+    pending.into()
+}
+
+/// A key from a rotation that was interrupted, the server may or may not know about it
+pub fn read_pending_key(path: &Path) -> Option<SigningKey> {
+    let pending = pending_key_path(path);
+    pending
+        .exists()
+        .then(|| sign_keypair_from_file(pending).ok())?
+}
+
+/// Replaces the current key with the pending one
+pub fn promote_pending_key(path: &Path) -> Result<()> {
+    let pending = pending_key_path(path);
+    std::fs::rename(&pending, path)
+        .with_context(|| format!("Failed to replace device key file {}", path.display()))
+}
+
+pub fn discard_pending_key(path: &Path) {
+    let _ = std::fs::remove_file(pending_key_path(path));
+}
+
+/// Registers a new key with the server. The old key file is only replaced once the server
+/// switched to the new key, so an interrupted rotation can't lock us out.
+pub async fn rotate(client: &mut DeviceClient, path: &Path) -> Result<VerifyingKey> {
+    let new_key = aegislib::crypto::random_sign_keypair();
+    let pending = pending_key_path(path);
+    std::fs::write(&pending, new_key.to_bytes())
+        .with_context(|| format!("Failed to write device key file {}", pending.display()))?;
+    client.rotate_key(new_key.clone()).await?;
+    promote_pending_key(path)?;
+    Ok(new_key.verifying_key())
+}
+
+fn key_age(path: &Path) -> Result<Duration> {
+    let modified = std::fs::metadata(path)?.modified()?;
+    Ok(SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or(Duration::ZERO))
+}
+
+/// Asks for a rotation whenever the key file gets older than `max_age`
+pub async fn rotate_on_schedule(path: PathBuf, max_age: Duration, event_tx: Sender<ClientEvent>) {
+    loop {
+        match key_age(&path) {
+            Ok(age) if age >= max_age => {
+                info!(
+                    "Device key is {} days old, rotating it",
+                    age.as_secs() / 86400
+                );
+                if event_tx.send(ClientEvent::RotateKey).await.is_err() {
+                    return;
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to check the device key age: {e}"),
+        }
+        sleep(KEY_AGE_CHECK_INTERVAL).await;
+    }
+}
@@ -1,4 +1,23 @@
+use aegislib::command::device::{CaptureTrigger, FileChunk, FileFetchFinished, ScriptResult};
+
 pub enum ClientEvent {
-    WebcamPicture(Vec<u8>),
+    /// A JPEG webcam picture or screenshot, counted against the upload quota
+    Picture(Vec<u8>, CaptureTrigger),
     InputWhileLockedWithoutWebcam,
+    /// A server command was rejected because it wasn't signed by the admin
+    TamperDetected(String),
+    /// Time to replace the device key, because an admin asked or on schedule
+    RotateKey,
+    /// A protection profile started or stopped, with the name of the one now active
+    ProfileChanged(Option<String>),
+    /// Sent on the interval of the active protection profile
+    Telemetry(String),
+    /// A signed script finished, with its output
+    ScriptResult(ScriptResult),
+    /// The next chunk of a fetched file, sent in order
+    FileChunk(FileChunk),
+    /// After the chunks of every file of the fetch
+    FileFetchFinished(FileFetchFinished),
+    /// Logged as an event while a fetch runs
+    FetchProgress(String),
 }
@@ -0,0 +1,285 @@
+//! Sends the files that the admin signed a fetch for, as long as they are under our allowed prefixes
+
+use crate::event::ClientEvent;
+use aegislib::command::device::{FetchFailure, FileChunk, FileFetchFinished};
+use aegislib::command::server::FileFetch;
+use aegislib::crypto::sealed::FileSealer;
+use anyhow::{bail, Result};
+use nix::fcntl::{open, openat, OFlag};
+use nix::sys::stat::Mode;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc::Sender;
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+/// Keeps us well under the websocket message rate that aegisd allows by default
+const CHUNK_INTERVAL: Duration = Duration::from_millis(100);
+/// How often we log how far along a fetch is
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+
+struct Fetcher {
+    fetch_id: u64,
+    encrypt_to: Option<[u8; 32]>,
+    client_event_tx: Sender<ClientEvent>,
+    failures: Vec<FetchFailure>,
+}
+
+pub async fn run(fetch: FileFetch, allowed: Vec<PathBuf>, client_event_tx: Sender<ClientEvent>) {
+    let mut fetcher = Fetcher {
+        fetch_id: fetch.fetch_id,
+        encrypt_to: fetch.encrypt_to,
+        client_event_tx,
+        failures: Vec::new(),
+    };
+    if let Err(e) = fetch.check() {
+        for path in fetch.paths {
+            fetcher.fail(path, format!("Refused file fetch: {e}"));
+        }
+        fetcher.finish().await;
+        return;
+    }
+    info!("Collecting files for fetch {}", fetch.fetch_id);
+
+    let (allowed, files, failures) = spawn_blocking(move || {
+        let allowed: Vec<PathBuf> = allowed
+            .iter()
+            .filter_map(|prefix| std::fs::canonicalize(prefix).ok())
+            .collect();
+        let (files, failures) = collect_files(&fetch.paths, &allowed);
+        (allowed, files, failures)
+    })
+    .await
+    .unwrap_or_default();
+    fetcher.failures = failures;
+    let total_size: u64 = files.iter().map(|(_, size)| size).sum();
+    fetcher
+        .progress(format!(
+            "sending {} files, {}",
+            files.len(),
+            format_size(total_size)
+        ))
+        .await;
+
+    let mut sent_size = 0;
+    let mut last_progress = Instant::now();
+    for (i, (path, size)) in files.iter().enumerate() {
+        if let Err(e) = fetcher.send_file(path, &allowed).await {
+            fetcher.fail(path.display().to_string(), e.to_string());
+        }
+        sent_size += size;
+        if last_progress.elapsed() >= PROGRESS_INTERVAL {
+            last_progress = Instant::now();
+            let message = format!(
+                "sent {}/{} files, {} of {}",
+                i + 1,
+                files.len(),
+                format_size(sent_size),
+                format_size(total_size)
+            );
+            fetcher.progress(message).await;
+        }
+    }
+    fetcher.finish().await;
+}
+
+impl Fetcher {
+    fn fail(&mut self, path: String, error: String) {
+        warn!("File fetch {}: {path}: {error}", self.fetch_id);
+        self.failures.push(FetchFailure { path, error });
+    }
+
+    async fn progress(&self, message: String) {
+        info!("File fetch {}: {message}", self.fetch_id);
+        let message = format!("File fetch {}: {message}", self.fetch_id);
+        let _ = self
+            .client_event_tx
+            .send(ClientEvent::FetchProgress(message))
+            .await;
+    }
+
+    async fn finish(self) {
+        let finished = FileFetchFinished {
+            fetch_id: self.fetch_id,
+            failures: self.failures,
+        };
+        let _ = self
+            .client_event_tx
+            .send(ClientEvent::FileFetchFinished(finished))
+            .await;
+    }
+
+    async fn send_file(&self, path: &Path, allowed: &[PathBuf]) -> Result<()> {
+        let Some(path_str) = path.to_str() else {
+            bail!("Path is not valid UTF-8");
+        };
+        let Some(prefix) = allowed.iter().find(|prefix| path.starts_with(prefix)) else {
+            bail!("Not under an allowed prefix");
+        };
+        let (prefix, relative) = (prefix.clone(), path.strip_prefix(prefix)?.to_owned());
+        let file = spawn_blocking(move || open_under(&prefix, &relative)).await??;
+        let mut file = File::from_std(file);
+        let metadata = file.metadata().await?;
+        if !metadata.is_file() {
+            bail!("Not a regular file anymore");
+        }
+        let mut sealer = self
+            .encrypt_to
+            .as_ref()
+            .map(|recipient| FileSealer::new(recipient, self.fetch_id, path_str))
+            .transpose()?;
+
+        let mut seq = 0;
+        let mut data = read_chunk(&mut file).await?;
+        loop {
+            let next = if data.len() == FileFetch::CHUNK_LEN {
+                read_chunk(&mut file).await?
+            } else {
+                Vec::new()
+            };
+            let last = next.is_empty();
+            if let Some((sealer, header)) = &mut sealer {
+                let sealed = sealer.seal(&data, last);
+                data = if seq == 0 {
+                    header.to_vec()
+                } else {
+                    Vec::new()
+                };
+                data.extend(sealed);
+            }
+            let chunk = FileChunk {
+                fetch_id: self.fetch_id,
+                path: path_str.to_owned(),
+                seq,
+                size: metadata.len(),
+                data,
+                last,
+            };
+            if self
+                .client_event_tx
+                .send(ClientEvent::FileChunk(chunk))
+                .await
+                .is_err()
+            {
+                bail!("Client event receiver closed");
+            }
+            if last {
+                return Ok(());
+            }
+            seq += 1;
+            data = next;
+            sleep(CHUNK_INTERVAL).await;
+        }
+    }
+}
+
+/// Fills a whole chunk unless the file ends first
+async fn read_chunk(file: &mut File) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(FileFetch::CHUNK_LEN);
+    let mut reader = AsyncReadExt::take(file, FileFetch::CHUNK_LEN as u64);
+    reader.read_to_end(&mut data).await?;
+    Ok(data)
+}
+
+/// Opens `relative` under `prefix` one component at a time without following symlinks.
+/// The file was checked when collected, but any directory on the way could since have been
+/// swapped for a symlink leading outside the prefix.
+fn open_under(prefix: &Path, relative: &Path) -> Result<std::fs::File> {
+    let dir_flags = OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    let file_flags = OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC;
+    let mut names = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => names.push(name),
+            _ => bail!("Unexpected path component"),
+        }
+    }
+    // The allowed prefixes are canonical, so they have no symlinks of their own
+    let Some((name, dirs)) = names.split_last() else {
+        return Ok(owned(open(prefix, file_flags, Mode::empty())?).into());
+    };
+    let mut dir = owned(open(prefix, dir_flags, Mode::empty())?);
+    for dir_name in dirs {
+        dir = owned(openat(
+            dir.as_raw_fd(),
+            *dir_name,
+            dir_flags,
+            Mode::empty(),
+        )?);
+    }
+    Ok(owned(openat(dir.as_raw_fd(), *name, file_flags, Mode::empty())?).into())
+}
+
+fn owned(fd: RawFd) -> OwnedFd {
+    // Safety: the fd was just opened and nothing else owns it
+    unsafe { OwnedFd::from_raw_fd(fd) }
+}
+
+/// The regular files under `paths` with their size, skipping what isn't under an allowed prefix.
+/// The `allowed` prefixes must already be canonical.
+fn collect_files(
+    paths: &[String],
+    allowed: &[PathBuf],
+) -> (Vec<(PathBuf, u64)>, Vec<FetchFailure>) {
+    let mut files = Vec::new();
+    let mut failures = Vec::new();
+    for path in paths {
+        // Resolves symlinks and `..`, so the prefix check sees where the path really leads
+        let canonical = match std::fs::canonicalize(path) {
+            Ok(canonical) => canonical,
+            Err(e) => {
+                failures.push(failure(Path::new(path), e));
+                continue;
+            }
+        };
+        if !allowed.iter().any(|prefix| canonical.starts_with(prefix)) {
+            failures.push(failure(Path::new(path), "Not under an allowed prefix"));
+            continue;
+        }
+        walk(&canonical, &mut files, &mut failures);
+    }
+    files.sort();
+    files.dedup();
+    (files, failures)
+}
+
+fn failure(path: &Path, error: impl ToString) -> FetchFailure {
+    FetchFailure {
+        path: path.display().to_string(),
+        error: error.to_string(),
+    }
+}
+
+/// Doesn't follow symlinks, they could lead outside the allowed prefixes
+fn walk(path: &Path, files: &mut Vec<(PathBuf, u64)>, failures: &mut Vec<FetchFailure>) {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => return failures.push(failure(path, e)),
+    };
+    if metadata.is_file() {
+        files.push((path.to_owned(), metadata.len()));
+    } else if metadata.is_dir() {
+        let entries = match std::fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) => return failures.push(failure(path, e)),
+        };
+        for entry in entries {
+            match entry {
+                Ok(entry) => walk(&entry.path(), files, failures),
+                Err(e) => failures.push(failure(path, e)),
+            }
+        }
+    } else if metadata.is_symlink() {
+        failures.push(failure(path, "Not following symlink"));
+    } else {
+        failures.push(failure(path, "Not a regular file"));
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
@@ -0,0 +1,51 @@
+use aegislib::client::DeviceClient;
+use aegislib::command::device::HardwareInfo;
+use std::path::Path;
+use tracing::{debug, error};
+
+const DMI_DIR: &str = "/sys/class/dmi/id";
+
+/// What firmware vendors leave in DMI fields they didn't bother to fill
+const PLACEHOLDERS: &[&str] = &[
+    "",
+    "0",
+    "Default string",
+    "None",
+    "Not Applicable",
+    "Not Specified",
+    "System Product Name",
+    "System Serial Number",
+    "To Be Filled By O.E.M.",
+];
+
+fn read_dmi_field(name: &str) -> Option<String> {
+    let path = Path::new(DMI_DIR).join(name);
+    match std::fs::read_to_string(&path) {
+        Ok(value) => {
+            let value = value.trim();
+            (!PLACEHOLDERS.iter().any(|p| p.eq_ignore_ascii_case(value))).then(|| value.to_owned())
+        }
+        Err(e) => {
+            // The serial number is only readable by root
+            debug!("Failed to read {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+pub fn read_info() -> HardwareInfo {
+    HardwareInfo {
+        serial_number: read_dmi_field("product_serial"),
+        hardware_model: read_dmi_field("product_name"),
+    }
+}
+
+pub async fn report_info(client: &mut DeviceClient) {
+    let info = read_info();
+    if info == HardwareInfo::default() {
+        return;
+    }
+    if let Err(e) = client.report_hardware(info).await {
+        error!("Failed to report hardware info: {e}")
+    }
+}
@@ -1,6 +1,8 @@
+use crate::capture::{self, send_event};
 use crate::run_as::run_as_root;
-use crate::webcam::capture_webcam_picture;
+use crate::webcam::{capture_webcam_jpeg, capture_webcam_picture};
 use crate::ClientEvent;
+use aegislib::command::device::CaptureTrigger;
 use aegislib::command::server::StatusUpdate;
 use anyhow::{anyhow, Result};
 use framebuffer::{Framebuffer, KdMode};
@@ -11,13 +13,11 @@ use lazy_static::lazy_static;
 use nix::libc::{ioctl, O_RDONLY, O_RDWR, O_WRONLY};
 use std::fs::{File, OpenOptions};
 use std::mem::{forget, size_of};
-use std::ops::DerefMut;
 use std::os::unix::prelude::*;
 use std::path::Path;
 use std::sync::atomic::Ordering::Acquire;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
-use tokio::sync::mpsc::Sender;
 use tokio::sync::Mutex;
 use tokio::task::{spawn_blocking, JoinHandle};
 use tokio::time::sleep;
@@ -27,7 +27,11 @@ static INPUT_WHILE_LOCKED_COOLDOWN: AtomicBool = AtomicBool::new(false);
 static INPUT_LOCKED: AtomicBool = AtomicBool::new(false);
 lazy_static! {
     static ref LIBINPUT_JOIN_HANDLE: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
-    static ref WEBCAM_PIC_EVENT_TX: Mutex<Option<Sender<ClientEvent>>> = Mutex::new(None);
+    static ref CURRENT_STATUS: Mutex<StatusUpdate> = Mutex::new(StatusUpdate {
+        vt_locked: false,
+        ssh_locked: false,
+        draw_decoy: false,
+    });
 }
 
 struct InputInterface;
@@ -57,7 +61,8 @@ async fn input_event_while_locked() {
         return;
     }
 
-    match capture_webcam_picture() {
+    let policy = capture::policy().await;
+    let captured = match capture_webcam_picture() {
         Ok(pic) => {
             let _ = std::fs::write(
                 "/sys/aegisk/alert",
@@ -70,10 +75,13 @@ async fn input_event_while_locked() {
             );
             let _ = pic.save(save_location);
             if let Ok(jpeg_data) = tokio::fs::read(save_location).await {
-                if let Some(tx) = WEBCAM_PIC_EVENT_TX.lock().await.deref_mut() {
-                    let _ = tx.send(ClientEvent::WebcamPicture(jpeg_data)).await;
-                }
+                send_event(ClientEvent::Picture(
+                    jpeg_data,
+                    CaptureTrigger::InputWhileLocked,
+                ))
+                .await;
             }
+            true
         }
         Err(e) => {
             let _ = std::fs::write(
@@ -81,20 +89,38 @@ async fn input_event_while_locked() {
                 "Detected input event while screen was locked. No webcam picture available.",
             );
             warn!("Input event while locked, but failed to capture pic: {e}");
-            if let Some(tx) = WEBCAM_PIC_EVENT_TX.lock().await.deref_mut() {
-                let _ = tx.send(ClientEvent::InputWhileLockedWithoutWebcam).await;
+            send_event(ClientEvent::InputWhileLockedWithoutWebcam).await;
+            false
+        }
+    };
+    // The rest of the burst, unless the device got unlocked in the meantime
+    for _ in 1..policy.burst_count {
+        if !captured {
+            break;
+        }
+        sleep(Duration::from_secs(policy.burst_interval_secs as u64)).await;
+        if !INPUT_LOCKED.load(Acquire) {
+            break;
+        }
+        match capture_webcam_jpeg() {
+            Ok(jpeg_data) => {
+                send_event(ClientEvent::Picture(
+                    jpeg_data,
+                    CaptureTrigger::InputWhileLocked,
+                ))
+                .await
+            }
+            Err(e) => {
+                warn!("Failed to capture burst webcam picture: {e}");
+                break;
             }
         }
     }
 
-    sleep(Duration::from_secs(5)).await;
+    sleep(Duration::from_secs(policy.input_cooldown_secs as u64)).await;
     INPUT_WHILE_LOCKED_COOLDOWN.store(false, Ordering::Release);
 }
 
-pub async fn register_event_tx(sender: Sender<ClientEvent>) {
-    WEBCAM_PIC_EVENT_TX.lock().await.replace(sender);
-}
-
 fn watch_input_events() {
     let mut input = Libinput::new_with_udev(InputInterface);
     input.udev_assign_seat("seat0").unwrap();
@@ -170,7 +196,7 @@ pub fn set_vt_lock(lock: bool) -> Result<()> {
 
 /// Note that the Rgba is a lie, it's actually Bgra (but that makes no difference for us)
 /// The image crate unfortunately removed support for Bgra in version 0.24
-fn get_screenshot() -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+pub(crate) fn get_screenshot() -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
     let mut capturer = captrs::Capturer::new(0).map_err(|s| anyhow!(s))?;
     let mut frame = capturer
         .capture_frame()
@@ -234,9 +260,15 @@ fn draw_screenshot(mut screen: ImageBuffer<Rgba<u8>, Vec<u8>>) -> Result<()> {
     Ok(())
 }
 
+/// The status we last applied
+pub async fn current_status() -> StatusUpdate {
+    *CURRENT_STATUS.lock().await
+}
+
 pub async fn apply_status(status: impl Into<StatusUpdate>) {
     let status = status.into();
     info!("Applying device status: {status:?}");
+    *CURRENT_STATUS.lock().await = status;
     if status.ssh_locked {
         if let Err(e) = run_as_root(vec!["systemctl", "stop", "ssh"]) {
             error!("Failed to lock SSH: {e}")
@@ -254,7 +286,10 @@ pub async fn apply_status(status: impl Into<StatusUpdate>) {
     } else {
         None
     };
-    if !status.vt_locked {
+    if status.vt_locked {
+        capture::start_schedule().await;
+    } else {
+        capture::stop_schedule().await;
         stop_watch_input_events().await;
         if let Err(e) = Framebuffer::set_kd_mode_ex("/dev/tty25", KdMode::Text) {
             warn!("Failed to switch TTY back to text mode: {e}")
@@ -1,27 +1,39 @@
+mod capture;
 mod client;
 mod config;
 mod device_key;
 mod event;
+mod fetch;
+mod hardware;
 mod lock;
 mod module;
 mod power;
+mod profile;
 mod run_as;
+mod script;
+mod verify;
 mod webcam;
 mod xorg;
 
+use crate::capture::UploadQuota;
 use crate::config::Config;
 use crate::event::ClientEvent;
+use crate::profile::ProfileRunner;
+use crate::verify::{Action, CommandVerifier};
 use crate::xorg::setup_xorg_env_vars;
 use aegislib::client::DeviceClient;
 use aegislib::command::device::{DeviceEvent, EventLogLevel};
 use aegislib::command::server::ServerCommand;
-use anyhow::Result;
+use aegislib::crypto::{public_key_from_base64, VerifyingKey};
+use anyhow::{Context, Result};
 use chrono::Utc;
 use clap::{arg, value_parser};
 use nix::unistd::{getpid, ROOT};
 use std::path::PathBuf;
+use std::time::Duration;
 use tokio::spawn;
-use tokio::sync::mpsc::{channel, Receiver};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::watch;
 use tracing::{error, info, trace, warn};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::layer::SubscriberExt;
@@ -63,12 +75,52 @@ fn check_privs_and_module() {
     }
 }
 
-async fn handle_server_events(mut event_rx: Receiver<ServerCommand>) {
+async fn handle_server_events(
+    mut event_rx: Receiver<ServerCommand>,
+    mut verifier: CommandVerifier,
+    mut profiles: ProfileRunner,
+    client_event_tx: Sender<ClientEvent>,
+    fetch_allowed_prefixes: Vec<PathBuf>,
+) {
     while let Some(event) = event_rx.recv().await {
         trace!("Received server event: {event:?}");
-        match event {
-            ServerCommand::StatusUpdate(status) => lock::apply_status(status).await,
-            ServerCommand::PowerCommand(cmd) => power::apply_command(cmd).await,
+        match verifier.check(event, lock::current_status().await) {
+            Ok(Action::Status(status)) => lock::apply_status(status).await,
+            Ok(Action::Power(cmd)) => power::apply_command(cmd).await,
+            Ok(Action::Profile(profile)) => {
+                if let Some(profile) = &profile {
+                    lock::apply_status(profile.status).await;
+                }
+                let name = profile.as_ref().map(|p| p.name.clone());
+                profiles.apply(profile);
+                let _ = client_event_tx
+                    .send(ClientEvent::ProfileChanged(name))
+                    .await;
+            }
+            Ok(Action::CapturePolicy(policy)) => capture::set_policy(policy).await,
+            Ok(Action::Capture) => capture::capture_requested().await,
+            Ok(Action::RotateKey) => {
+                let _ = client_event_tx.send(ClientEvent::RotateKey).await;
+            }
+            Ok(Action::RunScript(script)) => {
+                let client_event_tx = client_event_tx.clone();
+                tokio::spawn(async move {
+                    let result = script::run(script).await;
+                    let _ = client_event_tx
+                        .send(ClientEvent::ScriptResult(result))
+                        .await;
+                });
+            }
+            Ok(Action::FetchFiles(fetch)) => {
+                let allowed = fetch_allowed_prefixes.clone();
+                tokio::spawn(fetch::run(fetch, allowed, client_event_tx.clone()));
+            }
+            Err(reason) => {
+                error!("Possible tampering: {reason}");
+                let _ = client_event_tx
+                    .send(ClientEvent::TamperDetected(reason))
+                    .await;
+            }
         }
     }
     error!("Server event receiver closed, quitting immediately!");
@@ -78,15 +130,22 @@ async fn handle_server_events(mut event_rx: Receiver<ServerCommand>) {
 async fn handle_client_events(
     mut client: DeviceClient,
     mut client_event_rx: Receiver<ClientEvent>,
+    device_key_path: PathBuf,
+    device_pk_tx: watch::Sender<VerifyingKey>,
+    mut quota: UploadQuota,
 ) {
     while let Some(event) = client_event_rx.recv().await {
         match event {
-            ClientEvent::WebcamPicture(data) => {
+            ClientEvent::Picture(data, trigger) => {
+                if !quota.try_take(capture::policy().await.max_uploads_per_hour) {
+                    warn!("Upload quota reached, dropping {trigger:?} picture");
+                    continue;
+                }
                 let size = data.len() as f32 / 1024.0;
-                if let Err(e) = client.store_camera_picture(data).await {
-                    error!("Failed to upload webcam picture: {e}");
+                if let Err(e) = client.store_camera_picture(data, trigger).await {
+                    error!("Failed to upload {trigger:?} picture: {e}");
                 } else {
-                    info!("Successfully uploaded {size:.1}kB camera picture!")
+                    info!("Successfully uploaded {size:.1}kB {trigger:?} picture!")
                 }
             }
             ClientEvent::InputWhileLockedWithoutWebcam => {
@@ -98,6 +157,62 @@ async fn handle_client_events(
                     })
                     .await;
             }
+            ClientEvent::TamperDetected(reason) => {
+                let _ = client
+                    .log_event(DeviceEvent {
+                        timestamp: Utc::now().timestamp() as u64,
+                        level: EventLogLevel::Error,
+                        message: format!("Tamper detected: {reason}"),
+                    })
+                    .await;
+            }
+            ClientEvent::RotateKey => match device_key::rotate(&mut client, &device_key_path).await
+            {
+                Ok(new_pk) => {
+                    info!("Rotated device key");
+                    let _ = device_pk_tx.send(new_pk);
+                    let _ = client
+                        .log_event(DeviceEvent {
+                            timestamp: Utc::now().timestamp() as u64,
+                            level: EventLogLevel::Info,
+                            message: "Rotated device key".to_string(),
+                        })
+                        .await;
+                }
+                Err(e) => error!("Failed to rotate device key: {e}"),
+            },
+            ClientEvent::ProfileChanged(name) => {
+                if let Err(e) = client.report_profile(name).await {
+                    error!("Failed to report the active protection profile: {e}");
+                }
+            }
+            ClientEvent::ScriptResult(result) => {
+                let run_id = result.run_id;
+                if let Err(e) = client.upload_script_result(result).await {
+                    error!("Failed to upload the result of script {run_id}: {e}");
+                }
+            }
+            ClientEvent::FileChunk(chunk) => {
+                let (fetch_id, seq) = (chunk.fetch_id, chunk.seq);
+                if let Err(e) = client.upload_file_chunk(chunk).await {
+                    error!("Failed to upload chunk {seq} of file fetch {fetch_id}: {e}");
+                }
+            }
+            ClientEvent::FileFetchFinished(finished) => {
+                let fetch_id = finished.fetch_id;
+                if let Err(e) = client.finish_file_fetch(finished).await {
+                    error!("Failed to finish file fetch {fetch_id}: {e}");
+                }
+            }
+            ClientEvent::Telemetry(message) | ClientEvent::FetchProgress(message) => {
+                let _ = client
+                    .log_event(DeviceEvent {
+                        timestamp: Utc::now().timestamp() as u64,
+                        level: EventLogLevel::Info,
+                        message,
+                    })
+                    .await;
+            }
         }
     }
     error!("Client event receiver closed, quitting immediately!");
@@ -164,18 +279,81 @@ async fn main() -> Result<()> {
         error!("Failed to setup Xorg env, screenshots may not work: {e}");
     }
 
+    let root_pk = config
+        .root_public_signature_key
+        .as_deref()
+        .map(public_key_from_base64)
+        .transpose()
+        .context("Invalid root_public_signature_key")?;
+    if root_pk.is_none() {
+        warn!("No root_public_signature_key configured, trusting all commands from the server");
+    }
+
     let (event_tx, event_rx) = channel(1);
     let dev_key = device_key::get_or_create_keys(config.device_key_path.as_ref())?;
-    let mut client = client::connect(config, dev_key, event_tx).await?;
+    let (mut client, dev_pk) = client::connect(config, dev_key, event_tx).await?;
+    let (device_pk_tx, device_pk_rx) = watch::channel(dev_pk);
+    let verified_path = verify::path_for(&config.device_key_path);
+    let mut verifier = CommandVerifier::new(root_pk, device_pk_rx, verified_path);
     tracing::info!("Connected to server websocket");
 
     module::log_insert_time(&mut client).await;
-    lock::apply_status(client.status().await?).await;
-    spawn(handle_server_events(event_rx));
-
+    hardware::report_info(&mut client).await;
     let (client_event_tx, client_event_rx) = channel(1);
-    lock::register_event_tx(client_event_tx).await;
-    handle_client_events(client, client_event_rx).await;
+    // Before applying the status, a locked device starts capturing right away
+    capture::register_event_tx(client_event_tx.clone()).await;
+    match client.capture_policy().await {
+        Ok(Some(policy)) => capture::set_policy(policy).await,
+        Ok(None) => info!("Server is too old for capture policies, using the default"),
+        Err(e) => error!("Failed to fetch the capture policy, using the default: {e}"),
+    }
+    let status = match verifier.initial_status(client.status().await?.into()) {
+        Ok(status) => status,
+        Err(reason) => {
+            error!("Possible tampering: {reason}");
+            let _ = client
+                .log_event(DeviceEvent {
+                    timestamp: Utc::now().timestamp() as u64,
+                    level: EventLogLevel::Error,
+                    message: format!("Tamper detected: {reason}"),
+                })
+                .await;
+            verifier.last_status()
+        }
+    };
+    lock::apply_status(status).await;
+
+    let profile_path = profile::path_for(&config.device_key_path);
+    let mut profiles = ProfileRunner::new(profile_path, client_event_tx.clone());
+    // We just applied the status the server has, only the profile's behaviors are left to restart
+    let active_profile = profiles.load();
+    profiles.start(active_profile.as_ref());
+    if let Err(e) = client.report_profile(active_profile.map(|p| p.name)).await {
+        error!("Failed to report the active protection profile: {e}");
+    }
+    spawn(handle_server_events(
+        event_rx,
+        verifier,
+        profiles,
+        client_event_tx.clone(),
+        config.fetch_allowed_prefixes.clone(),
+    ));
+    if let Some(days) = config.key_rotation_days {
+        let max_age = Duration::from_secs(days as u64 * 24 * 60 * 60);
+        spawn(device_key::rotate_on_schedule(
+            config.device_key_path.clone(),
+            max_age,
+            client_event_tx.clone(),
+        ));
+    }
+    handle_client_events(
+        client,
+        client_event_rx,
+        config.device_key_path.clone(),
+        device_pk_tx,
+        UploadQuota::new(config.max_uploads_per_hour),
+    )
+    .await;
 
     Ok(())
 }
@@ -0,0 +1,133 @@
+//! Runs the behaviors of the active protection profile: periodic webcam pictures and telemetry.
+//! The profile is saved next to the device key, so it keeps running after a restart.
+
+use crate::event::ClientEvent;
+use crate::webcam::capture_webcam_jpeg;
+use aegislib::command::admin::MIN_PROFILE_INTERVAL_SECS;
+use aegislib::command::device::CaptureTrigger;
+use aegislib::command::server::DeviceProfile;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use sysinfo::SystemExt;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{error, info, warn};
+
+pub fn path_for(device_key_path: &Path) -> PathBuf {
+    device_key_path.with_file_name("profile.toml")
+}
+
+fn read_telemetry() -> String {
+    let mut sys = sysinfo::System::new();
+    sys.refresh_memory();
+    let load = sys.load_average();
+    let uptime = humantime::format_duration(Duration::from_secs(sys.uptime()));
+    format!(
+        "Telemetry: up {uptime}, load {:.2} {:.2} {:.2}, memory {}/{} MiB used",
+        load.one,
+        load.five,
+        load.fifteen,
+        sys.used_memory() / (1024 * 1024),
+        sys.total_memory() / (1024 * 1024),
+    )
+}
+
+/// Sends an event every `secs`, starting after the first interval
+pub fn every(
+    secs: u32,
+    event_tx: Sender<ClientEvent>,
+    event: fn() -> Option<ClientEvent>,
+) -> JoinHandle<()> {
+    let period = Duration::from_secs(secs.max(MIN_PROFILE_INTERVAL_SECS) as u64);
+    tokio::spawn(async move {
+        let mut ticks = interval(period);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let Ok(Some(event)) = tokio::task::spawn_blocking(event).await else {
+                continue;
+            };
+            if event_tx.send(event).await.is_err() {
+                return;
+            }
+        }
+    })
+}
+
+pub struct ProfileRunner {
+    path: PathBuf,
+    event_tx: Sender<ClientEvent>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl ProfileRunner {
+    pub fn new(path: PathBuf, event_tx: Sender<ClientEvent>) -> Self {
+        Self {
+            path,
+            event_tx,
+            tasks: Vec::new(),
+        }
+    }
+
+    /// The profile that was active before we restarted, if any
+    pub fn load(&self) -> Option<DeviceProfile> {
+        let data = std::fs::read_to_string(&self.path).ok()?;
+        toml::from_str(&data)
+            .map_err(|e| warn!("Ignoring invalid {}: {e}", self.path.display()))
+            .ok()
+    }
+
+    /// Stops the behaviors of the current profile, and starts those of `profile`
+    pub fn start(&mut self, profile: Option<&DeviceProfile>) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+        let Some(profile) = profile else {
+            return;
+        };
+        info!(
+            profile = profile.name.as_str(),
+            "Running protection profile"
+        );
+        if let Some(secs) = profile.capture_interval_secs {
+            self.tasks.push(every(
+                secs,
+                self.event_tx.clone(),
+                || match capture_webcam_jpeg() {
+                    Ok(data) => Some(ClientEvent::Picture(data, CaptureTrigger::Periodic)),
+                    Err(e) => {
+                        warn!("Failed to capture periodic webcam picture: {e}");
+                        None
+                    }
+                },
+            ));
+        }
+        if let Some(secs) = profile.telemetry_interval_secs {
+            self.tasks.push(every(secs, self.event_tx.clone(), || {
+                Some(ClientEvent::Telemetry(read_telemetry()))
+            }));
+        }
+    }
+
+    /// Runs `profile` and saves it for the next start, None stops the active profile
+    pub fn apply(&mut self, profile: Option<DeviceProfile>) {
+        self.start(profile.as_ref());
+        let saved = match &profile {
+            Some(profile) => toml::to_string(profile)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| Ok(std::fs::write(&self.path, data)?)),
+            None => match std::fs::remove_file(&self.path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+        };
+        if let Err(e) = saved {
+            error!(
+                "Failed to save protection profile to {}: {e}",
+                self.path.display()
+            );
+        }
+    }
+}
@@ -2,11 +2,15 @@ use anyhow::{bail, Result};
 use std::process::{Command, Output};
 use tracing::debug;
 
-pub fn run_as_root(mut cmdline: Vec<&str>) -> Result<Output> {
+fn with_sudo(cmdline: &mut Vec<&str>) {
     if !nix::unistd::geteuid().is_root() {
         cmdline.insert(0, "sudo");
         cmdline.insert(1, "-n");
     }
+}
+
+pub fn run_as_root(mut cmdline: Vec<&str>) -> Result<Output> {
+    with_sudo(&mut cmdline);
     debug!("Running command: {}", cmdline.join(" "));
 
     let cmd = cmdline.remove(0);
@@ -16,3 +20,11 @@ pub fn run_as_root(mut cmdline: Vec<&str>) -> Result<Output> {
         Ok(out) => Ok(out),
     }
 }
+
+/// Like [`run_as_root`] for commands that run in the background, the caller spawns it
+pub fn root_command(mut cmdline: Vec<&str>) -> Command {
+    with_sudo(&mut cmdline);
+    let mut cmd = Command::new(cmdline.remove(0));
+    cmd.args(cmdline);
+    cmd
+}
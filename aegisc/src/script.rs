@@ -0,0 +1,118 @@
+//! Runs the scripts that the admin signed, for incident response
+
+use crate::run_as::root_command;
+use aegislib::command::device::ScriptResult;
+use aegislib::command::server::Script;
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use std::os::unix::process::CommandExt;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::spawn;
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+const PATH: &str = "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+/// Processes left behind by the script may keep its output open, we don't wait for them
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+/// Runs `script` with `/bin/sh` as root, in a clean environment
+pub async fn run(script: Script) -> ScriptResult {
+    let mut result = ScriptResult {
+        run_id: script.run_id,
+        exit_code: None,
+        timed_out: false,
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+        truncated: false,
+    };
+    if let Err(e) = script.check() {
+        result.stderr = format!("Refused to run script: {e}").into_bytes();
+        return result;
+    }
+    info!("Running script {}", script.run_id);
+
+    let env: Vec<String> = script
+        .env
+        .iter()
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect();
+    let mut cmdline = vec!["env", "-i", PATH];
+    cmdline.extend(env.iter().map(String::as_str));
+    cmdline.extend(["/bin/sh", "-s"]);
+    let mut cmd = root_command(cmdline);
+    // Its own process group, so a timeout kills everything the script started
+    cmd.process_group(0);
+    let spawned = Command::from(cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) => {
+            result.stderr = format!("Failed to start script: {e}").into_bytes();
+            return result;
+        }
+    };
+
+    let mut stdin = child.stdin.take().unwrap();
+    let source = script.source;
+    let stdin_task = spawn(async move {
+        let _ = stdin.write_all(source.as_bytes()).await;
+    });
+    let mut stdout_task = spawn(read_limited(child.stdout.take().unwrap()));
+    let mut stderr_task = spawn(read_limited(child.stderr.take().unwrap()));
+
+    let limit = Duration::from_secs(script.timeout_secs as u64);
+    match timeout(limit, child.wait()).await {
+        Ok(Ok(status)) => result.exit_code = status.code(),
+        Ok(Err(e)) => warn!("Failed to wait for script {}: {e}", result.run_id),
+        Err(_) => {
+            warn!("Script {} timed out, killing it", result.run_id);
+            result.timed_out = true;
+            if let Some(pid) = child.id() {
+                let _ = killpg(Pid::from_raw(pid as i32), Signal::SIGKILL);
+            }
+            let _ = child.kill().await;
+        }
+    }
+    stdin_task.abort();
+
+    for (task, output) in [
+        (&mut stdout_task, &mut result.stdout),
+        (&mut stderr_task, &mut result.stderr),
+    ] {
+        match timeout(OUTPUT_GRACE, &mut *task).await {
+            Ok(Ok((data, truncated))) => {
+                *output = data;
+                result.truncated |= truncated;
+            }
+            _ => {
+                task.abort();
+                result.truncated = true;
+            }
+        }
+    }
+    result
+}
+
+/// Keeps the first `Script::MAX_OUTPUT_LEN` bytes, and drains the rest so the script never blocks
+async fn read_limited(mut pipe: impl AsyncRead + Unpin) -> (Vec<u8>, bool) {
+    let mut data = Vec::new();
+    let mut truncated = false;
+    let mut buf = [0; 8192];
+    loop {
+        let n = match pipe.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let room = Script::MAX_OUTPUT_LEN - data.len();
+        truncated |= n > room;
+        data.extend_from_slice(&buf[..n.min(room)]);
+    }
+    (data, truncated)
+}
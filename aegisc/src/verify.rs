@@ -0,0 +1,251 @@
+//! Checks that server commands really come from the admin, see `aegislib::command::signed`
+
+use aegislib::command::server::{
+    CapturePolicy, DeviceProfile, FileFetch, PowerCommand, Script, ServerCommand, StatusUpdate,
+};
+use aegislib::command::signed::{AdminCommand, Validity, MAX_CLOCK_SKEW, MAX_COMMAND_AGE};
+use aegislib::crypto::VerifyingKey;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tracing::{error, warn};
+
+/// What a device with a root key starts with until the admin signs something else
+const FULLY_LOCKED: StatusUpdate = StatusUpdate {
+    vt_locked: true,
+    ssh_locked: true,
+    draw_decoy: true,
+};
+
+pub fn path_for(device_key_path: &Path) -> PathBuf {
+    device_key_path.with_file_name("verified.toml")
+}
+
+/// What a server command asks us to do, once we've decided to trust it
+pub enum Action {
+    Status(StatusUpdate),
+    Power(PowerCommand),
+    /// Start a protection profile, or stop the active one
+    Profile(Option<DeviceProfile>),
+    CapturePolicy(CapturePolicy),
+    /// Take a webcam picture if our VT is locked
+    Capture,
+    RotateKey,
+    RunScript(Script),
+    /// Send the files under our allowed prefixes
+    FetchFiles(FileFetch),
+}
+
+/// What we checked so far, saved so a restart doesn't make us forget it
+#[derive(Default, Serialize, Deserialize)]
+struct VerifiedState {
+    /// The last status we accepted
+    status: Option<StatusUpdate>,
+    /// Signatures of the commands we already accepted, until they expire
+    #[serde(default)]
+    seen: Vec<SeenCommand>,
+    /// Scripts we already ran, a fresh signature doesn't make us run one twice
+    #[serde(default)]
+    ran_scripts: Vec<RanScript>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SeenCommand {
+    signature: Vec<u8>,
+    expires_at: u64,
+}
+
+/// Kept until the command that ran the script expires, replaying it after that fails anyway
+#[derive(Serialize, Deserialize)]
+struct RanScript {
+    run_id: u64,
+    expires_at: u64,
+}
+
+pub struct CommandVerifier {
+    root_pk: Option<VerifyingKey>,
+    /// Changes when we rotate our key, admins sign commands for the current one
+    device_pk: watch::Receiver<VerifyingKey>,
+    path: PathBuf,
+    state: VerifiedState,
+}
+
+impl CommandVerifier {
+    /// Loads what we verified before restarting from `path`
+    pub fn new(
+        root_pk: Option<VerifyingKey>,
+        device_pk: watch::Receiver<VerifyingKey>,
+        path: PathBuf,
+    ) -> Self {
+        let state = match std::fs::read_to_string(&path) {
+            Ok(data) => toml::from_str(&data).unwrap_or_else(|e| {
+                warn!("Ignoring invalid {}: {e}", path.display());
+                VerifiedState::default()
+            }),
+            Err(_) => VerifiedState::default(),
+        };
+        Self {
+            root_pk,
+            device_pk,
+            path,
+            state,
+        }
+    }
+
+    /// The status to apply when we start, given the one the server has. That one isn't signed,
+    /// so with a root key we only take it if it doesn't unlock anything since the last status
+    /// we accepted, or since being fully locked if we never accepted any.
+    pub fn initial_status(&mut self, server_status: StatusUpdate) -> Result<StatusUpdate, String> {
+        if self.root_pk.is_some() {
+            let baseline = self.state.status.unwrap_or(FULLY_LOCKED);
+            if baseline.is_loosened_by(&server_status) {
+                return Err(format!(
+                    "Rejected unsigned startup status that unlocks the device: {server_status:?}"
+                ));
+            }
+        }
+        self.state.status = Some(server_status);
+        self.save();
+        Ok(server_status)
+    }
+
+    /// The status to fall back on when [`Self::initial_status`] rejects the server's
+    pub fn last_status(&self) -> StatusUpdate {
+        self.state.status.unwrap_or(FULLY_LOCKED)
+    }
+
+    /// Decides what to do with `cmd` given our `current` status, or says why it looks forged.
+    /// Without a root key we trust the server, like before commands were signed.
+    pub fn check(&mut self, cmd: ServerCommand, current: StatusUpdate) -> Result<Action, String> {
+        let action = self.action(cmd, current)?;
+        match &action {
+            Action::Status(status) => self.state.status = Some(*status),
+            Action::Profile(Some(profile)) => self.state.status = Some(profile.status),
+            _ => {}
+        }
+        self.save();
+        Ok(action)
+    }
+
+    fn save(&mut self) {
+        let now = unix_now();
+        self.state.seen.retain(|seen| seen.expires_at > now);
+        self.state.ran_scripts.retain(|ran| ran.expires_at > now);
+        // A crash mid-write must not leave us with a truncated state, which resets to unlocked
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let saved = toml::to_string(&self.state)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(std::fs::write(&tmp_path, data)?))
+            .and_then(|()| Ok(std::fs::rename(&tmp_path, &self.path)?));
+        if let Err(e) = saved {
+            error!(
+                "Failed to save verified state to {}: {e}",
+                self.path.display()
+            );
+        }
+    }
+
+    fn action(&mut self, cmd: ServerCommand, current: StatusUpdate) -> Result<Action, String> {
+        let root_pk = match &self.root_pk {
+            Some(root_pk) => root_pk,
+            None => return Self::trusted_action(cmd, current),
+        };
+        match cmd {
+            ServerCommand::Signed(signed) => {
+                let device_pk = *self.device_pk.borrow();
+                let payload = signed
+                    .verify(root_pk, &device_pk, SystemTime::now())
+                    .map_err(|e| format!("Rejected signed command: {e}"))?;
+                let seen = &mut self.state.seen;
+                if seen.iter().any(|seen| seen.signature == signed.signature) {
+                    return Err("Rejected replayed signed command".into());
+                }
+                let Validity::Once { issued_at } = payload.validity;
+                let expires_at = issued_at + (MAX_COMMAND_AGE + MAX_CLOCK_SKEW).as_secs();
+                if let AdminCommand::RunScript(script) = &payload.command {
+                    let ran_scripts = &mut self.state.ran_scripts;
+                    if ran_scripts.iter().any(|ran| ran.run_id == script.run_id) {
+                        return Err(format!("Rejected script {} we already ran", script.run_id));
+                    }
+                    ran_scripts.push(RanScript {
+                        run_id: script.run_id,
+                        expires_at,
+                    });
+                }
+                self.state.seen.push(SeenCommand {
+                    signature: signed.signature,
+                    expires_at,
+                });
+                Ok(Self::admin_action(payload.command, current))
+            }
+            // Locking down further is always safe, the server does it when we connect
+            ServerCommand::StatusUpdate(status) if !current.is_loosened_by(&status) => {
+                Ok(Action::Status(status))
+            }
+            ServerCommand::StatusUpdate(status) => Err(format!(
+                "Rejected unsigned status update that unlocks the device: {status:?}"
+            )),
+            // Like locking, powering off never gives anyone access. Automation rules send it.
+            ServerCommand::PowerCommand(PowerCommand::Poweroff) => {
+                Ok(Action::Power(PowerCommand::Poweroff))
+            }
+            ServerCommand::PowerCommand(cmd) => {
+                Err(format!("Rejected unsigned power command: {cmd:?}"))
+            }
+            // A new key never gives anyone more access, whoever asks for it
+            ServerCommand::RotateKey => Ok(Action::RotateKey),
+            // Unsigned, so only within the limits devices accept, and under our own upload quota
+            ServerCommand::CapturePolicy(policy) => Ok(Action::CapturePolicy(policy.clamped())),
+            // We only capture while locked anyway, like the capture policy's schedule
+            ServerCommand::Capture => Ok(Action::Capture),
+        }
+    }
+
+    fn trusted_action(cmd: ServerCommand, current: StatusUpdate) -> Result<Action, String> {
+        Ok(match cmd {
+            ServerCommand::StatusUpdate(status) => Action::Status(status),
+            ServerCommand::PowerCommand(cmd) => Action::Power(cmd),
+            ServerCommand::RotateKey => Action::RotateKey,
+            ServerCommand::CapturePolicy(policy) => Action::CapturePolicy(policy.clamped()),
+            ServerCommand::Capture => Action::Capture,
+            ServerCommand::Signed(signed) => {
+                warn!("No root public key configured, not checking the command signature");
+                let payload = signed
+                    .unverified_payload()
+                    .map_err(|e| format!("Invalid signed command: {e}"))?;
+                // Trusting the server is one thing, letting it run anything as root or read our
+                // files is another
+                match payload.command {
+                    AdminCommand::RunScript(script) => Err(format!(
+                        "Rejected script {}, there is no root public key to verify it",
+                        script.run_id
+                    ))?,
+                    AdminCommand::FetchFiles(fetch) => Err(format!(
+                        "Rejected file fetch {}, there is no root public key to verify it",
+                        fetch.fetch_id
+                    ))?,
+                    command => Self::admin_action(command, current),
+                }
+            }
+        })
+    }
+
+    fn admin_action(cmd: AdminCommand, current: StatusUpdate) -> Action {
+        match cmd {
+            AdminCommand::SetStatus(change) => Action::Status(change.apply_to(current)),
+            AdminCommand::Power(cmd) => Action::Power(cmd),
+            AdminCommand::SetProfile(profile) => Action::Profile(profile),
+            AdminCommand::RunScript(script) => Action::RunScript(script),
+            AdminCommand::FetchFiles(fetch) => Action::FetchFiles(fetch),
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -1,5 +1,6 @@
 use anyhow::{bail, Result};
-use image::{ImageBuffer, Rgb};
+use image::codecs::jpeg::JpegEncoder;
+use image::{ColorType, ImageBuffer, Rgb};
 use rscam::Camera;
 
 fn find_res_or_better(cam: &Camera, res: (u32, u32)) -> Result<(u32, u32)> {
@@ -55,3 +56,10 @@ pub fn capture_webcam_picture() -> Result<ImageBuffer<Rgb<u8>, rscam::Frame>> {
     let frame = ImageBuffer::from_raw(frame.resolution.0, frame.resolution.1, frame).unwrap();
     Ok(frame)
 }
+
+pub fn capture_webcam_jpeg() -> Result<Vec<u8>> {
+    let pic = capture_webcam_picture()?;
+    let mut jpeg_data = Vec::new();
+    JpegEncoder::new(&mut jpeg_data).encode(&pic, pic.width(), pic.height(), ColorType::Rgb8)?;
+    Ok(jpeg_data)
+}
@@ -7,11 +7,152 @@ pub use delete_pending::delete_pending;
 mod confirm_pending;
 pub use confirm_pending::confirm_pending;
 
+mod enrollment;
+pub use enrollment::{create_token, delete_token, import_devices, list_tokens, token_pending};
+
 mod list_registered;
 pub use list_registered::list_registered;
 
-mod delete_registered;
-pub use delete_registered::delete_registered;
+mod archive;
+pub use archive::{archive_device, list_archived, purge_device, restore_device};
+
+mod rotate_key;
+pub use rotate_key::rotate_key;
+
+mod device_info;
+pub use device_info::{rename_device, set_device_info};
 
 mod set_status;
-pub use set_status::set_status;
+pub use set_status::{cancel_schedule, set_status, status_history, status_schedules};
+
+mod profiles;
+pub use profiles::{apply_profile, clear_profile, delete_profile, profiles, set_profile};
+
+mod capture_policy;
+pub use capture_policy::{capture_policy, set_capture_policy};
+
+mod rules;
+pub use rules::{delete_rule, rule_log, rules, set_rule};
+
+mod scripts;
+pub use scripts::{run_script, script_output, script_runs};
+
+mod fetches;
+pub use fetches::{delete_fetch, download_fetch, fetch_files, file_fetches};
+
+mod watch;
+pub use watch::watch;
+
+mod retention;
+pub use retention::{retention, set_retention, set_stolen};
+
+mod events;
+pub use events::events;
+
+mod pictures;
+pub use pictures::pictures;
+
+mod picture_info;
+pub use picture_info::picture_info;
+
+mod picture;
+pub use picture::{delete_picture, picture};
+
+mod export;
+pub use export::export;
+
+use aegislib::command::device::EventLogLevel;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local};
+use clap::ArgMatches;
+
+fn parse_bool(s: &str) -> Result<bool> {
+    let s = s.to_lowercase();
+    if s == "1" || s == "y" || s == "yes" || s == "t" || s == "true" {
+        Ok(true)
+    } else if s == "0" || s == "n" || s == "no" || s == "f" || s == "false" {
+        Ok(false)
+    } else {
+        bail!("Invalid boolean value: {}", s);
+    }
+}
+
+fn parse_level(s: &str) -> Result<EventLogLevel> {
+    Ok(match s.to_lowercase().as_str() {
+        "trace" => EventLogLevel::Trace,
+        "debug" => EventLogLevel::Debug,
+        "info" => EventLogLevel::Info,
+        "warn" => EventLogLevel::Warn,
+        "error" => EventLogLevel::Error,
+        _ => bail!("Invalid level: {}", s),
+    })
+}
+
+/// Accepts either a unix timestamp or an RFC 3339 date
+fn parse_time(s: &str) -> Result<u64> {
+    if let Ok(timestamp) = s.parse() {
+        return Ok(timestamp);
+    }
+    match DateTime::parse_from_rfc3339(s) {
+        Ok(time) if time.timestamp() >= 0 => Ok(time.timestamp() as u64),
+        _ => bail!(
+            "Invalid time: {} (expected a unix timestamp or RFC 3339)",
+            s
+        ),
+    }
+}
+
+/// Accepts a number of seconds, or a number followed by s, m, h or d
+fn parse_duration(s: &str) -> Result<u64> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 24 * 3600,
+        _ => bail!("Invalid duration: {} (expected e.g. 90s, 30m, 2h or 1d)", s),
+    };
+    match number.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(n * unit),
+        _ => bail!("Invalid duration: {} (expected e.g. 90s, 30m, 2h or 1d)", s),
+    }
+}
+
+/// The reverse of `parse_duration`
+fn format_interval(secs: Option<u32>) -> String {
+    match secs {
+        None => "never".to_owned(),
+        Some(secs) if secs % 3600 == 0 => format!("{}h", secs / 3600),
+        Some(secs) if secs % 60 == 0 => format!("{}m", secs / 60),
+        Some(secs) => format!("{secs}s"),
+    }
+}
+
+fn parse_interval(args: &ArgMatches, name: &str) -> Result<Option<u32>> {
+    let Some(value) = args.get_one::<String>(name) else {
+        return Ok(None);
+    };
+    let secs = parse_duration(value)?;
+    Ok(Some(u32::try_from(secs).context("Interval is too long")?))
+}
+
+/// Parses a `HH:MM` time of day into minutes after midnight
+fn parse_time_of_day(s: &str) -> Result<u16> {
+    let parsed = s
+        .split_once(':')
+        .and_then(|(h, m)| Some((h.parse::<u16>().ok()?, m.parse::<u16>().ok()?)));
+    match parsed {
+        Some((h, m)) if h < 24 && m < 60 => Ok(h * 60 + m),
+        _ => bail!("Invalid time of day: {} (expected HH:MM)", s),
+    }
+}
+
+fn format_time(timestamp: u64) -> String {
+    match DateTime::from_timestamp(timestamp as i64, 0) {
+        Some(time) => time.with_timezone(&Local).format("%F %T").to_string(),
+        None => timestamp.to_string(),
+    }
+}
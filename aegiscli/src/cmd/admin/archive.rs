@@ -0,0 +1,64 @@
+use crate::config::Config;
+use aegislib::client::AdminClient;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use cli_table::{print_stdout, Cell, Style, Table};
+
+pub async fn archive_device(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let name: &String = args.get_one("name").unwrap();
+    client.archive_device(name.to_owned()).await?;
+    println!("Device '{name}' archived, use purge-device to delete its pictures and events");
+    Ok(())
+}
+
+pub async fn list_archived(
+    _config: &Config,
+    mut client: AdminClient,
+    _args: &ArgMatches,
+) -> Result<()> {
+    let devices = client.list_archived().await?;
+    let table = devices
+        .into_iter()
+        .map(|archived| {
+            vec![
+                archived.device.pubkey,
+                archived.device.name,
+                format!("{:?}", DateTime::<Utc>::from(archived.device.created_at)),
+                format!("{:?}", DateTime::<Utc>::from(archived.archived_at)),
+            ]
+        })
+        .table()
+        .title(vec![
+            "Pubkey".cell().bold(true),
+            "Name".cell().bold(true),
+            "Created at".cell().bold(true),
+            "Archived at".cell().bold(true),
+        ]);
+    print_stdout(table)?;
+    Ok(())
+}
+
+pub async fn restore_device(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let name: &String = args.get_one("name").unwrap();
+    client.restore_device(name.to_owned()).await?;
+    Ok(())
+}
+
+pub async fn purge_device(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let name: &String = args.get_one("name").unwrap();
+    client.purge_device(name.to_owned()).await?;
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use crate::cmd::admin::{format_interval, parse_duration, parse_interval};
+use crate::config::Config;
+use aegislib::client::AdminClient;
+use aegislib::command::admin::SetCapturePolicyArg;
+use aegislib::command::server::CapturePolicy;
+use anyhow::{Context, Result};
+use clap::ArgMatches;
+
+pub async fn capture_policy(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let name: &String = args.get_one("name").unwrap();
+    let policy = client.get_capture_policy(name.to_owned()).await?;
+    println!(
+        "Webcam picture every: {}",
+        format_interval(policy.webcam_interval_secs)
+    );
+    println!(
+        "Screenshot every: {}",
+        format_interval(policy.screenshot_interval_secs)
+    );
+    println!(
+        "On input: {} pictures, {}s apart, then ignore input for {}s",
+        policy.burst_count, policy.burst_interval_secs, policy.input_cooldown_secs
+    );
+    println!("Max uploads per hour: {}", policy.max_uploads_per_hour);
+    Ok(())
+}
+
+pub async fn set_capture_policy(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let name: &String = args.get_one("name").unwrap();
+    let policy = if args.get_flag("default") {
+        None
+    } else {
+        let default = CapturePolicy::default();
+        let secs = |name: &str, default: u32| -> Result<u32> {
+            match args.get_one::<String>(name) {
+                Some(value) if value == "0" => Ok(0),
+                Some(value) => u32::try_from(parse_duration(value)?).context("Too long"),
+                None => Ok(default),
+            }
+        };
+        Some(CapturePolicy {
+            webcam_interval_secs: parse_interval(args, "webcam-every")?,
+            screenshot_interval_secs: parse_interval(args, "screenshot-every")?,
+            burst_count: args
+                .get_one::<u32>("burst")
+                .copied()
+                .unwrap_or(default.burst_count),
+            burst_interval_secs: secs("burst-interval", default.burst_interval_secs)?,
+            input_cooldown_secs: secs("cooldown", default.input_cooldown_secs)?,
+            max_uploads_per_hour: args
+                .get_one::<u32>("max-uploads-per-hour")
+                .copied()
+                .unwrap_or(default.max_uploads_per_hour),
+        })
+    };
+    if let Some(policy) = &policy {
+        policy.check()?;
+    }
+    client
+        .set_capture_policy(SetCapturePolicyArg {
+            dev_name: name.to_owned(),
+            policy,
+        })
+        .await
+}
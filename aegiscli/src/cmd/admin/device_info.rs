@@ -0,0 +1,36 @@
+use crate::config::Config;
+use aegislib::client::AdminClient;
+use aegislib::command::admin::SetDeviceInfoArg;
+use anyhow::Result;
+use clap::ArgMatches;
+
+pub async fn rename_device(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let name: &String = args.get_one("name").unwrap();
+    let new_name: &String = args.get_one("new_name").unwrap();
+    client
+        .rename_device(name.to_owned(), new_name.to_owned())
+        .await?;
+    Ok(())
+}
+
+pub async fn set_device_info(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let name: &String = args.get_one("name").unwrap();
+    let arg = SetDeviceInfoArg {
+        dev_name: name.to_owned(),
+        owner: args.get_one("owner").cloned(),
+        contact: args.get_one("contact").cloned(),
+        notes: args.get_one("notes").cloned(),
+        serial_number: args.get_one("serial").cloned(),
+        hardware_model: args.get_one("model").cloned(),
+    };
+    client.set_device_info(arg).await?;
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use crate::cmd::admin::{format_time, parse_time};
+use crate::config::Config;
+use aegislib::client::AdminClient;
+use aegislib::command::admin::{CreateEnrollmentTokenArg, ImportedDevice};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use cli_table::{print_stdout, Cell, Style, Table};
+use std::path::PathBuf;
+
+pub async fn create_token(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let label: &String = args.get_one("label").unwrap();
+    let created = client
+        .create_enrollment_token(CreateEnrollmentTokenArg {
+            label: label.to_owned(),
+            max_uses: args.get_one::<u32>("max-uses").copied(),
+            expires_at_timestamp: args
+                .get_one::<String>("expires")
+                .map(|s| parse_time(s))
+                .transpose()?,
+            auto_approve: args.get_flag("auto-approve"),
+        })
+        .await?;
+    println!(
+        "Created enrollment token {}, it won't be shown again:",
+        created.id
+    );
+    println!("{}", created.token);
+    Ok(())
+}
+
+pub async fn list_tokens(
+    _config: &Config,
+    mut client: AdminClient,
+    _args: &ArgMatches,
+) -> Result<()> {
+    let tokens = client.list_enrollment_tokens().await?;
+    let table = tokens
+        .into_iter()
+        .map(|token| {
+            vec![
+                token.id.to_string(),
+                token.label,
+                format_time(token.created_at_timestamp),
+                token
+                    .expires_at_timestamp
+                    .map(format_time)
+                    .unwrap_or_else(|| "never".to_owned()),
+                match token.max_uses {
+                    Some(max_uses) => format!("{}/{max_uses}", token.uses),
+                    None => token.uses.to_string(),
+                },
+                token.auto_approve.to_string(),
+                token.pending_devices.to_string(),
+            ]
+        })
+        .table()
+        .title(vec![
+            "ID".cell().bold(true),
+            "Label".cell().bold(true),
+            "Created at".cell().bold(true),
+            "Expires at".cell().bold(true),
+            "Uses".cell().bold(true),
+            "Auto-approve".cell().bold(true),
+            "Pending".cell().bold(true),
+        ]);
+    print_stdout(table)?;
+    Ok(())
+}
+
+pub async fn delete_token(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let id: i32 = *args.get_one("id").unwrap();
+    client.delete_enrollment_token(id).await
+}
+
+pub async fn token_pending(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let id: i32 = *args.get_one("id").unwrap();
+    let pending = client.list_token_pending(id).await?;
+    let table = pending
+        .into_iter()
+        .map(|dev| {
+            vec![
+                dev.pubkey,
+                dev.name,
+                format!("{}", DateTime::<Utc>::from(dev.created_at)),
+            ]
+        })
+        .table()
+        .title(vec![
+            "Pubkey".cell().bold(true),
+            "Name".cell().bold(true),
+            "Created at".cell().bold(true),
+        ]);
+    print_stdout(table)?;
+    Ok(())
+}
+
+/// Reads one `name pubkey` pair per line, blank lines and lines starting with # are skipped
+fn parse_device_list(contents: &str) -> Result<Vec<ImportedDevice>> {
+    let mut devices = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(name), Some(pubkey), None) => devices.push(ImportedDevice {
+                name: name.to_owned(),
+                pubkey: pubkey.to_owned(),
+            }),
+            _ => bail!("Line {}: expected a device name and public key", i + 1),
+        }
+    }
+    Ok(devices)
+}
+
+pub async fn import_devices(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let path: &PathBuf = args.get_one("file").unwrap();
+    let devices = parse_device_list(&std::fs::read_to_string(path)?)?;
+    let reply = client.import_devices(devices).await?;
+    println!("Imported {} devices", reply.imported);
+    for name in reply.conflicts {
+        println!("Skipped {name}, the name or key is already registered");
+    }
+    Ok(())
+}
@@ -0,0 +1,40 @@
+use crate::cmd::admin::{format_time, parse_level, parse_time};
+use crate::config::Config;
+use aegislib::client::AdminClient;
+use aegislib::command::admin::GetEventsArg;
+use anyhow::Result;
+use clap::ArgMatches;
+
+pub async fn events(_config: &Config, mut client: AdminClient, args: &ArgMatches) -> Result<()> {
+    let arg = GetEventsArg {
+        dev_name: args.get_one::<String>("name").unwrap().clone(),
+        since: args
+            .get_one::<String>("since")
+            .map(|s| parse_time(s))
+            .transpose()?,
+        until: args
+            .get_one::<String>("until")
+            .map(|s| parse_time(s))
+            .transpose()?,
+        min_level: args
+            .get_one::<String>("level")
+            .map(|s| parse_level(s))
+            .transpose()?,
+        search: args.get_one::<String>("search").cloned(),
+        cursor: args.get_one::<String>("cursor").cloned(),
+        limit: args.get_one::<u32>("limit").copied(),
+    };
+    let page = client.list_device_events(arg).await?;
+    for event in page.events {
+        println!(
+            "{} {:?}: {}",
+            format_time(event.timestamp),
+            event.level,
+            event.message
+        );
+    }
+    if let Some(cursor) = page.next_cursor {
+        println!("More events available, continue with --cursor {cursor}");
+    }
+    Ok(())
+}
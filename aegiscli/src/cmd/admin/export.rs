@@ -0,0 +1,155 @@
+use crate::config::Config;
+use aegislib::client::AdminClient;
+use aegislib::command::admin::PictureIdArg;
+use aegislib::crypto::evidence::EvidenceBundle;
+use aegislib::crypto::sha256_hex;
+use anyhow::{bail, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::ArgMatches;
+use serde_json::json;
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+fn rfc3339(timestamp: u64) -> String {
+    match DateTime::from_timestamp(timestamp as i64, 0) {
+        Some(time) => time.to_rfc3339_opts(SecondsFormat::Secs, true),
+        None => timestamp.to_string(),
+    }
+}
+
+fn rfc3339_system(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn to_json(value: serde_json::Value) -> Result<Vec<u8>> {
+    let mut data = serde_json::to_vec_pretty(&value)?;
+    data.push(b'\n');
+    Ok(data)
+}
+
+pub async fn export(_config: &Config, mut client: AdminClient, args: &ArgMatches) -> Result<()> {
+    let name: &String = args.get_one("name").unwrap();
+    let output = args.get_one::<PathBuf>("output").unwrap();
+    let export = client.export_device(name.to_owned()).await?;
+
+    let mut bundle = EvidenceBundle::new();
+    let device = &export.device;
+    bundle.add(
+        "device.json",
+        to_json(json!({
+            "id": device.id,
+            "name": device.name,
+            "pubkey": device.pubkey,
+            "created_at": rfc3339_system(device.created_at),
+            "archived_at": export.archived_at.map(rfc3339_system),
+            "owner": device.owner,
+            "contact": device.contact,
+            "notes": device.notes,
+            "serial_number": device.serial_number,
+            "hardware_model": device.hardware_model,
+            "active_profile": device.active_profile,
+            "exported_at": rfc3339_system(export.exported_at),
+        }))?,
+    )?;
+    let status = &export.status;
+    let history: Vec<_> = export
+        .status_history
+        .iter()
+        .map(|entry| {
+            json!({
+                "changed_at": rfc3339(entry.changed_at_timestamp),
+                "changed_by": entry.changed_by,
+                "vt_locked": entry.vt_locked,
+                "ssh_locked": entry.ssh_locked,
+                "draw_decoy": entry.draw_decoy,
+            })
+        })
+        .collect();
+    bundle.add(
+        "status.json",
+        to_json(json!({
+            "current": {
+                "updated_at": rfc3339(status.updated_at_timestamp),
+                "is_connected": status.is_connected,
+                "vt_locked": status.vt_locked,
+                "ssh_locked": status.ssh_locked,
+                "draw_decoy": status.draw_decoy,
+            },
+            "history": history,
+        }))?,
+    )?;
+    let connections: Vec<_> = export
+        .connections
+        .iter()
+        .map(|connection| {
+            json!({
+                "connected_at": rfc3339(connection.connected_at_timestamp),
+                "disconnected_at": connection.disconnected_at_timestamp.map(rfc3339),
+                "remote_addr": connection.remote_addr,
+            })
+        })
+        .collect();
+    bundle.add("connections.json", to_json(json!(connections))?)?;
+    let events: Vec<_> = export
+        .events
+        .iter()
+        .map(|event| {
+            json!({
+                "timestamp": rfc3339(event.timestamp),
+                "level": format!("{:?}", event.level),
+                "message": event.message,
+            })
+        })
+        .collect();
+    bundle.add("events.json", to_json(json!(events))?)?;
+
+    let mut pictures = Vec::with_capacity(export.pictures.len());
+    for picture in &export.pictures {
+        let info = &picture.info;
+        let arg = PictureIdArg {
+            dev_name: name.to_owned(),
+            id: info.id,
+        };
+        let data = client.get_device_camera_picture(arg).await?.jpeg_data;
+        if sha256_hex(&data) != picture.sha256 {
+            bail!("Picture {} changed while it was being exported", info.id);
+        }
+        let path = format!("pictures/{}.jpg", info.id);
+        pictures.push(json!({
+            "id": info.id,
+            "file": path,
+            "created_at": rfc3339(info.created_at_timestamp),
+            "width": info.width,
+            "height": info.height,
+            "trigger": format!("{:?}", info.trigger),
+            "sha256": picture.sha256,
+        }));
+        bundle.add(path, data)?;
+    }
+    bundle.add("pictures.json", to_json(json!(pictures))?)?;
+
+    let files = client.sign_evidence(bundle);
+    let root = format!("{}-evidence", name.replace(['/', '\\'], "_"));
+    let mut archive = tar::Builder::new(File::create(output)?);
+    let mtime = export
+        .exported_at
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+    for (path, data) in &files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        header.set_cksum();
+        archive.append_data(&mut header, format!("{root}/{path}"), data.as_slice())?;
+    }
+    archive.into_inner()?.sync_all()?;
+    println!(
+        "Exported {} events and {} pictures to {}",
+        export.events.len(),
+        export.pictures.len(),
+        output.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use crate::cmd::admin::format_time;
+use crate::config::Config;
+use aegislib::client::AdminClient;
+use aegislib::command::admin::FileFetchIdArg;
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Component, Path, PathBuf};
+
+pub async fn fetch_files(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let dev_name = args.get_one::<String>("name").unwrap().to_owned();
+    let paths = args.get_many::<String>("paths").unwrap().cloned().collect();
+    let encrypt = args.get_flag("encrypt");
+    let fetch_id = client.fetch_files(dev_name, paths, encrypt).await?;
+    println!("Requested files, fetch id {fetch_id}");
+    Ok(())
+}
+
+pub async fn file_fetches(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let dev_name = args.get_one::<String>("name").unwrap().to_owned();
+    for fetch in client.list_file_fetches(dev_name).await? {
+        let state = match fetch.finished_at_timestamp {
+            Some(finished_at) => format!("finished {}", format_time(finished_at)),
+            None => "in progress".to_owned(),
+        };
+        let encrypted = if fetch.encrypted { ", encrypted" } else { "" };
+        println!(
+            "Fetch {} requested {} ({state}{encrypted}): {}",
+            fetch.fetch_id,
+            format_time(fetch.requested_at_timestamp),
+            fetch.paths.join(", ")
+        );
+        for file in fetch.files {
+            let progress = match (&file.error, file.complete) {
+                (Some(e), _) => format!("failed: {e}"),
+                (None, true) => format!("{} bytes", file.size),
+                (None, false) => format!("{} chunks of {} bytes", file.chunks, file.size),
+            };
+            println!("  {} ({progress})", file.path);
+        }
+    }
+    Ok(())
+}
+
+/// Where a fetched file goes under `dir`, refusing paths that would land outside of it
+fn output_path(dir: &Path, path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path.trim_start_matches('/'));
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        bail!("Refusing to write fetched file {path:?}");
+    }
+    Ok(dir.join(relative))
+}
+
+pub async fn download_fetch(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let dev_name = args.get_one::<String>("name").unwrap();
+    let fetch_id = *args.get_one::<u64>("fetch_id").unwrap();
+    let dir = args.get_one::<PathBuf>("dir").unwrap();
+    let fetches = client.list_file_fetches(dev_name.to_owned()).await?;
+    let Some(fetch) = fetches.into_iter().find(|f| f.fetch_id == fetch_id) else {
+        bail!("No file fetch {fetch_id} for {dev_name}");
+    };
+    for file in &fetch.files {
+        if !file.complete {
+            eprintln!("Skipping {}, it wasn't completely received", file.path);
+            continue;
+        }
+        let output = output_path(dir, &file.path)?;
+        if let Some(parent) = output.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut writer = BufWriter::new(
+            File::create(&output)
+                .with_context(|| format!("Failed to create {}", output.display()))?,
+        );
+        if let Err(e) = client
+            .download_fetched_file(dev_name, &fetch, file, &mut writer)
+            .await
+        {
+            drop(writer);
+            let _ = std::fs::remove_file(&output);
+            return Err(e.context(format!("Failed to download {}", file.path)));
+        }
+        writer.into_inner()?;
+        println!("{}", output.display());
+    }
+    Ok(())
+}
+
+pub async fn delete_fetch(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let arg = FileFetchIdArg {
+        dev_name: args.get_one::<String>("name").unwrap().to_owned(),
+        fetch_id: *args.get_one::<u64>("fetch_id").unwrap(),
+    };
+    client.delete_file_fetch(arg).await
+}
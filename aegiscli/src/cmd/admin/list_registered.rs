@@ -19,6 +19,12 @@ pub async fn list_registered(
                 BASE64_URL_SAFE_NO_PAD.encode(dev.pubkey),
                 dev.name,
                 format!("{:?}", DateTime::<Utc>::from(dev.created_at)),
+                dev.owner.unwrap_or_default(),
+                dev.contact.unwrap_or_default(),
+                dev.serial_number.unwrap_or_default(),
+                dev.hardware_model.unwrap_or_default(),
+                dev.active_profile.unwrap_or_default(),
+                dev.notes.unwrap_or_default(),
             ]
         })
         .table()
@@ -26,6 +32,12 @@ pub async fn list_registered(
             "Pubkey".cell().bold(true),
             "Name".cell().bold(true),
             "Created at".cell().bold(true),
+            "Owner".cell().bold(true),
+            "Contact".cell().bold(true),
+            "Serial".cell().bold(true),
+            "Model".cell().bold(true),
+            "Profile".cell().bold(true),
+            "Notes".cell().bold(true),
         ]);
     print_stdout(table)?;
     Ok(())
@@ -0,0 +1,33 @@
+use crate::config::Config;
+use aegislib::client::AdminClient;
+use aegislib::command::admin::PictureIdArg;
+use anyhow::Result;
+use clap::ArgMatches;
+use std::path::PathBuf;
+
+pub async fn picture(_config: &Config, mut client: AdminClient, args: &ArgMatches) -> Result<()> {
+    let arg = PictureIdArg {
+        dev_name: args.get_one::<String>("name").unwrap().clone(),
+        id: *args.get_one::<i32>("id").unwrap(),
+    };
+    let output = args.get_one::<PathBuf>("output").unwrap();
+    let pic = if args.get_flag("thumbnail") {
+        client.get_device_picture_thumbnail(arg).await?
+    } else {
+        client.get_device_camera_picture(arg).await?
+    };
+    std::fs::write(output, pic.jpeg_data)?;
+    Ok(())
+}
+
+pub async fn delete_picture(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let arg = PictureIdArg {
+        dev_name: args.get_one::<String>("name").unwrap().clone(),
+        id: *args.get_one::<i32>("id").unwrap(),
+    };
+    client.delete_device_camera_picture(arg).await
+}
@@ -0,0 +1,57 @@
+use crate::cmd::admin::{format_time, parse_time};
+use crate::config::Config;
+use aegislib::client::AdminClient;
+use aegislib::command::admin::GetPicturesArg;
+use anyhow::Result;
+use clap::ArgMatches;
+use cli_table::{print_stdout, Cell, Style, Table};
+
+pub async fn picture_info(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let arg = GetPicturesArg {
+        dev_name: args.get_one::<String>("name").unwrap().clone(),
+        since: args
+            .get_one::<String>("since")
+            .map(|s| parse_time(s))
+            .transpose()?,
+        until: args
+            .get_one::<String>("until")
+            .map(|s| parse_time(s))
+            .transpose()?,
+        cursor: args.get_one::<String>("cursor").cloned(),
+        limit: args.get_one::<u32>("limit").copied(),
+    };
+    let page = client.list_device_picture_info(arg).await?;
+    let table = page
+        .pictures
+        .into_iter()
+        .map(|pic| {
+            let resolution = match (pic.width, pic.height) {
+                (Some(w), Some(h)) => format!("{w}x{h}"),
+                _ => "?".to_string(),
+            };
+            vec![
+                pic.id.to_string(),
+                format_time(pic.created_at_timestamp),
+                format!("{}kiB", pic.size / 1024),
+                resolution,
+                format!("{:?}", pic.trigger),
+            ]
+        })
+        .table()
+        .title(vec![
+            "ID".cell().bold(true),
+            "Taken at".cell().bold(true),
+            "Size".cell().bold(true),
+            "Resolution".cell().bold(true),
+            "Trigger".cell().bold(true),
+        ]);
+    print_stdout(table)?;
+    if let Some(cursor) = page.next_cursor {
+        println!("More pictures available, continue with --cursor {cursor}");
+    }
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use crate::cmd::admin::parse_time;
+use crate::config::Config;
+use aegislib::client::AdminClient;
+use aegislib::command::admin::GetPicturesArg;
+use anyhow::Result;
+use clap::ArgMatches;
+use std::path::PathBuf;
+
+pub async fn pictures(_config: &Config, mut client: AdminClient, args: &ArgMatches) -> Result<()> {
+    let dev_name = args.get_one::<String>("name").unwrap();
+    let output = args.get_one::<PathBuf>("output").unwrap();
+    let arg = GetPicturesArg {
+        dev_name: dev_name.clone(),
+        since: args
+            .get_one::<String>("since")
+            .map(|s| parse_time(s))
+            .transpose()?,
+        until: args
+            .get_one::<String>("until")
+            .map(|s| parse_time(s))
+            .transpose()?,
+        cursor: args.get_one::<String>("cursor").cloned(),
+        limit: args.get_one::<u32>("limit").copied(),
+    };
+    let page = client.list_device_camera_pictures(arg).await?;
+    std::fs::create_dir_all(output)?;
+    for (i, pic) in page.pictures.iter().enumerate() {
+        // Several pictures can be taken in the same second
+        let path = output.join(format!("{dev_name}-{}-{i}.jpg", pic.created_at_timestamp));
+        std::fs::write(&path, &pic.jpeg_data)?;
+        println!("{}", path.display());
+    }
+    if let Some(cursor) = page.next_cursor {
+        println!("More pictures available, continue with --cursor {cursor}");
+    }
+    Ok(())
+}
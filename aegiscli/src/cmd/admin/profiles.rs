@@ -0,0 +1,92 @@
+use crate::cmd::admin::{format_interval, parse_bool, parse_interval};
+use crate::config::Config;
+use aegislib::client::AdminClient;
+use aegislib::command::admin::ProtectionProfile;
+use anyhow::Result;
+use clap::ArgMatches;
+use cli_table::{print_stdout, Cell, Style, Table};
+
+pub async fn profiles(_config: &Config, mut client: AdminClient, _args: &ArgMatches) -> Result<()> {
+    let profiles = client.list_profiles().await?;
+    let table = profiles
+        .into_iter()
+        .map(|p| {
+            vec![
+                p.name,
+                p.vt_locked.to_string(),
+                p.ssh_locked.to_string(),
+                p.draw_decoy.to_string(),
+                format_interval(p.capture_interval_secs),
+                format_interval(p.telemetry_interval_secs),
+            ]
+        })
+        .table()
+        .title(vec![
+            "Name".cell().bold(true),
+            "VT locked".cell().bold(true),
+            "SSH locked".cell().bold(true),
+            "Draw decoy".cell().bold(true),
+            "Capture every".cell().bold(true),
+            "Telemetry every".cell().bold(true),
+        ]);
+    print_stdout(table)?;
+    Ok(())
+}
+
+pub async fn set_profile(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let flag = |name: &str| {
+        args.get_one::<String>(name)
+            .map(|s| parse_bool(s))
+            .transpose()
+            .map(Option::unwrap_or_default)
+    };
+    let profile = ProtectionProfile {
+        name: args.get_one::<String>("profile").unwrap().to_owned(),
+        vt_locked: flag("vt-lock")?,
+        ssh_locked: flag("ssh-lock")?,
+        draw_decoy: flag("draw-decoy")?,
+        capture_interval_secs: parse_interval(args, "capture-every")?,
+        telemetry_interval_secs: parse_interval(args, "telemetry-every")?,
+    };
+    client.set_profile(profile).await
+}
+
+pub async fn delete_profile(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let name: &String = args.get_one("profile").unwrap();
+    client.delete_profile(name.to_owned()).await
+}
+
+pub async fn apply_profile(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let name: &String = args.get_one("name").unwrap();
+    let profile: &String = args.get_one("profile").unwrap();
+    let status = client
+        .apply_profile(name.to_owned(), profile.to_owned())
+        .await?;
+    println!("New device status: {status:#?}");
+    if !status.is_connected {
+        println!("The device is offline, it starts the profile's behaviors only if applied again");
+    }
+    Ok(())
+}
+
+pub async fn clear_profile(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let name: &String = args.get_one("name").unwrap();
+    client.clear_profile(name.to_owned()).await?;
+    Ok(())
+}
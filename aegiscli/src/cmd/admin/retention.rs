@@ -0,0 +1,63 @@
+use crate::cmd::admin::{parse_bool, parse_level};
+use crate::config::Config;
+use aegislib::client::AdminClient;
+use aegislib::command::admin::{RetentionPolicy, SetRetentionArg, SetStolenArg};
+use anyhow::Result;
+use clap::ArgMatches;
+
+pub async fn retention(_config: &Config, mut client: AdminClient, args: &ArgMatches) -> Result<()> {
+    let name: &String = args.get_one("name").unwrap();
+    let retention = client.get_device_retention(name.to_owned()).await?;
+    match retention.policy {
+        Some(policy) => println!("Retention policy: {policy:#?}"),
+        None => println!("Retention policy: server default"),
+    }
+    if retention.stolen {
+        println!("Flagged as stolen, nothing is pruned");
+    }
+    Ok(())
+}
+
+pub async fn set_retention(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let name: &String = args.get_one("name").unwrap();
+    let policy = if args.get_flag("default") {
+        None
+    } else {
+        Some(RetentionPolicy {
+            event_max_age_days: args.get_one::<u32>("event-max-age-days").copied(),
+            event_max_count: args.get_one::<u32>("event-max-count").copied(),
+            event_min_level: args
+                .get_one::<String>("event-min-level")
+                .map(|s| parse_level(s))
+                .transpose()?,
+            picture_max_age_days: args.get_one::<u32>("picture-max-age-days").copied(),
+            picture_max_count: args.get_one::<u32>("picture-max-count").copied(),
+            picture_max_bytes: args.get_one::<u64>("picture-max-bytes").copied(),
+        })
+    };
+    client
+        .set_device_retention(SetRetentionArg {
+            dev_name: name.to_owned(),
+            policy,
+        })
+        .await
+}
+
+pub async fn set_stolen(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let name: &String = args.get_one("name").unwrap();
+    let stolen = parse_bool(args.get_one::<String>("value").unwrap())?;
+    client
+        .set_device_stolen(SetStolenArg {
+            dev_name: name.to_owned(),
+            stolen,
+        })
+        .await
+}
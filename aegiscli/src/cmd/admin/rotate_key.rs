@@ -3,12 +3,12 @@ use aegislib::client::AdminClient;
 use anyhow::Result;
 use clap::ArgMatches;
 
-pub async fn delete_registered(
+pub async fn rotate_key(
     _config: &Config,
     mut client: AdminClient,
     args: &ArgMatches,
 ) -> Result<()> {
     let name: &String = args.get_one("name").unwrap();
-    client.delete_registered(name.to_owned()).await?;
+    client.request_key_rotation(name.to_owned()).await?;
     Ok(())
 }
@@ -0,0 +1,217 @@
+use crate::cmd::admin::{format_interval, format_time, parse_duration, parse_level};
+use crate::config::Config;
+use aegislib::client::AdminClient;
+use aegislib::command::admin::{AutomationRule, ListRuleExecutionsArg, RuleAction, RuleTrigger};
+use aegislib::command::device::CaptureTrigger;
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+use cli_table::{print_stdout, Cell, Style, Table};
+
+fn format_trigger(trigger: &RuleTrigger) -> String {
+    match trigger {
+        RuleTrigger::Event {
+            min_level,
+            contains: None,
+        } => format!("{min_level:?} event"),
+        RuleTrigger::Event {
+            min_level,
+            contains: Some(text),
+        } => format!("{min_level:?} event containing '{text}'"),
+        RuleTrigger::Picture { trigger: None } => "picture".to_owned(),
+        RuleTrigger::Picture {
+            trigger: Some(trigger),
+        } => format!("{trigger:?} picture"),
+        RuleTrigger::Connected {
+            while_locked,
+            new_address,
+        } => {
+            let mut text = "connected".to_owned();
+            if *new_address {
+                text += " from a new address";
+            }
+            if *while_locked {
+                text += " while locked";
+            }
+            text
+        }
+        RuleTrigger::Disconnected => "disconnected".to_owned(),
+        RuleTrigger::Offline { secs } => {
+            format!("offline for {}", format_interval(Some(*secs)))
+        }
+    }
+}
+
+fn format_action(action: &RuleAction) -> String {
+    match action {
+        RuleAction::Lock {
+            vt_locked,
+            ssh_locked,
+            draw_decoy,
+        } => {
+            let locks: Vec<_> = [
+                (*vt_locked, "VT"),
+                (*ssh_locked, "SSH"),
+                (*draw_decoy, "decoy"),
+            ]
+            .into_iter()
+            .filter_map(|(set, name)| set.then_some(name))
+            .collect();
+            format!("lock {}", locks.join(", "))
+        }
+        RuleAction::Poweroff => "poweroff".to_owned(),
+        RuleAction::Capture => "capture".to_owned(),
+        RuleAction::MarkStolen => "mark stolen".to_owned(),
+    }
+}
+
+fn parse_capture_trigger(s: &str) -> Result<CaptureTrigger> {
+    Ok(match s.to_lowercase().as_str() {
+        "input" => CaptureTrigger::InputWhileLocked,
+        "periodic" => CaptureTrigger::Periodic,
+        "scheduled" => CaptureTrigger::Scheduled,
+        "screenshot" => CaptureTrigger::Screenshot,
+        "requested" => CaptureTrigger::Requested,
+        _ => bail!("Invalid picture trigger: {}", s),
+    })
+}
+
+fn parse_trigger(args: &ArgMatches) -> Result<RuleTrigger> {
+    let on: &String = args.get_one("on").unwrap();
+    Ok(match on.as_str() {
+        "event" => RuleTrigger::Event {
+            min_level: parse_level(args.get_one::<String>("min-level").unwrap())?,
+            contains: args.get_one::<String>("contains").cloned(),
+        },
+        "picture" => RuleTrigger::Picture {
+            trigger: args
+                .get_one::<String>("picture-trigger")
+                .map(|s| parse_capture_trigger(s))
+                .transpose()?,
+        },
+        "connected" => RuleTrigger::Connected {
+            while_locked: args.get_flag("while-locked"),
+            new_address: args.get_flag("new-address"),
+        },
+        "disconnected" => RuleTrigger::Disconnected,
+        "offline" => {
+            let Some(duration) = args.get_one::<String>("offline-for") else {
+                bail!("Offline rules need --offline-for");
+            };
+            RuleTrigger::Offline {
+                secs: u32::try_from(parse_duration(duration)?).context("Too long")?,
+            }
+        }
+        _ => bail!("Invalid trigger: {}", on),
+    })
+}
+
+fn parse_action(args: &ArgMatches) -> Result<RuleAction> {
+    let then: &String = args.get_one("then").unwrap();
+    let draw_decoy = args.get_flag("draw-decoy");
+    Ok(match then.as_str() {
+        "lock" => RuleAction::Lock {
+            vt_locked: true,
+            ssh_locked: true,
+            draw_decoy,
+        },
+        "lock-vt" => RuleAction::Lock {
+            vt_locked: true,
+            ssh_locked: false,
+            draw_decoy,
+        },
+        "lock-ssh" => RuleAction::Lock {
+            vt_locked: false,
+            ssh_locked: true,
+            draw_decoy,
+        },
+        "poweroff" => RuleAction::Poweroff,
+        "capture" => RuleAction::Capture,
+        "mark-stolen" => RuleAction::MarkStolen,
+        _ => bail!("Invalid action: {}", then),
+    })
+}
+
+pub async fn rules(_config: &Config, mut client: AdminClient, _args: &ArgMatches) -> Result<()> {
+    let rules = client.list_rules().await?;
+    let table = rules
+        .into_iter()
+        .map(|rule| {
+            let threshold = match rule.threshold {
+                1 => "every time".to_owned(),
+                n => format!("{n} in {}", format_interval(Some(rule.window_secs))),
+            };
+            vec![
+                rule.name,
+                rule.dev_name.unwrap_or_else(|| "all".to_owned()),
+                format_trigger(&rule.trigger),
+                threshold,
+                format_action(&rule.action),
+                rule.enabled.to_string(),
+                rule.dry_run.to_string(),
+            ]
+        })
+        .table()
+        .title(vec![
+            "Name".cell().bold(true),
+            "Device".cell().bold(true),
+            "Trigger".cell().bold(true),
+            "Threshold".cell().bold(true),
+            "Action".cell().bold(true),
+            "Enabled".cell().bold(true),
+            "Dry run".cell().bold(true),
+        ]);
+    print_stdout(table)?;
+    Ok(())
+}
+
+pub async fn set_rule(_config: &Config, mut client: AdminClient, args: &ArgMatches) -> Result<()> {
+    let threshold = *args.get_one::<u32>("threshold").unwrap();
+    let window_secs = match args.get_one::<String>("window") {
+        Some(window) => u32::try_from(parse_duration(window)?).context("Too long")?,
+        None if threshold > 1 => bail!("A threshold needs a --window to count matches in"),
+        None => 0,
+    };
+    let rule = AutomationRule {
+        name: args.get_one::<String>("rule").unwrap().to_owned(),
+        dev_name: args.get_one::<String>("device").cloned(),
+        trigger: parse_trigger(args)?,
+        threshold,
+        window_secs,
+        action: parse_action(args)?,
+        enabled: !args.get_flag("disabled"),
+        dry_run: args.get_flag("dry-run"),
+    };
+    client.set_rule(rule).await
+}
+
+pub async fn delete_rule(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let name: &String = args.get_one("rule").unwrap();
+    client.delete_rule(name.to_owned()).await
+}
+
+pub async fn rule_log(_config: &Config, mut client: AdminClient, args: &ArgMatches) -> Result<()> {
+    let arg = ListRuleExecutionsArg {
+        rule_name: args.get_one::<String>("rule").cloned(),
+        limit: args.get_one::<u32>("limit").copied(),
+    };
+    let executions = client.list_rule_executions(arg).await?;
+    for execution in executions.into_iter().rev() {
+        let result = match (&execution.error, execution.dry_run) {
+            (_, true) => "dry run".to_owned(),
+            (None, false) => "ran".to_owned(),
+            (Some(e), false) => format!("failed: {e}"),
+        };
+        println!(
+            "{} {} on {}: {} ({result})",
+            format_time(execution.executed_at_timestamp),
+            execution.rule_name,
+            execution.dev_name,
+            format_action(&execution.action),
+        );
+    }
+    Ok(())
+}
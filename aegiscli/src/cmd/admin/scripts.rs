@@ -0,0 +1,108 @@
+use crate::cmd::admin::{format_time, parse_duration};
+use crate::config::Config;
+use aegislib::client::AdminClient;
+use aegislib::command::admin::ScriptRunIdArg;
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+use cli_table::{print_stdout, Cell, Style, Table};
+use std::io::Write;
+use std::path::PathBuf;
+
+fn parse_env(s: &str) -> Result<(String, String)> {
+    match s.split_once('=') {
+        Some((name, value)) => Ok((name.to_owned(), value.to_owned())),
+        None => bail!("Invalid environment variable: {} (expected NAME=value)", s),
+    }
+}
+
+pub async fn run_script(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let dev_name = args.get_one::<String>("name").unwrap().to_owned();
+    let path = args.get_one::<PathBuf>("file").unwrap();
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read script {}", path.display()))?;
+    let timeout = parse_duration(args.get_one::<String>("timeout").unwrap())?;
+    let env = args
+        .get_many::<String>("env")
+        .unwrap_or_default()
+        .map(|s| parse_env(s))
+        .collect::<Result<_>>()?;
+    let run_id = client
+        .run_script(
+            dev_name,
+            source,
+            u32::try_from(timeout).context("Too long")?,
+            env,
+        )
+        .await?;
+    println!("Sent script, run id {run_id}");
+    Ok(())
+}
+
+pub async fn script_runs(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let dev_name = args.get_one::<String>("name").unwrap().to_owned();
+    let runs = client.list_script_runs(dev_name).await?;
+    let table = runs
+        .into_iter()
+        .map(|run| {
+            let result = match (run.finished_at_timestamp, run.exit_code) {
+                (None, _) => "pending".to_owned(),
+                _ if run.timed_out => "timed out".to_owned(),
+                (Some(_), Some(code)) => format!("exit code {code}"),
+                (Some(_), None) => "failed".to_owned(),
+            };
+            vec![
+                run.run_id.to_string(),
+                format_time(run.requested_at_timestamp),
+                run.finished_at_timestamp
+                    .map(format_time)
+                    .unwrap_or_default(),
+                result,
+            ]
+        })
+        .table()
+        .title(vec![
+            "Run id".cell().bold(true),
+            "Requested".cell().bold(true),
+            "Finished".cell().bold(true),
+            "Result".cell().bold(true),
+        ]);
+    print_stdout(table)?;
+    Ok(())
+}
+
+pub async fn script_output(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let arg = ScriptRunIdArg {
+        dev_name: args.get_one::<String>("name").unwrap().to_owned(),
+        run_id: *args.get_one::<u64>("run_id").unwrap(),
+    };
+    let output = client.get_script_output(arg).await?;
+    if output.run.finished_at_timestamp.is_none() {
+        println!("Script {} is still running", output.run.run_id);
+        return Ok(());
+    }
+    std::io::stdout().write_all(&output.stdout)?;
+    std::io::stderr().write_all(&output.stderr)?;
+    if output.run.timed_out {
+        eprintln!("Timed out");
+    }
+    match output.run.exit_code {
+        Some(code) => eprintln!("Exit code: {code}"),
+        None => eprintln!("No exit code, the script was killed or failed to start"),
+    }
+    if output.truncated {
+        eprintln!("Some output was dropped");
+    }
+    Ok(())
+}
@@ -1,18 +1,13 @@
+use crate::cmd::admin::{format_time, parse_bool, parse_duration, parse_time, parse_time_of_day};
 use crate::config::Config;
 use aegislib::client::AdminClient;
-use aegislib::command::admin::SetStatusArg;
-use anyhow::{bail, Result};
+use aegislib::command::admin::{SetStatusArg, StatusSchedule, StatusScheduleIdArg};
+use anyhow::Result;
 use clap::ArgMatches;
+use cli_table::{print_stdout, Cell, Style, Table};
 
-fn parse_bool(s: &str) -> Result<bool> {
-    let s = s.to_lowercase();
-    if s == "1" || s == "y" || s == "yes" || s == "t" || s == "true" {
-        Ok(true)
-    } else if s == "0" || s == "n" || s == "no" || s == "f" || s == "false" {
-        Ok(false)
-    } else {
-        bail!("Invalid boolean value: {}", s);
-    }
+fn format_field(value: Option<bool>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
 }
 
 pub async fn set_status(
@@ -33,14 +28,114 @@ pub async fn set_status(
         .get_one::<String>("draw-decoy")
         .map(|s| parse_bool(s))
         .transpose()?;
+    let schedule = match (
+        args.get_one::<String>("at"),
+        args.get_one::<String>("daily"),
+    ) {
+        (Some(at), _) => Some(StatusSchedule::At {
+            timestamp: parse_time(at)?,
+        }),
+        (None, Some(daily)) => Some(StatusSchedule::Daily {
+            minute_of_day: parse_time_of_day(daily)?,
+        }),
+        (None, None) => None,
+    };
+    let expires_after_secs = args
+        .get_one::<String>("expires-in")
+        .map(|s| parse_duration(s))
+        .transpose()?;
     let status = client
         .set_status(SetStatusArg {
             dev_name: name.to_owned(),
             vt_locked,
             ssh_locked,
             draw_decoy,
+            schedule,
+            expires_after_secs,
         })
         .await?;
-    println!("New device status: {status:#?}");
+    match schedule {
+        Some(_) => println!("Status change scheduled, current device status: {status:#?}"),
+        None => println!("New device status: {status:#?}"),
+    }
+    Ok(())
+}
+
+pub async fn status_history(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let name: &String = args.get_one("name").unwrap();
+    let history = client.status_history(name.to_owned()).await?;
+    let table = history
+        .into_iter()
+        .map(|entry| {
+            vec![
+                format_time(entry.changed_at_timestamp),
+                entry.changed_by,
+                entry.vt_locked.to_string(),
+                entry.ssh_locked.to_string(),
+                entry.draw_decoy.to_string(),
+            ]
+        })
+        .table()
+        .title(vec![
+            "Changed at".cell().bold(true),
+            "Changed by".cell().bold(true),
+            "VT locked".cell().bold(true),
+            "SSH locked".cell().bold(true),
+            "Draw decoy".cell().bold(true),
+        ]);
+    print_stdout(table)?;
     Ok(())
 }
+
+pub async fn status_schedules(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let name: &String = args.get_one("name").unwrap();
+    let scheduled = client.list_status_schedules(name.to_owned()).await?;
+    let table = scheduled
+        .into_iter()
+        .map(|s| {
+            let kind = match (s.repeat_daily, s.is_expiry) {
+                (true, _) => "Daily",
+                (false, true) => "Expiry",
+                (false, false) => "Once",
+            };
+            vec![
+                s.id.to_string(),
+                format_time(s.apply_at_timestamp),
+                kind.to_owned(),
+                format_field(s.vt_locked),
+                format_field(s.ssh_locked),
+                format_field(s.draw_decoy),
+            ]
+        })
+        .table()
+        .title(vec![
+            "ID".cell().bold(true),
+            "Next change".cell().bold(true),
+            "Kind".cell().bold(true),
+            "VT locked".cell().bold(true),
+            "SSH locked".cell().bold(true),
+            "Draw decoy".cell().bold(true),
+        ]);
+    print_stdout(table)?;
+    Ok(())
+}
+
+pub async fn cancel_schedule(
+    _config: &Config,
+    mut client: AdminClient,
+    args: &ArgMatches,
+) -> Result<()> {
+    let arg = StatusScheduleIdArg {
+        dev_name: args.get_one::<String>("name").unwrap().clone(),
+        id: *args.get_one::<i32>("id").unwrap(),
+    };
+    client.cancel_status_schedule(arg).await
+}
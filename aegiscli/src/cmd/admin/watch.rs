@@ -0,0 +1,46 @@
+use crate::cmd::admin::{format_time, parse_level};
+use crate::config::Config;
+use aegislib::client::AdminClient;
+use aegislib::command::admin::{LiveEvent, SubscribeArg};
+use anyhow::{bail, Result};
+use clap::ArgMatches;
+
+pub async fn watch(_config: &Config, mut client: AdminClient, args: &ArgMatches) -> Result<()> {
+    let min_level = args
+        .get_one::<String>("level")
+        .map(|s| parse_level(s))
+        .transpose()?;
+    let filter = SubscribeArg {
+        dev_name: args.get_one::<String>("device").cloned(),
+        min_level,
+    };
+    let mut events = client.subscribe(filter).await?;
+    while let Some(event) = events.next().await? {
+        match event {
+            LiveEvent::Logged { dev_name, event } => println!(
+                "{} [{dev_name}] {:?}: {}",
+                format_time(event.timestamp),
+                event.level,
+                event.message
+            ),
+            LiveEvent::PictureUploaded {
+                dev_name,
+                timestamp,
+                size,
+            } => println!(
+                "{} [{dev_name}] Camera picture uploaded ({}kiB)",
+                format_time(timestamp),
+                size / 1024
+            ),
+            LiveEvent::Connected {
+                dev_name,
+                timestamp,
+            } => println!("{} [{dev_name}] Connected", format_time(timestamp)),
+            LiveEvent::Disconnected {
+                dev_name,
+                timestamp,
+            } => println!("{} [{dev_name}] Disconnected", format_time(timestamp)),
+        }
+    }
+    bail!("The server closed the event stream");
+}
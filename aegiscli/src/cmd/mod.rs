@@ -12,3 +12,6 @@ pub use derive_root_pubkey::derive_root_pubkey;
 
 mod derive_root_key_file;
 pub use derive_root_key_file::derive_root_key_file;
+
+mod verify_evidence;
+pub use verify_evidence::verify_evidence;
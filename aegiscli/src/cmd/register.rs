@@ -8,6 +8,7 @@ use std::path::PathBuf;
 pub async fn register(config: &Config, args: &ArgMatches) -> Result<()> {
     let name: &String = args.get_one("name").unwrap();
     let kp = sign_keypair_from_file(args.get_one::<PathBuf>("key").unwrap())?;
-    register_device(&config.into(), name, &kp.verifying_key()).await?;
+    let token = args.get_one::<String>("token").map(String::as_str);
+    register_device(&config.try_into()?, name, &kp.verifying_key(), token).await?;
     Ok(())
 }
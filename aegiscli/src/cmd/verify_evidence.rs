@@ -0,0 +1,54 @@
+use crate::config::Config;
+use aegislib::crypto::evidence;
+use aegislib::crypto::public_key_from_base64;
+use anyhow::{bail, Context, Result};
+use base64::prelude::*;
+use clap::ArgMatches;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+/// Reads the regular files of a bundle, without the top-level directory added by `admin export`
+fn read_bundle(path: &PathBuf) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut archive = tar::Archive::new(File::open(path)?);
+    let mut files = BTreeMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let entry_path = entry.path()?.to_string_lossy().into_owned();
+        let Some((_, bundle_path)) = entry_path.split_once('/') else {
+            bail!("{entry_path} is outside of the bundle's directory");
+        };
+        let bundle_path = bundle_path.to_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        if files.insert(bundle_path.clone(), data).is_some() {
+            bail!("Duplicate file in bundle: {bundle_path}");
+        }
+    }
+    Ok(files)
+}
+
+pub async fn verify_evidence(_config: &Config, args: &ArgMatches) -> Result<()> {
+    let path = args.get_one::<PathBuf>("bundle").unwrap();
+    let files = read_bundle(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let signer = evidence::verify(&files)?;
+    let signer = BASE64_URL_SAFE_NO_PAD.encode(signer.as_ref());
+    match args.get_one::<String>("pubkey") {
+        Some(expected) => {
+            let expected = public_key_from_base64(expected).context("Invalid --pubkey")?;
+            if BASE64_URL_SAFE_NO_PAD.encode(expected.as_ref()) != signer {
+                bail!("The bundle is intact, but was signed by {signer} instead");
+            }
+            println!("Valid, {} files signed by {signer}", files.len() - 3);
+        }
+        None => {
+            println!("Valid, {} files signed by {signer}", files.len() - 3);
+            println!("Check that this is your admin public key, or pass it with --pubkey");
+        }
+    }
+    Ok(())
+}
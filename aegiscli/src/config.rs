@@ -1,3 +1,4 @@
+use aegislib::client::read_pem;
 use serde::Deserialize;
 use std::path::{Path, PathBuf};
 
@@ -7,6 +8,13 @@ pub struct Config {
     pub use_tls: bool,
     #[serde(default)]
     pub use_rest: bool,
+    /// PEM files of CAs trusted in addition to the webpki roots
+    #[serde(default)]
+    pub extra_ca_certs: Vec<PathBuf>,
+    /// PEM file of the only server certificate we accept
+    pub pinned_server_cert: Option<PathBuf>,
+    /// Base64 SHA-256 of the server's DER SubjectPublicKeyInfo
+    pub pinned_spki_sha256: Option<String>,
 }
 
 impl Config {
@@ -25,12 +33,27 @@ pub fn default_path() -> PathBuf {
     dirs.config_dir().join("conf.toml")
 }
 
-impl From<&Config> for aegislib::client::ClientConfig {
-    fn from(config: &Config) -> Self {
-        Self {
+impl TryFrom<&Config> for aegislib::client::ClientConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(config: &Config) -> anyhow::Result<Self> {
+        Ok(Self {
             server_addr: config.server_addr.clone(),
             use_tls: config.use_tls,
             use_rest: config.use_rest,
-        }
+            extra_ca_certs_pem: config
+                .extra_ca_certs
+                .iter()
+                .map(|path| read_pem(path))
+                .collect::<anyhow::Result<_>>()?,
+            pinned_server_cert_pem: config
+                .pinned_server_cert
+                .as_deref()
+                .map(read_pem)
+                .transpose()?,
+            pinned_spki_sha256: config.pinned_spki_sha256.clone(),
+            // The encrypted channel is only for device websockets
+            server_public_key: None,
+        })
     }
 }
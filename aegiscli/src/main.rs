@@ -4,7 +4,7 @@ mod config;
 use aegislib::client::{AdminClient, DeviceClient};
 use aegislib::crypto::sign_keypair_from_file;
 use anyhow::{Context, Result};
-use clap::{arg, command, value_parser, Command};
+use clap::{arg, command, value_parser, ArgAction, Command};
 use std::path::PathBuf;
 
 #[tokio::main]
@@ -44,7 +44,17 @@ async fn main() -> Result<()> {
             Command::new("register")
                 .about("Register as a device pending validation by an admin")
                 .arg(arg!(<key> "The device private key file").value_parser(value_parser!(PathBuf)))
-                .arg(arg!(<name> "The device's name")),
+                .arg(arg!(<name> "The device's name"))
+                .arg(arg!(--token <token> "An enrollment token from an admin").required(false)),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Check the signature and hashes of an evidence bundle")
+                .arg(
+                    arg!(<bundle> "The tar file from admin export")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(arg!(--pubkey <key> "The admin public key that should have signed it").required(false)),
         )
         .subcommand(
             Command::new("admin")
@@ -64,10 +74,78 @@ async fn main() -> Result<()> {
                         .about("Confirm a device pending validation")
                         .arg(arg!(<name> "The device's name")),
                 )
+                .subcommand(
+                    Command::new("create-token")
+                        .about("Create an enrollment token that lets devices register")
+                        .arg(arg!(<label> "What the token is for"))
+                        .arg(
+                            arg!(--"max-uses" <count> "How many devices may register with it")
+                                .value_parser(value_parser!(u32))
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--expires <time> "Unix timestamp or RFC 3339 date")
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"auto-approve" "Confirm devices right away instead of queuing them")
+                                .required(false),
+                        ),
+                )
+                .subcommand(Command::new("list-tokens").about("List enrollment tokens"))
+                .subcommand(
+                    Command::new("delete-token")
+                        .about("Delete an enrollment token, its devices stay registered")
+                        .arg(arg!(<id> "The token's ID").value_parser(value_parser!(i32))),
+                )
+                .subcommand(
+                    Command::new("token-pending")
+                        .about("List devices pending validation that registered with a token")
+                        .arg(arg!(<id> "The token's ID").value_parser(value_parser!(i32))),
+                )
+                .subcommand(
+                    Command::new("import-devices")
+                        .about("Register devices from a file of `name pubkey` lines, already confirmed")
+                        .arg(arg!(<file> "The device list").value_parser(value_parser!(PathBuf))),
+                )
                 .subcommand(Command::new("list-device").about("List valid registered devices"))
                 .subcommand(
-                    Command::new("delete-device")
-                        .about("Delete a valid registered device")
+                    Command::new("archive-device")
+                        .visible_alias("delete-device")
+                        .about("Revoke a device's key, but keep its pictures and events")
+                        .arg(arg!(<name> "The device's name")),
+                )
+                .subcommand(Command::new("list-archived").about("List archived devices"))
+                .subcommand(
+                    Command::new("restore-device")
+                        .about("Let an archived device connect again")
+                        .arg(arg!(<name> "The device's name")),
+                )
+                .subcommand(
+                    Command::new("purge-device")
+                        .about("Permanently delete an archived device with its pictures and events")
+                        .arg(arg!(<name> "The device's name")),
+                )
+                .subcommand(
+                    Command::new("rename-device")
+                        .about("Rename a registered device")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(arg!(<new_name> "The device's new name")),
+                )
+                .subcommand(
+                    Command::new("set-device-info")
+                        .about("Update the owner, notes and asset info of a device")
+                        .after_help("Options left out are unchanged, an empty value clears one.")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(arg!(--owner <owner> "Who the device belongs to").required(false))
+                        .arg(arg!(--contact <contact> "How to reach the owner").required(false))
+                        .arg(arg!(--notes <notes> "Free-form notes").required(false))
+                        .arg(arg!(--serial <serial> "Serial number").required(false))
+                        .arg(arg!(--model <model> "Hardware model").required(false)),
+                )
+                .subcommand(
+                    Command::new("rotate-key")
+                        .about("Ask a connected device to replace its device key")
                         .arg(arg!(<name> "The device's name")),
                 )
                 .subcommand(
@@ -82,6 +160,343 @@ async fn main() -> Result<()> {
                         .arg(
                             arg!(--"draw-decoy" <value> "Use decoy TTY framebuffer")
                                 .required(false),
+                        )
+                        .arg(
+                            arg!(--at <time> "Apply the change later, unix timestamp or RFC 3339 date")
+                                .required(false)
+                                .conflicts_with("daily"),
+                        )
+                        .arg(
+                            arg!(--daily <time> "Apply the change every day at HH:MM UTC, only to lock")
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"expires-in" <duration> "Undo the change after e.g. 30m or 2h")
+                                .required(false)
+                                .conflicts_with("daily"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("status-history")
+                        .about("List every status a device went through, and who changed it")
+                        .arg(arg!(<name> "The device's name")),
+                )
+                .subcommand(
+                    Command::new("status-schedules")
+                        .about("List status changes waiting to be applied to a device")
+                        .arg(arg!(<name> "The device's name")),
+                )
+                .subcommand(
+                    Command::new("cancel-schedule")
+                        .about("Cancel a scheduled status change")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(arg!(<id> "The scheduled change's ID").value_parser(value_parser!(i32))),
+                )
+                .subcommand(Command::new("profiles").about("List protection profiles"))
+                .subcommand(
+                    Command::new("set-profile")
+                        .about("Create or replace a protection profile, e.g. for stolen devices")
+                        .arg(arg!(<profile> "The profile's name"))
+                        .arg(arg!(--"vt-lock" <value> "Lock the system onto a blank TTY").required(false))
+                        .arg(arg!(--"ssh-lock" <value> "Disable new SSH logins").required(false))
+                        .arg(arg!(--"draw-decoy" <value> "Use decoy TTY framebuffer").required(false))
+                        .arg(
+                            arg!(--"capture-every" <duration> "Take a webcam picture every e.g. 5m")
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"telemetry-every" <duration> "Log uptime, load and memory every e.g. 1h")
+                                .required(false),
+                        ),
+                )
+                .subcommand(
+                    Command::new("delete-profile")
+                        .about("Delete a protection profile, devices running it keep it")
+                        .arg(arg!(<profile> "The profile's name")),
+                )
+                .subcommand(
+                    Command::new("apply-profile")
+                        .about("Apply a protection profile's status to a device, and start its behaviors")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(arg!(<profile> "The profile's name")),
+                )
+                .subcommand(
+                    Command::new("clear-profile")
+                        .about("Stop the behaviors of a device's protection profile, keeping its status")
+                        .arg(arg!(<name> "The device's name")),
+                )
+                .subcommand(
+                    Command::new("events")
+                        .about("Print a page of events stored for a device, oldest first")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(arg!(--since <time> "Unix timestamp or RFC 3339 date").required(false))
+                        .arg(arg!(--until <time> "Unix timestamp or RFC 3339 date").required(false))
+                        .arg(
+                            arg!(--level <level> "Minimum level of events")
+                                .value_parser(["trace", "debug", "info", "warn", "error"])
+                                .required(false),
+                        )
+                        .arg(arg!(--search <text> "Only events containing this").required(false))
+                        .arg(
+                            arg!(--limit <count> "Maximum number of events")
+                                .value_parser(value_parser!(u32))
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--cursor <cursor> "Continue from a previous page").required(false),
+                        ),
+                )
+                .subcommand(
+                    Command::new("pictures")
+                        .about("Save a page of camera pictures stored for a device, oldest first")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(
+                            arg!(<output> "The destination folder")
+                                .value_parser(value_parser!(PathBuf)),
+                        )
+                        .arg(arg!(--since <time> "Unix timestamp or RFC 3339 date").required(false))
+                        .arg(arg!(--until <time> "Unix timestamp or RFC 3339 date").required(false))
+                        .arg(
+                            arg!(--limit <count> "Maximum number of pictures")
+                                .value_parser(value_parser!(u32))
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--cursor <cursor> "Continue from a previous page").required(false),
+                        ),
+                )
+                .subcommand(
+                    Command::new("picture-info")
+                        .about("List stored camera pictures of a device, without downloading them")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(arg!(--since <time> "Unix timestamp or RFC 3339 date").required(false))
+                        .arg(arg!(--until <time> "Unix timestamp or RFC 3339 date").required(false))
+                        .arg(
+                            arg!(--limit <count> "Maximum number of pictures")
+                                .value_parser(value_parser!(u32))
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--cursor <cursor> "Continue from a previous page").required(false),
+                        ),
+                )
+                .subcommand(
+                    Command::new("picture")
+                        .about("Save one stored camera picture")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(arg!(<id> "The picture's ID").value_parser(value_parser!(i32)))
+                        .arg(
+                            arg!(<output> "The destination file")
+                                .value_parser(value_parser!(PathBuf)),
+                        )
+                        .arg(arg!(--thumbnail "Only save a small preview").required(false)),
+                )
+                .subcommand(
+                    Command::new("delete-picture")
+                        .about("Delete one stored camera picture")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(arg!(<id> "The picture's ID").value_parser(value_parser!(i32))),
+                )
+                .subcommand(
+                    Command::new("export")
+                        .about("Save everything stored about a device as a signed evidence bundle")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(
+                            arg!(<output> "The destination tar file")
+                                .value_parser(value_parser!(PathBuf)),
+                        ),
+                )
+                .subcommand(
+                    Command::new("retention")
+                        .about("Show the retention policy of a device")
+                        .arg(arg!(<name> "The device's name")),
+                )
+                .subcommand(
+                    Command::new("set-retention")
+                        .about("Replace the server's retention policy for a device, unset limits keep everything")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(arg!(--default "Go back to the server's policy").required(false))
+                        .arg(
+                            arg!(--"event-max-age-days" <days> "Prune older events")
+                                .value_parser(value_parser!(u32))
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"event-max-count" <count> "Only keep the most recent events")
+                                .value_parser(value_parser!(u32))
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"event-min-level" <level> "Prune less severe events")
+                                .value_parser(["trace", "debug", "info", "warn", "error"])
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"picture-max-age-days" <days> "Prune older pictures")
+                                .value_parser(value_parser!(u32))
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"picture-max-count" <count> "Only keep the most recent pictures")
+                                .value_parser(value_parser!(u32))
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"picture-max-bytes" <bytes> "Only keep the most recent pictures up to this size")
+                                .value_parser(value_parser!(u64))
+                                .required(false),
+                        ),
+                )
+                .subcommand(
+                    Command::new("capture-policy")
+                        .about("Show how a device captures intruders while it is locked")
+                        .arg(arg!(<name> "The device's name")),
+                )
+                .subcommand(
+                    Command::new("set-capture-policy")
+                        .about("Replace the capture policy of a device, unset options use the defaults")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(arg!(--default "Go back to the default policy").required(false))
+                        .arg(
+                            arg!(--"webcam-every" <duration> "Take a webcam picture this often while locked, e.g. 5m")
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"screenshot-every" <duration> "Take a screenshot this often while locked")
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--burst <count> "Webcam pictures to take after input while locked")
+                                .value_parser(value_parser!(u32))
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"burst-interval" <duration> "Time between the pictures of a burst")
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--cooldown <duration> "Ignore input for this long after a burst")
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(--"max-uploads-per-hour" <count> "The server refuses pictures beyond this")
+                                .value_parser(value_parser!(u32))
+                                .required(false),
+                        ),
+                )
+                .subcommand(Command::new("rules").about("List automation rules"))
+                .subcommand(
+                    Command::new("set-rule")
+                        .about("Create or replace an automation rule, which reacts to what devices do")
+                        .arg(arg!(<rule> "The rule's name"))
+                        .arg(arg!(--device <name> "Only apply to this device, instead of all").required(false))
+                        .arg(
+                            arg!(--on <trigger> "What the rule reacts to")
+                                .value_parser(["event", "picture", "connected", "disconnected", "offline"]),
+                        )
+                        .arg(
+                            arg!(--"min-level" <level> "With --on event, only events at least this severe")
+                                .default_value("warn"),
+                        )
+                        .arg(arg!(--contains <text> "With --on event, only events containing this").required(false))
+                        .arg(
+                            arg!(--"picture-trigger" <trigger> "With --on picture, only input, periodic, scheduled, screenshot or requested pictures")
+                                .required(false),
+                        )
+                        .arg(arg!(--"while-locked" "With --on connected, only while the device is locked").required(false))
+                        .arg(arg!(--"new-address" "With --on connected, only from an IP never seen before").required(false))
+                        .arg(arg!(--"offline-for" <duration> "With --on offline, how long e.g. 2h").required(false))
+                        .arg(
+                            arg!(--then <action> "What the rule does")
+                                .value_parser(["lock", "lock-vt", "lock-ssh", "poweroff", "capture", "mark-stolen"]),
+                        )
+                        .arg(arg!(--"draw-decoy" "With a lock action, also draw the decoy").required(false))
+                        .arg(
+                            arg!(--threshold <count> "Only fire after this many matches")
+                                .value_parser(value_parser!(u32))
+                                .default_value("1"),
+                        )
+                        .arg(arg!(--window <duration> "Count matches over this long, e.g. 10m").required(false))
+                        .arg(arg!(--disabled "Keep the rule without running it").required(false))
+                        .arg(arg!(--"dry-run" "Only log what the rule would do").required(false)),
+                )
+                .subcommand(
+                    Command::new("delete-rule")
+                        .about("Delete an automation rule, its executions stay logged")
+                        .arg(arg!(<rule> "The rule's name")),
+                )
+                .subcommand(
+                    Command::new("rule-log")
+                        .about("Print the most recent automation rule executions, oldest first")
+                        .arg(arg!(--rule <rule> "Only executions of this rule").required(false))
+                        .arg(
+                            arg!(--limit <count> "How many executions to print")
+                                .value_parser(value_parser!(u32))
+                                .required(false),
+                        ),
+                )
+                .subcommand(
+                    Command::new("run-script")
+                        .about("Run a signed shell script as root on a connected device, needs a root key")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(arg!(<file> "The script to run").value_parser(value_parser!(PathBuf)))
+                        .arg(arg!(--timeout <duration> "Kill the script after this long, e.g. 90s").default_value("5m"))
+                        .arg(
+                            arg!(--env <var> "Set an environment variable, as NAME=value")
+                                .action(ArgAction::Append)
+                                .required(false),
+                        ),
+                )
+                .subcommand(
+                    Command::new("script-runs")
+                        .about("List the scripts sent to a device, oldest first")
+                        .arg(arg!(<name> "The device's name")),
+                )
+                .subcommand(
+                    Command::new("script-output")
+                        .about("Print the output and exit code of a script run")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(arg!(<run_id> "The run id printed by run-script").value_parser(value_parser!(u64))),
+                )
+                .subcommand(
+                    Command::new("fetch-files")
+                        .about("Fetch files from a connected device, under the prefixes its config allows")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(arg!(<paths> ... "Absolute paths, directories are fetched with their content"))
+                        .arg(arg!(--encrypt "Have the device encrypt the files so only the root key opens them").required(false)),
+                )
+                .subcommand(
+                    Command::new("file-fetches")
+                        .about("List the files fetched from a device, with their progress")
+                        .arg(arg!(<name> "The device's name")),
+                )
+                .subcommand(
+                    Command::new("download-fetch")
+                        .about("Download the completely received files of a fetch, decrypting them if needed")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(arg!(<fetch_id> "The fetch id printed by fetch-files").value_parser(value_parser!(u64)))
+                        .arg(arg!(<dir> "Files are written under this directory, by their path on the device").value_parser(value_parser!(PathBuf))),
+                )
+                .subcommand(
+                    Command::new("delete-fetch")
+                        .about("Delete a fetch and the files received for it")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(arg!(<fetch_id> "The fetch id printed by fetch-files").value_parser(value_parser!(u64))),
+                )
+                .subcommand(
+                    Command::new("set-stolen")
+                        .about("Flag a device as stolen, which preserves all its events and pictures")
+                        .arg(arg!(<name> "The device's name"))
+                        .arg(arg!(<value> "Whether the device is stolen")),
+                )
+                .subcommand(
+                    Command::new("watch")
+                        .about("Print live events from devices as they happen")
+                        .arg(arg!(--device <name> "Only events about this device").required(false))
+                        .arg(
+                            arg!(--level <level> "Minimum level of device log events")
+                                .value_parser(["trace", "debug", "info", "warn", "error"])
+                                .required(false),
                         ),
                 ),
         )
@@ -114,10 +529,11 @@ async fn main() -> Result<()> {
         ("derive-root-key-file", sub_args) => cmd::derive_root_key_file(config, sub_args).await,
         ("derive-root-pubkey", sub_args) => cmd::derive_root_pubkey(config, sub_args).await,
         ("register", sub_args) => cmd::register(config, sub_args).await,
+        ("verify", sub_args) => cmd::verify_evidence(config, sub_args).await,
         ("admin", admin_args) => {
             let root_keys = std::fs::read(admin_args.get_one::<PathBuf>("key").unwrap())?;
             let root_keys = bincode::deserialize(&root_keys)?;
-            let client = AdminClient::new(&config.into(), &root_keys).await?;
+            let client = AdminClient::new(&config.try_into()?, &root_keys).await?;
             match admin_args.subcommand().unwrap() {
                 ("list-pending", sub_args) => {
                     cmd::admin::list_pending(config, client, sub_args).await
@@ -128,19 +544,119 @@ async fn main() -> Result<()> {
                 ("confirm-pending", sub_args) => {
                     cmd::admin::confirm_pending(config, client, sub_args).await
                 }
+                ("create-token", sub_args) => {
+                    cmd::admin::create_token(config, client, sub_args).await
+                }
+                ("list-tokens", sub_args) => {
+                    cmd::admin::list_tokens(config, client, sub_args).await
+                }
+                ("delete-token", sub_args) => {
+                    cmd::admin::delete_token(config, client, sub_args).await
+                }
+                ("token-pending", sub_args) => {
+                    cmd::admin::token_pending(config, client, sub_args).await
+                }
+                ("import-devices", sub_args) => {
+                    cmd::admin::import_devices(config, client, sub_args).await
+                }
                 ("list-device", sub_args) => {
                     cmd::admin::list_registered(config, client, sub_args).await
                 }
-                ("delete-device", sub_args) => {
-                    cmd::admin::delete_registered(config, client, sub_args).await
+                ("archive-device", sub_args) => {
+                    cmd::admin::archive_device(config, client, sub_args).await
+                }
+                ("list-archived", sub_args) => {
+                    cmd::admin::list_archived(config, client, sub_args).await
+                }
+                ("restore-device", sub_args) => {
+                    cmd::admin::restore_device(config, client, sub_args).await
+                }
+                ("purge-device", sub_args) => {
+                    cmd::admin::purge_device(config, client, sub_args).await
                 }
+                ("rename-device", sub_args) => {
+                    cmd::admin::rename_device(config, client, sub_args).await
+                }
+                ("set-device-info", sub_args) => {
+                    cmd::admin::set_device_info(config, client, sub_args).await
+                }
+                ("rotate-key", sub_args) => cmd::admin::rotate_key(config, client, sub_args).await,
                 ("set-status", sub_args) => cmd::admin::set_status(config, client, sub_args).await,
+                ("status-history", sub_args) => {
+                    cmd::admin::status_history(config, client, sub_args).await
+                }
+                ("status-schedules", sub_args) => {
+                    cmd::admin::status_schedules(config, client, sub_args).await
+                }
+                ("cancel-schedule", sub_args) => {
+                    cmd::admin::cancel_schedule(config, client, sub_args).await
+                }
+                ("profiles", sub_args) => cmd::admin::profiles(config, client, sub_args).await,
+                ("set-profile", sub_args) => {
+                    cmd::admin::set_profile(config, client, sub_args).await
+                }
+                ("delete-profile", sub_args) => {
+                    cmd::admin::delete_profile(config, client, sub_args).await
+                }
+                ("apply-profile", sub_args) => {
+                    cmd::admin::apply_profile(config, client, sub_args).await
+                }
+                ("clear-profile", sub_args) => {
+                    cmd::admin::clear_profile(config, client, sub_args).await
+                }
+                ("events", sub_args) => cmd::admin::events(config, client, sub_args).await,
+                ("pictures", sub_args) => cmd::admin::pictures(config, client, sub_args).await,
+                ("picture-info", sub_args) => {
+                    cmd::admin::picture_info(config, client, sub_args).await
+                }
+                ("picture", sub_args) => cmd::admin::picture(config, client, sub_args).await,
+                ("delete-picture", sub_args) => {
+                    cmd::admin::delete_picture(config, client, sub_args).await
+                }
+                ("export", sub_args) => cmd::admin::export(config, client, sub_args).await,
+                ("retention", sub_args) => cmd::admin::retention(config, client, sub_args).await,
+                ("set-retention", sub_args) => {
+                    cmd::admin::set_retention(config, client, sub_args).await
+                }
+                ("set-stolen", sub_args) => cmd::admin::set_stolen(config, client, sub_args).await,
+                ("capture-policy", sub_args) => {
+                    cmd::admin::capture_policy(config, client, sub_args).await
+                }
+                ("set-capture-policy", sub_args) => {
+                    cmd::admin::set_capture_policy(config, client, sub_args).await
+                }
+                ("rules", sub_args) => cmd::admin::rules(config, client, sub_args).await,
+                ("set-rule", sub_args) => cmd::admin::set_rule(config, client, sub_args).await,
+                ("delete-rule", sub_args) => {
+                    cmd::admin::delete_rule(config, client, sub_args).await
+                }
+                ("rule-log", sub_args) => cmd::admin::rule_log(config, client, sub_args).await,
+                ("run-script", sub_args) => cmd::admin::run_script(config, client, sub_args).await,
+                ("script-runs", sub_args) => {
+                    cmd::admin::script_runs(config, client, sub_args).await
+                }
+                ("script-output", sub_args) => {
+                    cmd::admin::script_output(config, client, sub_args).await
+                }
+                ("fetch-files", sub_args) => {
+                    cmd::admin::fetch_files(config, client, sub_args).await
+                }
+                ("file-fetches", sub_args) => {
+                    cmd::admin::file_fetches(config, client, sub_args).await
+                }
+                ("download-fetch", sub_args) => {
+                    cmd::admin::download_fetch(config, client, sub_args).await
+                }
+                ("delete-fetch", sub_args) => {
+                    cmd::admin::delete_fetch(config, client, sub_args).await
+                }
+                ("watch", sub_args) => cmd::admin::watch(config, client, sub_args).await,
                 _ => unreachable!(),
             }
         }
         ("device", dev_args) => {
             let dev_key = sign_keypair_from_file(dev_args.get_one::<PathBuf>("key").unwrap())?;
-            let client = DeviceClient::new(&config.into(), dev_key, None)
+            let client = DeviceClient::new(&config.try_into()?, dev_key, None)
                 .await
                 .map_err(|(_, e)| e)?;
             match dev_args.subcommand().unwrap() {
@@ -84,7 +84,7 @@ pub fn device_handler(args: TokenStream, input: TokenStream) -> TokenStream {
     let input_block = &input.block;
 
     let outer_fn = quote! {
-        pub async fn #http_fn_ident(axum::extract::State(db): axum::extract::State<sqlx::Pool<sqlx::Postgres>>,
+        pub async fn #http_fn_ident(axum::extract::State(db): axum::extract::State<crate::db::DbPool>,
                                     req: axum::http::Request<axum::body::Body>) -> Result<Bytes, crate::error::Error> {
             let dev_id = *req.extensions()
                              .get::<DeviceId>()
@@ -96,7 +96,7 @@ pub fn device_handler(args: TokenStream, input: TokenStream) -> TokenStream {
                 handler: |db, id, arg| Box::pin(#handler_fn_ident(db, id, arg)),
             });
 
-            pub async fn #handler_fn_ident(db: sqlx::PgPool, dev_id: DeviceId, body: Bytes) -> Result<Bytes, crate::error::Error> {
+            pub async fn #handler_fn_ident(db: crate::db::DbPool, dev_id: DeviceId, body: Bytes) -> Result<Bytes, crate::error::Error> {
                 let args: #input_arg_ty = bincode::deserialize(body.as_ref()).map_err(|e| {
                     crate::error::Error::Response(axum::http::StatusCode::BAD_REQUEST, format!("Invalid argument: {}", e))
                 })?;
@@ -108,7 +108,7 @@ pub fn device_handler(args: TokenStream, input: TokenStream) -> TokenStream {
                     #input_block
                 }
 
-                #input_fn_ident(&mut *conn, dev_id, args)
+                #input_fn_ident(&mut conn, dev_id, args)
                     .await
                     .map(|r| Bytes::from(bincode::serialize(&r).unwrap()))
                     .map_err(|e| match e.downcast_ref::<sqlx::Error>() {
@@ -168,9 +168,9 @@ pub fn admin_handler(args: TokenStream, input: TokenStream) -> TokenStream {
             let args: () = bincode::deserialize_from(body_buf.reader()).map_err(|_| {
                 crate::error::Error::Response(axum::http::StatusCode::BAD_REQUEST, format!("Failed to deserialize payload"))
             })?;
-            #input_fn_ident(&mut *conn)
+            #input_fn_ident(&mut conn)
         )
-    } else if args.len() == 2 {
+    } else if args.len() == 2 || args.len() == 3 {
         let input_arg = match &args[1] {
             syn::FnArg::Receiver(_) => {
                 return quote_spanned! {
@@ -181,22 +181,37 @@ pub fn admin_handler(args: TokenStream, input: TokenStream) -> TokenStream {
             syn::FnArg::Typed(ty) => ty,
         };
         let input_arg_ty = &input_arg.ty;
-        quote!(
-            #body_buf;
-            let args: #input_arg_ty = bincode::deserialize_from(body_buf.reader()).map_err(|e| {
-                crate::error::Error::Response(axum::http::StatusCode::BAD_REQUEST, format!("Invalid argument: {}", e))
-            })?;
-            #input_fn_ident(&mut *conn, args)
-        )
+        if args.len() == 2 {
+            quote!(
+                #body_buf;
+                let args: #input_arg_ty = bincode::deserialize_from(body_buf.reader()).map_err(|e| {
+                    crate::error::Error::Response(axum::http::StatusCode::BAD_REQUEST, format!("Invalid argument: {}", e))
+                })?;
+                #input_fn_ident(&mut conn, args)
+            )
+        } else {
+            // The handler also takes the admin's protocol, and may read an older argument shape
+            quote!(
+                let protocol = req.extensions()
+                                  .get::<aegislib::protocol::PeerProtocol>()
+                                  .cloned()
+                                  .expect("Missing peer protocol in admin request handler");
+                #body_buf;
+                let args = <#input_arg_ty as crate::protocol::AdminArg>::read(body_buf.reader(), &protocol).map_err(|e| {
+                    crate::error::Error::Response(axum::http::StatusCode::BAD_REQUEST, format!("Invalid argument: {}", e))
+                })?;
+                #input_fn_ident(&mut conn, args, protocol)
+            )
+        }
     } else {
         return quote_spanned! {
-            args.span() => compile_error!("admin_handlers take two argument: A db handle, and a deserializable Arg struct");
+            args.span() => compile_error!("admin_handlers take two or three arguments: A db handle, a deserializable Arg struct, and optionally the admin's PeerProtocol");
         }
             .into();
     };
 
     let outer_fn = quote! {
-        pub async fn #http_fn_ident(axum::extract::State(db): axum::extract::State<sqlx::Pool<sqlx::Postgres>>,
+        pub async fn #http_fn_ident(axum::extract::State(db): axum::extract::State<crate::db::DbPool>,
                                     req: axum::http::Request<axum::body::Body>) -> Result<Bytes, crate::error::Error> {
             inventory::submit!(handler_inventory::AdminHandler {
                 path: #path,
@@ -222,3 +237,60 @@ pub fn admin_handler(args: TokenStream, input: TokenStream) -> TokenStream {
     };
     outer_fn.into_token_stream().into()
 }
+
+/// Runs a database test in a fresh migrated SQLite database, then in Postgres too when
+/// `DATABASE_URL` is set in the environment. The test takes a `DbPool`.
+#[proc_macro_attribute]
+pub fn db_test(args: TokenStream, input: TokenStream) -> TokenStream {
+    let args = syn::parse_macro_input!(args as AttributeArgs);
+    if !args.is_empty() {
+        return quote! {
+            compile_error!("db_test takes no arguments");
+        }
+        .into();
+    }
+
+    let input = parse_macro_input!(input as ItemFn);
+    let name = &input.sig.ident;
+    let inputs = &input.sig.inputs;
+    let ret = &input.sig.output;
+    let body = &input.block;
+    let attrs = &input.attrs;
+
+    if input.sig.asyncness.is_none() || inputs.len() != 1 {
+        return quote_spanned! {
+            name.span() => compile_error!("db tests are async, take a single DbPool argument and return a Result");
+        }
+        .into();
+    }
+
+    let outer_fn = quote! {
+        #[::core::prelude::v1::test]
+        #(#attrs)*
+        fn #name() #ret {
+            async fn #name(#inputs) #ret {
+                #body
+            }
+            async fn postgres(db: sqlx::PgPool) #ret {
+                #name(db.into()).await
+            }
+            async fn sqlite(db: sqlx::SqlitePool) #ret {
+                #name(db.into()).await
+            }
+
+            let test_path = concat!(module_path!(), "::", stringify!(#name));
+            if std::env::var_os("DATABASE_URL").is_some() {
+                let mut args = sqlx::testing::TestArgs::new(test_path);
+                args.migrator(&crate::db::POSTGRES_MIGRATOR);
+                let f: fn(_) -> _ = postgres;
+                sqlx::testing::TestFn::run_test(f, args)?;
+            }
+
+            let mut args = sqlx::testing::TestArgs::new(test_path);
+            args.migrator(&crate::db::SQLITE_MIGRATOR);
+            let f: fn(_) -> _ = sqlite;
+            sqlx::testing::TestFn::run_test(f, args)
+        }
+    };
+    outer_fn.into_token_stream().into()
+}
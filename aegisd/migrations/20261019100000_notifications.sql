@@ -0,0 +1,33 @@
+CREATE TYPE notification_trigger AS ENUM (
+    'picture_uploaded',
+    'device_confirmed',
+    'locked_device_online',
+    'forged_signature',
+    'pending_registration'
+);
+CREATE TABLE notification
+(
+    id         integer PRIMARY KEY GENERATED BY DEFAULT AS IDENTITY,
+    created_at timestamp            NOT NULL,
+    trigger    notification_trigger NOT NULL,
+    device     TEXT,
+    message    TEXT                 NOT NULL,
+    -- Set once deliveries have been queued for every configured sink
+    dispatched boolean              NOT NULL DEFAULT FALSE
+);
+CREATE INDEX notification_undispatched_idx ON notification (id) WHERE NOT dispatched;
+
+CREATE TABLE notification_delivery
+(
+    id              integer PRIMARY KEY GENERATED BY DEFAULT AS IDENTITY,
+    notification_id integer REFERENCES notification (id) ON DELETE CASCADE NOT NULL,
+    sink            TEXT                                                   NOT NULL,
+    attempts        integer                                                NOT NULL DEFAULT 0,
+    -- NULL once delivered, or after the last retry failed
+    next_attempt_at timestamp,
+    delivered_at    timestamp,
+    last_error      TEXT,
+    UNIQUE (notification_id, sink)
+);
+CREATE INDEX notification_delivery_due_idx ON notification_delivery (next_attempt_at)
+    WHERE next_attempt_at IS NOT NULL;
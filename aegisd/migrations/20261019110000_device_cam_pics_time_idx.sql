@@ -0,0 +1 @@
+CREATE INDEX device_cam_pics_dev_time_idx ON device_cam_pics (dev_id, created_at, id);
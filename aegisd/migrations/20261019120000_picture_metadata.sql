@@ -0,0 +1,5 @@
+CREATE TYPE capture_trigger AS ENUM ('unknown', 'input_while_locked');
+ALTER TABLE device_cam_pics
+    ADD COLUMN width   integer,
+    ADD COLUMN height  integer,
+    ADD COLUMN trigger capture_trigger NOT NULL DEFAULT 'unknown';
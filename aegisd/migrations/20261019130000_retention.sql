@@ -0,0 +1,11 @@
+ALTER TABLE device ADD COLUMN stolen boolean NOT NULL DEFAULT false;
+CREATE TABLE device_retention
+(
+    dev_id               integer PRIMARY KEY REFERENCES device (id) ON DELETE CASCADE,
+    event_max_age_days   integer,
+    event_max_count      integer,
+    event_min_level      event_log_level,
+    picture_max_age_days integer,
+    picture_max_count    integer,
+    picture_max_bytes    bigint
+);
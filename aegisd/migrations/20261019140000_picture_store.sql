@@ -0,0 +1,12 @@
+CREATE TYPE picture_backend AS ENUM ('postgres', 'filesystem', 's3');
+ALTER TABLE device_cam_pics
+    ALTER COLUMN jpeg_data DROP NOT NULL,
+    ADD COLUMN size    integer,
+    ADD COLUMN sha256  text,
+    ADD COLUMN backend picture_backend NOT NULL DEFAULT 'postgres';
+UPDATE device_cam_pics
+SET size   = octet_length(jpeg_data),
+    sha256 = encode(sha256(jpeg_data), 'hex');
+ALTER TABLE device_cam_pics
+    ALTER COLUMN size SET NOT NULL,
+    ALTER COLUMN sha256 SET NOT NULL;
@@ -0,0 +1,14 @@
+CREATE TABLE enrollment_token
+(
+    id           serial PRIMARY KEY,
+    -- The token itself is only shown once, when it is created
+    token_hash   text UNIQUE NOT NULL,
+    label        text        NOT NULL,
+    created_at   timestamp   NOT NULL,
+    expires_at   timestamp,
+    max_uses     integer,
+    uses         integer     NOT NULL DEFAULT 0,
+    auto_approve boolean     NOT NULL
+);
+ALTER TABLE device
+    ADD COLUMN enrollment_token_id integer REFERENCES enrollment_token (id) ON DELETE SET NULL;
@@ -0,0 +1,6 @@
+ALTER TABLE device ADD COLUMN owner text;
+ALTER TABLE device ADD COLUMN contact text;
+ALTER TABLE device ADD COLUMN notes text;
+-- Reported by the device when it can read them, admins may override them
+ALTER TABLE device ADD COLUMN serial_number text;
+ALTER TABLE device ADD COLUMN hardware_model text;
@@ -0,0 +1,22 @@
+-- "admin", or the scheduled change that was applied
+ALTER TABLE device_status_history
+    ADD COLUMN changed_by text NOT NULL DEFAULT 'admin';
+
+-- Status changes that aegisd's scheduler applies later
+CREATE TABLE device_status_schedule
+(
+    id           serial PRIMARY KEY,
+    dev_id       integer   NOT NULL REFERENCES device (id) ON DELETE CASCADE,
+    apply_at     timestamp NOT NULL,
+    -- Moved to the next day once applied, instead of deleted
+    repeat_daily boolean   NOT NULL,
+    -- The revert of a change that expires
+    is_expiry    boolean   NOT NULL,
+    -- NULL fields are left as they are
+    vt_locked    boolean,
+    ssh_locked   boolean,
+    draw_decoy   boolean,
+    -- Bincode SignedCommand to relay, only changes that lock may go unsigned
+    command      bytea
+);
+CREATE INDEX device_status_schedule_apply_at_idx ON device_status_schedule (apply_at);
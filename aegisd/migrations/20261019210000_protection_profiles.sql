@@ -0,0 +1,15 @@
+-- Named sets of status flags and device behaviors, applied to devices in one call
+CREATE TABLE protection_profile
+(
+    name                    text PRIMARY KEY,
+    vt_locked               boolean NOT NULL,
+    ssh_locked              boolean NOT NULL,
+    draw_decoy              boolean NOT NULL,
+    capture_interval_secs   integer,
+    telemetry_interval_secs integer
+);
+
+-- As reported by the device
+ALTER TABLE device ADD COLUMN active_profile text;
+
+ALTER TYPE capture_trigger ADD VALUE 'periodic';
@@ -0,0 +1,14 @@
+-- How a device captures intruders while it is locked, devices without a row use the default
+CREATE TABLE device_capture_policy
+(
+    dev_id                   integer PRIMARY KEY REFERENCES device (id) ON DELETE CASCADE,
+    webcam_interval_secs     integer,
+    screenshot_interval_secs integer,
+    burst_count              integer NOT NULL,
+    burst_interval_secs      integer NOT NULL,
+    input_cooldown_secs      integer NOT NULL,
+    max_uploads_per_hour     integer NOT NULL
+);
+
+ALTER TYPE capture_trigger ADD VALUE 'scheduled';
+ALTER TYPE capture_trigger ADD VALUE 'screenshot';
@@ -0,0 +1,40 @@
+-- Admin-defined reactions to what devices do
+CREATE TABLE automation_rule
+(
+    name        text PRIMARY KEY,
+    -- Applies to every device when unset
+    dev_id      integer REFERENCES device (id) ON DELETE CASCADE,
+    -- Bincode RuleTrigger
+    trigger     bytea   NOT NULL,
+    threshold   integer NOT NULL,
+    window_secs integer NOT NULL,
+    -- Bincode RuleAction
+    action      bytea   NOT NULL,
+    enabled     boolean NOT NULL,
+    dry_run     boolean NOT NULL
+);
+
+-- When a rule's trigger matched on a device, until there are enough for the rule to fire
+CREATE TABLE automation_rule_hit
+(
+    rule_name text      NOT NULL REFERENCES automation_rule (name) ON DELETE CASCADE,
+    dev_id    integer   NOT NULL REFERENCES device (id) ON DELETE CASCADE,
+    hit_at    timestamp NOT NULL
+);
+CREATE INDEX automation_rule_hit_idx ON automation_rule_hit (rule_name, dev_id, hit_at);
+
+-- Outlives the rule, as a record of what the server did on its own
+CREATE TABLE automation_rule_execution
+(
+    id          serial PRIMARY KEY,
+    rule_name   text      NOT NULL,
+    dev_id      integer   NOT NULL REFERENCES device (id) ON DELETE CASCADE,
+    executed_at timestamp NOT NULL,
+    dry_run     boolean   NOT NULL,
+    -- Bincode RuleAction
+    action      bytea     NOT NULL,
+    error       text
+);
+CREATE INDEX automation_rule_execution_time_idx ON automation_rule_execution (executed_at, id);
+
+ALTER TYPE capture_trigger ADD VALUE 'requested';
@@ -0,0 +1,13 @@
+-- Chunk data goes to the picture stores too, rows record which backend has it
+ALTER TABLE device_fetched_chunk
+    ALTER COLUMN data DROP NOT NULL,
+    ADD COLUMN backend picture_backend NOT NULL DEFAULT 'postgres';
+
+-- Bytes stored for the fetch so far, aegisd refuses chunks past its quota
+ALTER TABLE device_file_fetch
+    ADD COLUMN received_bytes bigint NOT NULL DEFAULT 0;
+UPDATE device_file_fetch x
+SET received_bytes = (SELECT coalesce(sum(octet_length(c.data)), 0)
+                      FROM device_fetched_chunk c
+                               JOIN device_fetched_file f ON f.id = c.file
+                      WHERE f.file_fetch = x.id);
@@ -0,0 +1,98 @@
+-- The Postgres schema as of its 20261019140000 migration. Enums are text with a CHECK, and
+-- later changes to the Postgres schema get a matching migration here.
+CREATE TABLE device
+(
+    id         integer PRIMARY KEY AUTOINCREMENT,
+    created_at timestamp NOT NULL,
+    name       text UNIQUE NOT NULL,
+    pubkey     text UNIQUE NOT NULL,
+    pending    boolean   NOT NULL,
+    stolen     boolean   NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE device_status
+(
+    dev_id     integer REFERENCES device (id) ON DELETE CASCADE NOT NULL,
+    updated_at timestamp NOT NULL,
+    vt_locked  boolean   NOT NULL DEFAULT FALSE,
+    ssh_locked boolean   NOT NULL DEFAULT FALSE,
+    draw_decoy boolean   NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE device_cam_pics
+(
+    id         integer PRIMARY KEY AUTOINCREMENT,
+    dev_id     integer REFERENCES device (id) ON DELETE CASCADE NOT NULL,
+    created_at timestamp NOT NULL,
+    -- Only set for pictures stored in the database
+    jpeg_data  blob,
+    width      integer,
+    height     integer,
+    trigger    text      NOT NULL DEFAULT 'unknown'
+        CHECK (trigger IN ('unknown', 'input_while_locked')),
+    size       integer   NOT NULL,
+    sha256     text      NOT NULL,
+    backend    text      NOT NULL DEFAULT 'postgres'
+        CHECK (backend IN ('postgres', 'filesystem', 's3'))
+);
+CREATE INDEX device_cam_pics_dev_time_idx ON device_cam_pics (dev_id, created_at, id);
+
+CREATE TABLE device_event
+(
+    id         integer PRIMARY KEY AUTOINCREMENT,
+    dev_id     integer REFERENCES device (id) ON DELETE CASCADE NOT NULL,
+    created_at timestamp NOT NULL,
+    level      text      NOT NULL CHECK (level IN ('trace', 'debug', 'info', 'warn', 'error')),
+    -- Text doesn't sort like the Postgres enum
+    level_rank integer GENERATED ALWAYS AS (
+        CASE level
+            WHEN 'trace' THEN 0
+            WHEN 'debug' THEN 1
+            WHEN 'info' THEN 2
+            WHEN 'warn' THEN 3
+            ELSE 4
+        END) VIRTUAL,
+    message    text      NOT NULL,
+    UNIQUE (dev_id, created_at, level, message)
+);
+CREATE INDEX event_dev_time_idx ON device_event (dev_id, created_at);
+
+CREATE TABLE notification
+(
+    id         integer PRIMARY KEY AUTOINCREMENT,
+    created_at timestamp NOT NULL,
+    trigger    text      NOT NULL CHECK (trigger IN ('picture_uploaded', 'device_confirmed',
+                                                     'locked_device_online', 'forged_signature',
+                                                     'pending_registration')),
+    device     text,
+    message    text      NOT NULL,
+    -- Set once deliveries have been queued for every configured sink
+    dispatched boolean   NOT NULL DEFAULT FALSE
+);
+CREATE INDEX notification_undispatched_idx ON notification (id) WHERE NOT dispatched;
+
+CREATE TABLE notification_delivery
+(
+    id              integer PRIMARY KEY AUTOINCREMENT,
+    notification_id integer REFERENCES notification (id) ON DELETE CASCADE NOT NULL,
+    sink            text    NOT NULL,
+    attempts        integer NOT NULL DEFAULT 0,
+    -- NULL once delivered, or after the last retry failed
+    next_attempt_at timestamp,
+    delivered_at    timestamp,
+    last_error      text,
+    UNIQUE (notification_id, sink)
+);
+CREATE INDEX notification_delivery_due_idx ON notification_delivery (next_attempt_at)
+    WHERE next_attempt_at IS NOT NULL;
+
+CREATE TABLE device_retention
+(
+    dev_id               integer PRIMARY KEY REFERENCES device (id) ON DELETE CASCADE,
+    event_max_age_days   integer,
+    event_max_count      integer,
+    event_min_level      text CHECK (event_min_level IN ('trace', 'debug', 'info', 'warn', 'error')),
+    picture_max_age_days integer,
+    picture_max_count    integer,
+    picture_max_bytes    bigint
+);
@@ -0,0 +1,2 @@
+-- Archived devices can't authenticate anymore, but their pictures and events are kept
+ALTER TABLE device ADD COLUMN archived_at timestamp;
@@ -0,0 +1,23 @@
+-- Where each device connected from, kept as evidence
+CREATE TABLE device_connection
+(
+    id              integer PRIMARY KEY AUTOINCREMENT,
+    dev_id          integer   NOT NULL REFERENCES device (id) ON DELETE CASCADE,
+    connected_at    timestamp NOT NULL,
+    -- NULL while the device is connected, or if aegisd stopped before it disconnected
+    disconnected_at timestamp,
+    remote_addr     text      NOT NULL
+);
+CREATE INDEX device_connection_dev_id_idx ON device_connection (dev_id, connected_at);
+
+-- The status of a device after each change, device_status only has the latest one
+CREATE TABLE device_status_history
+(
+    id         integer PRIMARY KEY AUTOINCREMENT,
+    dev_id     integer   NOT NULL REFERENCES device (id) ON DELETE CASCADE,
+    changed_at timestamp NOT NULL,
+    vt_locked  boolean   NOT NULL,
+    ssh_locked boolean   NOT NULL,
+    draw_decoy boolean   NOT NULL
+);
+CREATE INDEX device_status_history_dev_id_idx ON device_status_history (dev_id, changed_at);
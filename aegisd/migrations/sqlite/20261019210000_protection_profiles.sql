@@ -0,0 +1,37 @@
+-- Named sets of status flags and device behaviors, applied to devices in one call
+CREATE TABLE protection_profile
+(
+    name                    text PRIMARY KEY,
+    vt_locked               boolean NOT NULL,
+    ssh_locked              boolean NOT NULL,
+    draw_decoy              boolean NOT NULL,
+    capture_interval_secs   integer,
+    telemetry_interval_secs integer
+);
+
+-- As reported by the device
+ALTER TABLE device ADD COLUMN active_profile text;
+
+-- SQLite can't alter a CHECK constraint, so rebuild the table to allow 'periodic'
+CREATE TABLE device_cam_pics_new
+(
+    id         integer PRIMARY KEY AUTOINCREMENT,
+    dev_id     integer REFERENCES device (id) ON DELETE CASCADE NOT NULL,
+    created_at timestamp NOT NULL,
+    -- Only set for pictures stored in the database
+    jpeg_data  blob,
+    width      integer,
+    height     integer,
+    trigger    text      NOT NULL DEFAULT 'unknown'
+        CHECK (trigger IN ('unknown', 'input_while_locked', 'periodic')),
+    size       integer   NOT NULL,
+    sha256     text      NOT NULL,
+    backend    text      NOT NULL DEFAULT 'postgres'
+        CHECK (backend IN ('postgres', 'filesystem', 's3'))
+);
+INSERT INTO device_cam_pics_new
+SELECT id, dev_id, created_at, jpeg_data, width, height, trigger, size, sha256, backend
+FROM device_cam_pics;
+DROP TABLE device_cam_pics;
+ALTER TABLE device_cam_pics_new RENAME TO device_cam_pics;
+CREATE INDEX device_cam_pics_dev_time_idx ON device_cam_pics (dev_id, created_at, id);
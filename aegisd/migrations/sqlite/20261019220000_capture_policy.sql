@@ -0,0 +1,35 @@
+-- How a device captures intruders while it is locked, devices without a row use the default
+CREATE TABLE device_capture_policy
+(
+    dev_id                   integer PRIMARY KEY REFERENCES device (id) ON DELETE CASCADE,
+    webcam_interval_secs     integer,
+    screenshot_interval_secs integer,
+    burst_count              integer NOT NULL,
+    burst_interval_secs      integer NOT NULL,
+    input_cooldown_secs      integer NOT NULL,
+    max_uploads_per_hour     integer NOT NULL
+);
+
+-- SQLite can't alter a CHECK constraint, so rebuild the table to allow the new triggers
+CREATE TABLE device_cam_pics_new
+(
+    id         integer PRIMARY KEY AUTOINCREMENT,
+    dev_id     integer REFERENCES device (id) ON DELETE CASCADE NOT NULL,
+    created_at timestamp NOT NULL,
+    -- Only set for pictures stored in the database
+    jpeg_data  blob,
+    width      integer,
+    height     integer,
+    trigger    text      NOT NULL DEFAULT 'unknown'
+        CHECK (trigger IN ('unknown', 'input_while_locked', 'periodic', 'scheduled', 'screenshot')),
+    size       integer   NOT NULL,
+    sha256     text      NOT NULL,
+    backend    text      NOT NULL DEFAULT 'postgres'
+        CHECK (backend IN ('postgres', 'filesystem', 's3'))
+);
+INSERT INTO device_cam_pics_new
+SELECT id, dev_id, created_at, jpeg_data, width, height, trigger, size, sha256, backend
+FROM device_cam_pics;
+DROP TABLE device_cam_pics;
+ALTER TABLE device_cam_pics_new RENAME TO device_cam_pics;
+CREATE INDEX device_cam_pics_dev_time_idx ON device_cam_pics (dev_id, created_at, id);
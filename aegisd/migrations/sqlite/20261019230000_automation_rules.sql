@@ -0,0 +1,63 @@
+-- Admin-defined reactions to what devices do
+CREATE TABLE automation_rule
+(
+    name        text PRIMARY KEY,
+    -- Applies to every device when unset
+    dev_id      integer REFERENCES device (id) ON DELETE CASCADE,
+    -- Bincode RuleTrigger
+    trigger     blob    NOT NULL,
+    threshold   integer NOT NULL,
+    window_secs integer NOT NULL,
+    -- Bincode RuleAction
+    action      blob    NOT NULL,
+    enabled     boolean NOT NULL,
+    dry_run     boolean NOT NULL
+);
+
+-- When a rule's trigger matched on a device, until there are enough for the rule to fire
+CREATE TABLE automation_rule_hit
+(
+    rule_name text      NOT NULL REFERENCES automation_rule (name) ON DELETE CASCADE,
+    dev_id    integer   NOT NULL REFERENCES device (id) ON DELETE CASCADE,
+    hit_at    timestamp NOT NULL
+);
+CREATE INDEX automation_rule_hit_idx ON automation_rule_hit (rule_name, dev_id, hit_at);
+
+-- Outlives the rule, as a record of what the server did on its own
+CREATE TABLE automation_rule_execution
+(
+    id          integer PRIMARY KEY AUTOINCREMENT,
+    rule_name   text      NOT NULL,
+    dev_id      integer   NOT NULL REFERENCES device (id) ON DELETE CASCADE,
+    executed_at timestamp NOT NULL,
+    dry_run     boolean   NOT NULL,
+    -- Bincode RuleAction
+    action      blob      NOT NULL,
+    error       text
+);
+CREATE INDEX automation_rule_execution_time_idx ON automation_rule_execution (executed_at, id);
+
+-- SQLite can't alter a CHECK constraint, so rebuild the table to allow the new trigger
+CREATE TABLE device_cam_pics_new
+(
+    id         integer PRIMARY KEY AUTOINCREMENT,
+    dev_id     integer REFERENCES device (id) ON DELETE CASCADE NOT NULL,
+    created_at timestamp NOT NULL,
+    -- Only set for pictures stored in the database
+    jpeg_data  blob,
+    width      integer,
+    height     integer,
+    trigger    text      NOT NULL DEFAULT 'unknown'
+        CHECK (trigger IN ('unknown', 'input_while_locked', 'periodic', 'scheduled', 'screenshot',
+                           'requested')),
+    size       integer   NOT NULL,
+    sha256     text      NOT NULL,
+    backend    text      NOT NULL DEFAULT 'postgres'
+        CHECK (backend IN ('postgres', 'filesystem', 's3'))
+);
+INSERT INTO device_cam_pics_new
+SELECT id, dev_id, created_at, jpeg_data, width, height, trigger, size, sha256, backend
+FROM device_cam_pics;
+DROP TABLE device_cam_pics;
+ALTER TABLE device_cam_pics_new RENAME TO device_cam_pics;
+CREATE INDEX device_cam_pics_dev_time_idx ON device_cam_pics (dev_id, created_at, id);
@@ -0,0 +1,18 @@
+-- Scripts the admin signed for a device, and what they did
+CREATE TABLE device_script_run
+(
+    id           integer PRIMARY KEY AUTOINCREMENT,
+    dev_id       integer   NOT NULL REFERENCES device (id) ON DELETE CASCADE,
+    -- Chosen by the admin, unique per device
+    run_id       integer   NOT NULL,
+    source       text      NOT NULL,
+    requested_at timestamp NOT NULL,
+    -- Set with the rest once the device reports a result
+    finished_at  timestamp,
+    exit_code    integer,
+    timed_out    boolean   NOT NULL DEFAULT FALSE,
+    stdout       blob,
+    stderr       blob,
+    truncated    boolean   NOT NULL DEFAULT FALSE,
+    UNIQUE (dev_id, run_id)
+);
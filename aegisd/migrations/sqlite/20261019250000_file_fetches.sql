@@ -0,0 +1,36 @@
+-- Files the admin signed a request for, sent by the device in chunks
+CREATE TABLE device_file_fetch
+(
+    id           integer PRIMARY KEY AUTOINCREMENT,
+    dev_id       integer   NOT NULL REFERENCES device (id) ON DELETE CASCADE,
+    -- Chosen by the admin, unique per device
+    fetch_id     integer   NOT NULL,
+    -- Bincode list of the requested paths
+    paths        blob      NOT NULL,
+    encrypted    boolean   NOT NULL,
+    requested_at timestamp NOT NULL,
+    -- Set once the device is done sending files
+    finished_at  timestamp,
+    UNIQUE (dev_id, fetch_id)
+);
+
+CREATE TABLE device_fetched_file
+(
+    id         integer PRIMARY KEY AUTOINCREMENT,
+    file_fetch integer NOT NULL REFERENCES device_file_fetch (id) ON DELETE CASCADE,
+    path       text    NOT NULL,
+    size       integer NOT NULL DEFAULT 0,
+    chunks     integer NOT NULL DEFAULT 0,
+    complete   boolean NOT NULL DEFAULT FALSE,
+    -- Set instead of chunks when the device couldn't send the path
+    error      text,
+    UNIQUE (file_fetch, path)
+);
+
+CREATE TABLE device_fetched_chunk
+(
+    file integer NOT NULL REFERENCES device_fetched_file (id) ON DELETE CASCADE,
+    seq  integer NOT NULL,
+    data blob    NOT NULL,
+    PRIMARY KEY (file, seq)
+);
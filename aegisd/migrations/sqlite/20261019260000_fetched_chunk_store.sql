@@ -0,0 +1,26 @@
+-- Chunk data goes to the picture stores too, rows record which backend has it.
+-- SQLite can't drop a NOT NULL constraint, so rebuild the table.
+CREATE TABLE device_fetched_chunk_new
+(
+    file    integer NOT NULL REFERENCES device_fetched_file (id) ON DELETE CASCADE,
+    seq     integer NOT NULL,
+    -- Only set for chunks stored in the database
+    data    blob,
+    backend text    NOT NULL DEFAULT 'postgres'
+        CHECK (backend IN ('postgres', 'filesystem', 's3')),
+    PRIMARY KEY (file, seq)
+);
+INSERT INTO device_fetched_chunk_new (file, seq, data)
+SELECT file, seq, data
+FROM device_fetched_chunk;
+DROP TABLE device_fetched_chunk;
+ALTER TABLE device_fetched_chunk_new RENAME TO device_fetched_chunk;
+
+-- Bytes stored for the fetch so far, aegisd refuses chunks past its quota
+ALTER TABLE device_file_fetch
+    ADD COLUMN received_bytes integer NOT NULL DEFAULT 0;
+UPDATE device_file_fetch
+SET received_bytes = (SELECT coalesce(sum(length(c.data)), 0)
+                      FROM device_fetched_chunk c
+                               JOIN device_fetched_file f ON f.id = c.file
+                      WHERE f.file_fetch = device_file_fetch.id);
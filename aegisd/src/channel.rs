@@ -0,0 +1,60 @@
+//! Server side of the encrypted device channel, see `aegislib::crypto::channel`
+
+use aegislib::crypto::{random_sign_keypair, sign_keypair_from_file, SigningKey};
+use anyhow::{Context, Result};
+use base64::prelude::*;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::info;
+
+/// Our identity in channel handshakes, `None` if encrypted channels aren't configured
+#[derive(Clone)]
+pub struct ServerKey(pub Option<Arc<SigningKey>>);
+
+impl ServerKey {
+    /// Loads the key at `path`, or creates it on first start
+    pub fn load_or_create(path: &Path) -> Result<Self> {
+        let key = if path.exists() {
+            sign_keypair_from_file(path)?
+        } else {
+            let key = random_sign_keypair();
+            // Only readable by us from the start, and never clobbers a key created meanwhile
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+                .and_then(|mut file| file.write_all(&key.to_bytes()))
+                .with_context(|| format!("Failed to write server key {}", path.display()))?;
+            key
+        };
+        let public_key = BASE64_URL_SAFE_NO_PAD.encode(key.verifying_key());
+        info!(%public_key, "Encrypted device channels enabled, set server_public_key on devices");
+        Ok(Self(Some(Arc::new(key))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn created_key_is_private() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("server.key");
+        let created = ServerKey::load_or_create(&path)?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let loaded = ServerKey::load_or_create(&path)?;
+        assert_eq!(
+            created.0.unwrap().verifying_key(),
+            loaded.0.unwrap().verifying_key()
+        );
+        Ok(())
+    }
+}
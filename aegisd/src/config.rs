@@ -1,39 +1,345 @@
+use crate::model::notifications::Trigger;
+use crate::model::pics::store::Backend;
+use aegislib::command::admin::RetentionPolicy;
+use aegislib::command::device::EventLogLevel;
 use base64::prelude::*;
 use ed25519_dalek::VerifyingKey;
 use serde::de::{Error, Unexpected, Visitor};
 use serde::{Deserialize, Deserializer};
+use std::collections::HashSet;
 use std::fmt::Formatter;
-use std::path::Path;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Deserialize)]
 pub struct Config {
-    pub port: u16,
-    pub db_host: String,
-    pub db_name: String,
-    pub db_user: String,
-    pub db_pass: String,
+    /// Plain HTTP port on all IPv4 interfaces, only used when no `[[listen]]` is configured
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub listen: Vec<ListenConfig>,
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub db_backend: DbBackend,
+    /// Postgres only
+    pub db_host: Option<String>,
+    pub db_name: Option<String>,
+    pub db_user: Option<String>,
+    pub db_pass: Option<String>,
+    /// SQLite only, the database file is created if missing
+    pub db_path: Option<PathBuf>,
     #[serde(default = "db_max_conn_default")]
     pub db_max_conn: u32,
     #[serde(deserialize_with = "deserialize_pub_sig_key")]
     pub root_public_signature_key: VerifyingKey,
+    /// Private key for encrypted device channels, created if missing. Unset disables them.
+    pub server_key_path: Option<PathBuf>,
+    #[serde(default)]
+    pub webhook: Vec<WebhookConfig>,
+    #[serde(default)]
+    pub email: Vec<EmailConfig>,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub pictures: PictureStoreConfig,
+    #[serde(default)]
+    pub registration: RegistrationConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DbBackend {
+    #[default]
+    Postgres,
+    /// A single file, for small deployments without a database server
+    Sqlite,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum ListenConfig {
+    Tcp {
+        addr: SocketAddr,
+        #[serde(default)]
+        tls: bool,
+    },
+    /// For deployments behind a local reverse proxy
+    Unix { unix_socket: PathBuf },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first
+    pub cert_path: PathBuf,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1)
+    pub key_path: PathBuf,
+}
+
+/// Notifications POSTed as JSON, signed with HMAC-SHA256
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebhookConfig {
+    /// Identifies the sink in the delivery log
+    pub name: String,
+    pub url: String,
+    pub secret: String,
+    /// Unset sends every notification
+    pub triggers: Option<Vec<Trigger>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct EmailConfig {
+    /// Identifies the sink in the delivery log
+    pub name: String,
+    pub smtp_host: String,
+    pub smtp_port: Option<u16>,
+    #[serde(default)]
+    pub smtp_security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// Unset sends every notification
+    pub triggers: Option<Vec<Trigger>>,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    /// Implicit TLS, usually on port 465
+    Tls,
+    #[default]
+    Starttls,
+    /// Cleartext, only meant for a relay on localhost
+    None,
+}
+
+/// Server-wide limits on stored events and pictures, admins can override them per device.
+/// Everything is kept forever by default.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// How often pruning runs, defaults to hourly
+    pub interval_minutes: Option<u32>,
+    pub event_max_age_days: Option<u32>,
+    pub event_max_count: Option<u32>,
+    /// Less severe events are pruned regardless of their age
+    #[serde(default, deserialize_with = "deserialize_min_level")]
+    pub event_min_level: Option<EventLogLevel>,
+    pub picture_max_age_days: Option<u32>,
+    pub picture_max_count: Option<u32>,
+    pub picture_max_bytes: Option<u64>,
+}
+
+impl RetentionConfig {
+    pub fn policy(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            event_max_age_days: self.event_max_age_days,
+            event_max_count: self.event_max_count,
+            event_min_level: self.event_min_level,
+            picture_max_age_days: self.picture_max_age_days,
+            picture_max_count: self.picture_max_count,
+            picture_max_bytes: self.picture_max_bytes,
+        }
+    }
+}
+
+/// Who may register a device. Devices with an enrollment token are always accepted.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegistrationConfig {
+    /// Let devices without a token register and wait for an admin to confirm them
+    #[serde(default = "registration_open_default")]
+    pub open: bool,
+    /// How many untokened devices may wait for confirmation at once
+    #[serde(default = "registration_max_pending_default")]
+    pub max_pending: u32,
+}
+
+impl Default for RegistrationConfig {
+    fn default() -> Self {
+        Self {
+            open: registration_open_default(),
+            max_pending: registration_max_pending_default(),
+        }
+    }
+}
+
+/// Limits on unauthenticated endpoints and device websockets. A limit of 0 turns it off.
+/// Clients behind a unix socket listener are never limited, the reverse proxy in front of
+/// aegisd has to limit them.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Registrations per minute from one address
+    pub register_per_minute: u32,
+    /// Websocket connection attempts per minute from one address
+    pub websocket_per_minute: u32,
+    /// Websocket connections per minute for one device key, once they proved they hold it
+    pub websocket_per_key_per_minute: u32,
+    /// Sustained rate of messages a device may send on its websocket
+    pub ws_messages_per_second: u32,
+    /// How many websocket messages a device may send at once above the sustained rate
+    pub ws_message_burst: u32,
+    /// Forged signatures from one address before it gets banned
+    pub ban_after_forged_signatures: u32,
+    /// How long bans last, forged signatures are also counted over this window
+    pub ban_minutes: u32,
+    /// Addresses that are never limited or banned
+    pub exempt: Vec<IpAddr>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            register_per_minute: 10,
+            websocket_per_minute: 60,
+            websocket_per_key_per_minute: 20,
+            ws_messages_per_second: 20,
+            ws_message_burst: 100,
+            ban_after_forged_signatures: 5,
+            ban_minutes: 15,
+            exempt: Vec::new(),
+        }
+    }
+}
+
+/// Who may scrape `/metrics`, which is unauthenticated. By default it's served on the main
+/// listeners to anyone.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Serve `/metrics` on these listeners only, e.g. one on localhost or an internal network
+    pub listen: Vec<ListenConfig>,
+    /// Addresses allowed to scrape, empty allows everyone
+    pub allow: Vec<IpAddr>,
+}
+
+/// Where new camera pictures are stored. Pictures in other backends stay readable as long as
+/// those are configured, until `aegisd migrate-pictures` moves them.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PictureStoreConfig {
+    #[serde(default)]
+    pub backend: Backend,
+    pub filesystem: Option<FilesystemStoreConfig>,
+    pub s3: Option<S3StoreConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilesystemStoreConfig {
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct S3StoreConfig {
+    /// e.g. `https://s3.us-east-1.amazonaws.com` or a MinIO server, buckets are addressed path-style
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "s3_region_default")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
 }
 
 impl Config {
     pub fn from_file(path: impl AsRef<Path>) -> Config {
         let contents = std::fs::read_to_string(path.as_ref()).expect("Failed to read config file");
-        toml::from_str(&contents).expect("Invalid config file format")
+        let config: Config = toml::from_str(&contents).expect("Invalid config file format");
+        if let Err(e) = config.validate() {
+            panic!("Invalid config file: {e}");
+        }
+        config
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match self.db_backend {
+            DbBackend::Postgres => {
+                if self.db_host.is_none() || self.db_name.is_none() || self.db_user.is_none() {
+                    return Err("db_host, db_name and db_user must be set for Postgres".into());
+                }
+            }
+            DbBackend::Sqlite => {
+                if self.db_path.is_none() {
+                    return Err("db_path must be set for SQLite".into());
+                }
+            }
+        }
+        if self.listen.is_empty() && self.port.is_none() {
+            return Err("Either port or at least one [[listen]] must be set".into());
+        }
+        let uses_tls = self
+            .listen
+            .iter()
+            .chain(&self.metrics.listen)
+            .any(|l| matches!(l, ListenConfig::Tcp { tls: true, .. }));
+        if uses_tls && self.tls.is_none() {
+            return Err("A listener has tls enabled, but the [tls] section is missing".into());
+        }
+        let mut sink_names = HashSet::new();
+        let names = self.webhook.iter().map(|w| &w.name);
+        for name in names.chain(self.email.iter().map(|e| &e.name)) {
+            if !sink_names.insert(name) {
+                return Err(format!("Duplicate notification sink name {name}"));
+            }
+        }
+        if let Some(email) = self.email.iter().find(|e| e.to.is_empty()) {
+            return Err(format!("Email sink {} has no recipients", email.name));
+        }
+        if self.retention.interval_minutes == Some(0) {
+            return Err("retention.interval_minutes must not be zero".into());
+        }
+        let pictures = &self.pictures;
+        match pictures.backend {
+            Backend::Filesystem if pictures.filesystem.is_none() => {
+                return Err("The filesystem picture backend needs a [pictures.filesystem]".into())
+            }
+            Backend::S3 if pictures.s3.is_none() => {
+                return Err("The s3 picture backend needs a [pictures.s3]".into())
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The configured listeners, or the legacy plain HTTP listener on `port`
+    pub fn listeners(&self) -> Vec<ListenConfig> {
+        match (self.listen.is_empty(), self.port) {
+            (true, Some(port)) => vec![ListenConfig::Tcp {
+                addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port),
+                tls: false,
+            }],
+            _ => self.listen.clone(),
+        }
     }
 
     #[cfg(test)]
     pub(crate) fn test_config(test_root_public_key: VerifyingKey) -> Self {
         Self {
-            port: 8080,
-            db_host: ":memory:".to_string(),
-            db_name: "aegisd".to_string(),
-            db_user: "aegisd".to_string(),
-            db_pass: "test_password".to_string(),
+            port: Some(8080),
+            listen: Vec::new(),
+            tls: None,
+            db_backend: DbBackend::Sqlite,
+            db_host: None,
+            db_name: None,
+            db_user: None,
+            db_pass: None,
+            db_path: Some(":memory:".into()),
             db_max_conn: db_max_conn_default(),
             root_public_signature_key: test_root_public_key,
+            server_key_path: None,
+            webhook: Vec::new(),
+            email: Vec::new(),
+            retention: Default::default(),
+            pictures: Default::default(),
+            registration: Default::default(),
+            rate_limit: Default::default(),
+            metrics: Default::default(),
         }
     }
 }
@@ -42,6 +348,34 @@ fn db_max_conn_default() -> u32 {
     16
 }
 
+fn registration_open_default() -> bool {
+    true
+}
+
+fn registration_max_pending_default() -> u32 {
+    3
+}
+
+fn s3_region_default() -> String {
+    "us-east-1".to_string()
+}
+
+fn deserialize_min_level<'de, D>(deser: D) -> Result<Option<EventLogLevel>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    const LEVELS: &[&str] = &["trace", "debug", "info", "warn", "error"];
+    let level = String::deserialize(deser)?;
+    Ok(Some(match level.as_str() {
+        "trace" => EventLogLevel::Trace,
+        "debug" => EventLogLevel::Debug,
+        "info" => EventLogLevel::Info,
+        "warn" => EventLogLevel::Warn,
+        "error" => EventLogLevel::Error,
+        _ => return Err(Error::unknown_variant(&level, LEVELS)),
+    }))
+}
+
 fn deserialize_pub_sig_key<'de, D>(deser: D) -> Result<VerifyingKey, D::Error>
 where
     D: Deserializer<'de>,
@@ -72,3 +406,240 @@ where
 
     deser.deserialize_str(StrVisitor {})
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(extra: &str) -> Config {
+        let root_key = ed25519_dalek::SigningKey::generate(&mut rand::thread_rng());
+        let root_key = BASE64_URL_SAFE_NO_PAD.encode(root_key.verifying_key());
+        let contents = format!(
+            r#"
+            db_host = "localhost"
+            db_name = "aegisd"
+            db_user = "aegisd"
+            db_pass = "test_password"
+            root_public_signature_key = "{root_key}"
+            {extra}
+            "#
+        );
+        toml::from_str(&contents).unwrap()
+    }
+
+    #[test]
+    fn legacy_port() {
+        let config = parse("port = 8080");
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.listeners(),
+            vec![ListenConfig::Tcp {
+                addr: "0.0.0.0:8080".parse().unwrap(),
+                tls: false
+            }]
+        );
+    }
+
+    #[test]
+    fn listeners() {
+        let config = parse(
+            r#"
+            port = 8080
+            [[listen]]
+            addr = "[::]:443"
+            tls = true
+            [[listen]]
+            addr = "127.0.0.1:8080"
+            [[listen]]
+            unix_socket = "/run/aegisd/aegisd.sock"
+            [tls]
+            cert_path = "/etc/aegisd/cert.pem"
+            key_path = "/etc/aegisd/key.pem"
+            "#,
+        );
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.listeners(),
+            vec![
+                ListenConfig::Tcp {
+                    addr: "[::]:443".parse().unwrap(),
+                    tls: true
+                },
+                ListenConfig::Tcp {
+                    addr: "127.0.0.1:8080".parse().unwrap(),
+                    tls: false
+                },
+                ListenConfig::Unix {
+                    unix_socket: "/run/aegisd/aegisd.sock".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_listeners() {
+        assert!(parse("").validate().is_err());
+        let missing_tls = parse(
+            r#"
+            [[listen]]
+            addr = "[::]:443"
+            tls = true
+            "#,
+        );
+        assert!(missing_tls.validate().is_err());
+    }
+
+    #[test]
+    fn metrics() {
+        let config = parse("port = 8080");
+        assert!(config.metrics.listen.is_empty());
+        assert!(config.metrics.allow.is_empty());
+
+        let config = parse(
+            r#"
+            port = 8080
+            [metrics]
+            listen = [{ addr = "127.0.0.1:9100" }]
+            allow = ["127.0.0.1", "10.0.0.5"]
+            "#,
+        );
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.metrics.listen,
+            vec![ListenConfig::Tcp {
+                addr: "127.0.0.1:9100".parse().unwrap(),
+                tls: false
+            }]
+        );
+        assert_eq!(config.metrics.allow.len(), 2);
+
+        let missing_tls = parse(
+            r#"
+            port = 8080
+            [metrics]
+            listen = [{ addr = "[::]:9100", tls = true }]
+            "#,
+        );
+        assert!(missing_tls.validate().is_err());
+    }
+
+    #[test]
+    fn notification_sinks() {
+        let config = parse(
+            r#"
+            port = 8080
+            [[webhook]]
+            name = "ops"
+            url = "https://hooks.example.com/aegis"
+            secret = "hunter2"
+            triggers = ["picture_uploaded", "forged_signature"]
+            [[email]]
+            name = "me"
+            smtp_host = "smtp.example.com"
+            from = "aegisd@example.com"
+            to = ["me@example.com"]
+            "#,
+        );
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.webhook[0].triggers,
+            Some(vec![Trigger::PictureUploaded, Trigger::ForgedSignature])
+        );
+        assert_eq!(config.email[0].smtp_security, SmtpSecurity::Starttls);
+        assert_eq!(config.email[0].triggers, None);
+
+        let duplicate = parse(
+            r#"
+            port = 8080
+            [[webhook]]
+            name = "ops"
+            url = "https://hooks.example.com/aegis"
+            secret = "hunter2"
+            [[email]]
+            name = "ops"
+            smtp_host = "smtp.example.com"
+            from = "aegisd@example.com"
+            to = ["me@example.com"]
+            "#,
+        );
+        assert!(duplicate.validate().is_err());
+    }
+
+    #[test]
+    fn retention() {
+        let config = parse("port = 8080");
+        assert_eq!(config.retention.policy(), RetentionPolicy::default());
+
+        let config = parse(
+            r#"
+            port = 8080
+            [retention]
+            event_max_age_days = 90
+            event_min_level = "info"
+            picture_max_bytes = 100000000
+            "#,
+        );
+        assert!(config.validate().is_ok());
+        let policy = config.retention.policy();
+        assert_eq!(policy.event_max_age_days, Some(90));
+        assert_eq!(policy.event_min_level, Some(EventLogLevel::Info));
+        assert_eq!(policy.picture_max_bytes, Some(100_000_000));
+        assert_eq!(policy.picture_max_count, None);
+    }
+
+    #[test]
+    fn picture_store() {
+        let config = parse("port = 8080");
+        assert_eq!(config.pictures.backend, Backend::Postgres);
+
+        let config = parse(
+            r#"
+            port = 8080
+            [pictures]
+            backend = "s3"
+            [pictures.s3]
+            endpoint = "http://localhost:9000"
+            bucket = "aegis"
+            access_key = "minioadmin"
+            secret_key = "minioadmin"
+            "#,
+        );
+        assert!(config.validate().is_ok());
+        assert_eq!(config.pictures.backend, Backend::S3);
+        assert_eq!(config.pictures.s3.unwrap().region, "us-east-1");
+
+        let missing = parse(
+            r#"
+            port = 8080
+            [pictures]
+            backend = "filesystem"
+            "#,
+        );
+        assert!(missing.validate().is_err());
+    }
+
+    #[test]
+    fn database() {
+        let config = parse("port = 8080");
+        assert!(config.validate().is_ok());
+        assert_eq!(config.db_backend, DbBackend::Postgres);
+
+        let root_key = ed25519_dalek::SigningKey::generate(&mut rand::thread_rng());
+        let root_key = BASE64_URL_SAFE_NO_PAD.encode(root_key.verifying_key());
+        let sqlite = |extra: &str| -> Config {
+            let contents = format!(
+                r#"
+                port = 8080
+                db_backend = "sqlite"
+                root_public_signature_key = "{root_key}"
+                {extra}
+                "#
+            );
+            toml::from_str(&contents).unwrap()
+        };
+        let config = sqlite(r#"db_path = "/var/lib/aegisd/aegisd.db""#);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.db_backend, DbBackend::Sqlite);
+        assert!(sqlite("").validate().is_err());
+    }
+}
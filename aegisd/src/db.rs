@@ -0,0 +1,141 @@
+//! The database aegisd keeps its state in, either Postgres or a single SQLite file.
+//! Model functions take a [`DbConnection`] and run the matching query for each backend.
+
+use crate::config::{Config, DbBackend};
+use anyhow::{anyhow, Result};
+use sqlx::migrate::Migrator;
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::{PgPool, Postgres, Sqlite, SqlitePool};
+use std::time::Duration;
+use tracing::info;
+
+pub static POSTGRES_MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+pub static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+#[derive(Clone, Debug)]
+pub enum DbPool {
+    Postgres(PgPool),
+    Sqlite(SqlitePool),
+}
+
+#[allow(clippy::large_enum_variant)]
+pub enum DbConnection {
+    Postgres(PoolConnection<Postgres>),
+    Sqlite(PoolConnection<Sqlite>),
+}
+
+/// Runs the same code on the connection of either backend, for queries checked at runtime
+macro_rules! with_conn {
+    ($conn:expr, |$c:ident| $body:expr) => {
+        match $conn {
+            $crate::db::DbConnection::Postgres($c) => $body,
+            $crate::db::DbConnection::Sqlite($c) => $body,
+        }
+    };
+}
+pub(crate) use with_conn;
+
+impl DbPool {
+    pub async fn connect(config: &Config) -> Result<Self> {
+        Ok(match config.db_backend {
+            DbBackend::Postgres => {
+                let field = |value: &Option<String>| value.clone().unwrap_or_default();
+                let url = format!(
+                    "postgres://{}:{}@{}/{}",
+                    field(&config.db_user),
+                    field(&config.db_pass),
+                    field(&config.db_host),
+                    field(&config.db_name)
+                );
+                info!(
+                    db_host = config.db_host.as_deref(),
+                    db_name = config.db_name.as_deref(),
+                    db_user = config.db_user.as_deref(),
+                    "Connecting to database..."
+                );
+                let pool = PgPoolOptions::new()
+                    .max_connections(config.db_max_conn)
+                    .connect(&url)
+                    .await?;
+                DbPool::Postgres(pool)
+            }
+            DbBackend::Sqlite => {
+                let path = config
+                    .db_path
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("db_path is required for SQLite"))?;
+                let options = SqliteConnectOptions::new()
+                    .filename(path)
+                    .create_if_missing(true)
+                    .journal_mode(SqliteJournalMode::Wal)
+                    .busy_timeout(Duration::from_secs(30))
+                    .foreign_keys(true);
+                info!(db_path = %path.display(), "Opening database...");
+                let pool = SqlitePoolOptions::new()
+                    .max_connections(config.db_max_conn)
+                    .connect_with(options)
+                    .await?;
+                DbPool::Sqlite(pool)
+            }
+        })
+    }
+
+    pub async fn migrate(&self) -> Result<()> {
+        match self {
+            DbPool::Postgres(pool) => POSTGRES_MIGRATOR.run(pool).await?,
+            DbPool::Sqlite(pool) => SQLITE_MIGRATOR.run(pool).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn acquire(&self) -> sqlx::Result<DbConnection> {
+        Ok(match self {
+            DbPool::Postgres(pool) => DbConnection::Postgres(pool.acquire().await?),
+            DbPool::Sqlite(pool) => DbConnection::Sqlite(pool.acquire().await?),
+        })
+    }
+
+    #[cfg(test)]
+    pub async fn close(&self) {
+        match self {
+            DbPool::Postgres(pool) => pool.close().await,
+            DbPool::Sqlite(pool) => pool.close().await,
+        }
+    }
+
+    /// Open connections, idle or not
+    pub fn size(&self) -> u32 {
+        match self {
+            DbPool::Postgres(pool) => pool.size(),
+            DbPool::Sqlite(pool) => pool.size(),
+        }
+    }
+
+    pub fn num_idle(&self) -> usize {
+        match self {
+            DbPool::Postgres(pool) => pool.num_idle(),
+            DbPool::Sqlite(pool) => pool.num_idle(),
+        }
+    }
+
+    pub fn max_connections(&self) -> u32 {
+        match self {
+            DbPool::Postgres(pool) => pool.options().get_max_connections(),
+            DbPool::Sqlite(pool) => pool.options().get_max_connections(),
+        }
+    }
+}
+
+impl From<PgPool> for DbPool {
+    fn from(pool: PgPool) -> Self {
+        DbPool::Postgres(pool)
+    }
+}
+
+impl From<SqlitePool> for DbPool {
+    fn from(pool: SqlitePool) -> Self {
+        DbPool::Sqlite(pool)
+    }
+}
@@ -1,5 +1,5 @@
-///! Defines an newtype wrapper around anyhow::Error
-///! Required because the orphan rule prevent us from having From<sqlx::Error> for actix_web::Error
+//! Defines an newtype wrapper around anyhow::Error
+//! Required because the orphan rule prevent us from having From<sqlx::Error> for actix_web::Error
 use anyhow::anyhow;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
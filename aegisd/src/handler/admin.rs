@@ -1,26 +1,73 @@
 //! Admin handlers are authenticated. They are reachable only by REST, not by websocket.
+//! Admins can also follow live events as they happen, see [`crate::live`].
 
 mod handler_inventory;
 pub use handler_inventory::admin_handler_iter;
 
+use crate::db::DbConnection;
 use crate::handler::device::DeviceId;
 use crate::model::device::*;
-use crate::model::{events, pics};
-use crate::ws::ws_for_device;
+use crate::model::notifications::Trigger;
+use crate::model::page::{naive_from_timestamp, page_limit, PageCursor};
+use crate::model::status_schedule::{self, ScheduledStatus};
+use crate::model::{
+    capture_policy, connections, enrollment, events, fetches, pics, profiles, retention, rules,
+    scripts, status_history,
+};
+use crate::notify::notify;
+use crate::picture;
+use crate::protocol::legacy::{ArchivedDeviceReply, DeviceExportReply, RegisteredDeviceReply};
+use crate::scheduler::next_daily;
+use crate::ws::{disconnect_device, push_status, ws_for_device};
 use aegisd_handler_macros::admin_handler;
 use aegislib::command::admin::{
-    PendingDevice, RegisteredDevice, SendPowerCommandArg, SetStatusArg, StoredCameraPicture,
+    ApplyProfileArg, AutomationRule, CreateEnrollmentTokenArg, CreatedEnrollmentToken,
+    DeviceExport, DeviceRetention, EnrollmentToken, EventPage, FetchFilesArg, FetchedChunkArg,
+    FileFetchIdArg, FileFetchInfo, GetEventsArg, GetPicturesArg, ImportDevicesReply,
+    ImportedDevice, ListRuleExecutionsArg, PendingDevice, PictureIdArg, PictureInfo,
+    PictureInfoPage, PicturePage, ProtectionProfile, RenameDeviceArg, RuleAction, RuleExecution,
+    RuleTrigger, RunScriptArg, ScheduledStatusChange, ScriptOutput, ScriptRun, ScriptRunIdArg,
+    SendPowerCommandArg, SetCapturePolicyArg, SetDeviceInfoArg, SetRetentionArg, SetStolenArg,
+    SignedArg, SignedStatusArg, StatusHistoryEntry, StatusSchedule, StatusScheduleIdArg,
+    StoredCameraPicture, MAX_PROFILE_NAME_LEN, MAX_RULE_NAME_LEN, MIN_PROFILE_INTERVAL_SECS,
 };
 use aegislib::command::device::{DeviceEvent, EventLogLevel, StatusReply};
-use aegislib::command::server::{ServerCommand, StatusUpdate};
+use aegislib::command::server::{CapturePolicy, ServerCommand};
+use aegislib::command::signed::{AdminCommand, SignedCommand, Validity, MAX_COMMAND_AGE};
+use aegislib::protocol::{Capability, PeerProtocol};
 use anyhow::{bail, Result};
 use axum::body::Bytes;
-use chrono::Utc;
-use sqlx::PgConnection;
+use base64::prelude::*;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::VerifyingKey;
+use std::time::SystemTime;
 use tracing::warn;
 
+/// We can't verify the admin's signature (only devices have the root public key to check it),
+/// but we refuse to relay a signed command that doesn't match what the request asks us to do
+async fn check_signed_command(
+    db: &mut DbConnection,
+    dev_id: i32,
+    signed: &SignedCommand,
+    expected: AdminCommand,
+) -> Result<()> {
+    let payload = signed.unverified_payload()?;
+    if payload.device_pubkey != get_pubkey_by_id(db, dev_id).await? {
+        bail!("Signed command is for another device");
+    }
+    if payload.command != expected {
+        bail!("Signed command does not match the request");
+    }
+    Ok(())
+}
+
+fn command_issued_at(signed: &SignedCommand) -> Result<u64> {
+    let Validity::Once { issued_at } = signed.unverified_payload()?.validity;
+    Ok(issued_at)
+}
+
 #[admin_handler("/list_pending_devices")]
-pub async fn list_pending_devices(db: &mut PgConnection) -> Result<Vec<PendingDevice>> {
+pub async fn list_pending_devices(db: &mut DbConnection) -> Result<Vec<PendingDevice>> {
     Ok(list_pending(db)
         .await?
         .into_iter()
@@ -29,15 +76,21 @@ pub async fn list_pending_devices(db: &mut PgConnection) -> Result<Vec<PendingDe
 }
 
 #[admin_handler("/delete_pending_device")]
-pub async fn delete_pending_device(db: &mut PgConnection, name: String) -> Result<()> {
+pub async fn delete_pending_device(db: &mut DbConnection, name: String) -> Result<()> {
     delete_pending(db, &name).await?;
     Ok(())
 }
 
 #[admin_handler("/confirm_pending_device")]
-pub async fn confirm_pending_device(db: &mut PgConnection, name: String) -> Result<()> {
-    confirm_pending(db, &name).await?;
-    let dev_id = get_dev_id_by_name(db, &name).await?;
+pub async fn confirm_pending_device(db: &mut DbConnection, name: String) -> Result<()> {
+    confirm_device(db, &name).await
+}
+
+/// Also used at registration, for devices with an auto-approving enrollment token
+pub(crate) async fn confirm_device(db: &mut DbConnection, name: &str) -> Result<()> {
+    confirm_pending(db, name).await?;
+    let dev_id = get_dev_id_by_name(db, name).await?;
+    notify(db, Trigger::DeviceConfirmed, Some(name), "Device confirmed").await;
     let _ = events::insert(
         db,
         dev_id,
@@ -51,57 +104,495 @@ pub async fn confirm_pending_device(db: &mut PgConnection, name: String) -> Resu
     Ok(())
 }
 
+#[admin_handler("/create_enrollment_token")]
+pub async fn create_enrollment_token(
+    db: &mut DbConnection,
+    arg: CreateEnrollmentTokenArg,
+) -> Result<CreatedEnrollmentToken> {
+    let expires_at = arg
+        .expires_at_timestamp
+        .map(naive_from_timestamp)
+        .transpose()?;
+    let max_uses = arg.max_uses.map(i32::try_from).transpose()?;
+    let (id, token) =
+        enrollment::create(db, &arg.label, expires_at, max_uses, arg.auto_approve).await?;
+    Ok(CreatedEnrollmentToken { id, token })
+}
+
+#[admin_handler("/list_enrollment_tokens")]
+pub async fn list_enrollment_tokens(db: &mut DbConnection) -> Result<Vec<EnrollmentToken>> {
+    Ok(enrollment::list(db)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect())
+}
+
+#[admin_handler("/delete_enrollment_token")]
+pub async fn delete_enrollment_token(db: &mut DbConnection, id: i32) -> Result<()> {
+    enrollment::delete(db, id).await
+}
+
+#[admin_handler("/list_token_pending_devices")]
+pub async fn list_token_pending_devices(
+    db: &mut DbConnection,
+    id: i32,
+) -> Result<Vec<PendingDevice>> {
+    Ok(list_pending_for_token(db, id)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect())
+}
+
+#[admin_handler("/import_devices")]
+pub async fn import_devices(
+    db: &mut DbConnection,
+    devices: Vec<ImportedDevice>,
+) -> Result<ImportDevicesReply> {
+    for dev in &devices {
+        let pubkey = BASE64_URL_SAFE_NO_PAD.decode(&dev.pubkey).ok();
+        if pubkey
+            .and_then(|pk| pk.try_into().ok())
+            .and_then(|pk| VerifyingKey::from_bytes(&pk).ok())
+            .is_none()
+        {
+            bail!("Invalid public key for device '{}'", dev.name);
+        }
+    }
+    let total = devices.len();
+    let conflicts = import(db, devices).await?;
+    Ok(ImportDevicesReply {
+        imported: (total - conflicts.len()) as u32,
+        conflicts,
+    })
+}
+
 #[admin_handler("/list_registered_devices")]
-pub async fn list_registered_devices(db: &mut PgConnection) -> Result<Vec<RegisteredDevice>> {
+pub async fn list_registered_devices(
+    db: &mut DbConnection,
+    _: (),
+    protocol: PeerProtocol,
+) -> Result<Vec<RegisteredDeviceReply>> {
     Ok(list_registered(db)
         .await?
         .into_iter()
-        .map(Into::into)
+        .map(|device| RegisteredDeviceReply::new(device.into(), protocol.version))
+        .collect())
+}
+
+#[admin_handler("/list_archived_devices")]
+pub async fn list_archived_devices(
+    db: &mut DbConnection,
+    _: (),
+    protocol: PeerProtocol,
+) -> Result<Vec<ArchivedDeviceReply>> {
+    Ok(list_archived(db)
+        .await?
+        .into_iter()
+        .map(|archived| ArchivedDeviceReply::new(archived.into(), protocol.version))
         .collect())
 }
 
-#[admin_handler("/delete_registered_device")]
-pub async fn delete_registered_device(db: &mut PgConnection, name: String) -> Result<()> {
-    delete_registered(db, &name).await?;
+#[admin_handler("/export_device")]
+pub async fn export_device(
+    db: &mut DbConnection,
+    name: String,
+    protocol: PeerProtocol,
+) -> Result<DeviceExportReply> {
+    let dev_id = get_dev_id_by_name(db, &name).await?;
+    let device = get_by_id(db, dev_id).await?;
+    let archived_at = device
+        .archived_at
+        .map(|t| DateTime::<Utc>::from_naive_utc_and_offset(t, Utc).into());
+    let mut events = events::get_for_device(db, dev_id).await?;
+    events.sort_by_key(|e| e.timestamp);
+    let export = DeviceExport {
+        device: device.into(),
+        archived_at,
+        exported_at: SystemTime::now(),
+        status: get_status(db, dev_id).await?.into(),
+        status_history: status_history::list_for_device(db, dev_id)
+            .await?
+            .into_iter()
+            .map(Into::into)
+            .collect(),
+        connections: connections::list_for_device(db, dev_id)
+            .await?
+            .into_iter()
+            .map(Into::into)
+            .collect(),
+        events,
+        pictures: pics::export_info_for_device(db, dev_id).await?,
+    };
+    let _ = events::insert(
+        db,
+        dev_id,
+        DeviceEvent {
+            timestamp: Utc::now().timestamp() as u64,
+            level: EventLogLevel::Info,
+            message: format!(
+                "Evidence exported ({} events, {} pictures)",
+                export.events.len(),
+                export.pictures.len()
+            ),
+        },
+    )
+    .await;
+    Ok(DeviceExportReply::new(export, protocol.version))
+}
+
+#[admin_handler("/archive_device")]
+pub async fn archive_device(db: &mut DbConnection, name: String) -> Result<()> {
+    let dev_id = get_dev_id_by_name(db, &name).await?;
+    archive(db, dev_id).await?;
+    disconnect_device(DeviceId(dev_id));
+    let _ = events::insert(
+        db,
+        dev_id,
+        DeviceEvent {
+            timestamp: Utc::now().timestamp() as u64,
+            level: EventLogLevel::Info,
+            message: "Device archived, its key was revoked".into(),
+        },
+    )
+    .await;
+    Ok(())
+}
+
+#[admin_handler("/restore_device")]
+pub async fn restore_device(db: &mut DbConnection, name: String) -> Result<()> {
+    let dev_id = get_archived_dev_id_by_name(db, &name).await?;
+    restore(db, dev_id).await?;
+    let _ = events::insert(
+        db,
+        dev_id,
+        DeviceEvent {
+            timestamp: Utc::now().timestamp() as u64,
+            level: EventLogLevel::Info,
+            message: "Device restored from the archive".into(),
+        },
+    )
+    .await;
+    Ok(())
+}
+
+#[admin_handler("/purge_device")]
+pub async fn purge_device(db: &mut DbConnection, name: String) -> Result<()> {
+    // Only archived devices can be purged, so a single mistaken command can't destroy evidence
+    let dev_id = get_archived_dev_id_by_name(db, &name).await?;
+    pics::delete_all_for_device(db, dev_id).await?;
+    fetches::delete_all_for_device(db, dev_id).await?;
+    purge_archived(db, dev_id).await?;
+    Ok(())
+}
+
+#[admin_handler("/rename_device")]
+pub async fn rename_device(db: &mut DbConnection, arg: RenameDeviceArg) -> Result<()> {
+    if arg.new_name.trim().is_empty() {
+        bail!("The new device name can't be empty");
+    }
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    rename(db, dev_id, &arg.new_name).await?;
+    let _ = events::insert(
+        db,
+        dev_id,
+        DeviceEvent {
+            timestamp: Utc::now().timestamp() as u64,
+            level: EventLogLevel::Info,
+            message: format!("Renamed from '{}' to '{}'", arg.dev_name, arg.new_name),
+        },
+    )
+    .await;
+    Ok(())
+}
+
+#[admin_handler("/set_device_info")]
+pub async fn set_device_info(db: &mut DbConnection, arg: SetDeviceInfoArg) -> Result<()> {
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    set_info(db, dev_id, &arg).await?;
     Ok(())
 }
 
 #[admin_handler("/set_status")]
-pub async fn set_status(db: &mut PgConnection, arg: SetStatusArg) -> Result<StatusReply> {
+pub async fn set_status(
+    db: &mut DbConnection,
+    signed_arg: SignedStatusArg,
+    _protocol: PeerProtocol,
+) -> Result<StatusReply> {
+    let SignedStatusArg {
+        arg,
+        command,
+        revert,
+    } = signed_arg;
     let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
-    let status: StatusReply =
-        update_status(db, dev_id, arg.vt_locked, arg.ssh_locked, arg.draw_decoy)
+    let change = arg.change();
+    check_signed_command(db, dev_id, &command, AdminCommand::SetStatus(change)).await?;
+    let issued_at = command_issued_at(&command)?;
+    let expiry = match (arg.expires_after_secs, &revert) {
+        (None, None) => None,
+        (Some(secs), Some(revert)) => {
+            let undo = AdminCommand::SetStatus(change.inverted());
+            check_signed_command(db, dev_id, revert, undo).await?;
+            let expires_at = issued_at.saturating_add(secs);
+            if command_issued_at(revert)? != expires_at {
+                bail!("The revert command is not signed for the expiry time");
+            }
+            Some(naive_from_timestamp(expires_at)?)
+        }
+        _ => bail!("Expiring status changes need a signed revert command"),
+    };
+    if arg.is_no_op() && (arg.schedule.is_some() || expiry.is_some()) {
+        bail!("Nothing to schedule, the status change is empty");
+    }
+
+    let status: StatusReply = match arg.schedule {
+        None => {
+            let status: StatusReply = update_status(
+                db,
+                dev_id,
+                arg.vt_locked,
+                arg.ssh_locked,
+                arg.draw_decoy,
+                "admin",
+            )
             .await?
             .into();
-    if !arg.is_no_op() {
+            if !arg.is_no_op() {
+                let _ = events::insert(
+                    db,
+                    dev_id,
+                    DeviceEvent {
+                        timestamp: Utc::now().timestamp() as u64,
+                        level: EventLogLevel::Info,
+                        message: format!("Status updated: {status:?}"),
+                    },
+                )
+                .await;
+            }
+            push_status(DeviceId(dev_id), status.clone().into(), Some(command)).await;
+            status
+        }
+        Some(schedule) => {
+            let scheduled = match schedule {
+                StatusSchedule::At { timestamp } => {
+                    if timestamp != issued_at {
+                        bail!("The command is not signed for the scheduled time");
+                    }
+                    let apply_at = naive_from_timestamp(timestamp)?;
+                    if (Utc::now().naive_utc() - apply_at)
+                        .to_std()
+                        .unwrap_or_default()
+                        > MAX_COMMAND_AGE
+                    {
+                        bail!("The scheduled time has already passed");
+                    }
+                    ScheduledStatus::new(dev_id, apply_at, change, Some(&command))
+                }
+                StatusSchedule::Daily { minute_of_day } => {
+                    // Each unlock would need a command signed for that day
+                    if !change.only_locks() || expiry.is_some() {
+                        bail!("Only changes that lock can repeat daily, and they can't expire");
+                    }
+                    let apply_at = next_daily(minute_of_day, Utc::now().naive_utc())?;
+                    let mut scheduled = ScheduledStatus::new(dev_id, apply_at, change, None);
+                    scheduled.repeat_daily = true;
+                    scheduled
+                }
+            };
+            let id = scheduled.insert(db).await?;
+            let _ = events::insert(
+                db,
+                dev_id,
+                DeviceEvent {
+                    timestamp: Utc::now().timestamp() as u64,
+                    level: EventLogLevel::Info,
+                    message: format!("Status change scheduled as #{id}: {schedule:?} {change:?}"),
+                },
+            )
+            .await;
+            get_status(db, dev_id).await?.into()
+        }
+    };
+
+    if let (Some(expires_at), Some(revert)) = (expiry, revert) {
+        let mut scheduled =
+            ScheduledStatus::new(dev_id, expires_at, change.inverted(), Some(&revert));
+        scheduled.is_expiry = true;
+        let id = scheduled.insert(db).await?;
         let _ = events::insert(
             db,
             dev_id,
             DeviceEvent {
                 timestamp: Utc::now().timestamp() as u64,
                 level: EventLogLevel::Info,
-                message: format!("Status updated: {status:?}"),
+                message: format!("Status change expires at {expires_at} UTC, reverted by #{id}"),
             },
         )
         .await;
     }
-    if let Some(ws) = ws_for_device(DeviceId(dev_id)) {
-        let status_update = StatusUpdate {
-            ssh_locked: status.ssh_locked,
-            vt_locked: status.vt_locked,
-            draw_decoy: status.draw_decoy,
-        };
-        ws.send(status_update.into()).await.unwrap_or_else(|e| {
-            warn!("Failed to send status update to websocket for device {dev_id}: {e}",);
-        });
+    Ok(status)
+}
+
+#[admin_handler("/status_history")]
+pub async fn status_history(
+    db: &mut DbConnection,
+    dev_name: String,
+) -> Result<Vec<StatusHistoryEntry>> {
+    let dev_id = get_dev_id_by_name(db, &dev_name).await?;
+    let history = status_history::list_for_device(db, dev_id).await?;
+    Ok(history.into_iter().map(Into::into).collect())
+}
+
+#[admin_handler("/list_status_schedules")]
+pub async fn list_status_schedules(
+    db: &mut DbConnection,
+    dev_name: String,
+) -> Result<Vec<ScheduledStatusChange>> {
+    let dev_id = get_dev_id_by_name(db, &dev_name).await?;
+    let scheduled = status_schedule::list_for_device(db, dev_id).await?;
+    Ok(scheduled.into_iter().map(Into::into).collect())
+}
+
+#[admin_handler("/cancel_status_schedule")]
+pub async fn cancel_status_schedule(db: &mut DbConnection, arg: StatusScheduleIdArg) -> Result<()> {
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    status_schedule::delete(db, dev_id, arg.id).await?;
+    let _ = events::insert(
+        db,
+        dev_id,
+        DeviceEvent {
+            timestamp: Utc::now().timestamp() as u64,
+            level: EventLogLevel::Info,
+            message: format!("Scheduled status change #{} cancelled", arg.id),
+        },
+    )
+    .await;
+    Ok(())
+}
+
+#[admin_handler("/list_profiles")]
+pub async fn list_profiles(db: &mut DbConnection) -> Result<Vec<ProtectionProfile>> {
+    profiles::list(db).await
+}
+
+#[admin_handler("/set_profile")]
+pub async fn set_profile(db: &mut DbConnection, profile: ProtectionProfile) -> Result<()> {
+    if profile.name.trim().is_empty() || profile.name.len() > MAX_PROFILE_NAME_LEN {
+        bail!("Protection profile names must have 1 to {MAX_PROFILE_NAME_LEN} bytes");
     }
+    for interval in [
+        profile.capture_interval_secs,
+        profile.telemetry_interval_secs,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if !(MIN_PROFILE_INTERVAL_SECS..=i32::MAX as u32).contains(&interval) {
+            bail!("Profile intervals must be at least {MIN_PROFILE_INTERVAL_SECS} seconds");
+        }
+    }
+    profiles::upsert(db, &profile).await
+}
+
+#[admin_handler("/delete_profile")]
+pub async fn delete_profile(db: &mut DbConnection, name: String) -> Result<()> {
+    profiles::delete(db, &name).await
+}
+
+#[admin_handler("/apply_profile")]
+pub async fn apply_profile(db: &mut DbConnection, arg: ApplyProfileArg) -> Result<StatusReply> {
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    let profile = match &arg.profile {
+        Some(name) => Some(profiles::get(db, name).await?),
+        None => None,
+    };
+    let expected = AdminCommand::SetProfile(profile.as_ref().map(|p| p.device_profile()));
+    check_signed_command(db, dev_id, &arg.profile_command, expected).await?;
+    let ws = ws_for_device(DeviceId(dev_id));
+    let supports_profiles = ws
+        .as_ref()
+        .is_some_and(|ws| ws.protocol.supports(Capability::Profiles));
+
+    let Some(profile) = profile else {
+        if let (Some(ws), true) = (ws, supports_profiles) {
+            ws.send(ServerCommand::Signed(arg.profile_command))
+                .await
+                .unwrap_or_else(|e| {
+                    warn!("Failed to send profile to websocket for device {dev_id}: {e}");
+                });
+        }
+        let _ = events::insert(
+            db,
+            dev_id,
+            DeviceEvent {
+                timestamp: Utc::now().timestamp() as u64,
+                level: EventLogLevel::Info,
+                message: "Protection profile cleared".into(),
+            },
+        )
+        .await;
+        return Ok(get_status(db, dev_id).await?.into());
+    };
 
+    let Some(status_command) = arg.status_command else {
+        bail!("Applying a profile needs a signed status command");
+    };
+    let change = profile.status_change();
+    check_signed_command(db, dev_id, &status_command, AdminCommand::SetStatus(change)).await?;
+    let changed_by = format!("profile '{}'", profile.name);
+    let status: StatusReply = update_status(
+        db,
+        dev_id,
+        change.vt_locked,
+        change.ssh_locked,
+        change.draw_decoy,
+        &changed_by,
+    )
+    .await?
+    .into();
+    // Otherwise the device doesn't run the profile's behaviors, and won't report it as active
+    let message = match supports_profiles {
+        true => format!("Protection profile '{}' applied: {status:?}", profile.name),
+        false => format!(
+            "Status of protection profile '{}' applied, the device is offline or too old to \
+             run the profile: {status:?}",
+            profile.name
+        ),
+    };
+    let _ = events::insert(
+        db,
+        dev_id,
+        DeviceEvent {
+            timestamp: Utc::now().timestamp() as u64,
+            level: EventLogLevel::Info,
+            message,
+        },
+    )
+    .await;
+    match ws {
+        Some(ws) if supports_profiles => {
+            ws.send(ServerCommand::Signed(arg.profile_command))
+                .await
+                .unwrap_or_else(|e| {
+                    warn!("Failed to send profile to websocket for device {dev_id}: {e}");
+                });
+        }
+        _ => {
+            push_status(
+                DeviceId(dev_id),
+                status.clone().into(),
+                Some(status_command),
+            )
+            .await;
+        }
+    }
     Ok(status)
 }
 
 #[admin_handler("/get_device_camera_pictures")]
 pub async fn get_device_camera_pictures(
-    db: &mut PgConnection,
+    db: &mut DbConnection,
     dev_name: String,
 ) -> Result<Vec<StoredCameraPicture>> {
     let dev_id = get_dev_id_by_name(db, &dev_name).await?;
@@ -110,8 +601,83 @@ pub async fn get_device_camera_pictures(
     Ok(pics.into_iter().map(Into::into).collect())
 }
 
+#[admin_handler("/list_device_camera_pictures")]
+pub async fn list_device_camera_pictures(
+    db: &mut DbConnection,
+    arg: GetPicturesArg,
+) -> Result<PicturePage> {
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    let filter = pics::PictureFilter {
+        since: arg.since.map(naive_from_timestamp).transpose()?,
+        until: arg.until.map(naive_from_timestamp).transpose()?,
+        after: arg.cursor.as_deref().map(PageCursor::decode).transpose()?,
+        limit: page_limit(arg.limit, 10, 100),
+    };
+    let (pics, next) = pics::list_for_device(db, dev_id, filter).await?;
+    tracing::info!("Sending a page of {} device camera pictures", pics.len());
+    Ok(PicturePage {
+        pictures: pics.into_iter().map(Into::into).collect(),
+        next_cursor: next.map(|c| c.encode()),
+    })
+}
+
+#[admin_handler("/list_device_picture_info")]
+pub async fn list_device_picture_info(
+    db: &mut DbConnection,
+    arg: GetPicturesArg,
+    protocol: PeerProtocol,
+) -> Result<PictureInfoPage> {
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    let filter = pics::PictureFilter {
+        since: arg.since.map(naive_from_timestamp).transpose()?,
+        until: arg.until.map(naive_from_timestamp).transpose()?,
+        after: arg.cursor.as_deref().map(PageCursor::decode).transpose()?,
+        limit: page_limit(arg.limit, 100, 1000),
+    };
+    let (pics, next) = pics::list_info_for_device(db, dev_id, filter).await?;
+    Ok(PictureInfoPage {
+        pictures: pics
+            .into_iter()
+            .map(|pic| {
+                let mut info = PictureInfo::from(pic);
+                info.trigger = info.trigger.for_version(protocol.version);
+                info
+            })
+            .collect(),
+        next_cursor: next.map(|c| c.encode()),
+    })
+}
+
+#[admin_handler("/get_device_camera_picture")]
+pub async fn get_device_camera_picture(
+    db: &mut DbConnection,
+    arg: PictureIdArg,
+) -> Result<StoredCameraPicture> {
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    Ok(pics::get_by_id(db, dev_id, arg.id).await?.into())
+}
+
+#[admin_handler("/get_device_picture_thumbnail")]
+pub async fn get_device_picture_thumbnail(
+    db: &mut DbConnection,
+    arg: PictureIdArg,
+) -> Result<StoredCameraPicture> {
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    let mut pic: StoredCameraPicture = pics::get_by_id(db, dev_id, arg.id).await?.into();
+    pic.jpeg_data =
+        tokio::task::spawn_blocking(move || picture::thumbnail(&pic.jpeg_data)).await??;
+    Ok(pic)
+}
+
+#[admin_handler("/delete_device_camera_picture")]
+pub async fn delete_device_camera_picture(db: &mut DbConnection, arg: PictureIdArg) -> Result<()> {
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    pics::delete_by_id(db, dev_id, arg.id).await?;
+    Ok(())
+}
+
 #[admin_handler("/delete_device_camera_pictures")]
-pub async fn delete_device_camera_pictures(db: &mut PgConnection, dev_name: String) -> Result<()> {
+pub async fn delete_device_camera_pictures(db: &mut DbConnection, dev_name: String) -> Result<()> {
     let dev_id = get_dev_id_by_name(db, &dev_name).await?;
     pics::delete_for_device(db, dev_id).await?;
     let _ = events::insert(
@@ -127,19 +693,105 @@ pub async fn delete_device_camera_pictures(db: &mut PgConnection, dev_name: Stri
     Ok(())
 }
 
+#[admin_handler("/get_device_retention")]
+pub async fn get_device_retention(
+    db: &mut DbConnection,
+    dev_name: String,
+) -> Result<DeviceRetention> {
+    let dev_id = get_dev_id_by_name(db, &dev_name).await?;
+    Ok(DeviceRetention {
+        policy: retention::get_override(db, dev_id).await?,
+        stolen: is_stolen(db, dev_id).await?,
+    })
+}
+
+#[admin_handler("/set_device_retention")]
+pub async fn set_device_retention(db: &mut DbConnection, arg: SetRetentionArg) -> Result<()> {
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    retention::set_override(db, dev_id, arg.policy).await?;
+    Ok(())
+}
+
+#[admin_handler("/set_device_stolen")]
+pub async fn set_device_stolen(db: &mut DbConnection, arg: SetStolenArg) -> Result<()> {
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    set_stolen(db, dev_id, arg.stolen).await?;
+    let message = match arg.stolen {
+        true => "Flagged as stolen, retention policies are suspended",
+        false => "No longer flagged as stolen",
+    };
+    let _ = events::insert(
+        db,
+        dev_id,
+        DeviceEvent {
+            timestamp: Utc::now().timestamp() as u64,
+            level: EventLogLevel::Warn,
+            message: message.into(),
+        },
+    )
+    .await;
+    Ok(())
+}
+
+#[admin_handler("/get_capture_policy")]
+pub async fn get_capture_policy(db: &mut DbConnection, dev_name: String) -> Result<CapturePolicy> {
+    let dev_id = get_dev_id_by_name(db, &dev_name).await?;
+    capture_policy::get(db, dev_id).await
+}
+
+#[admin_handler("/set_capture_policy")]
+pub async fn set_capture_policy(db: &mut DbConnection, arg: SetCapturePolicyArg) -> Result<()> {
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    if let Some(policy) = &arg.policy {
+        policy.check()?;
+    }
+    capture_policy::set(db, dev_id, arg.policy).await?;
+    let policy = arg.policy.unwrap_or_default();
+    let _ = events::insert(
+        db,
+        dev_id,
+        DeviceEvent {
+            timestamp: Utc::now().timestamp() as u64,
+            level: EventLogLevel::Info,
+            message: format!("Capture policy updated: {policy:?}"),
+        },
+    )
+    .await;
+
+    // Devices also fetch their policy when they start
+    if let Some(ws) = ws_for_device(DeviceId(dev_id)) {
+        if ws.protocol.supports(Capability::CapturePolicy) {
+            ws.send(ServerCommand::CapturePolicy(policy))
+                .await
+                .unwrap_or_else(|e| {
+                    warn!("Failed to send capture policy to websocket for device {dev_id}: {e}");
+                });
+        }
+    }
+    Ok(())
+}
+
 #[admin_handler("/send_power_command")]
-pub async fn send_power_command(db: &mut PgConnection, arg: SendPowerCommandArg) -> Result<()> {
+pub async fn send_power_command(
+    db: &mut DbConnection,
+    signed_arg: SignedArg<SendPowerCommandArg>,
+) -> Result<()> {
+    let SignedArg { arg, command } = signed_arg;
     let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    check_signed_command(db, dev_id, &command, AdminCommand::Power(arg.command)).await?;
     let ws = match ws_for_device(DeviceId(dev_id)) {
         Some(ws) => ws,
         None => bail!("Device is not connected"),
     };
 
-    ws.send(ServerCommand::PowerCommand(arg.command))
-        .await
-        .unwrap_or_else(|e| {
-            warn!("Failed to send power command to websocket for device {dev_id}: {e}",);
-        });
+    let server_cmd = if ws.protocol.supports(Capability::SignedCommands) {
+        ServerCommand::Signed(command)
+    } else {
+        ServerCommand::PowerCommand(arg.command)
+    };
+    ws.send(server_cmd).await.unwrap_or_else(|e| {
+        warn!("Failed to send power command to websocket for device {dev_id}: {e}",);
+    });
     let _ = events::insert(
         db,
         dev_id,
@@ -154,50 +806,277 @@ pub async fn send_power_command(db: &mut PgConnection, arg: SendPowerCommandArg)
     Ok(())
 }
 
-#[admin_handler("/get_device_events")]
-pub async fn get_device_events(
-    db: &mut PgConnection,
-    dev_name: String,
-) -> Result<Vec<DeviceEvent>> {
-    let dev_id = get_dev_id_by_name(db, &dev_name).await?;
-    let events = events::get_for_device(db, dev_id).await?;
-    tracing::info!("Sending {} device events", events.len());
-    Ok(events)
-}
-
-#[admin_handler("/delete_device_events")]
-pub async fn delete_device_events(db: &mut PgConnection, dev_name: String) -> Result<()> {
+#[admin_handler("/request_key_rotation")]
+pub async fn request_key_rotation(db: &mut DbConnection, dev_name: String) -> Result<()> {
     let dev_id = get_dev_id_by_name(db, &dev_name).await?;
-    events::delete_for_device(db, dev_id).await?;
+    let ws = match ws_for_device(DeviceId(dev_id)) {
+        Some(ws) => ws,
+        None => bail!("Device is not connected"),
+    };
+    ws.send(ServerCommand::RotateKey).await?;
+    let _ = events::insert(
+        db,
+        dev_id,
+        DeviceEvent {
+            timestamp: Utc::now().timestamp() as u64,
+            level: EventLogLevel::Info,
+            message: "Requested a key rotation".into(),
+        },
+    )
+    .await;
     Ok(())
 }
 
-#[cfg(test)]
-mod test {
-    use crate::error::Result;
-    use crate::model::device;
-    use crate::model::device::test::{insert_test_device, insert_test_pending_device};
-    use crate::server::{make_test_server, TestServer};
-    use aegislib::command::admin::{PendingDevice, RegisteredDevice, SetStatusArg};
-    use aegislib::crypto::{randomized_signature, SigningKey};
-    use anyhow::anyhow;
-    use axum::body::Bytes;
-    use axum::response::Response;
-    use base64::prelude::*;
+#[admin_handler("/run_script")]
+pub async fn run_script(db: &mut DbConnection, signed_arg: SignedArg<RunScriptArg>) -> Result<()> {
+    let SignedArg { arg, command } = signed_arg;
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    arg.script.check()?;
+    let expected = AdminCommand::RunScript(arg.script.clone());
+    check_signed_command(db, dev_id, &command, expected).await?;
+    let ws = match ws_for_device(DeviceId(dev_id)) {
+        Some(ws) => ws,
+        None => bail!("Device is not connected"),
+    };
+    if !ws.protocol.supports(Capability::Scripts) {
+        bail!("Device is too old to run scripts");
+    }
+    scripts::insert(db, dev_id, &arg.script, Utc::now().naive_utc()).await?;
+    ws.send(ServerCommand::Signed(command)).await?;
+    let _ = events::insert(
+        db,
+        dev_id,
+        DeviceEvent {
+            timestamp: Utc::now().timestamp() as u64,
+            level: EventLogLevel::Warn,
+            message: format!("Sent script {}", arg.script.run_id),
+        },
+    )
+    .await;
+    Ok(())
+}
+
+#[admin_handler("/list_script_runs")]
+pub async fn list_script_runs(db: &mut DbConnection, dev_name: String) -> Result<Vec<ScriptRun>> {
+    let dev_id = get_dev_id_by_name(db, &dev_name).await?;
+    scripts::list_for_device(db, dev_id).await
+}
+
+#[admin_handler("/get_script_output")]
+pub async fn get_script_output(db: &mut DbConnection, arg: ScriptRunIdArg) -> Result<ScriptOutput> {
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    scripts::get_output(db, dev_id, arg.run_id).await
+}
+
+#[admin_handler("/fetch_files")]
+pub async fn fetch_files(
+    db: &mut DbConnection,
+    signed_arg: SignedArg<FetchFilesArg>,
+) -> Result<()> {
+    let SignedArg { arg, command } = signed_arg;
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    arg.fetch.check()?;
+    let expected = AdminCommand::FetchFiles(arg.fetch.clone());
+    check_signed_command(db, dev_id, &command, expected).await?;
+    let ws = match ws_for_device(DeviceId(dev_id)) {
+        Some(ws) => ws,
+        None => bail!("Device is not connected"),
+    };
+    if !ws.protocol.supports(Capability::FileFetch) {
+        bail!("Device is too old to send files");
+    }
+    fetches::insert(db, dev_id, &arg.fetch, Utc::now().naive_utc()).await?;
+    ws.send(ServerCommand::Signed(command)).await?;
+    let _ = events::insert(
+        db,
+        dev_id,
+        DeviceEvent {
+            timestamp: Utc::now().timestamp() as u64,
+            level: EventLogLevel::Warn,
+            message: format!(
+                "Requested files for fetch {}: {}",
+                arg.fetch.fetch_id,
+                arg.fetch.paths.join(", ")
+            ),
+        },
+    )
+    .await;
+    Ok(())
+}
+
+#[admin_handler("/list_file_fetches")]
+pub async fn list_file_fetches(
+    db: &mut DbConnection,
+    dev_name: String,
+) -> Result<Vec<FileFetchInfo>> {
+    let dev_id = get_dev_id_by_name(db, &dev_name).await?;
+    fetches::list_for_device(db, dev_id).await
+}
+
+#[admin_handler("/get_fetched_chunk")]
+pub async fn get_fetched_chunk(db: &mut DbConnection, arg: FetchedChunkArg) -> Result<Vec<u8>> {
+    // One chunk at a time, so big files never have to fit in a single reply
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    fetches::get_chunk(db, dev_id, arg.fetch_id, &arg.path, arg.seq).await
+}
+
+#[admin_handler("/delete_file_fetch")]
+pub async fn delete_file_fetch(db: &mut DbConnection, arg: FileFetchIdArg) -> Result<()> {
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    fetches::delete(db, dev_id, arg.fetch_id).await
+}
+
+#[admin_handler("/get_device_events")]
+pub async fn get_device_events(
+    db: &mut DbConnection,
+    dev_name: String,
+) -> Result<Vec<DeviceEvent>> {
+    let dev_id = get_dev_id_by_name(db, &dev_name).await?;
+    let events = events::get_for_device(db, dev_id).await?;
+    tracing::info!("Sending {} device events", events.len());
+    Ok(events)
+}
+
+#[admin_handler("/list_device_events")]
+pub async fn list_device_events(db: &mut DbConnection, arg: GetEventsArg) -> Result<EventPage> {
+    let dev_id = get_dev_id_by_name(db, &arg.dev_name).await?;
+    let filter = events::EventFilter {
+        since: arg.since.map(naive_from_timestamp).transpose()?,
+        until: arg.until.map(naive_from_timestamp).transpose()?,
+        min_level: arg.min_level,
+        search: arg.search.filter(|s| !s.is_empty()),
+        after: arg.cursor.as_deref().map(PageCursor::decode).transpose()?,
+        limit: page_limit(arg.limit, 100, 1000),
+    };
+    let (events, next) = events::list_for_device(db, dev_id, filter).await?;
+    tracing::info!("Sending a page of {} device events", events.len());
+    Ok(EventPage {
+        events,
+        next_cursor: next.map(|c| c.encode()),
+    })
+}
+
+#[admin_handler("/delete_device_events")]
+pub async fn delete_device_events(db: &mut DbConnection, dev_name: String) -> Result<()> {
+    let dev_id = get_dev_id_by_name(db, &dev_name).await?;
+    events::delete_for_device(db, dev_id).await?;
+    Ok(())
+}
+
+#[admin_handler("/list_rules")]
+pub async fn list_rules(db: &mut DbConnection) -> Result<Vec<AutomationRule>> {
+    rules::list(db).await
+}
+
+#[admin_handler("/set_rule")]
+pub async fn set_rule(db: &mut DbConnection, rule: AutomationRule) -> Result<()> {
+    if rule.name.trim().is_empty() || rule.name.len() > MAX_RULE_NAME_LEN {
+        bail!("Automation rule names must have 1 to {MAX_RULE_NAME_LEN} bytes");
+    }
+    if rule.threshold == 0 || rule.threshold > i32::MAX as u32 {
+        bail!("Automation rules must fire after at least one match");
+    }
+    if rule.window_secs > i32::MAX as u32 || (rule.threshold > 1 && rule.window_secs == 0) {
+        bail!("Automation rules with a threshold need a window to count matches in");
+    }
+    match rule.trigger {
+        RuleTrigger::Offline { secs } if secs == 0 || secs > i32::MAX as u32 => {
+            bail!("Offline rules need a duration")
+        }
+        RuleTrigger::Offline { .. } if rule.threshold > 1 => {
+            bail!("Offline rules fire once per disconnection, they can't have a threshold")
+        }
+        _ => {}
+    }
+    if let RuleAction::Lock {
+        vt_locked: false,
+        ssh_locked: false,
+        draw_decoy: false,
+    } = rule.action
+    {
+        bail!("Lock actions must lock something");
+    }
+    let dev_id = match &rule.dev_name {
+        Some(dev_name) => Some(get_dev_id_by_name(db, dev_name).await?),
+        None => None,
+    };
+    rules::upsert(db, &rule, dev_id).await
+}
+
+#[admin_handler("/delete_rule")]
+pub async fn delete_rule(db: &mut DbConnection, name: String) -> Result<()> {
+    rules::delete(db, &name).await
+}
+
+#[admin_handler("/list_rule_executions")]
+pub async fn list_rule_executions(
+    db: &mut DbConnection,
+    arg: ListRuleExecutionsArg,
+) -> Result<Vec<RuleExecution>> {
+    let limit = page_limit(arg.limit, 100, 1000);
+    rules::list_executions(db, arg.rule_name.as_deref(), limit).await
+}
+
+#[cfg(test)]
+mod test {
+    use crate::db::DbPool;
+    use crate::error::Result;
+    use crate::model::device;
+    use crate::model::device::test::{insert_test_device, insert_test_pending_device};
+    use crate::model::page::naive_from_timestamp;
+    use crate::model::pics::{DbCaptureTrigger, DeviceCameraPicture};
+    use crate::model::{connections, events, fetches, scripts};
+    use crate::picture::test::test_jpeg;
+    use crate::picture::THUMBNAIL_SIZE;
+    use crate::protocol::legacy::{RegisteredDeviceV3, RegisteredDeviceV6, SetStatusArgV6};
+    use crate::server::{make_test_server, serve_test_server, TestServer};
+    use aegisd_handler_macros::db_test;
+    use aegislib::client::{ClientConfig, DeviceClient};
+    use aegislib::command::admin::{
+        ApplyProfileArg, ArchivedDevice, AutomationRule, CreateEnrollmentTokenArg,
+        CreatedEnrollmentToken, DeviceExport, DeviceRetention, EnrollmentToken, EventPage,
+        FetchFilesArg, FetchedChunkArg, FileFetchIdArg, FileFetchInfo, GetEventsArg,
+        GetPicturesArg, ImportDevicesReply, ImportedDevice, ListRuleExecutionsArg, PendingDevice,
+        PictureIdArg, PictureInfoPage, PicturePage, ProtectionProfile, RegisteredDevice,
+        RenameDeviceArg, RetentionPolicy, RuleAction, RuleExecution, RuleTrigger, RunScriptArg,
+        ScheduledStatusChange, ScriptOutput, ScriptRun, ScriptRunIdArg, SetCapturePolicyArg,
+        SetDeviceInfoArg, SetRetentionArg, SetStatusArg, SetStolenArg, SignedArg, SignedStatusArg,
+        StatusHistoryEntry, StatusSchedule, StatusScheduleIdArg, StoredCameraPicture,
+    };
+    use aegislib::command::device::{
+        CaptureTrigger, DeviceEvent, EventLogLevel, FileChunk, ScriptResult, StatusReply,
+    };
+    use aegislib::command::server::{
+        CapturePolicy, FileFetch, PowerCommand, Script, ServerCommand,
+    };
+    use aegislib::command::signed::{AdminCommand, SignedCommand, StatusChange};
+    use aegislib::crypto::sha256_hex;
+    use aegislib::crypto::{random_sign_keypair, randomized_signature, SigningKey};
+    use aegislib::protocol::{
+        PeerProtocol, CAPABILITIES_HEADER, MIN_ADMIN_PROTOCOL_VERSION, PROTECTION_PROFILE_VERSION,
+        PROTOCOL_VERSION, PROTOCOL_VERSION_HEADER, STATUS_SCHEDULE_VERSION,
+    };
+    use anyhow::anyhow;
+    use axum::body::Bytes;
+    use axum::response::Response;
+    use base64::prelude::*;
+    use chrono::Utc;
     use http::{Request, StatusCode};
     use hyper::Body;
     use serde::de::DeserializeOwned;
     use serde::Serialize;
-    use sqlx::PgPool;
+    use std::time::Duration;
+    use tokio::sync::mpsc::channel;
     use tower_service::Service;
 
     fn signed_request(url: &str, body: Bytes, key: &SigningKey) -> Request<Body> {
         let sig = randomized_signature(key, url.as_bytes(), body.as_ref());
         let sig = BASE64_URL_SAFE_NO_PAD.encode(sig);
-        Request::post(url)
-            .header("Authorization", "Bearer ".to_string() + &sig)
-            .body(body.into())
-            .unwrap()
+        let mut req = Request::post(url).header("Authorization", "Bearer ".to_string() + &sig);
+        for (name, value) in PeerProtocol::current().header_values() {
+            req = req.header(name, value);
+        }
+        req.body(body.into()).unwrap()
     }
 
     async fn raw_request<T: Into<Bytes>>(
@@ -213,17 +1092,32 @@ mod test {
         server: &mut TestServer,
         url: &str,
         body: T,
+    ) -> Result<U> {
+        request_as(server, PROTOCOL_VERSION, url, body).await
+    }
+
+    /// Like [`request`], from an admin that speaks `version`
+    async fn request_as<T: Serialize, U: DeserializeOwned>(
+        server: &mut TestServer,
+        version: u32,
+        url: &str,
+        body: T,
     ) -> Result<U> {
         let body = Bytes::from(bincode::serialize(&body).unwrap());
-        let mut resp = raw_request(server, url, body).await?;
+        let mut req = signed_request(url, body, &server.root_key);
+        req.headers_mut().insert(
+            PROTOCOL_VERSION_HEADER,
+            version.to_string().parse().unwrap(),
+        );
+        let mut resp = server.app.call(req).await?;
         assert_eq!(resp.status(), StatusCode::OK);
         let resp_data = hyper::body::to_bytes(resp.body_mut()).await?;
         Ok(bincode::deserialize_from(resp_data.as_ref())
             .map_err(|e| anyhow!("Failed to deserialize: {e}"))?)
     }
 
-    #[sqlx::test]
-    async fn missing_auth_header(db: PgPool) -> Result<()> {
+    #[db_test]
+    async fn missing_auth_header(db: DbPool) -> Result<()> {
         let mut server = make_test_server(db).await?;
         let req = Request::post("/admin/list_pending_devices")
             .body(Body::empty())
@@ -236,8 +1130,8 @@ mod test {
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn bad_auth_header(db: PgPool) -> Result<()> {
+    #[db_test]
+    async fn bad_auth_header(db: DbPool) -> Result<()> {
         let mut server = make_test_server(db).await?;
         let bad_key = SigningKey::generate(&mut rand::thread_rng());
         let req = signed_request("/admin/list_pending_devices", Bytes::new(), &bad_key);
@@ -249,16 +1143,62 @@ mod test {
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn good_auth_header(db: PgPool) -> Result<()> {
+    #[db_test]
+    async fn ban_after_forged_signatures(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db).await?;
+        let bad_key = SigningKey::generate(&mut rand::thread_rng());
+        for _ in 0..=server.config.rate_limit.ban_after_forged_signatures {
+            let req = signed_request("/admin/list_pending_devices", Bytes::new(), &bad_key);
+            let resp = server.app.call(req).await?;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        }
+
+        // Even correctly signed requests are turned away while the ban lasts
+        let mut resp = raw_request(&mut server, "/admin/list_pending_devices", vec![]).await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body = hyper::body::to_bytes(resp.body_mut()).await?;
+        assert_eq!(body, b"Temporarily banned"[..]);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn good_auth_header(db: DbPool) -> Result<()> {
         let mut server = make_test_server(db).await?;
         let resp = raw_request(&mut server, "/admin/list_pending_devices", vec![]).await?;
         assert_eq!(resp.status(), StatusCode::OK);
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn list_pending(db: PgPool) -> Result<()> {
+    #[db_test]
+    async fn protocol_negotiation(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db).await?;
+        let resp = raw_request(&mut server, "/admin/list_pending_devices", vec![]).await?;
+        let server_protocol = PeerProtocol::from_header_values(
+            resp.headers()
+                .get(PROTOCOL_VERSION_HEADER)
+                .map(|v| v.as_bytes()),
+            resp.headers()
+                .get(CAPABILITIES_HEADER)
+                .map(|v| v.as_bytes()),
+        )
+        .map_err(|e| anyhow!(e))?;
+        assert_eq!(server_protocol, PeerProtocol::current());
+
+        let mut req = signed_request(
+            "/admin/list_pending_devices",
+            Bytes::new(),
+            &server.root_key,
+        );
+        let outdated = (MIN_ADMIN_PROTOCOL_VERSION - 1).to_string();
+        req.headers_mut()
+            .insert(PROTOCOL_VERSION_HEADER, outdated.parse().unwrap());
+        let resp = server.app.call(req).await?;
+        assert_eq!(resp.status(), StatusCode::UPGRADE_REQUIRED);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn list_pending(db: DbPool) -> Result<()> {
         let mut server = make_test_server(db.clone()).await?;
         let devs: Vec<PendingDevice> =
             request(&mut server, "/admin/list_pending_devices", ()).await?;
@@ -277,15 +1217,68 @@ mod test {
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn confirm_pending(db: PgPool) -> Result<()> {
+    #[db_test]
+    async fn rename_and_set_info(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        for name in ["laptop", "desktop"] {
+            let device_key = SigningKey::generate(&mut rand::thread_rng());
+            let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+            insert_test_device(conn, device_pk, name.into()).await?;
+        }
+
+        let taken = RenameDeviceArg {
+            dev_name: "laptop".into(),
+            new_name: "desktop".into(),
+        };
+        let body = Bytes::from(bincode::serialize(&taken).unwrap());
+        let resp = raw_request(&mut server, "/admin/rename_device", body).await?;
+        assert_ne!(resp.status(), StatusCode::OK);
+
+        let rename = RenameDeviceArg {
+            dev_name: "laptop".into(),
+            new_name: "alice-laptop".into(),
+        };
+        request::<_, ()>(&mut server, "/admin/rename_device", rename).await?;
+
+        let info = SetDeviceInfoArg {
+            dev_name: "alice-laptop".into(),
+            owner: Some("Alice".into()),
+            notes: Some("Spare charger in the IT closet".into()),
+            serial_number: Some("PF2ABCDE".into()),
+            ..Default::default()
+        };
+        request::<_, ()>(&mut server, "/admin/set_device_info", info).await?;
+        // Unset fields are kept, empty ones are cleared
+        let info = SetDeviceInfoArg {
+            dev_name: "alice-laptop".into(),
+            contact: Some("alice@example.com".into()),
+            notes: Some(String::new()),
+            ..Default::default()
+        };
+        request::<_, ()>(&mut server, "/admin/set_device_info", info).await?;
+
+        let devs: Vec<RegisteredDevice> =
+            request(&mut server, "/admin/list_registered_devices", ()).await?;
+        let dev = devs.iter().find(|d| d.name == "alice-laptop").unwrap();
+        assert_eq!(dev.owner.as_deref(), Some("Alice"));
+        assert_eq!(dev.contact.as_deref(), Some("alice@example.com"));
+        assert_eq!(dev.notes, None);
+        assert_eq!(dev.serial_number.as_deref(), Some("PF2ABCDE"));
+        assert_eq!(dev.hardware_model, None);
+        assert!(devs.iter().all(|d| d.name != "laptop"));
+        Ok(())
+    }
+
+    #[db_test]
+    async fn confirm_pending(db: DbPool) -> Result<()> {
         let mut server = make_test_server(db.clone()).await?;
         let conn = &mut db.acquire().await?;
         let device_key = SigningKey::generate(&mut rand::thread_rng());
         let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
         insert_test_pending_device(conn, device_pk.clone(), "test".into()).await?;
 
-        request(&mut server, "/admin/confirm_pending_device", "test").await?;
+        let () = request(&mut server, "/admin/confirm_pending_device", "test").await?;
         let pending = device::list_pending(conn).await?;
         assert!(pending.is_empty());
         let devs = device::list_registered(conn).await?;
@@ -296,15 +1289,15 @@ mod test {
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn delete_pending(db: PgPool) -> Result<()> {
+    #[db_test]
+    async fn delete_pending(db: DbPool) -> Result<()> {
         let mut server = make_test_server(db.clone()).await?;
         let conn = &mut db.acquire().await?;
         let device_key = SigningKey::generate(&mut rand::thread_rng());
         let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
         insert_test_pending_device(conn, device_pk.clone(), "test".into()).await?;
 
-        request(&mut server, "/admin/delete_pending_device", "test").await?;
+        let () = request(&mut server, "/admin/delete_pending_device", "test").await?;
         let pending = device::list_pending(conn).await?;
         assert!(pending.is_empty());
         let devs = device::list_registered(conn).await?;
@@ -312,8 +1305,74 @@ mod test {
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn list_registered(db: PgPool) -> Result<()> {
+    #[db_test]
+    async fn enrollment_tokens(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let arg = CreateEnrollmentTokenArg {
+            label: "lab laptops".into(),
+            max_uses: Some(5),
+            expires_at_timestamp: None,
+            auto_approve: false,
+        };
+        let created: CreatedEnrollmentToken =
+            request(&mut server, "/admin/create_enrollment_token", arg).await?;
+        assert!(!created.token.is_empty());
+
+        let tokens: Vec<EnrollmentToken> =
+            request(&mut server, "/admin/list_enrollment_tokens", ()).await?;
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].id, created.id);
+        assert_eq!(tokens[0].label, "lab laptops");
+        assert_eq!(tokens[0].max_uses, Some(5));
+        assert_eq!(tokens[0].uses, 0);
+
+        let () = request(&mut server, "/admin/delete_enrollment_token", created.id).await?;
+        let tokens: Vec<EnrollmentToken> =
+            request(&mut server, "/admin/list_enrollment_tokens", ()).await?;
+        assert!(tokens.is_empty());
+        Ok(())
+    }
+
+    #[db_test]
+    async fn import_devices(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        let existing_pk = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
+        insert_test_device(conn, existing_pk, "existing".into()).await?;
+
+        let new_pk = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
+        let other_pk = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
+        let devices = vec![
+            ImportedDevice {
+                name: "new".into(),
+                pubkey: new_pk.clone(),
+            },
+            ImportedDevice {
+                name: "existing".into(),
+                pubkey: other_pk,
+            },
+        ];
+        let reply: ImportDevicesReply =
+            request(&mut server, "/admin/import_devices", devices).await?;
+        assert_eq!(reply.imported, 1);
+        assert_eq!(reply.conflicts, vec!["existing".to_owned()]);
+
+        let dev_id = device::get_dev_id_by_name(conn, "new").await?;
+        assert_eq!(device::get_pubkey_by_id(conn, dev_id).await?, new_pk);
+        assert!(!device::get_status(conn, dev_id).await?.vt_locked);
+
+        let bad = vec![ImportedDevice {
+            name: "bad".into(),
+            pubkey: "not a key".into(),
+        }];
+        let body = Bytes::from(bincode::serialize(&bad).unwrap());
+        let resp = raw_request(&mut server, "/admin/import_devices", body).await?;
+        assert_ne!(resp.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn list_registered(db: DbPool) -> Result<()> {
         let mut server = make_test_server(db.clone()).await?;
         let devs: Vec<RegisteredDevice> =
             request(&mut server, "/admin/list_registered_devices", ()).await?;
@@ -329,38 +1388,152 @@ mod test {
         assert_eq!(devs.len(), 1);
         assert_eq!(devs[0].name, "test");
         assert_eq!(devs[0].pubkey, device_pk);
+
+        // Admins that predate protection profiles get devices without the active one
+        let url = "/admin/list_registered_devices";
+        let version = PROTECTION_PROFILE_VERSION - 1;
+        let devs: Vec<RegisteredDeviceV6> = request_as(&mut server, version, url, ()).await?;
+        assert_eq!(devs[0].name, "test");
+        assert_eq!(devs[0].pubkey, device_pk);
+        // And those that predate device metadata get none of it
+        let version = MIN_ADMIN_PROTOCOL_VERSION;
+        let devs: Vec<RegisteredDeviceV3> = request_as(&mut server, version, url, ()).await?;
+        assert_eq!(devs[0].name, "test");
+        assert_eq!(devs[0].pubkey, device_pk);
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn delete_registered(db: PgPool) -> Result<()> {
+    #[db_test]
+    async fn export_device(db: DbPool) -> Result<()> {
         let mut server = make_test_server(db.clone()).await?;
         let conn = &mut db.acquire().await?;
         let device_key = SigningKey::generate(&mut rand::thread_rng());
         let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
         insert_test_device(conn, device_pk.clone(), "test".into()).await?;
+        let dev_id = device::get_dev_id_by_name(conn, "test").await?;
+
+        device::update_status(conn, dev_id, Some(true), None, None, "admin").await?;
+        device::update_status(conn, dev_id, None, None, None, "admin").await?;
+        device::update_status(conn, dev_id, Some(false), Some(true), None, "admin").await?;
+        let connection = connections::open(conn, dev_id, "192.0.2.1:4242").await?;
+        connections::close(conn, connection).await?;
+        connections::open(conn, dev_id, "198.51.100.7:4242").await?;
+        let jpeg_data = test_jpeg(64, 48);
+        DeviceCameraPicture {
+            id: 0,
+            dev_id,
+            created_at: naive_from_timestamp(3000)?,
+            jpeg_data: jpeg_data.clone(),
+            width: Some(64),
+            height: Some(48),
+            trigger: DbCaptureTrigger::InputWhileLocked,
+        }
+        .insert(conn)
+        .await?;
+        device::archive(conn, dev_id).await?;
+
+        let export: DeviceExport = request(&mut server, "/admin/export_device", "test").await?;
+        assert_eq!(export.device.pubkey, device_pk);
+        assert!(export.archived_at.is_some());
+        assert!(export.status.ssh_locked && !export.status.vt_locked);
+        // No-op updates aren't changes
+        let history: Vec<_> = export
+            .status_history
+            .iter()
+            .map(|s| (s.vt_locked, s.ssh_locked))
+            .collect();
+        assert_eq!(history, [(true, false), (false, true)]);
+        assert_eq!(export.connections.len(), 2);
+        assert_eq!(export.connections[0].remote_addr, "192.0.2.1:4242");
+        assert!(export.connections[0].disconnected_at_timestamp.is_some());
+        assert!(export.connections[1].disconnected_at_timestamp.is_none());
+        assert_eq!(export.pictures.len(), 1);
+        assert_eq!(export.pictures[0].sha256, sha256_hex(&jpeg_data));
+        assert_eq!(
+            export.pictures[0].info.trigger,
+            CaptureTrigger::InputWhileLocked
+        );
+        assert!(export.events.is_empty());
+        // The export itself is logged, for the next one
+        let events = events::get_for_device(conn, dev_id).await?;
+        assert!(events[0].message.starts_with("Evidence exported"));
+        Ok(())
+    }
 
-        request(&mut server, "/admin/delete_registered_device", "test").await?;
+    #[db_test]
+    async fn archive_restore_purge(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        insert_test_device(conn, device_pk.clone(), "test".into()).await?;
+        let status_url = format!("/device/{device_pk}/status");
+        let req = signed_request(&status_url, Bytes::new(), &device_key);
+        assert_eq!(server.app.call(req).await?.status(), StatusCode::OK);
+
+        // Devices must be archived before they can be purged
+        let body = Bytes::from(bincode::serialize("test").unwrap());
+        let resp = raw_request(&mut server, "/admin/purge_device", body).await?;
+        assert_ne!(resp.status(), StatusCode::OK);
+
+        let () = request(&mut server, "/admin/archive_device", "test").await?;
         assert!(device::list_registered(conn).await?.is_empty());
+        let archived: Vec<ArchivedDevice> =
+            request(&mut server, "/admin/list_archived_devices", ()).await?;
+        assert_eq!(archived.len(), 1);
+        assert_eq!(archived[0].device.name, "test");
+        let req = signed_request(&status_url, Bytes::new(), &device_key);
+        assert_eq!(server.app.call(req).await?.status(), StatusCode::FORBIDDEN);
+        // The history is still there
+        let events: Vec<DeviceEvent> =
+            request(&mut server, "/admin/get_device_events", "test").await?;
+        assert!(events.iter().any(|e| e.message.contains("archived")));
+
+        let () = request(&mut server, "/admin/restore_device", "test").await?;
+        assert_eq!(device::list_registered(conn).await?.len(), 1);
+        let req = signed_request(&status_url, Bytes::new(), &device_key);
+        assert_eq!(server.app.call(req).await?.status(), StatusCode::OK);
+
+        let () = request(&mut server, "/admin/archive_device", "test").await?;
+        let () = request(&mut server, "/admin/purge_device", "test").await?;
+        let archived: Vec<ArchivedDevice> =
+            request(&mut server, "/admin/list_archived_devices", ()).await?;
+        assert!(archived.is_empty());
+        assert!(device::get_dev_id_by_name(conn, "test").await.is_err());
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn set_status(db: PgPool) -> Result<()> {
+    #[db_test]
+    async fn set_status(db: DbPool) -> Result<()> {
         let mut server = make_test_server(db.clone()).await?;
         let conn = &mut db.acquire().await?;
         let device_key = SigningKey::generate(&mut rand::thread_rng());
         let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
         insert_test_device(conn, device_pk.clone(), "test".into()).await?;
 
-        request(
+        let change = StatusChange {
+            vt_locked: Some(true),
+            ssh_locked: Some(false),
+            draw_decoy: None,
+        };
+        let command = SignedCommand::sign(
+            &server.root_key,
+            device_pk.clone(),
+            AdminCommand::SetStatus(change),
+        );
+        let _: StatusReply = request(
             &mut server,
             "/admin/set_status",
-            SetStatusArg {
-                dev_name: "test".to_string(),
-                vt_locked: Some(true),
-                ssh_locked: Some(false),
-                draw_decoy: None,
+            SignedStatusArg {
+                arg: SetStatusArg {
+                    dev_name: "test".to_string(),
+                    vt_locked: Some(true),
+                    ssh_locked: Some(false),
+                    draw_decoy: None,
+                    ..Default::default()
+                },
+                command,
+                revert: None,
             },
         )
         .await?;
@@ -369,6 +1542,830 @@ mod test {
         assert!(status.vt_locked);
         assert!(!status.ssh_locked);
         assert!(!status.draw_decoy);
+
+        // Admins that predate schedules send the change without them
+        let change = StatusChange {
+            vt_locked: None,
+            ssh_locked: None,
+            draw_decoy: Some(true),
+        };
+        let command =
+            SignedCommand::sign(&server.root_key, device_pk, AdminCommand::SetStatus(change));
+        let arg = SignedArg {
+            arg: SetStatusArgV6 {
+                dev_name: "test".to_string(),
+                vt_locked: None,
+                ssh_locked: None,
+                draw_decoy: Some(true),
+            },
+            command,
+        };
+        let version = STATUS_SCHEDULE_VERSION - 1;
+        let _: StatusReply = request_as(&mut server, version, "/admin/set_status", arg).await?;
+        let status = device::get_status(conn, id).await?;
+        assert!(status.vt_locked && status.draw_decoy);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn scheduled_status(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        insert_test_device(conn, device_pk.clone(), "test".into()).await?;
+        let lock = StatusChange {
+            vt_locked: Some(true),
+            ssh_locked: None,
+            draw_decoy: None,
+        };
+        let lock_arg = |schedule, expires_after_secs| SetStatusArg {
+            dev_name: "test".to_string(),
+            vt_locked: Some(true),
+            schedule,
+            expires_after_secs,
+            ..Default::default()
+        };
+        let root_key = server.root_key.clone();
+        let sign = |change: StatusChange, issued_at| {
+            let cmd = AdminCommand::SetStatus(change);
+            SignedCommand::sign_at(&root_key, device_pk.clone(), cmd, issued_at)
+        };
+
+        // Lock for two hours
+        let now = Utc::now().timestamp() as u64;
+        let arg = SignedStatusArg {
+            arg: lock_arg(None, Some(7200)),
+            command: sign(lock, now),
+            revert: Some(sign(lock.inverted(), now + 7200)),
+        };
+        let status: StatusReply = request(&mut server, "/admin/set_status", arg).await?;
+        assert!(status.vt_locked);
+        let scheduled: Vec<ScheduledStatusChange> =
+            request(&mut server, "/admin/list_status_schedules", "test").await?;
+        assert_eq!(scheduled.len(), 1);
+        assert!(scheduled[0].is_expiry && !scheduled[0].repeat_daily);
+        assert_eq!(scheduled[0].apply_at_timestamp, now + 7200);
+        assert_eq!(scheduled[0].vt_locked, Some(false));
+
+        // The revert must be signed for the expiry time
+        let arg = SignedStatusArg {
+            arg: lock_arg(None, Some(7200)),
+            command: sign(lock, now),
+            revert: Some(sign(lock.inverted(), now)),
+        };
+        let body = bincode::serialize(&arg).unwrap();
+        let resp = raw_request(&mut server, "/admin/set_status", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Unlocking every day would need a new signature every day
+        let arg = SignedStatusArg {
+            arg: SetStatusArg {
+                vt_locked: Some(false),
+                ..lock_arg(Some(StatusSchedule::Daily { minute_of_day: 420 }), None)
+            },
+            command: sign(lock.inverted(), now),
+            revert: None,
+        };
+        let body = bincode::serialize(&arg).unwrap();
+        let resp = raw_request(&mut server, "/admin/set_status", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Lock every night at 22:00, until cancelled
+        let nightly = Some(StatusSchedule::Daily {
+            minute_of_day: 22 * 60,
+        });
+        let arg = SignedStatusArg {
+            arg: lock_arg(nightly, None),
+            command: sign(lock, now),
+            revert: None,
+        };
+        let _: StatusReply = request(&mut server, "/admin/set_status", arg).await?;
+        let scheduled: Vec<ScheduledStatusChange> =
+            request(&mut server, "/admin/list_status_schedules", "test").await?;
+        let nightly = scheduled.iter().find(|s| s.repeat_daily).unwrap();
+        assert_eq!(nightly.apply_at_timestamp % (24 * 3600), 22 * 3600);
+        let arg = StatusScheduleIdArg {
+            dev_name: "test".to_string(),
+            id: nightly.id,
+        };
+        request::<_, ()>(&mut server, "/admin/cancel_status_schedule", arg).await?;
+        let scheduled: Vec<ScheduledStatusChange> =
+            request(&mut server, "/admin/list_status_schedules", "test").await?;
+        assert_eq!(scheduled.len(), 1);
+
+        let history: Vec<StatusHistoryEntry> =
+            request(&mut server, "/admin/status_history", "test").await?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].changed_by, "admin");
+        assert!(history[0].vt_locked);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn protection_profiles(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        insert_test_device(conn, device_pk.clone(), "test".into()).await?;
+
+        let mut stolen = ProtectionProfile {
+            name: "stolen".to_string(),
+            vt_locked: true,
+            ssh_locked: true,
+            draw_decoy: false,
+            capture_interval_secs: Some(60),
+            telemetry_interval_secs: None,
+        };
+        let too_often = ProtectionProfile {
+            capture_interval_secs: Some(1),
+            ..stolen.clone()
+        };
+        let body = bincode::serialize(&too_often).unwrap();
+        let resp = raw_request(&mut server, "/admin/set_profile", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        request::<_, ()>(&mut server, "/admin/set_profile", &stolen).await?;
+        stolen.draw_decoy = true;
+        request::<_, ()>(&mut server, "/admin/set_profile", &stolen).await?;
+        let profiles: Vec<ProtectionProfile> =
+            request(&mut server, "/admin/list_profiles", ()).await?;
+        assert_eq!(profiles, [stolen.clone()]);
+
+        let root_key = server.root_key.clone();
+        let sign = |cmd| SignedCommand::sign(&root_key, device_pk.clone(), cmd);
+        let apply = |status_command| ApplyProfileArg {
+            dev_name: "test".to_string(),
+            profile: Some("stolen".to_string()),
+            profile_command: sign(AdminCommand::SetProfile(Some(stolen.device_profile()))),
+            status_command,
+        };
+
+        // The status command must match the profile
+        let unlock = StatusChange {
+            vt_locked: Some(false),
+            ..stolen.status_change()
+        };
+        let arg = apply(Some(sign(AdminCommand::SetStatus(unlock))));
+        let body = bincode::serialize(&arg).unwrap();
+        let resp = raw_request(&mut server, "/admin/apply_profile", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let arg = apply(Some(sign(AdminCommand::SetStatus(stolen.status_change()))));
+        let status: StatusReply = request(&mut server, "/admin/apply_profile", arg).await?;
+        assert!(status.vt_locked && status.ssh_locked && status.draw_decoy);
+        let history: Vec<StatusHistoryEntry> =
+            request(&mut server, "/admin/status_history", "test").await?;
+        assert_eq!(history.last().unwrap().changed_by, "profile 'stolen'");
+
+        // Clearing the profile leaves the status alone
+        let arg = ApplyProfileArg {
+            dev_name: "test".to_string(),
+            profile: None,
+            profile_command: sign(AdminCommand::SetProfile(None)),
+            status_command: None,
+        };
+        let status: StatusReply = request(&mut server, "/admin/apply_profile", arg).await?;
+        assert!(status.vt_locked);
+
+        request::<_, ()>(&mut server, "/admin/delete_profile", "stolen").await?;
+        let body = bincode::serialize("stolen").unwrap();
+        let resp = raw_request(&mut server, "/admin/delete_profile", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = raw_request(
+            &mut server,
+            "/admin/apply_profile",
+            bincode::serialize(&apply(None)).unwrap(),
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn mismatched_signed_command(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        insert_test_device(conn, device_pk.clone(), "test".into()).await?;
+
+        // The signed command unlocks, while the request claims to lock
+        let unlock = StatusChange {
+            vt_locked: Some(false),
+            ssh_locked: None,
+            draw_decoy: None,
+        };
+        let command =
+            SignedCommand::sign(&server.root_key, device_pk, AdminCommand::SetStatus(unlock));
+        let arg = SignedStatusArg {
+            arg: SetStatusArg {
+                dev_name: "test".to_string(),
+                vt_locked: Some(true),
+                ssh_locked: None,
+                draw_decoy: None,
+                ..Default::default()
+            },
+            command,
+            revert: None,
+        };
+        let body = bincode::serialize(&arg).unwrap();
+        let resp = raw_request(&mut server, "/admin/set_status", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Signed for another device
+        let other_pk = BASE64_URL_SAFE_NO_PAD.encode(
+            SigningKey::generate(&mut rand::thread_rng())
+                .verifying_key()
+                .as_ref(),
+        );
+        let command = SignedCommand::sign(
+            &server.root_key,
+            other_pk,
+            AdminCommand::Power(PowerCommand::Reboot),
+        );
+        let arg = SignedArg {
+            arg: aegislib::command::admin::SendPowerCommandArg {
+                dev_name: "test".to_string(),
+                command: PowerCommand::Reboot,
+            },
+            command,
+        };
+        let body = bincode::serialize(&arg).unwrap();
+        let resp = raw_request(&mut server, "/admin/send_power_command", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn list_events_filtered(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        insert_test_device(conn, device_pk, "test".into()).await?;
+        let dev_id = device::get_dev_id_by_name(conn, "test").await?;
+        let levels = [
+            EventLogLevel::Info,
+            EventLogLevel::Warn,
+            EventLogLevel::Error,
+        ];
+        for i in 0..9 {
+            let event = DeviceEvent {
+                timestamp: 1000 + i,
+                level: levels[i as usize % 3],
+                message: format!("Event {i} 50%_off"),
+            };
+            events::insert(conn, dev_id, event).await?;
+        }
+
+        let mut arg = GetEventsArg {
+            dev_name: "test".into(),
+            since: Some(1001),
+            until: Some(1008),
+            min_level: Some(EventLogLevel::Warn),
+            search: None,
+            cursor: None,
+            limit: Some(3),
+        };
+        let page: EventPage = request(&mut server, "/admin/list_device_events", &arg).await?;
+        let stamps: Vec<_> = page.events.iter().map(|e| e.timestamp).collect();
+        assert_eq!(stamps, [1001, 1002, 1004]);
+        arg.cursor = page.next_cursor;
+        let page: EventPage = request(&mut server, "/admin/list_device_events", &arg).await?;
+        let stamps: Vec<_> = page.events.iter().map(|e| e.timestamp).collect();
+        assert_eq!(stamps, [1005, 1007]);
+        assert!(page.next_cursor.is_none());
+
+        let arg = GetEventsArg {
+            dev_name: "test".into(),
+            since: None,
+            until: None,
+            min_level: None,
+            search: Some("EVENT 4 50%_".into()),
+            cursor: None,
+            limit: None,
+        };
+        let page: EventPage = request(&mut server, "/admin/list_device_events", &arg).await?;
+        assert_eq!(page.events.len(), 1);
+        assert_eq!(page.events[0].timestamp, 1004);
+
+        let bad_cursor = GetEventsArg {
+            cursor: Some("garbage".into()),
+            ..arg
+        };
+        let body = bincode::serialize(&bad_cursor).unwrap();
+        let resp = raw_request(&mut server, "/admin/list_device_events", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn list_pictures_paged(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        insert_test_device(conn, device_pk, "test".into()).await?;
+        let dev_id = device::get_dev_id_by_name(conn, "test").await?;
+        // Pictures taken in the same second must not be skipped or repeated across pages
+        for i in 0..5u8 {
+            let pic = DeviceCameraPicture {
+                id: 0,
+                dev_id,
+                created_at: naive_from_timestamp(2000 + i as u64 / 2)?,
+                jpeg_data: vec![i],
+                width: None,
+                height: None,
+                trigger: DbCaptureTrigger::Unknown,
+            };
+            pic.insert(conn).await?;
+        }
+
+        let mut arg = GetPicturesArg {
+            dev_name: "test".into(),
+            since: None,
+            until: None,
+            cursor: None,
+            limit: Some(2),
+        };
+        let mut seen = Vec::new();
+        loop {
+            let page: PicturePage =
+                request(&mut server, "/admin/list_device_camera_pictures", &arg).await?;
+            seen.extend(page.pictures.into_iter().map(|p| p.jpeg_data[0]));
+            match page.next_cursor {
+                Some(cursor) => arg.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(seen, [0, 1, 2, 3, 4]);
+
+        arg.cursor = None;
+        arg.since = Some(2001);
+        arg.limit = None;
+        let page: PicturePage =
+            request(&mut server, "/admin/list_device_camera_pictures", &arg).await?;
+        assert_eq!(page.pictures.len(), 3);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn single_pictures(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        for name in ["test", "other"] {
+            let device_key = SigningKey::generate(&mut rand::thread_rng());
+            let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+            insert_test_device(conn, device_pk, name.into()).await?;
+        }
+        let dev_id = device::get_dev_id_by_name(conn, "test").await?;
+        let jpeg_data = test_jpeg(640, 480);
+        DeviceCameraPicture {
+            id: 0,
+            dev_id,
+            created_at: naive_from_timestamp(3000)?,
+            jpeg_data: jpeg_data.clone(),
+            width: Some(640),
+            height: Some(480),
+            trigger: DbCaptureTrigger::InputWhileLocked,
+        }
+        .insert(conn)
+        .await?;
+
+        let arg = GetPicturesArg {
+            dev_name: "test".into(),
+            since: None,
+            until: None,
+            cursor: None,
+            limit: None,
+        };
+        let page: PictureInfoPage =
+            request(&mut server, "/admin/list_device_picture_info", &arg).await?;
+        assert_eq!(page.pictures.len(), 1);
+        let info = &page.pictures[0];
+        assert_eq!(info.created_at_timestamp, 3000);
+        assert_eq!(info.size, jpeg_data.len() as u64);
+        assert_eq!((info.width, info.height), (Some(640), Some(480)));
+        assert_eq!(info.trigger, CaptureTrigger::InputWhileLocked);
+
+        let id_arg = PictureIdArg {
+            dev_name: "test".into(),
+            id: info.id,
+        };
+        let pic: StoredCameraPicture =
+            request(&mut server, "/admin/get_device_camera_picture", &id_arg).await?;
+        assert_eq!(pic.jpeg_data, jpeg_data);
+        let thumb: StoredCameraPicture =
+            request(&mut server, "/admin/get_device_picture_thumbnail", &id_arg).await?;
+        assert_eq!(
+            crate::picture::dimensions(&thumb.jpeg_data),
+            Some((THUMBNAIL_SIZE, THUMBNAIL_SIZE * 3 / 4))
+        );
+
+        // Pictures are only reachable through the device that took them
+        let wrong_device = PictureIdArg {
+            dev_name: "other".into(),
+            id: info.id,
+        };
+        let body = bincode::serialize(&wrong_device).unwrap();
+        let resp = raw_request(&mut server, "/admin/get_device_camera_picture", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = bincode::serialize(&wrong_device).unwrap();
+        let resp = raw_request(&mut server, "/admin/delete_device_camera_picture", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let () = request(&mut server, "/admin/delete_device_camera_picture", &id_arg).await?;
+        let page: PictureInfoPage =
+            request(&mut server, "/admin/list_device_picture_info", &arg).await?;
+        assert!(page.pictures.is_empty());
+        Ok(())
+    }
+
+    #[db_test]
+    async fn older_admins_get_known_triggers(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        insert_test_device(conn, "pk".into(), "test".into()).await?;
+        let dev_id = device::get_dev_id_by_name(conn, "test").await?;
+        DeviceCameraPicture {
+            id: 0,
+            dev_id,
+            created_at: naive_from_timestamp(3000)?,
+            jpeg_data: test_jpeg(64, 48),
+            width: Some(64),
+            height: Some(48),
+            trigger: DbCaptureTrigger::Requested,
+        }
+        .insert(conn)
+        .await?;
+
+        let arg = GetPicturesArg {
+            dev_name: "test".into(),
+            since: None,
+            until: None,
+            cursor: None,
+            limit: None,
+        };
+        let url = "/admin/list_device_picture_info";
+        let page: PictureInfoPage = request(&mut server, url, &arg).await?;
+        assert_eq!(page.pictures[0].trigger, CaptureTrigger::Requested);
+        let version = MIN_ADMIN_PROTOCOL_VERSION;
+        let page: PictureInfoPage = request_as(&mut server, version, url, &arg).await?;
+        assert_eq!(page.pictures[0].trigger, CaptureTrigger::Unknown);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn device_retention(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        insert_test_device(conn, device_pk, "test".into()).await?;
+
+        let retention: DeviceRetention =
+            request(&mut server, "/admin/get_device_retention", "test").await?;
+        assert!(retention.policy.is_none());
+        assert!(!retention.stolen);
+
+        let policy = RetentionPolicy {
+            event_max_age_days: Some(30),
+            event_min_level: Some(EventLogLevel::Warn),
+            picture_max_bytes: Some(1 << 30),
+            ..Default::default()
+        };
+        let arg = SetRetentionArg {
+            dev_name: "test".into(),
+            policy: Some(policy.clone()),
+        };
+        let () = request(&mut server, "/admin/set_device_retention", arg).await?;
+        let arg = SetStolenArg {
+            dev_name: "test".into(),
+            stolen: true,
+        };
+        let () = request(&mut server, "/admin/set_device_stolen", arg).await?;
+        let retention: DeviceRetention =
+            request(&mut server, "/admin/get_device_retention", "test").await?;
+        assert_eq!(retention.policy, Some(policy));
+        assert!(retention.stolen);
+
+        let arg = SetRetentionArg {
+            dev_name: "test".into(),
+            policy: None,
+        };
+        let () = request(&mut server, "/admin/set_device_retention", arg).await?;
+        let retention: DeviceRetention =
+            request(&mut server, "/admin/get_device_retention", "test").await?;
+        assert!(retention.policy.is_none());
+        Ok(())
+    }
+
+    #[db_test]
+    async fn capture_policy(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        insert_test_device(conn, device_pk, "test".into()).await?;
+
+        let policy: CapturePolicy =
+            request(&mut server, "/admin/get_capture_policy", "test").await?;
+        assert_eq!(policy, CapturePolicy::default());
+
+        let custom = CapturePolicy {
+            webcam_interval_secs: Some(300),
+            screenshot_interval_secs: Some(600),
+            burst_count: 3,
+            burst_interval_secs: 2,
+            input_cooldown_secs: 30,
+            max_uploads_per_hour: 60,
+        };
+        let arg = SetCapturePolicyArg {
+            dev_name: "test".into(),
+            policy: Some(custom),
+        };
+        let () = request(&mut server, "/admin/set_capture_policy", arg).await?;
+        let policy: CapturePolicy =
+            request(&mut server, "/admin/get_capture_policy", "test").await?;
+        assert_eq!(policy, custom);
+
+        // Devices would clamp it anyway, but the admin should know
+        let arg = SetCapturePolicyArg {
+            dev_name: "test".into(),
+            policy: Some(CapturePolicy {
+                webcam_interval_secs: Some(1),
+                ..custom
+            }),
+        };
+        let body = bincode::serialize(&arg).unwrap();
+        let resp = raw_request(&mut server, "/admin/set_capture_policy", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let arg = SetCapturePolicyArg {
+            dev_name: "test".into(),
+            policy: None,
+        };
+        let () = request(&mut server, "/admin/set_capture_policy", arg).await?;
+        let policy: CapturePolicy =
+            request(&mut server, "/admin/get_capture_policy", "test").await?;
+        assert_eq!(policy, CapturePolicy::default());
+        Ok(())
+    }
+
+    #[db_test]
+    async fn automation_rules(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        insert_test_device(conn, device_pk, "test".into()).await?;
+
+        let rule = AutomationRule {
+            name: "lock on failed logins".into(),
+            dev_name: Some("test".into()),
+            trigger: RuleTrigger::Event {
+                min_level: EventLogLevel::Warn,
+                contains: Some("login".into()),
+            },
+            threshold: 3,
+            window_secs: 600,
+            action: RuleAction::Lock {
+                vt_locked: true,
+                ssh_locked: true,
+                draw_decoy: false,
+            },
+            enabled: true,
+            dry_run: true,
+        };
+        let () = request(&mut server, "/admin/set_rule", rule.clone()).await?;
+        let rules: Vec<AutomationRule> = request(&mut server, "/admin/list_rules", ()).await?;
+        assert_eq!(rules, std::slice::from_ref(&rule));
+
+        let invalid = [
+            AutomationRule {
+                threshold: 2,
+                window_secs: 0,
+                ..rule.clone()
+            },
+            AutomationRule {
+                action: RuleAction::Lock {
+                    vt_locked: false,
+                    ssh_locked: false,
+                    draw_decoy: false,
+                },
+                ..rule.clone()
+            },
+            AutomationRule {
+                trigger: RuleTrigger::Offline { secs: 0 },
+                ..rule.clone()
+            },
+        ];
+        for invalid in invalid {
+            let body = bincode::serialize(&invalid).unwrap();
+            let resp = raw_request(&mut server, "/admin/set_rule", body).await?;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+
+        let arg = ListRuleExecutionsArg {
+            rule_name: None,
+            limit: None,
+        };
+        let executions: Vec<RuleExecution> =
+            request(&mut server, "/admin/list_rule_executions", arg).await?;
+        assert!(executions.is_empty());
+
+        let () = request(&mut server, "/admin/delete_rule", rule.name.clone()).await?;
+        let rules: Vec<AutomationRule> = request(&mut server, "/admin/list_rules", ()).await?;
+        assert!(rules.is_empty());
+        let body = bincode::serialize(&rule.name).unwrap();
+        let resp = raw_request(&mut server, "/admin/delete_rule", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn script_runs(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let addr = serve_test_server(db.clone(), &server.config).await?;
+        let conn = &mut db.acquire().await?;
+        // Websocket tests running alongside share the connection map, with their first devices
+        for name in ["spare", "spare2", "spare3"] {
+            let pk = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
+            insert_test_device(conn, pk, name.into()).await?;
+        }
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        insert_test_device(conn, device_pk.clone(), "test".into()).await?;
+        let dev_id = device::get_dev_id_by_name(conn, "test").await?;
+        let client_config = ClientConfig {
+            server_addr: addr.to_string(),
+            use_tls: false,
+            use_rest: false,
+            extra_ca_certs_pem: vec![],
+            pinned_server_cert_pem: None,
+            pinned_spki_sha256: None,
+            server_public_key: None,
+        };
+        let (event_tx, mut event_rx) = channel(1);
+        let _device = DeviceClient::new(&client_config, device_key, Some(event_tx))
+            .await
+            .map_err(|(_, e)| anyhow!(e))?;
+
+        let script = Script {
+            run_id: 1,
+            source: "uname -a".into(),
+            timeout_secs: 30,
+            env: Vec::new(),
+        };
+        let root_key = server.root_key.clone();
+        let run = |signed: Script| {
+            let command = SignedCommand::sign(
+                &root_key,
+                device_pk.clone(),
+                AdminCommand::RunScript(signed),
+            );
+            let arg = SignedArg {
+                arg: RunScriptArg {
+                    dev_name: "test".into(),
+                    script: script.clone(),
+                },
+                command,
+            };
+            bincode::serialize(&arg).unwrap()
+        };
+        // The server can't swap the script the admin signed
+        let tampered = Script {
+            source: "rm -rf /".into(),
+            ..script.clone()
+        };
+        let body = run(tampered);
+        let mut resp = raw_request(&mut server, "/admin/run_script", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(resp.body_mut()).await?;
+        assert_eq!(body, b"Signed command does not match the request"[..]);
+        let body = run(script.clone());
+        let resp = raw_request(&mut server, "/admin/run_script", body).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let received = tokio::time::timeout(Duration::from_secs(5), event_rx.recv())
+            .await
+            .unwrap();
+        match received {
+            Some(ServerCommand::Signed(signed)) => assert_eq!(
+                signed.unverified_payload()?.command,
+                AdminCommand::RunScript(script.clone())
+            ),
+            other => panic!("Unexpected server command: {other:?}"),
+        }
+
+        let result = ScriptResult {
+            run_id: 1,
+            exit_code: Some(3),
+            timed_out: false,
+            stdout: Vec::new(),
+            stderr: b"oops".to_vec(),
+            truncated: true,
+        };
+        scripts::record_result(conn, dev_id, &result, Utc::now().naive_utc()).await?;
+        let runs: Vec<ScriptRun> = request(&mut server, "/admin/list_script_runs", "test").await?;
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].source, "uname -a");
+        assert_eq!(runs[0].exit_code, Some(3));
+
+        let arg = ScriptRunIdArg {
+            dev_name: "test".into(),
+            run_id: 1,
+        };
+        let output: ScriptOutput = request(&mut server, "/admin/get_script_output", arg).await?;
+        assert_eq!(output.stderr, b"oops");
+        assert!(output.truncated);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn file_fetches(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        insert_test_device(conn, device_pk.clone(), "test".into()).await?;
+        let dev_id = device::get_dev_id_by_name(conn, "test").await?;
+
+        let fetch = FileFetch {
+            fetch_id: 9,
+            paths: vec!["/home/user/project".into()],
+            encrypt_to: None,
+        };
+        let root_key = server.root_key.clone();
+        let request_fetch = |signed: FileFetch| {
+            let command = SignedCommand::sign(
+                &root_key,
+                device_pk.clone(),
+                AdminCommand::FetchFiles(signed),
+            );
+            let arg = SignedArg {
+                arg: FetchFilesArg {
+                    dev_name: "test".into(),
+                    fetch: fetch.clone(),
+                },
+                command,
+            };
+            bincode::serialize(&arg).unwrap()
+        };
+        // The server can't widen what the admin signed
+        let tampered = FileFetch {
+            paths: vec!["/".into()],
+            ..fetch.clone()
+        };
+        let body = request_fetch(tampered);
+        let resp = raw_request(&mut server, "/admin/fetch_files", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = request_fetch(fetch.clone());
+        let resp = raw_request(&mut server, "/admin/fetch_files", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        fetches::insert(conn, dev_id, &fetch, Utc::now().naive_utc()).await?;
+        let chunk = FileChunk {
+            fetch_id: 9,
+            path: "/home/user/project/main.rs".into(),
+            seq: 0,
+            size: 12,
+            data: b"fn main() {}".to_vec(),
+            last: true,
+        };
+        fetches::store_chunk(conn, dev_id, &chunk).await?;
+        let fetched: Vec<FileFetchInfo> =
+            request(&mut server, "/admin/list_file_fetches", "test").await?;
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].paths, fetch.paths);
+        assert!(!fetched[0].encrypted);
+        assert!(fetched[0].finished_at_timestamp.is_none());
+        assert!(fetched[0].files[0].complete);
+
+        let arg = FetchedChunkArg {
+            dev_name: "test".into(),
+            fetch_id: 9,
+            path: chunk.path.clone(),
+            seq: 0,
+        };
+        let data: Vec<u8> = request(&mut server, "/admin/get_fetched_chunk", arg.clone()).await?;
+        assert_eq!(data, chunk.data);
+        let missing = FetchedChunkArg { seq: 1, ..arg };
+        let body = bincode::serialize(&missing).unwrap();
+        let resp = raw_request(&mut server, "/admin/get_fetched_chunk", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let arg = FileFetchIdArg {
+            dev_name: "test".into(),
+            fetch_id: 9,
+        };
+        let () = request(&mut server, "/admin/delete_file_fetch", arg.clone()).await?;
+        let fetched: Vec<FileFetchInfo> =
+            request(&mut server, "/admin/list_file_fetches", "test").await?;
+        assert!(fetched.is_empty());
+        let body = bincode::serialize(&arg).unwrap();
+        let resp = raw_request(&mut server, "/admin/delete_file_fetch", body).await?;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
         Ok(())
     }
 }
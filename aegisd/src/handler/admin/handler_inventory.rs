@@ -1,13 +1,13 @@
+use crate::db::DbPool;
 use crate::error::Error;
 use axum::body::{Body, Bytes};
 use axum::extract::State;
 use axum::http::Request;
-use sqlx::PgPool;
 use std::future::Future;
 use std::pin::Pin;
 
 type PinBoxFut<T> = Pin<Box<dyn Future<Output = T> + Send>>;
-pub type AdminHttpHandlerFn = fn(State<PgPool>, Request<Body>) -> PinBoxFut<Result<Bytes, Error>>;
+pub type AdminHttpHandlerFn = fn(State<DbPool>, Request<Body>) -> PinBoxFut<Result<Bytes, Error>>;
 
 pub struct AdminHandler {
     pub path: &'static str,
@@ -2,26 +2,44 @@
 mod handler_inventory;
 pub use handler_inventory::{device_handler_iter, DeviceHandlerFn};
 
+use crate::live::publish_for_device;
 use aegisd_handler_macros::device_handler;
+use aegislib::command::admin::{LiveEvent, MAX_PROFILE_NAME_LEN};
 use aegislib::command::device::{
-    DeviceEvent, EventLogLevel, StatusArg, StatusReply, StoreCameraPictureArg,
-    StoreCameraPictureReply,
+    CaptureTrigger, DeviceEvent, EventLogLevel, FileChunk, FileFetchFinished, HardwareInfo,
+    RotateKeyArg, RotateKeyReply, ScriptResult, StatusArg, StatusReply, StoreCameraPictureArg,
+    StoreCameraPictureReply, UploadCameraPictureArg,
 };
+use aegislib::command::server::{CapturePolicy, FileFetch, Script};
+use aegislib::crypto::{public_key_from_base64, sealed};
 
+use crate::db::DbConnection;
+use crate::model::capture_policy;
+use crate::model::device;
 use crate::model::device::get_status;
 use crate::model::events;
-use crate::model::pics::DeviceCameraPicture;
-use anyhow::Result;
+use crate::model::fetches;
+use crate::model::notifications::Trigger;
+use crate::model::pics::{self, DeviceCameraPicture};
+use crate::model::scripts;
+use crate::notify::notify_device;
+use crate::picture;
+use crate::rules::{self, RuleEvent};
+use anyhow::{bail, Result};
 use axum::body::Bytes;
-use chrono::Utc;
-use sqlx::PgConnection;
+use base64::prelude::*;
+use chrono::{Duration, Utc};
+use tracing::warn;
+
+/// Longest serial number or model name we store for a device
+const MAX_HARDWARE_FIELD_LEN: usize = 256;
 
 #[derive(Copy, Clone, Eq, PartialEq, Hash)]
 pub struct DeviceId(pub i32);
 
 #[device_handler("/status")]
 pub async fn status(
-    db: &mut PgConnection,
+    db: &mut DbConnection,
     dev_id: DeviceId,
     _args: StatusArg,
 ) -> Result<StatusReply> {
@@ -30,50 +48,264 @@ pub async fn status(
 
 #[device_handler("/store_camera_picture")]
 pub async fn store_camera_picture(
-    db: &mut PgConnection,
+    db: &mut DbConnection,
     dev_id: DeviceId,
     args: StoreCameraPictureArg,
+) -> Result<StoreCameraPictureReply> {
+    store_picture(db, dev_id, args.jpeg_data, CaptureTrigger::Unknown).await
+}
+
+#[device_handler("/upload_camera_picture")]
+pub async fn upload_camera_picture(
+    db: &mut DbConnection,
+    dev_id: DeviceId,
+    args: UploadCameraPictureArg,
+) -> Result<StoreCameraPictureReply> {
+    store_picture(db, dev_id, args.jpeg_data, args.trigger).await
+}
+
+async fn store_picture(
+    db: &mut DbConnection,
+    dev_id: DeviceId,
+    jpeg_data: Vec<u8>,
+    trigger: CaptureTrigger,
 ) -> Result<StoreCameraPictureReply> {
     let now = Utc::now().naive_utc();
-    let pic_size_kb = args.jpeg_data.len() / 1024;
+    let quota = capture_policy::get(db, dev_id.0)
+        .await?
+        .max_uploads_per_hour;
+    if pics::count_since(db, dev_id.0, now - Duration::hours(1)).await? >= quota as i64 {
+        bail!("Upload quota of {quota} pictures per hour exceeded");
+    }
+    let pic_size = jpeg_data.len() as u64;
+    let pic_size_kb = pic_size / 1024;
+    let dimensions = picture::dimensions(&jpeg_data);
+    if dimensions.is_none() {
+        warn!(
+            "Device {} uploaded a picture that isn't a valid JPEG",
+            dev_id.0
+        );
+    }
     DeviceCameraPicture {
         id: 0,
         dev_id: dev_id.0,
         created_at: now,
-        jpeg_data: args.jpeg_data,
+        jpeg_data,
+        width: dimensions.map(|(w, _)| w as i32),
+        height: dimensions.map(|(_, h)| h as i32),
+        trigger: trigger.into(),
     }
     .insert(db)
     .await?;
+    publish_for_device(db, dev_id.0, |dev_name| LiveEvent::PictureUploaded {
+        dev_name,
+        timestamp: now.and_utc().timestamp() as u64,
+        size: pic_size,
+    })
+    .await;
+    let message = format!("Camera picture uploaded ({pic_size_kb}kiB)");
+    notify_device(db, Trigger::PictureUploaded, dev_id.0, &message).await;
     let _ = events::insert(
         db,
         dev_id.0,
         DeviceEvent {
             timestamp: now.and_utc().timestamp() as u64,
             level: EventLogLevel::Info,
-            message: format!("Camera picture uploaded ({pic_size_kb}kiB)"),
+            message,
         },
     )
     .await;
+    rules::evaluate(db, dev_id.0, RuleEvent::Picture(trigger)).await;
     Ok(StoreCameraPictureReply {})
 }
 
 #[device_handler("/log_event")]
-pub async fn log_event(db: &mut PgConnection, dev_id: DeviceId, event: DeviceEvent) -> Result<()> {
-    events::insert(db, dev_id.0, event).await?;
+pub async fn log_event(db: &mut DbConnection, dev_id: DeviceId, event: DeviceEvent) -> Result<()> {
+    events::insert(db, dev_id.0, event.clone()).await?;
+    rules::evaluate(db, dev_id.0, RuleEvent::Logged(&event)).await;
+    Ok(())
+}
+
+#[device_handler("/report_hardware")]
+pub async fn report_hardware(
+    db: &mut DbConnection,
+    dev_id: DeviceId,
+    info: HardwareInfo,
+) -> Result<()> {
+    for field in [&info.serial_number, &info.hardware_model]
+        .into_iter()
+        .flatten()
+    {
+        if field.len() > MAX_HARDWARE_FIELD_LEN {
+            bail!("Hardware info fields are limited to {MAX_HARDWARE_FIELD_LEN} bytes");
+        }
+    }
+    device::report_hardware(db, dev_id.0, &info).await
+}
+
+#[device_handler("/capture_policy")]
+pub async fn get_capture_policy(
+    db: &mut DbConnection,
+    dev_id: DeviceId,
+    _args: (),
+) -> Result<CapturePolicy> {
+    capture_policy::get(db, dev_id.0).await
+}
+
+#[device_handler("/report_profile")]
+pub async fn report_profile(
+    db: &mut DbConnection,
+    dev_id: DeviceId,
+    name: Option<String>,
+) -> Result<()> {
+    if name
+        .as_ref()
+        .is_some_and(|n| n.len() > MAX_PROFILE_NAME_LEN)
+    {
+        bail!("Protection profile names are limited to {MAX_PROFILE_NAME_LEN} bytes");
+    }
+    let previous = device::get_by_id(db, dev_id.0).await?.active_profile;
+    if previous == name {
+        return Ok(());
+    }
+    device::report_profile(db, dev_id.0, name.as_deref()).await?;
+    let message = match name {
+        Some(name) => format!("Protection profile '{name}' is active"),
+        None => "No protection profile is active anymore".into(),
+    };
+    let _ = events::insert(
+        db,
+        dev_id.0,
+        DeviceEvent {
+            timestamp: Utc::now().timestamp() as u64,
+            level: EventLogLevel::Info,
+            message,
+        },
+    )
+    .await;
+    Ok(())
+}
+
+#[device_handler("/script_result")]
+pub async fn script_result(
+    db: &mut DbConnection,
+    dev_id: DeviceId,
+    result: ScriptResult,
+) -> Result<()> {
+    if result.stdout.len().max(result.stderr.len()) > Script::MAX_OUTPUT_LEN {
+        bail!(
+            "Script output is limited to {} bytes",
+            Script::MAX_OUTPUT_LEN
+        );
+    }
+    let now = Utc::now().naive_utc();
+    scripts::record_result(db, dev_id.0, &result, now).await?;
+    let (level, outcome) = match (result.timed_out, result.exit_code) {
+        (true, _) => (EventLogLevel::Warn, "timed out".to_owned()),
+        (false, Some(0)) => (EventLogLevel::Info, "succeeded".to_owned()),
+        (false, Some(code)) => (EventLogLevel::Warn, format!("exited with code {code}")),
+        (false, None) => (
+            EventLogLevel::Warn,
+            "was killed or failed to start".to_owned(),
+        ),
+    };
+    let _ = events::insert(
+        db,
+        dev_id.0,
+        DeviceEvent {
+            timestamp: now.and_utc().timestamp() as u64,
+            level,
+            message: format!("Script {} {outcome}", result.run_id),
+        },
+    )
+    .await;
     Ok(())
 }
 
+#[device_handler("/file_chunk")]
+pub async fn file_chunk(db: &mut DbConnection, dev_id: DeviceId, chunk: FileChunk) -> Result<()> {
+    // The first chunk of a sealed file also carries its header
+    let max_len = FileFetch::CHUNK_LEN + sealed::HEADER_LEN + sealed::TAG_LEN;
+    if chunk.data.len() > max_len {
+        bail!("File chunks are limited to {} bytes", FileFetch::CHUNK_LEN);
+    }
+    fetches::store_chunk(db, dev_id.0, &chunk).await
+}
+
+#[device_handler("/file_fetch_finished")]
+pub async fn file_fetch_finished(
+    db: &mut DbConnection,
+    dev_id: DeviceId,
+    finished: FileFetchFinished,
+) -> Result<()> {
+    let now = Utc::now().naive_utc();
+    fetches::finish(db, dev_id.0, &finished, now).await?;
+    let (level, outcome) = match finished.failures.len() {
+        0 => (EventLogLevel::Info, "finished".to_owned()),
+        n => (EventLogLevel::Warn, format!("finished, {n} paths failed")),
+    };
+    let _ = events::insert(
+        db,
+        dev_id.0,
+        DeviceEvent {
+            timestamp: now.and_utc().timestamp() as u64,
+            level,
+            message: format!("File fetch {} {outcome}", finished.fetch_id),
+        },
+    )
+    .await;
+    Ok(())
+}
+
+#[device_handler("/rotate_key")]
+pub async fn rotate_key(
+    db: &mut DbConnection,
+    dev_id: DeviceId,
+    arg: RotateKeyArg,
+) -> Result<RotateKeyReply> {
+    // The device keeps its ID, so its events and pictures stay attached to it
+    let current_pubkey = device::get_pubkey_by_id(db, dev_id.0).await?;
+    let new_pubkey = arg.verify(&public_key_from_base64(&current_pubkey)?)?;
+    let new_pubkey = BASE64_URL_SAFE_NO_PAD.encode(new_pubkey);
+    device::rotate_key(db, dev_id.0, &current_pubkey, &new_pubkey).await?;
+    let _ = events::insert(
+        db,
+        dev_id.0,
+        DeviceEvent {
+            timestamp: Utc::now().timestamp() as u64,
+            level: EventLogLevel::Info,
+            message: format!("Device key rotated, the new public key is {new_pubkey}"),
+        },
+    )
+    .await;
+    Ok(RotateKeyReply {})
+}
+
 #[cfg(test)]
 mod test {
+    use crate::config::Config;
+    use crate::db::DbPool;
     use crate::error::Result;
     use crate::model::device::test::insert_test_device;
-    use crate::server::make_test_server;
-    use aegislib::crypto::{randomized_signature, SigningKey};
+    use crate::model::device::{get_dev_id_by_name, get_pubkey_by_id, list_registered};
+    use crate::model::pics::{self, DbCaptureTrigger};
+    use crate::model::{capture_policy, events, fetches, scripts};
+    use crate::picture::test::test_jpeg;
+    use crate::server::{make_test_server, serve_test_server};
+    use aegisd_handler_macros::db_test;
+    use aegislib::client::{AdminClient, ClientConfig, DeviceClient};
+    use aegislib::command::device::{
+        CaptureTrigger, DeviceEvent, EventLogLevel, FetchFailure, FileChunk, FileFetchFinished,
+        HardwareInfo, RotateKeyArg, ScriptResult, StoreCameraPictureArg, UploadCameraPictureArg,
+    };
+    use aegislib::command::server::{CapturePolicy, FileFetch, Script};
+    use aegislib::crypto::sealed::{self, FileSealer};
+    use aegislib::crypto::{random_sign_keypair, randomized_signature, RootKeys, SigningKey};
     use axum::body::Bytes;
     use base64::prelude::*;
+    use chrono::Utc;
     use http::{Request, Response, StatusCode};
     use hyper::Body;
-    use sqlx::PgPool;
     use tower::Service;
 
     fn signed_request<T: Into<Bytes>>(url: &str, body: T, key: &SigningKey) -> Request<Body> {
@@ -86,8 +318,8 @@ mod test {
             .unwrap()
     }
 
-    #[sqlx::test]
-    async fn missing_auth_header(db: PgPool) -> Result<()> {
+    #[db_test]
+    async fn missing_auth_header(db: DbPool) -> Result<()> {
         let device_key = SigningKey::generate(&mut rand::thread_rng());
         let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
         let conn = &mut db.acquire().await?;
@@ -105,8 +337,8 @@ mod test {
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn bad_auth_header(db: PgPool) -> Result<()> {
+    #[db_test]
+    async fn bad_auth_header(db: DbPool) -> Result<()> {
         let device_key = SigningKey::generate(&mut rand::thread_rng());
         let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
         let conn = &mut db.acquire().await?;
@@ -123,8 +355,8 @@ mod test {
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn good_auth_header(db: PgPool) -> Result<()> {
+    #[db_test]
+    async fn good_auth_header(db: DbPool) -> Result<()> {
         let device_key = SigningKey::generate(&mut rand::thread_rng());
         let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
         let conn = &mut db.acquire().await?;
@@ -140,4 +372,402 @@ mod test {
         assert_eq!(resp.status(), StatusCode::OK);
         Ok(())
     }
+
+    #[db_test]
+    async fn upload_camera_picture(db: DbPool) -> Result<()> {
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        let conn = &mut db.acquire().await?;
+        insert_test_device(conn, device_pk.clone(), "test".into()).await?;
+        let dev_id = get_dev_id_by_name(conn, "test").await?;
+
+        let mut server = make_test_server(db.clone()).await?;
+        let arg = UploadCameraPictureArg {
+            jpeg_data: test_jpeg(32, 24),
+            trigger: CaptureTrigger::InputWhileLocked,
+        };
+        let url = format!("/device/{device_pk}/upload_camera_picture");
+        let req = signed_request(&url, bincode::serialize(&arg).unwrap(), &device_key);
+        assert_eq!(server.app.call(req).await?.status(), StatusCode::OK);
+        // Devices that predate capture triggers still use the old route
+        let arg = StoreCameraPictureArg {
+            jpeg_data: b"not a jpeg".to_vec(),
+        };
+        let url = format!("/device/{device_pk}/store_camera_picture");
+        let req = signed_request(&url, bincode::serialize(&arg).unwrap(), &device_key);
+        assert_eq!(server.app.call(req).await?.status(), StatusCode::OK);
+
+        let stored = pics::get_for_device(conn, dev_id).await?;
+        assert_eq!(stored.len(), 2);
+        let (new, legacy) = match stored[0].trigger {
+            DbCaptureTrigger::InputWhileLocked => (&stored[0], &stored[1]),
+            _ => (&stored[1], &stored[0]),
+        };
+        assert_eq!((new.width, new.height), (Some(32), Some(24)));
+        assert_eq!(legacy.trigger, DbCaptureTrigger::Unknown);
+        assert_eq!((legacy.width, legacy.height), (None, None));
+        Ok(())
+    }
+
+    #[db_test]
+    async fn rotate_key(db: DbPool) -> Result<()> {
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        let conn = &mut db.acquire().await?;
+        insert_test_device(conn, device_pk.clone(), "test".into()).await?;
+        let dev_id = get_dev_id_by_name(conn, "test").await?;
+        let event = DeviceEvent {
+            timestamp: 1_650_000_000,
+            level: EventLogLevel::Info,
+            message: "Before the rotation".into(),
+        };
+        events::insert(conn, dev_id, event).await?;
+
+        let mut server = make_test_server(db.clone()).await?;
+        let url = format!("/device/{device_pk}/rotate_key");
+        let new_key = SigningKey::generate(&mut rand::thread_rng());
+        // The proof must come from the new key
+        let forged = RotateKeyArg {
+            proof: RotateKeyArg::new(&device_key.verifying_key(), &device_key).proof,
+            ..RotateKeyArg::new(&device_key.verifying_key(), &new_key)
+        };
+        let req = signed_request(&url, bincode::serialize(&forged).unwrap(), &device_key);
+        assert_ne!(server.app.call(req).await?.status(), StatusCode::OK);
+
+        let arg = RotateKeyArg::new(&device_key.verifying_key(), &new_key);
+        let req = signed_request(&url, bincode::serialize(&arg).unwrap(), &device_key);
+        assert_eq!(server.app.call(req).await?.status(), StatusCode::OK);
+        assert_eq!(get_pubkey_by_id(conn, dev_id).await?, arg.new_pubkey);
+        assert_eq!(get_dev_id_by_name(conn, "test").await?, dev_id);
+        let history = events::get_for_device(conn, dev_id).await?;
+        assert!(history.iter().any(|e| e.message == "Before the rotation"));
+
+        let old_url = format!("/device/{device_pk}/status");
+        let req = signed_request(&old_url, Vec::new(), &device_key);
+        assert_eq!(server.app.call(req).await?.status(), StatusCode::FORBIDDEN);
+        let new_url = format!("/device/{}/status", arg.new_pubkey);
+        let req = signed_request(&new_url, Vec::new(), &new_key);
+        assert_eq!(server.app.call(req).await?.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn report_hardware(db: DbPool) -> Result<()> {
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        let conn = &mut db.acquire().await?;
+        insert_test_device(conn, device_pk.clone(), "test".into()).await?;
+
+        let mut server = make_test_server(db.clone()).await?;
+        let url = format!("/device/{device_pk}/report_hardware");
+        let info = HardwareInfo {
+            serial_number: Some("PF2ABCDE".into()),
+            hardware_model: Some("ThinkPad X1 Carbon".into()),
+        };
+        let req = signed_request(&url, bincode::serialize(&info).unwrap(), &device_key);
+        assert_eq!(server.app.call(req).await?.status(), StatusCode::OK);
+
+        // Fields the device couldn't read are left alone
+        let partial = HardwareInfo {
+            serial_number: None,
+            hardware_model: Some("ThinkPad X1 Carbon Gen 9".into()),
+        };
+        let req = signed_request(&url, bincode::serialize(&partial).unwrap(), &device_key);
+        assert_eq!(server.app.call(req).await?.status(), StatusCode::OK);
+
+        let too_long = HardwareInfo {
+            serial_number: Some("x".repeat(1000)),
+            hardware_model: None,
+        };
+        let req = signed_request(&url, bincode::serialize(&too_long).unwrap(), &device_key);
+        assert_ne!(server.app.call(req).await?.status(), StatusCode::OK);
+
+        let dev = list_registered(conn).await?.pop().unwrap();
+        assert_eq!(dev.serial_number.as_deref(), Some("PF2ABCDE"));
+        assert_eq!(
+            dev.hardware_model.as_deref(),
+            Some("ThinkPad X1 Carbon Gen 9")
+        );
+        Ok(())
+    }
+
+    #[db_test]
+    async fn report_profile(db: DbPool) -> Result<()> {
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        let conn = &mut db.acquire().await?;
+        insert_test_device(conn, device_pk.clone(), "test".into()).await?;
+
+        let mut server = make_test_server(db.clone()).await?;
+        let url = format!("/device/{device_pk}/report_profile");
+        let report = |name: Option<&str>| {
+            let body = bincode::serialize(&name).unwrap();
+            signed_request(&url, body, &device_key)
+        };
+        assert_eq!(
+            server.app.call(report(Some("stolen"))).await?.status(),
+            StatusCode::OK
+        );
+        let dev = list_registered(conn).await?.pop().unwrap();
+        assert_eq!(dev.active_profile.as_deref(), Some("stolen"));
+
+        let too_long = "x".repeat(1000);
+        let status = server.app.call(report(Some(&too_long))).await?.status();
+        assert_ne!(status, StatusCode::OK);
+
+        assert_eq!(
+            server.app.call(report(None)).await?.status(),
+            StatusCode::OK
+        );
+        let dev = list_registered(conn).await?.pop().unwrap();
+        assert_eq!(dev.active_profile, None);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn capture_policy_quota(db: DbPool) -> Result<()> {
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        let conn = &mut db.acquire().await?;
+        insert_test_device(conn, device_pk.clone(), "test".into()).await?;
+        let dev_id = get_dev_id_by_name(conn, "test").await?;
+        let policy = CapturePolicy {
+            webcam_interval_secs: Some(60),
+            max_uploads_per_hour: 2,
+            ..Default::default()
+        };
+        capture_policy::set(conn, dev_id, Some(policy)).await?;
+
+        let mut server = make_test_server(db.clone()).await?;
+        let url = format!("/device/{device_pk}/capture_policy");
+        let req = signed_request(&url, bincode::serialize(&()).unwrap(), &device_key);
+        let mut resp = server.app.call(req).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.body_mut()).await?;
+        assert_eq!(
+            bincode::deserialize::<CapturePolicy>(&body).unwrap(),
+            policy
+        );
+
+        let arg = UploadCameraPictureArg {
+            jpeg_data: test_jpeg(32, 24),
+            trigger: CaptureTrigger::Scheduled,
+        };
+        let url = format!("/device/{device_pk}/upload_camera_picture");
+        let upload = || signed_request(&url, bincode::serialize(&arg).unwrap(), &device_key);
+        for _ in 0..2 {
+            assert_eq!(server.app.call(upload()).await?.status(), StatusCode::OK);
+        }
+        assert_ne!(server.app.call(upload()).await?.status(), StatusCode::OK);
+        let stored = pics::get_for_device(conn, dev_id).await?;
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0].trigger, DbCaptureTrigger::Scheduled);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn script_result(db: DbPool) -> Result<()> {
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        let conn = &mut db.acquire().await?;
+        insert_test_device(conn, device_pk.clone(), "test".into()).await?;
+        let dev_id = get_dev_id_by_name(conn, "test").await?;
+        let script = Script {
+            run_id: 42,
+            source: "echo hi".into(),
+            timeout_secs: 10,
+            env: Vec::new(),
+        };
+        scripts::insert(conn, dev_id, &script, Utc::now().naive_utc()).await?;
+
+        let mut server = make_test_server(db.clone()).await?;
+        let url = format!("/device/{device_pk}/script_result");
+        let report = |run_id| {
+            let result = ScriptResult {
+                run_id,
+                exit_code: Some(0),
+                timed_out: false,
+                stdout: b"hi\n".to_vec(),
+                stderr: Vec::new(),
+                truncated: false,
+            };
+            signed_request(&url, bincode::serialize(&result).unwrap(), &device_key)
+        };
+        // Devices can only report scripts they were sent, once
+        assert_ne!(server.app.call(report(7)).await?.status(), StatusCode::OK);
+        assert_eq!(server.app.call(report(42)).await?.status(), StatusCode::OK);
+        assert_ne!(server.app.call(report(42)).await?.status(), StatusCode::OK);
+
+        let output = scripts::get_output(conn, dev_id, 42).await?;
+        assert_eq!(output.stdout, b"hi\n");
+        assert_eq!(output.run.exit_code, Some(0));
+        assert!(output.run.finished_at_timestamp.is_some());
+        Ok(())
+    }
+
+    #[db_test]
+    async fn file_chunks(db: DbPool) -> Result<()> {
+        let device_key = SigningKey::generate(&mut rand::thread_rng());
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key().as_ref());
+        let conn = &mut db.acquire().await?;
+        insert_test_device(conn, device_pk.clone(), "test".into()).await?;
+        let dev_id = get_dev_id_by_name(conn, "test").await?;
+        let fetch = FileFetch {
+            fetch_id: 5,
+            paths: vec!["/home/user/notes".into()],
+            encrypt_to: None,
+        };
+        fetches::insert(conn, dev_id, &fetch, Utc::now().naive_utc()).await?;
+
+        let mut server = make_test_server(db.clone()).await?;
+        let url = format!("/device/{device_pk}/file_chunk");
+        let send = |fetch_id, seq, data: &[u8], last| {
+            let chunk = FileChunk {
+                fetch_id,
+                path: "/home/user/notes/todo.txt".into(),
+                seq,
+                size: 6,
+                data: data.to_vec(),
+                last,
+            };
+            signed_request(&url, bincode::serialize(&chunk).unwrap(), &device_key)
+        };
+        // Chunks must belong to a fetch, and come in order
+        assert_ne!(
+            server.app.call(send(6, 0, b"abc", false)).await?.status(),
+            StatusCode::OK
+        );
+        assert_ne!(
+            server.app.call(send(5, 1, b"def", true)).await?.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            server.app.call(send(5, 0, b"abc", false)).await?.status(),
+            StatusCode::OK
+        );
+        assert_ne!(
+            server.app.call(send(5, 0, b"abc", false)).await?.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            server.app.call(send(5, 1, b"def", true)).await?.status(),
+            StatusCode::OK
+        );
+        assert_ne!(
+            server.app.call(send(5, 2, b"ghi", true)).await?.status(),
+            StatusCode::OK
+        );
+
+        let finished = FileFetchFinished {
+            fetch_id: 5,
+            failures: vec![FetchFailure {
+                path: "/home/user/notes/secret".into(),
+                error: "Permission denied".into(),
+            }],
+        };
+        let url = format!("/device/{device_pk}/file_fetch_finished");
+        let req = signed_request(&url, bincode::serialize(&finished).unwrap(), &device_key);
+        assert_eq!(server.app.call(req).await?.status(), StatusCode::OK);
+
+        let fetched = fetches::list_for_device(conn, dev_id).await?;
+        assert_eq!(fetched.len(), 1);
+        assert!(fetched[0].finished_at_timestamp.is_some());
+        let files = &fetched[0].files;
+        assert_eq!(files.len(), 2);
+        assert!(files[0].complete);
+        assert_eq!((files[0].size, files[0].chunks), (6, 2));
+        assert_eq!(files[1].error.as_deref(), Some("Permission denied"));
+        let chunk = fetches::get_chunk(conn, dev_id, 5, &files[0].path, 1).await?;
+        assert_eq!(chunk, b"def");
+        Ok(())
+    }
+
+    #[db_test]
+    async fn sealed_fetch_over_websocket(db: DbPool) -> Result<()> {
+        let root_keys = RootKeys {
+            sig: random_sign_keypair(),
+            enc: [3; 32].into(),
+        };
+        let config = Config::test_config(root_keys.sig.verifying_key());
+        let addr = serve_test_server(db.clone(), &config).await?;
+        let conn = &mut db.acquire().await?;
+        // Websocket tests running alongside share the connection map, with their first device
+        for name in ["spare", "spare2"] {
+            let pk = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
+            insert_test_device(conn, pk, name.into()).await?;
+        }
+        let device_key = random_sign_keypair();
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key());
+        insert_test_device(conn, device_pk, "test".into()).await?;
+        let dev_id = get_dev_id_by_name(conn, "test").await?;
+        let fetch = FileFetch {
+            fetch_id: 77,
+            paths: vec!["/srv".into()],
+            encrypt_to: Some(sealed::recipient_public_key(&root_keys.enc)),
+        };
+        fetches::insert(conn, dev_id, &fetch, Utc::now().naive_utc()).await?;
+
+        let client_config = ClientConfig {
+            server_addr: addr.to_string(),
+            use_tls: false,
+            use_rest: false,
+            extra_ca_certs_pem: vec![],
+            pinned_server_cert_pem: None,
+            pinned_spki_sha256: None,
+            server_public_key: None,
+        };
+        let mut device = DeviceClient::new(&client_config, device_key, None)
+            .await
+            .map_err(|(_, e)| anyhow::anyhow!(e))?;
+        // Two and a half chunks, sealed the way devices do it
+        let contents: Vec<u8> = (0..FileFetch::CHUNK_LEN * 5 / 2).map(|i| i as u8).collect();
+        let (mut sealer, header) =
+            FileSealer::new(&fetch.encrypt_to.unwrap(), 77, "/srv/data.bin")?;
+        let pieces: Vec<_> = contents.chunks(FileFetch::CHUNK_LEN).collect();
+        for (seq, piece) in pieces.iter().enumerate() {
+            let last = seq + 1 == pieces.len();
+            let mut data = if seq == 0 {
+                header.to_vec()
+            } else {
+                Vec::new()
+            };
+            data.extend(sealer.seal(piece, last));
+            let chunk = FileChunk {
+                fetch_id: 77,
+                path: "/srv/data.bin".into(),
+                seq: seq as u32,
+                size: contents.len() as u64,
+                data,
+                last,
+            };
+            device
+                .upload_file_chunk(chunk)
+                .await
+                .map_err(anyhow::Error::from)?;
+        }
+        let finished = FileFetchFinished {
+            fetch_id: 77,
+            failures: Vec::new(),
+        };
+        device
+            .finish_file_fetch(finished)
+            .await
+            .map_err(anyhow::Error::from)?;
+
+        let mut admin = AdminClient::new(&client_config, &root_keys).await?;
+        let fetched = admin.list_file_fetches("test".into()).await?.pop().unwrap();
+        assert!(fetched.encrypted);
+        let file = &fetched.files[0];
+        assert_eq!(file.chunks, 3);
+        let mut downloaded = Vec::new();
+        admin
+            .download_fetched_file("test", &fetched, file, &mut downloaded)
+            .await?;
+        assert_eq!(downloaded, contents);
+
+        // The server only ever had ciphertext
+        let stored = fetches::get_chunk(conn, dev_id, 77, &file.path, 0).await?;
+        assert!(!stored.windows(64).any(|w| w == &contents[..64]));
+        Ok(())
+    }
 }
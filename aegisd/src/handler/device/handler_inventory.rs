@@ -1,15 +1,15 @@
+use crate::db::DbPool;
 use crate::error::Error;
 use crate::handler::device::DeviceId;
 use axum::body::{Body, Bytes};
 use axum::extract::State;
 use axum::http::Request;
-use sqlx::PgPool;
 use std::future::Future;
 use std::pin::Pin;
 
 type PinBoxFut<T> = Pin<Box<dyn Future<Output = T> + Send>>;
-pub type DeviceHttpHandlerFn = fn(State<PgPool>, Request<Body>) -> PinBoxFut<Result<Bytes, Error>>;
-pub type DeviceHandlerFn = fn(PgPool, DeviceId, Bytes) -> PinBoxFut<Result<Bytes, Error>>;
+pub type DeviceHttpHandlerFn = fn(State<DbPool>, Request<Body>) -> PinBoxFut<Result<Bytes, Error>>;
+pub type DeviceHandlerFn = fn(DbPool, DeviceId, Bytes) -> PinBoxFut<Result<Bytes, Error>>;
 
 pub struct DeviceHandler {
     pub path: &'static str,
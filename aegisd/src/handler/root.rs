@@ -1,30 +1,73 @@
 //! Root handlers are unauthenticated. They are reachable only by REST, not by websocket.
 
+use crate::channel::ServerKey;
+use crate::config::RegistrationConfig;
+use crate::db::{with_conn, DbPool};
 use crate::error::{bail, Result};
+use crate::handler::admin::confirm_device;
 use crate::handler::device::DeviceId;
 use crate::model::device;
+use crate::model::device::get_status;
 use crate::model::device::{count_pending, PendingDevice};
+use crate::model::notifications::Trigger;
+use crate::model::{enrollment, events};
+use crate::notify::notify;
+use crate::protocol::parse_peer_protocol;
+use crate::ratelimit::RateLimits;
 use crate::ws::WsConn;
+use aegislib::command::device::{DeviceEvent, EventLogLevel};
+use aegislib::crypto::channel::{accept_client, CHANNEL_HEADER};
+use aegislib::protocol::{ENROLLMENT_TOKEN_HEADER, MIN_DEVICE_PROTOCOL_VERSION};
 use axum::extract::{ConnectInfo, Path, State, WebSocketUpgrade};
 use axum::response::{IntoResponse, Response};
+use axum::Extension;
 use base64::prelude::*;
 use chrono::Utc;
 use ed25519_dalek::VerifyingKey;
 use futures::StreamExt;
-use http::Request;
+use http::{HeaderMap, HeaderValue, Request};
 use hyper::{Body, StatusCode};
-use sqlx::{Error as SqlxError, PgPool};
+use sqlx::Error as SqlxError;
 use std::net::SocketAddr;
-use tracing::{debug, error};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, error, warn};
 
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Liveness check, succeeds as long as the server is able to answer requests
 pub async fn health() -> &'static str {
     "ok"
 }
 
+/// Readiness check, fails while the database can't be reached
+pub async fn ready(State(db): State<DbPool>) -> Result<&'static str> {
+    let probe = async {
+        let mut conn = db.acquire().await?;
+        with_conn!(&mut conn, |c| sqlx::query("SELECT 1")
+            .execute(&mut **c)
+            .await
+            .map(drop))
+    };
+    match tokio::time::timeout(READY_TIMEOUT, probe).await {
+        Ok(Ok(_)) => Ok("ok"),
+        Ok(Err(e)) => {
+            warn!("Readiness check failed: {e}");
+            bail!(StatusCode::SERVICE_UNAVAILABLE, "Database unavailable")
+        }
+        Err(_) => {
+            warn!("Readiness check timed out");
+            bail!(StatusCode::SERVICE_UNAVAILABLE, "Database unavailable")
+        }
+    }
+}
+
 pub async fn websocket_upgrade(
-    State(db): State<PgPool>,
+    State(db): State<DbPool>,
     Path(device_pk): Path<String>,
     ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    Extension(server_key): Extension<ServerKey>,
+    Extension(limits): Extension<RateLimits>,
+    headers: HeaderMap,
     ws_upgrade: WebSocketUpgrade,
 ) -> Result<Response> {
     let device_pk = BASE64_URL_SAFE_NO_PAD.decode(device_pk).ok();
@@ -41,20 +84,93 @@ pub async fn websocket_upgrade(
         Err(e) => return Err((StatusCode::FORBIDDEN, format!("Device not found: {e}")).into()),
         Ok(id) => DeviceId(id),
     };
-    Ok(ws_upgrade.on_upgrade(move |ws| async move {
-        debug!(%remote_addr, "Device websocket connection established");
-        let ws_conn = WsConn::new(db, device_pk, device_id, remote_addr.to_string());
+
+    let protocol = parse_peer_protocol(&headers)?;
+    if let Err(e) = protocol.check_min_version(MIN_DEVICE_PROTOCOL_VERSION) {
+        warn!(%remote_addr, "Rejecting device websocket: {e}");
+        // An outdated aegisc won't fix itself, make sure the admin hears about it
+        let _ = events::insert(
+            conn,
+            device_id.0,
+            DeviceEvent {
+                timestamp: Utc::now().timestamp() as u64,
+                level: EventLogLevel::Warn,
+                message: format!("Rejected device connection: {e}"),
+            },
+        )
+        .await;
+        bail!(StatusCode::UPGRADE_REQUIRED, e.to_string());
+    }
+
+    if let Ok(status) = get_status(conn, device_id.0).await {
+        if status.vt_locked || status.ssh_locked {
+            let name = device::get_name_by_id(conn, device_id.0).await.ok();
+            let msg = format!("Device came online while locked, from {remote_addr}");
+            notify(conn, Trigger::LockedDeviceOnline, name.as_deref(), &msg).await;
+        }
+    }
+
+    // Devices that don't ask for an encrypted channel (including older ones) stay in cleartext
+    let client_hello = headers
+        .get(CHANNEL_HEADER)
+        .map(|v| v.to_str().unwrap_or_default());
+    let (channel, server_hello) = match (client_hello, &server_key.0) {
+        (None, _) => (None, None),
+        (Some(_), None) => bail!(
+            StatusCode::BAD_REQUEST,
+            "Encrypted channels are not enabled on this server"
+        ),
+        (Some(hello), Some(key)) => {
+            match accept_client(key, &device_pk, hello, SystemTime::now()) {
+                Ok((sealer, opener, server_hello)) => (Some((sealer, opener)), Some(server_hello)),
+                Err(e) => {
+                    warn!(%remote_addr, "Rejecting encrypted channel: {e}");
+                    bail!(StatusCode::FORBIDDEN, e.to_string());
+                }
+            }
+        }
+    };
+
+    let mut resp = ws_upgrade.on_upgrade(move |ws| async move {
+        debug!(
+            %remote_addr,
+            protocol = protocol.version,
+            encrypted = channel.is_some(),
+            "Device websocket connection established"
+        );
+        let ws_conn = WsConn::new(
+            db,
+            device_pk,
+            device_id,
+            protocol,
+            channel,
+            remote_addr,
+            limits,
+        );
         if let Err(e) = ws_conn.handle(ws).await {
             error!("Error handling ws client {}: {}", remote_addr, e)
         }
-    }))
+    });
+    if let Some(server_hello) = server_hello {
+        let value = HeaderValue::from_str(&server_hello).expect("Invalid channel header value");
+        resp.headers_mut().insert(CHANNEL_HEADER, value);
+    }
+    Ok(resp)
 }
 
 pub async fn register(
-    State(db): State<PgPool>,
+    State(db): State<DbPool>,
     Path((device_pk, name)): Path<(String, String)>,
+    Extension(registration): Extension<RegistrationConfig>,
     request: Request<Body>,
 ) -> Result<Response> {
+    let token = match request.headers().get(ENROLLMENT_TOKEN_HEADER) {
+        Some(token) => match token.to_str() {
+            Ok(token) => Some(token.to_owned()),
+            Err(_) => bail!(StatusCode::BAD_REQUEST, "Invalid enrollment token"),
+        },
+        None => None,
+    };
     if request.into_body().next().await.is_some() {
         bail!(StatusCode::BAD_REQUEST, "Unexpected body");
     }
@@ -69,42 +185,241 @@ pub async fn register(
 
     let mut conn = db.acquire().await?;
 
-    if count_pending(&mut conn).await? >= 3 {
-        bail!(StatusCode::BAD_REQUEST, "Too many pending devices");
-    }
+    let enrollment = match token {
+        Some(token) => match enrollment::consume(&mut conn, &token).await? {
+            Some(enrollment) => Some(enrollment),
+            None => bail!(
+                StatusCode::FORBIDDEN,
+                "Unknown, expired or used up enrollment token"
+            ),
+        },
+        None if !registration.open => bail!(
+            StatusCode::FORBIDDEN,
+            "Registration needs an enrollment token"
+        ),
+        None => {
+            if count_pending(&mut conn).await? >= registration.max_pending as i64 {
+                bail!(StatusCode::BAD_REQUEST, "Too many pending devices");
+            }
+            None
+        }
+    };
 
     let pubkey_str = BASE64_URL_SAFE_NO_PAD.encode(device_pk);
     let insert_result = PendingDevice {
         created_at: Utc::now().naive_utc(),
-        name,
+        name: name.clone(),
         pubkey: pubkey_str,
+        enrollment_token_id: enrollment.as_ref().map(|e| e.token_id),
     }
     .insert(&mut conn)
     .await;
-    let unique_violation_code = "23505"; // Per https://www.postgresql.org/docs/current/errcodes-appendix.html
     match insert_result {
-        Err(SqlxError::Database(e)) if e.code().as_deref() == Some(unique_violation_code) => {
-            return Ok(StatusCode::CONFLICT.into_response())
+        Err(SqlxError::Database(e)) if e.is_unique_violation() => {
+            if let Some(enrollment) = &enrollment {
+                enrollment::refund(&mut conn, enrollment.token_id).await?;
+            }
+            return Ok(StatusCode::CONFLICT.into_response());
         }
         result => result.map(|_| ())?,
     };
+    match enrollment {
+        Some(enrollment) if enrollment.auto_approve => confirm_device(&mut conn, &name).await?,
+        _ => {
+            let msg = "New device waiting for confirmation";
+            notify(&mut conn, Trigger::PendingRegistration, Some(&name), msg).await;
+        }
+    }
     Ok(().into_response())
 }
 
 #[cfg(test)]
 mod test {
+    use crate::config::{Config, ListenConfig};
+    use crate::db::DbPool;
     use crate::error::Result;
-    use crate::model::device::list_pending;
-    use crate::server::make_test_server;
-    use aegislib::crypto::random_sign_keypair;
+    use crate::handler::device::DeviceId;
+    use crate::model::device::test::insert_test_device;
+    use crate::model::device::{
+        count_pending, get_dev_id_by_name, get_pubkey_by_id, list_pending, list_pending_for_token,
+        list_registered, update_status,
+    };
+    use crate::model::enrollment;
+    use crate::server::{make_router, make_test_server, serve_test_server};
+    use crate::ws::ws_for_device;
+    use aegisd_handler_macros::db_test;
+    use aegislib::client::{ClientConfig, ClientError, DeviceClient};
+    use aegislib::command::server::{ServerCommand, StatusUpdate};
+    use aegislib::crypto::{random_sign_keypair, sign_keypair_from_file, SigningKey};
+    use aegislib::protocol::ENROLLMENT_TOKEN_HEADER;
     use base64::prelude::*;
+    use chrono::Utc;
     use http::{Request, Response, StatusCode};
     use hyper::Body;
-    use sqlx::PgPool;
+    use std::net::SocketAddr;
+    use std::path::Path;
+    use std::time::Duration;
+    use tokio::sync::mpsc::channel;
     use tower::Service;
 
-    #[sqlx::test]
-    async fn health(db: PgPool) -> Result<()> {
+    async fn serve(db: DbPool, server_key_path: Option<&Path>) -> Result<SocketAddr> {
+        let mut config = Config::test_config(random_sign_keypair().verifying_key());
+        config.server_key_path = server_key_path.map(ToOwned::to_owned);
+        Ok(serve_test_server(db, &config).await?)
+    }
+
+    fn client_config(addr: SocketAddr, server_public_key: Option<String>) -> ClientConfig {
+        ClientConfig {
+            server_addr: addr.to_string(),
+            use_tls: false,
+            use_rest: false,
+            extra_ca_certs_pem: vec![],
+            pinned_server_cert_pem: None,
+            pinned_spki_sha256: None,
+            server_public_key,
+        }
+    }
+
+    async fn add_device(db: &DbPool) -> Result<(SigningKey, DeviceId)> {
+        let conn = &mut db.acquire().await?;
+        let device_key = random_sign_keypair();
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key());
+        insert_test_device(conn, device_pk, "test".into()).await?;
+        let id = get_dev_id_by_name(conn, "test").await?;
+        Ok((device_key, DeviceId(id)))
+    }
+
+    #[db_test]
+    async fn encrypted_channel(db: DbPool) -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join("server.key");
+        let addr = serve(db.clone(), Some(&key_path)).await?;
+        let server_pk = sign_keypair_from_file(&key_path)?.verifying_key();
+        let server_pk = BASE64_URL_SAFE_NO_PAD.encode(server_pk);
+        let (device_key, dev_id) = add_device(&db).await?;
+        update_status(
+            &mut db.acquire().await?,
+            dev_id.0,
+            Some(true),
+            None,
+            None,
+            "admin",
+        )
+        .await?;
+
+        let config = client_config(addr, Some(server_pk));
+        let (event_tx, mut event_rx) = channel(1);
+        let mut client = DeviceClient::new(&config, device_key, Some(event_tx))
+            .await
+            .map_err(|(_, e)| anyhow::anyhow!(e))?;
+        let status = client.status().await.map_err(anyhow::Error::from)?;
+        assert!(status.vt_locked);
+
+        let update = StatusUpdate {
+            vt_locked: false,
+            ssh_locked: true,
+            draw_decoy: false,
+        };
+        ws_for_device(dev_id).unwrap().send(update.into()).await?;
+        let received = tokio::time::timeout(Duration::from_secs(5), event_rx.recv())
+            .await
+            .unwrap();
+        match received {
+            Some(ServerCommand::StatusUpdate(received)) => assert_eq!(received, update),
+            other => panic!("Unexpected server command: {other:?}"),
+        }
+        Ok(())
+    }
+
+    #[db_test]
+    async fn rotate_key_over_websocket(db: DbPool) -> Result<()> {
+        let addr = serve(db.clone(), None).await?;
+        let (device_key, dev_id) = add_device(&db).await?;
+
+        let config = client_config(addr, None);
+        let mut client = DeviceClient::new(&config, device_key, None)
+            .await
+            .map_err(|(_, e)| anyhow::anyhow!(e))?;
+        let new_key = random_sign_keypair();
+        client
+            .rotate_key(new_key.clone())
+            .await
+            .map_err(anyhow::Error::from)?;
+        let new_pk = BASE64_URL_SAFE_NO_PAD.encode(new_key.verifying_key());
+        let conn = &mut db.acquire().await?;
+        assert_eq!(get_pubkey_by_id(conn, dev_id.0).await?, new_pk);
+
+        // The client reconnected with its new key
+        client.status().await.map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    #[db_test]
+    async fn pending_key_kept_on_transient_errors(db: DbPool) -> Result<()> {
+        // Devices only discard a pending key when the server refuses it
+        let addr = serve(db.clone(), None).await?;
+        let unknown_key = random_sign_keypair();
+        match DeviceClient::new(&client_config(addr, None), unknown_key, None).await {
+            Err((_, e)) => assert!(e.is_forbidden(), "Unexpected error: {e}"),
+            Ok(_) => panic!("Connected with an unknown key"),
+        }
+
+        // Not when the server can't be reached
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .map_err(anyhow::Error::from)?;
+        let (device_key, _) = add_device(&db).await?;
+        match DeviceClient::new(&client_config(closed, None), device_key, None).await {
+            Err((_, e)) => assert!(!e.is_forbidden(), "Unexpected error: {e}"),
+            Ok(_) => panic!("Connected to a closed port"),
+        }
+        Ok(())
+    }
+
+    #[db_test]
+    async fn encrypted_channel_wrong_server_key(db: DbPool) -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let addr = serve(db.clone(), Some(&dir.path().join("server.key"))).await?;
+        let (device_key, _) = add_device(&db).await?;
+
+        let impostor = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
+        let config = client_config(addr, Some(impostor));
+        assert!(DeviceClient::new(&config, device_key, None).await.is_err());
+        Ok(())
+    }
+
+    #[db_test]
+    async fn encrypted_channel_not_enabled(db: DbPool) -> Result<()> {
+        let addr = serve(db.clone(), None).await?;
+        let (device_key, _) = add_device(&db).await?;
+
+        let server_pk = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
+        let config = client_config(addr, Some(server_pk));
+        match DeviceClient::new(&config, device_key, None).await {
+            Err((_, ClientError::Http(e))) => assert_eq!(e.code, StatusCode::BAD_REQUEST),
+            Err((_, e)) => panic!("Unexpected error: {e}"),
+            Ok(_) => panic!("Connected without the encrypted channel"),
+        }
+        Ok(())
+    }
+
+    #[db_test]
+    async fn cleartext_channel_still_works(db: DbPool) -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let addr = serve(db.clone(), Some(&dir.path().join("server.key"))).await?;
+        let (device_key, _) = add_device(&db).await?;
+
+        let config = client_config(addr, None);
+        let mut client = DeviceClient::new(&config, device_key, None)
+            .await
+            .map_err(|(_, e)| anyhow::anyhow!(e))?;
+        let status = client.status().await.map_err(anyhow::Error::from)?;
+        assert!(!status.vt_locked);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn health(db: DbPool) -> Result<()> {
         let mut server = make_test_server(db).await?;
         let req = Request::get("/health").body(Body::empty()).unwrap();
         let mut resp: Response<_> = server.app.call(req).await?;
@@ -115,8 +430,71 @@ mod test {
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn register_unexpected_body(db: PgPool) -> Result<()> {
+    #[db_test]
+    async fn health_ready(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let req = Request::get("/health/ready").body(Body::empty()).unwrap();
+        let resp: Response<_> = server.app.call(req).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        db.close().await;
+        let req = Request::get("/health/ready").body(Body::empty()).unwrap();
+        let resp: Response<_> = server.app.call(req).await?;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let req = Request::get("/health/live").body(Body::empty()).unwrap();
+        let resp: Response<_> = server.app.call(req).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn metrics(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db).await?;
+        let req = Request::get("/health").body(Body::empty()).unwrap();
+        server.app.call(req).await?;
+
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let mut resp: Response<_> = server.app.call(req).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.body_mut()).await?;
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains(r#"aegisd_requests_total{path="/health",status="200"}"#));
+        assert!(body.contains("aegisd_ws_connected_devices"));
+        assert!(body.contains("aegisd_stored_picture_bytes"));
+        Ok(())
+    }
+
+    #[db_test]
+    async fn metrics_restricted(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        // Requests without a connection come from 0.0.0.0
+        server.config.metrics.allow = vec!["127.0.0.1".parse().unwrap()];
+        let mut app = make_router(db.clone(), &server.config).await?;
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let resp: Response<_> = app.call(req).await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        server.config.metrics.allow = vec!["0.0.0.0".parse().unwrap()];
+        let mut app = make_router(db.clone(), &server.config).await?;
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let resp: Response<_> = app.call(req).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Served on its own listeners only
+        server.config.metrics.listen = vec![ListenConfig::Tcp {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            tls: false,
+        }];
+        let mut app = make_router(db, &server.config).await?;
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let resp: Response<_> = app.call(req).await?;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn register_unexpected_body(db: DbPool) -> Result<()> {
         let mut server = make_test_server(db).await?;
         let dev_pk = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
         let req = Request::post(format!("/register/{dev_pk}/name/test"))
@@ -127,8 +505,8 @@ mod test {
         Ok(())
     }
 
-    #[sqlx::test]
-    async fn register(db: PgPool) -> Result<()> {
+    #[db_test]
+    async fn register(db: DbPool) -> Result<()> {
         let mut server = make_test_server(db.clone()).await?;
         let dev_pk = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
         let req = Request::post(format!("/register/{dev_pk}/name/test"))
@@ -145,4 +523,172 @@ mod test {
         assert_eq!(pending[0].name, "test");
         Ok(())
     }
+
+    fn register_request(name: &str, token: Option<&str>) -> Request<Body> {
+        let dev_pk = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
+        let mut req = Request::post(format!("/register/{dev_pk}/name/{name}"));
+        if let Some(token) = token {
+            req = req.header(ENROLLMENT_TOKEN_HEADER, token);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[db_test]
+    async fn register_token_auto_approve(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        let (_, token) = enrollment::create(conn, "test", None, None, true).await?;
+
+        let resp = server
+            .app
+            .call(register_request("test", Some(&token)))
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(list_pending(conn).await?.is_empty());
+        let devs = list_registered(conn).await?;
+        assert_eq!(devs.len(), 1);
+        assert_eq!(devs[0].name, "test");
+        Ok(())
+    }
+
+    #[db_test]
+    async fn register_token_queue(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        let (token_id, token) = enrollment::create(conn, "test", None, None, false).await?;
+
+        // Tokened devices don't count against the cap on untokened ones
+        for i in 0..5 {
+            let req = register_request(&format!("test{i}"), Some(&token));
+            assert_eq!(server.app.call(req).await?.status(), StatusCode::OK);
+        }
+        assert_eq!(list_pending_for_token(conn, token_id).await?.len(), 5);
+        assert_eq!(count_pending(conn).await?, 0);
+        let resp = server.app.call(register_request("public", None)).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn register_token_used_up(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        let (token_id, token) = enrollment::create(conn, "test", None, Some(1), false).await?;
+
+        let resp = server
+            .app
+            .call(register_request("first", Some(&token)))
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = server
+            .app
+            .call(register_request("second", Some(&token)))
+            .await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // A registration that conflicts doesn't use up the token
+        let (_, token) = enrollment::create(conn, "test", None, Some(1), false).await?;
+        let resp = server
+            .app
+            .call(register_request("first", Some(&token)))
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let resp = server
+            .app
+            .call(register_request("third", Some(&token)))
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let tokens = enrollment::list(conn).await?;
+        assert_eq!(tokens[0].id, token_id);
+        assert_eq!(tokens[1].uses, 1);
+        assert_eq!(tokens[1].pending_devices, 1);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn register_token_expired(db: DbPool) -> Result<()> {
+        let mut server = make_test_server(db.clone()).await?;
+        let conn = &mut db.acquire().await?;
+        let expired = Utc::now().naive_utc() - chrono::Duration::hours(1);
+        let (_, token) = enrollment::create(conn, "test", Some(expired), None, true).await?;
+
+        let resp = server
+            .app
+            .call(register_request("test", Some(&token)))
+            .await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = server
+            .app
+            .call(register_request("test", Some("bogus")))
+            .await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(list_pending(conn).await?.is_empty());
+        Ok(())
+    }
+
+    #[db_test]
+    async fn register_closed(db: DbPool) -> Result<()> {
+        let mut config = Config::test_config(random_sign_keypair().verifying_key());
+        config.registration.open = false;
+        let mut app = make_router(db.clone(), &config).await?;
+        let conn = &mut db.acquire().await?;
+
+        let resp = app.call(register_request("public", None)).await?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let (_, token) = enrollment::create(conn, "test", None, None, false).await?;
+        let resp = app.call(register_request("test", Some(&token))).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn websocket_key_limited_once_authenticated(db: DbPool) -> Result<()> {
+        let mut config = Config::test_config(random_sign_keypair().verifying_key());
+        config.rate_limit.websocket_per_key_per_minute = 1;
+        let addr = serve_test_server(db.clone(), &config).await?;
+        let (device_key, _) = add_device(&db).await?;
+
+        // Knowing the public key isn't enough to use up the device's connections
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key());
+        for _ in 0..3 {
+            let resp = reqwest::get(format!("http://{addr}/ws/{device_pk}"))
+                .await
+                .map_err(anyhow::Error::from)?;
+            assert_ne!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+        let config = client_config(addr, None);
+        let mut client = DeviceClient::new(&config, device_key.clone(), None)
+            .await
+            .map_err(|(_, e)| anyhow::anyhow!(e))?;
+        client.status().await.map_err(anyhow::Error::from)?;
+
+        // The client keeps reconnecting when refused, its request never goes through
+        let second = DeviceClient::new(&config, device_key, None).await;
+        let refused = match second {
+            Ok(mut client) => {
+                let status = tokio::time::timeout(Duration::from_secs(2), client.status()).await;
+                !matches!(status, Ok(Ok(_)))
+            }
+            Err(_) => true,
+        };
+        assert!(refused);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn register_rate_limited(db: DbPool) -> Result<()> {
+        let mut config = Config::test_config(random_sign_keypair().verifying_key());
+        config.rate_limit.register_per_minute = 2;
+        let mut app = make_router(db, &config).await?;
+
+        for name in ["first", "second"] {
+            let resp = app.call(register_request(name, None)).await?;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let resp = app.call(register_request("third", None)).await?;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        Ok(())
+    }
 }
@@ -0,0 +1,482 @@
+//! Accepts connections on the configured TCP, TLS and Unix socket listeners.
+//! Every connection is funneled into the same hyper server, and reports a `SocketAddr` as its
+//! `ConnectInfo`, so handlers and middlewares log all of them the same way.
+
+use crate::config::{ListenConfig, TlsConfig};
+use anyhow::{anyhow, bail, Context as _, Result};
+use axum::extract::connect_info::Connected;
+use hyper::server::accept::Accept;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::BufReader;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{any_supported_type, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Accept fails when we run out of file descriptors, don't spin while that lasts
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+/// Unix socket peers have no useful address, they are a proxy running on this host.
+/// No TCP peer has port 0, so rate limits can tell them apart.
+pub const UNIX_SOCKET_PEER_ADDR: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+
+/// Certificate and key served by every TLS listener, reloaded from disk on SIGHUP
+pub struct TlsCertificates {
+    config: TlsConfig,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl TlsCertificates {
+    pub fn load(config: &TlsConfig) -> Result<Arc<Self>> {
+        let current = RwLock::new(Arc::new(Self::read_certified_key(config)?));
+        Ok(Arc::new(Self {
+            config: config.clone(),
+            current,
+        }))
+    }
+
+    fn read_certified_key(config: &TlsConfig) -> Result<CertifiedKey> {
+        let cert_file = std::fs::File::open(&config.cert_path)
+            .with_context(|| format!("Failed to open {}", config.cert_path.display()))?;
+        let certs = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+            .with_context(|| format!("Failed to parse {}", config.cert_path.display()))?;
+        if certs.is_empty() {
+            bail!("No certificate found in {}", config.cert_path.display());
+        }
+
+        let key_file = std::fs::File::open(&config.key_path)
+            .with_context(|| format!("Failed to open {}", config.key_path.display()))?;
+        let key = rustls_pemfile::read_all(&mut BufReader::new(key_file))
+            .with_context(|| format!("Failed to parse {}", config.key_path.display()))?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("No private key found in {}", config.key_path.display()))?;
+        let key = any_supported_type(&PrivateKey(key))
+            .map_err(|e| anyhow!("Unsupported private key: {e}"))?;
+
+        Ok(CertifiedKey::new(
+            certs.into_iter().map(Certificate).collect(),
+            key,
+        ))
+    }
+
+    /// On error, we keep serving the previous certificate
+    pub fn reload(&self) -> Result<()> {
+        let key = Self::read_certified_key(&self.config)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+
+    pub fn reload_on_sighup(self: &Arc<Self>) -> Result<()> {
+        let mut sighup = signal(SignalKind::hangup())?;
+        let certs = self.clone();
+        tokio::spawn(async move {
+            while sighup.recv().await.is_some() {
+                match certs.reload() {
+                    Ok(()) => info!("Reloaded TLS certificate"),
+                    Err(e) => {
+                        error!("Failed to reload TLS certificate, keeping the old one: {e:#}")
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    pub fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+impl ResolvesServerCert for TlsCertificates {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+pub enum Listener {
+    Tcp {
+        listener: TcpListener,
+        tls: Option<TlsAcceptor>,
+    },
+    Unix {
+        listener: UnixListener,
+        path: PathBuf,
+    },
+}
+
+impl Listener {
+    pub async fn bind(config: &ListenConfig, tls: Option<&Arc<TlsCertificates>>) -> Result<Self> {
+        Ok(match config {
+            ListenConfig::Tcp { addr, tls: use_tls } => {
+                let tls = match (use_tls, tls) {
+                    (false, _) => None,
+                    (true, Some(certs)) => Some(certs.acceptor()),
+                    (true, None) => bail!("Listener {addr} uses TLS, but no certificate is set"),
+                };
+                let listener = TcpListener::bind(addr)
+                    .await
+                    .with_context(|| format!("Failed to listen on {addr}"))?;
+                Listener::Tcp { listener, tls }
+            }
+            ListenConfig::Unix { unix_socket } => {
+                // A socket left behind by a previous run would make bind fail
+                if let Ok(meta) = std::fs::symlink_metadata(unix_socket) {
+                    if meta.file_type().is_socket() {
+                        std::fs::remove_file(unix_socket)?;
+                    }
+                }
+                let listener = UnixListener::bind(unix_socket)
+                    .with_context(|| format!("Failed to listen on {}", unix_socket.display()))?;
+                Listener::Unix {
+                    listener,
+                    path: unix_socket.clone(),
+                }
+            }
+        })
+    }
+
+    async fn accept_loop(self, conn_tx: Sender<Connection>) {
+        loop {
+            let conn = match &self {
+                Listener::Tcp { listener, tls } => match listener.accept().await {
+                    Ok((stream, remote_addr)) => match tls {
+                        None => Connection {
+                            io: ConnectionIo::Tcp(stream),
+                            remote_addr,
+                        },
+                        Some(acceptor) => {
+                            // Don't let a slow handshake hold up the other clients
+                            let handshake = tls_handshake(acceptor.clone(), stream, remote_addr);
+                            let conn_tx = conn_tx.clone();
+                            tokio::spawn(async move {
+                                if let Some(conn) = handshake.await {
+                                    let _ = conn_tx.send(conn).await;
+                                }
+                            });
+                            continue;
+                        }
+                    },
+                    Err(e) => {
+                        warn!(listener = %self, "Failed to accept connection: {e}");
+                        tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                        continue;
+                    }
+                },
+                Listener::Unix { listener, .. } => match listener.accept().await {
+                    Ok((stream, _)) => Connection {
+                        io: ConnectionIo::Unix(stream),
+                        remote_addr: UNIX_SOCKET_PEER_ADDR,
+                    },
+                    Err(e) => {
+                        warn!(listener = %self, "Failed to accept connection: {e}");
+                        tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                        continue;
+                    }
+                },
+            };
+            if conn_tx.send(conn).await.is_err() {
+                // The server is gone
+                return;
+            }
+        }
+    }
+}
+
+async fn tls_handshake(
+    acceptor: TlsAcceptor,
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+) -> Option<Connection> {
+    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => Some(Connection {
+            io: ConnectionIo::Tls(Box::new(stream)),
+            remote_addr,
+        }),
+        Ok(Err(e)) => {
+            debug!(%remote_addr, "TLS handshake failed: {e}");
+            None
+        }
+        Err(_) => {
+            debug!(%remote_addr, "TLS handshake timed out");
+            None
+        }
+    }
+}
+
+impl Display for Listener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listener::Tcp { listener, tls } => {
+                let proto = if tls.is_some() { "https" } else { "http" };
+                match listener.local_addr() {
+                    Ok(addr) => write!(f, "{proto}://{addr}"),
+                    Err(_) => write!(f, "{proto}://<unknown>"),
+                }
+            }
+            Listener::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Serves the connections of all our listeners to a single hyper server
+pub struct ConnectionAcceptor {
+    conn_rx: Receiver<Connection>,
+}
+
+impl ConnectionAcceptor {
+    pub fn new(listeners: Vec<Listener>) -> Self {
+        let (conn_tx, conn_rx) = channel(listeners.len().max(1) * 16);
+        for listener in listeners {
+            tokio::spawn(listener.accept_loop(conn_tx.clone()));
+        }
+        Self { conn_rx }
+    }
+}
+
+impl Accept for ConnectionAcceptor {
+    type Conn = Connection;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.conn_rx.poll_recv(cx).map(|conn| conn.map(Ok))
+    }
+}
+
+enum ConnectionIo {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Unix(UnixStream),
+}
+
+pub struct Connection {
+    io: ConnectionIo,
+    remote_addr: SocketAddr,
+}
+
+impl Connected<&Connection> for SocketAddr {
+    fn connect_info(conn: &Connection) -> Self {
+        conn.remote_addr
+    }
+}
+
+macro_rules! with_io {
+    ($self:ident, $io:ident => $expr:expr) => {
+        match &mut $self.get_mut().io {
+            ConnectionIo::Tcp($io) => {
+                let $io = Pin::new($io);
+                $expr
+            }
+            ConnectionIo::Tls($io) => {
+                let $io = Pin::new($io);
+                $expr
+            }
+            ConnectionIo::Unix($io) => {
+                let $io = Pin::new($io);
+                $expr
+            }
+        }
+    };
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        with_io!(self, io => io.poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        with_io!(self, io => io.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        with_io!(self, io => io.poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        with_io!(self, io => io.poll_shutdown(cx))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        with_io!(self, io => io.poll_write_vectored(cx, bufs))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match &self.io {
+            ConnectionIo::Tcp(io) => io.is_write_vectored(),
+            ConnectionIo::Tls(io) => io.is_write_vectored(),
+            ConnectionIo::Unix(io) => io.is_write_vectored(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::extract::ConnectInfo;
+    use axum::routing::get;
+    use axum::Router;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerName};
+    use tokio_rustls::TlsConnector;
+
+    fn testdata(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    fn local_addr(listener: &Listener) -> SocketAddr {
+        match listener {
+            Listener::Tcp { listener, .. } => listener.local_addr().unwrap(),
+            Listener::Unix { .. } => unreachable!(),
+        }
+    }
+
+    /// Serves a single route that answers with the ConnectInfo of the request
+    fn serve(listeners: Vec<Listener>) {
+        let app = Router::new().route(
+            "/peer",
+            get(|ConnectInfo(addr): ConnectInfo<SocketAddr>| async move { addr.to_string() }),
+        );
+        let server = axum::Server::builder(ConnectionAcceptor::new(listeners))
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+        tokio::spawn(server);
+    }
+
+    async fn get_peer(mut stream: impl AsyncRead + AsyncWrite + Unpin) -> String {
+        stream
+            .write_all(b"GET /peer HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+        resp.split("\r\n\r\n").nth(1).unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn tcp_listener() -> Result<()> {
+        let config = ListenConfig::Tcp {
+            addr: "127.0.0.1:0".parse()?,
+            tls: false,
+        };
+        let listener = Listener::bind(&config, None).await?;
+        let addr = local_addr(&listener);
+        serve(vec![listener]);
+
+        let stream = TcpStream::connect(addr).await?;
+        let our_addr = stream.local_addr()?;
+        assert_eq!(get_peer(stream).await, our_addr.to_string());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unix_listener() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = ListenConfig::Unix {
+            unix_socket: dir.path().join("aegisd.sock"),
+        };
+        // Binding twice checks that we replace the stale socket of a previous run
+        drop(Listener::bind(&config, None).await?);
+        let listener = Listener::bind(&config, None).await?;
+        serve(vec![listener]);
+
+        let stream = UnixStream::connect(dir.path().join("aegisd.sock")).await?;
+        assert_eq!(get_peer(stream).await, UNIX_SOCKET_PEER_ADDR.to_string());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_listener() -> Result<()> {
+        let certs = TlsCertificates::load(&TlsConfig {
+            cert_path: testdata("localhost.crt"),
+            key_path: testdata("localhost.key"),
+        })?;
+        let config = ListenConfig::Tcp {
+            addr: "127.0.0.1:0".parse()?,
+            tls: true,
+        };
+        let listener = Listener::bind(&config, Some(&certs)).await?;
+        let addr = local_addr(&listener);
+        serve(vec![listener]);
+        certs.reload()?;
+
+        let ca_pem = std::fs::read(testdata("ca.crt"))?;
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut ca_pem.as_slice())? {
+            roots.add(&Certificate(cert))?;
+        }
+        let client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let stream = TcpStream::connect(addr).await?;
+        let our_addr = stream.local_addr()?;
+        let stream = connector
+            .connect(ServerName::try_from("localhost")?, stream)
+            .await?;
+        assert_eq!(get_peer(stream).await, our_addr.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn reload_keeps_certificate_on_error() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = TlsConfig {
+            cert_path: dir.path().join("cert.pem"),
+            key_path: dir.path().join("key.pem"),
+        };
+        std::fs::copy(testdata("localhost.crt"), &config.cert_path)?;
+        std::fs::copy(testdata("localhost.key"), &config.key_path)?;
+        let certs = TlsCertificates::load(&config)?;
+        let before = certs.current.read().unwrap().clone();
+
+        std::fs::write(&config.key_path, "not a key")?;
+        assert!(certs.reload().is_err());
+        assert!(Arc::ptr_eq(&before, &certs.current.read().unwrap()));
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+//! Live events pushed to subscribed admins as server-sent events.
+//! Nothing is stored, admins only see what happens while they are subscribed.
+
+use crate::db::{DbConnection, DbPool};
+use crate::error::{bail, Result};
+use crate::model::device::{get_dev_id_by_name, get_name_by_id};
+use aegislib::command::admin::{LiveEvent, SubscribeArg};
+use async_stream::stream;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::IntoResponse;
+use base64::prelude::*;
+use futures::Stream;
+use http::StatusCode;
+use lazy_static::lazy_static;
+use std::convert::Infallible;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+const LIVE_EVENTS_CAPACITY: usize = 256;
+
+lazy_static! {
+    static ref LIVE_EVENTS: broadcast::Sender<LiveEvent> =
+        broadcast::channel(LIVE_EVENTS_CAPACITY).0;
+}
+
+/// Publishes an event about a device, the name is only looked up if an admin is listening
+pub async fn publish_for_device(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    make_event: impl FnOnce(String) -> LiveEvent,
+) {
+    if LIVE_EVENTS.receiver_count() == 0 {
+        return;
+    }
+    match get_name_by_id(conn, dev_id).await {
+        Ok(dev_name) => {
+            let _ = LIVE_EVENTS.send(make_event(dev_name));
+        }
+        Err(e) => warn!("Failed to publish live event for device {dev_id}: {e}"),
+    }
+}
+
+fn live_events(
+    mut rx: broadcast::Receiver<LiveEvent>,
+    filter: SubscribeArg,
+) -> impl Stream<Item = std::result::Result<Event, Infallible>> {
+    stream! {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Admin subscriber is too slow, dropped {missed} live events");
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            if !event.matches(&filter) {
+                continue;
+            }
+            let data = bincode::serialize(&event).unwrap();
+            yield Ok(Event::default().data(BASE64_URL_SAFE_NO_PAD.encode(data)));
+        }
+    }
+}
+
+/// Streams live events until the admin disconnects. Authenticated by the admin middleware.
+pub async fn subscribe(State(db): State<DbPool>, body: Bytes) -> Result<impl IntoResponse> {
+    let filter: SubscribeArg = match bincode::deserialize(&body) {
+        Ok(filter) => filter,
+        Err(e) => bail!(StatusCode::BAD_REQUEST, format!("Invalid argument: {e}")),
+    };
+    if let Some(dev_name) = &filter.dev_name {
+        let conn = &mut db.acquire().await?;
+        if get_dev_id_by_name(conn, dev_name).await.is_err() {
+            bail!(
+                StatusCode::NOT_FOUND,
+                format!("Device {dev_name} not found")
+            );
+        }
+    }
+    // Subscribe before answering, so the admin doesn't miss what happens right after
+    let rx = LIVE_EVENTS.subscribe();
+    Ok(Sse::new(live_events(rx, filter)).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Config;
+    use crate::db::DbPool;
+    use crate::error::Result;
+    use crate::model::device::get_dev_id_by_name;
+    use crate::model::device::test::insert_test_device;
+    use crate::model::events;
+    use crate::server::serve_test_server;
+    use aegisd_handler_macros::db_test;
+    use aegislib::client::{AdminClient, ClientConfig, DeviceClient, LiveEventStream};
+    use aegislib::command::admin::{LiveEvent, SubscribeArg};
+    use aegislib::command::device::{DeviceEvent, EventLogLevel};
+    use aegislib::crypto::{random_sign_keypair, RootKeys};
+    use base64::prelude::*;
+    use std::time::Duration;
+
+    async fn next_event(stream: &mut LiveEventStream) -> LiveEvent {
+        tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("Timed out waiting for a live event")
+            .unwrap()
+            .expect("Live event stream closed")
+    }
+
+    #[db_test]
+    async fn subscribe(db: DbPool) -> Result<()> {
+        let root_keys = RootKeys {
+            sig: random_sign_keypair(),
+            enc: Default::default(),
+        };
+        let config = Config::test_config(root_keys.sig.verifying_key());
+        let addr = serve_test_server(db.clone(), &config).await?;
+        let device_key = random_sign_keypair();
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key());
+        let conn = &mut db.acquire().await?;
+        insert_test_device(conn, device_pk, "live".into()).await?;
+        let dev_id = get_dev_id_by_name(conn, "live").await?;
+
+        let client_config = ClientConfig {
+            server_addr: addr.to_string(),
+            use_tls: false,
+            use_rest: false,
+            extra_ca_certs_pem: vec![],
+            pinned_server_cert_pem: None,
+            pinned_spki_sha256: None,
+            server_public_key: None,
+        };
+        let mut admin = AdminClient::new(&client_config, &root_keys).await?;
+        let unknown = SubscribeArg {
+            dev_name: Some("unknown".into()),
+            min_level: None,
+        };
+        assert!(admin.subscribe(unknown).await.is_err());
+
+        let mut stream = admin
+            .subscribe(SubscribeArg {
+                dev_name: Some("live".into()),
+                min_level: Some(EventLogLevel::Warn),
+            })
+            .await?;
+        for level in [EventLogLevel::Info, EventLogLevel::Error] {
+            let event = DeviceEvent {
+                timestamp: 0,
+                level,
+                message: format!("{level:?}"),
+            };
+            events::insert(conn, dev_id, event).await?;
+        }
+        match next_event(&mut stream).await {
+            LiveEvent::Logged { dev_name, event } => {
+                assert_eq!(dev_name, "live");
+                assert_eq!(event.level, EventLogLevel::Error);
+            }
+            other => panic!("Unexpected live event {other:?}"),
+        }
+
+        let _device = DeviceClient::new(&client_config, device_key, None)
+            .await
+            .map_err(|(_, e)| anyhow::anyhow!(e))?;
+        match next_event(&mut stream).await {
+            LiveEvent::Connected { dev_name, .. } => assert_eq!(dev_name, "live"),
+            other => panic!("Unexpected live event {other:?}"),
+        }
+        Ok(())
+    }
+}
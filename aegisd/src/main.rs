@@ -1,13 +1,27 @@
+mod channel;
 mod config;
+mod db;
 mod error;
 mod handler;
+mod listener;
+mod live;
+mod metrics;
 mod middleware;
 mod model;
+mod notify;
+mod picture;
+mod protocol;
+mod ratelimit;
+mod retention;
+mod rules;
+mod scheduler;
 mod server;
 mod ws;
 
+use crate::db::DbPool;
+use crate::model::pics;
+use crate::model::pics::store::{set_stores, stores, PictureStores};
 use clap::{value_parser, Arg};
-use sqlx::postgres::PgPoolOptions;
 use std::path::PathBuf;
 use tracing::info;
 use tracing_subscriber::EnvFilter;
@@ -35,27 +49,26 @@ async fn main() -> anyhow::Result<()> {
                 .value_parser(value_parser!(PathBuf))
                 .help("Path to the config file"),
         )
+        .subcommand(
+            clap::Command::new("migrate-pictures")
+                .about("Move stored pictures into the configured picture store, then exit"),
+        )
         .get_matches();
     let config_path: &PathBuf = args.get_one("config").unwrap();
     let config = config::Config::from_file(config_path);
 
-    info!(
-        db_host = &*config.db_host,
-        db_name = &*config.db_name,
-        db_user = &*config.db_user,
-        "Connecting to database..."
-    );
-    let pool = PgPoolOptions::new()
-        .max_connections(config.db_max_conn)
-        .connect(&format!(
-            "postgres://{}:{}@{}/{}",
-            config.db_user, config.db_pass, config.db_host, config.db_name
-        ))
-        .await?;
+    let pool = DbPool::connect(&config).await?;
     info!("Running migrations...");
-    sqlx::migrate!().run(&pool).await?;
+    pool.migrate().await?;
     info!("Migration done");
+    set_stores(PictureStores::new(&config.pictures)?);
 
+    if args.subcommand_matches("migrate-pictures").is_some() {
+        info!(backend = ?config.pictures.backend, "Moving pictures...");
+        let moved = pics::migrate_to_active(&mut pool.acquire().await?, &stores()).await?;
+        info!("Moved {moved} pictures");
+        return Ok(());
+    }
     server::run_server(pool, &config).await?;
     Ok(())
 }
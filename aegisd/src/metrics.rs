@@ -0,0 +1,196 @@
+//! Prometheus metrics, served on `/metrics`
+
+use crate::db::DbPool;
+use crate::error::{bail, Result};
+use crate::ratelimit::remote_addr;
+use crate::ws::connected_device_count;
+use axum::extract::{MatchedPath, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http::{header, Request, StatusCode};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec_with_registry, register_int_counter_vec_with_registry,
+    register_int_counter_with_registry, register_int_gauge_with_registry, Encoder, HistogramVec,
+    IntCounter, IntCounterVec, IntGauge, Registry, TextEncoder,
+};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+lazy_static! {
+    static ref REGISTRY: Registry =
+        Registry::new_custom(Some("aegisd".into()), None).expect("Invalid metrics registry");
+    static ref WS_CONNECTED_DEVICES: IntGauge = register_int_gauge_with_registry!(
+        "ws_connected_devices",
+        "Devices with an open websocket",
+        REGISTRY
+    )
+    .unwrap();
+    static ref REQUESTS: IntCounterVec = register_int_counter_vec_with_registry!(
+        "requests_total",
+        "Handled requests, by route and status code",
+        &["path", "status"],
+        REGISTRY
+    )
+    .unwrap();
+    static ref REQUEST_DURATION: HistogramVec = register_histogram_vec_with_registry!(
+        "request_duration_seconds",
+        "Time to handle a request, by route",
+        &["path"],
+        REGISTRY
+    )
+    .unwrap();
+    static ref SIGNATURE_FAILURES: IntCounterVec = register_int_counter_vec_with_registry!(
+        "signature_failures_total",
+        "Requests rejected for an invalid signature, by kind of client",
+        &["client"],
+        REGISTRY
+    )
+    .unwrap();
+    static ref RATE_LIMITED: IntCounterVec = register_int_counter_vec_with_registry!(
+        "rate_limited_total",
+        "Requests and websocket messages rejected by rate limits or bans, by limit",
+        &["limit"],
+        REGISTRY
+    )
+    .unwrap();
+    static ref BANS: IntCounter = register_int_counter_with_registry!(
+        "bans_total",
+        "Addresses banned for sending forged signatures",
+        REGISTRY
+    )
+    .unwrap();
+    static ref SERVER_COMMAND_SEND_FAILURES: IntCounter = register_int_counter_with_registry!(
+        "server_command_send_failures_total",
+        "Server commands that could not be queued to a device websocket",
+        REGISTRY
+    )
+    .unwrap();
+    static ref DB_POOL_CONNECTIONS: IntGauge = register_int_gauge_with_registry!(
+        "db_pool_connections",
+        "Open database connections",
+        REGISTRY
+    )
+    .unwrap();
+    static ref DB_POOL_IDLE_CONNECTIONS: IntGauge = register_int_gauge_with_registry!(
+        "db_pool_idle_connections",
+        "Open database connections that are not in use",
+        REGISTRY
+    )
+    .unwrap();
+    static ref DB_POOL_MAX_CONNECTIONS: IntGauge = register_int_gauge_with_registry!(
+        "db_pool_max_connections",
+        "Maximum size of the database pool",
+        REGISTRY
+    )
+    .unwrap();
+    static ref STORED_PICTURE_BYTES: IntGauge = register_int_gauge_with_registry!(
+        "stored_picture_bytes",
+        "Total size of the stored camera pictures, as of the last retention run",
+        REGISTRY
+    )
+    .unwrap();
+}
+
+/// Which kind of client sent a request with a bad signature
+#[derive(Copy, Clone)]
+pub enum SignatureClient {
+    Admin,
+    Device,
+    DeviceWebsocket,
+}
+
+pub fn record_signature_failure(client: SignatureClient) {
+    let client = match client {
+        SignatureClient::Admin => "admin",
+        SignatureClient::Device => "device",
+        SignatureClient::DeviceWebsocket => "device_websocket",
+    };
+    SIGNATURE_FAILURES.with_label_values(&[client]).inc();
+}
+
+/// Which limit rejected a request or websocket message
+#[derive(Copy, Clone)]
+pub enum RateLimit {
+    Register,
+    Websocket,
+    WebsocketKey,
+    WebsocketMessage,
+    Banned,
+}
+
+pub fn record_rate_limited(limit: RateLimit) {
+    let limit = match limit {
+        RateLimit::Register => "register",
+        RateLimit::Websocket => "websocket",
+        RateLimit::WebsocketKey => "websocket_key",
+        RateLimit::WebsocketMessage => "websocket_message",
+        RateLimit::Banned => "banned",
+    };
+    RATE_LIMITED.with_label_values(&[limit]).inc();
+}
+
+pub fn record_ban() {
+    BANS.inc();
+}
+
+pub fn record_send_failure() {
+    SERVER_COMMAND_SEND_FAILURES.inc();
+}
+
+pub fn record_stored_picture_bytes(size: i64) {
+    STORED_PICTURE_BYTES.set(size);
+}
+
+/// Records a handled request. `path` must be a route, never something the client chose.
+pub fn record_request(path: &str, status: &str, start: Instant) {
+    REQUESTS.with_label_values(&[path, status]).inc();
+    REQUEST_DURATION
+        .with_label_values(&[path])
+        .observe(start.elapsed().as_secs_f64());
+}
+
+/// Counts REST requests by matched route, so device keys in the URL don't become labels
+pub async fn track_requests<B>(req: Request<B>, next: Next<B>) -> Response {
+    let path = match req.extensions().get::<MatchedPath>() {
+        Some(path) => path.as_str().to_owned(),
+        None => return next.run(req).await,
+    };
+    let start = Instant::now();
+    let resp = next.run(req).await;
+    record_request(&path, resp.status().as_str(), start);
+    resp
+}
+
+/// Turns away scrapers that aren't in `[metrics] allow`
+pub async fn restrict_scrapers<B>(
+    State(allow): State<Arc<Vec<IpAddr>>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response> {
+    if !allow.contains(&remote_addr(&req).ip()) {
+        bail!(StatusCode::FORBIDDEN, "Not allowed to read metrics");
+    }
+    Ok(next.run(req).await)
+}
+
+pub async fn metrics(State(db): State<DbPool>) -> Result<Response> {
+    WS_CONNECTED_DEVICES.set(connected_device_count() as i64);
+    DB_POOL_CONNECTIONS.set(db.size() as i64);
+    DB_POOL_IDLE_CONNECTIONS.set(db.num_idle() as i64);
+    DB_POOL_MAX_CONNECTIONS.set(db.max_connections() as i64);
+    // Only the retention task sets it, export it before its first run
+    lazy_static::initialize(&STORED_PICTURE_BYTES);
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder
+        .encode(&REGISTRY.gather(), &mut body)
+        .map_err(anyhow::Error::from)?;
+    Ok((
+        [(header::CONTENT_TYPE, encoder.format_type().to_owned())],
+        body,
+    )
+        .into_response())
+}
@@ -1,6 +1,13 @@
 use crate::config::Config;
+use crate::db::DbPool;
 use crate::error::{bail, Error};
+use crate::metrics::{record_signature_failure, SignatureClient};
+use crate::model::notifications::Trigger;
+use crate::notify::notify;
+use crate::protocol::check_peer_protocol;
+use crate::ratelimit::RateLimits;
 use aegislib::crypto::check_signature;
+use aegislib::protocol::MIN_ADMIN_PROTOCOL_VERSION;
 use axum::extract::{ConnectInfo, OriginalUri};
 use axum::response::{IntoResponse, Response};
 use base64::prelude::*;
@@ -20,11 +27,13 @@ use tracing::warn;
 #[derive(Clone)]
 pub struct AdminAuthLayer {
     pub config: Config,
+    pub db: DbPool,
+    pub limits: RateLimits,
 }
 
 impl AdminAuthLayer {
-    pub fn new(config: Config) -> Self {
-        Self { config }
+    pub fn new(config: Config, db: DbPool, limits: RateLimits) -> Self {
+        Self { config, db, limits }
     }
 }
 
@@ -35,6 +44,8 @@ impl<S> Layer<S> for AdminAuthLayer {
         AdminAuthMiddleware {
             inner,
             root_pk: self.config.root_public_signature_key,
+            db: self.db.clone(),
+            limits: self.limits.clone(),
         }
     }
 }
@@ -43,6 +54,8 @@ impl<S> Layer<S> for AdminAuthLayer {
 pub struct AdminAuthMiddleware<S> {
     inner: S,
     root_pk: VerifyingKey,
+    db: DbPool,
+    limits: RateLimits,
 }
 
 impl<S> Service<Request<Body>> for AdminAuthMiddleware<S>
@@ -62,6 +75,8 @@ where
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
         let root_sig_pk = self.root_pk;
+        let db = self.db.clone();
+        let limits = self.limits.clone();
         // We must only use the service that was poll_ready, and store back the clone
         let clone = self.inner.clone();
         let mut inner = std::mem::replace(&mut self.inner, clone);
@@ -81,7 +96,7 @@ where
                     _ => bail!(StatusCode::FORBIDDEN, "Invalid Authorization header"),
                 };
 
-                let (parts, body) = req.into_parts();
+                let (mut parts, body) = req.into_parts();
                 let body_bytes = hyper::body::to_bytes(body).await.map_err(|e| {
                     Error::Response(StatusCode::BAD_REQUEST, format!("Failed to read body: {e}"))
                 })?;
@@ -98,9 +113,18 @@ where
                         None => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
                     };
                     warn!(%remote_addr, "Received forged signature from admin client!");
+                    record_signature_failure(SignatureClient::Admin);
+                    limits.record_forged_signature(remote_addr);
+                    if let Ok(mut conn) = db.acquire().await {
+                        let msg = format!("Forged admin signature from {remote_addr}");
+                        notify(&mut conn, Trigger::ForgedSignature, None, &msg).await;
+                    }
                     bail!(StatusCode::FORBIDDEN, "Invalid signature");
                 }
 
+                let protocol = check_peer_protocol(&parts.headers, MIN_ADMIN_PROTOCOL_VERSION)?;
+                let _ = parts.extensions.insert(protocol);
+
                 let req = Request::from_parts(parts, body_bytes.into());
                 inner.call(req).await.map_err(Error::from)
             }
@@ -1,7 +1,14 @@
+use crate::db::DbPool;
 use crate::error::{bail, Error};
 use crate::handler::device::DeviceId;
+use crate::metrics::{record_signature_failure, SignatureClient};
 use crate::model::device::get_dev_id_by_pk;
+use crate::model::notifications::Trigger;
+use crate::notify::notify_device;
+use crate::protocol::check_peer_protocol;
+use crate::ratelimit::RateLimits;
 use aegislib::crypto::check_signature;
+use aegislib::protocol::MIN_DEVICE_PROTOCOL_VERSION;
 use anyhow::anyhow;
 use axum::extract::{ConnectInfo, FromRequestParts, OriginalUri, Path};
 use axum::response::{IntoResponse, Response};
@@ -10,7 +17,6 @@ use futures::future::Future;
 use futures::TryFutureExt;
 use http::{Request, StatusCode};
 use hyper::Body;
-use sqlx::PgPool;
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::fmt::Debug;
@@ -22,12 +28,13 @@ use tracing::warn;
 
 #[derive(Clone)]
 pub struct DeviceAuthLayer {
-    pub db: PgPool,
+    pub db: DbPool,
+    pub limits: RateLimits,
 }
 
 impl DeviceAuthLayer {
-    pub fn new(db: PgPool) -> Self {
-        Self { db }
+    pub fn new(db: DbPool, limits: RateLimits) -> Self {
+        Self { db, limits }
     }
 }
 
@@ -38,6 +45,7 @@ impl<S> Layer<S> for DeviceAuthLayer {
         Self::Service {
             inner,
             db: self.db.clone(),
+            limits: self.limits.clone(),
         }
     }
 }
@@ -45,7 +53,8 @@ impl<S> Layer<S> for DeviceAuthLayer {
 #[derive(Clone)]
 pub struct DeviceAuthMiddleware<S> {
     inner: S,
-    db: PgPool,
+    db: DbPool,
+    limits: RateLimits,
 }
 
 impl<S> Service<Request<Body>> for DeviceAuthMiddleware<S>
@@ -65,6 +74,7 @@ where
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
         let db = self.db.clone();
+        let limits = self.limits.clone();
         // We must only use the service that was poll_ready, and store back the clone
         let clone = self.inner.clone();
         let mut inner = std::mem::replace(&mut self.inner, clone);
@@ -128,9 +138,16 @@ where
                         None => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
                     };
                     warn!(%remote_addr, "Received forged signature from client!");
+                    record_signature_failure(SignatureClient::Device);
+                    limits.record_forged_signature(remote_addr);
+                    let msg = format!("Forged device signature from {remote_addr}");
+                    notify_device(&mut conn, Trigger::ForgedSignature, dev_id, &msg).await;
                     bail!(StatusCode::FORBIDDEN, "Invalid signature");
                 }
 
+                let protocol = check_peer_protocol(&parts.headers, MIN_DEVICE_PROTOCOL_VERSION)?;
+                let _ = parts.extensions.insert(protocol);
+
                 let req = Request::from_parts(parts, body_bytes.into());
                 inner.call(req).await.map_err(Error::from)
             }
@@ -1,3 +1,15 @@
+pub mod capture_policy;
+pub mod connections;
 pub mod device;
+pub mod enrollment;
 pub mod events;
+pub mod fetches;
+pub mod notifications;
+pub mod page;
 pub mod pics;
+pub mod profiles;
+pub mod retention;
+pub mod rules;
+pub mod scripts;
+pub mod status_history;
+pub mod status_schedule;
@@ -0,0 +1,132 @@
+use crate::db::DbConnection;
+use aegislib::command::server::CapturePolicy;
+use anyhow::Result;
+
+#[derive(sqlx::FromRow)]
+struct DbCapturePolicy {
+    webcam_interval_secs: Option<i32>,
+    screenshot_interval_secs: Option<i32>,
+    burst_count: i32,
+    burst_interval_secs: i32,
+    input_cooldown_secs: i32,
+    max_uploads_per_hour: i32,
+}
+
+impl From<DbCapturePolicy> for CapturePolicy {
+    fn from(p: DbCapturePolicy) -> Self {
+        Self {
+            webcam_interval_secs: p.webcam_interval_secs.map(|v| v as u32),
+            screenshot_interval_secs: p.screenshot_interval_secs.map(|v| v as u32),
+            burst_count: p.burst_count as u32,
+            burst_interval_secs: p.burst_interval_secs as u32,
+            input_cooldown_secs: p.input_cooldown_secs as u32,
+            max_uploads_per_hour: p.max_uploads_per_hour as u32,
+        }
+    }
+}
+
+/// The device's policy, or the default if no admin set one
+pub async fn get(conn: &mut DbConnection, dev_id: i32) -> Result<CapturePolicy> {
+    let record = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                DbCapturePolicy,
+                "SELECT webcam_interval_secs, screenshot_interval_secs, burst_count,
+                    burst_interval_secs, input_cooldown_secs, max_uploads_per_hour
+             FROM device_capture_policy WHERE dev_id = $1",
+                dev_id
+            )
+            .fetch_optional(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT webcam_interval_secs, screenshot_interval_secs, burst_count,
+                        burst_interval_secs, input_cooldown_secs, max_uploads_per_hour
+                 FROM device_capture_policy WHERE dev_id = $1",
+            )
+            .bind(dev_id)
+            .fetch_optional(&mut **conn)
+            .await?
+        }
+    };
+    Ok(record.map(Into::into).unwrap_or_default())
+}
+
+/// Replaces the device's policy, or goes back to the default when `policy` is None
+pub async fn set(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    policy: Option<CapturePolicy>,
+) -> Result<()> {
+    let Some(policy) = policy else {
+        match conn {
+            DbConnection::Postgres(conn) => {
+                sqlx::query!(
+                    "DELETE FROM device_capture_policy WHERE dev_id = $1",
+                    dev_id
+                )
+                .execute(&mut **conn)
+                .await?;
+            }
+            DbConnection::Sqlite(conn) => {
+                sqlx::query("DELETE FROM device_capture_policy WHERE dev_id = $1")
+                    .bind(dev_id)
+                    .execute(&mut **conn)
+                    .await?;
+            }
+        }
+        return Ok(());
+    };
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "INSERT INTO device_capture_policy
+                 (dev_id, webcam_interval_secs, screenshot_interval_secs, burst_count,
+                  burst_interval_secs, input_cooldown_secs, max_uploads_per_hour)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)
+                 ON CONFLICT (dev_id) DO UPDATE SET
+                     webcam_interval_secs = EXCLUDED.webcam_interval_secs,
+                     screenshot_interval_secs = EXCLUDED.screenshot_interval_secs,
+                     burst_count = EXCLUDED.burst_count,
+                     burst_interval_secs = EXCLUDED.burst_interval_secs,
+                     input_cooldown_secs = EXCLUDED.input_cooldown_secs,
+                     max_uploads_per_hour = EXCLUDED.max_uploads_per_hour",
+                dev_id,
+                policy.webcam_interval_secs.map(|v| v as i32),
+                policy.screenshot_interval_secs.map(|v| v as i32),
+                policy.burst_count as i32,
+                policy.burst_interval_secs as i32,
+                policy.input_cooldown_secs as i32,
+                policy.max_uploads_per_hour as i32,
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query(
+                "INSERT INTO device_capture_policy
+                 (dev_id, webcam_interval_secs, screenshot_interval_secs, burst_count,
+                  burst_interval_secs, input_cooldown_secs, max_uploads_per_hour)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)
+                 ON CONFLICT (dev_id) DO UPDATE SET
+                     webcam_interval_secs = EXCLUDED.webcam_interval_secs,
+                     screenshot_interval_secs = EXCLUDED.screenshot_interval_secs,
+                     burst_count = EXCLUDED.burst_count,
+                     burst_interval_secs = EXCLUDED.burst_interval_secs,
+                     input_cooldown_secs = EXCLUDED.input_cooldown_secs,
+                     max_uploads_per_hour = EXCLUDED.max_uploads_per_hour",
+            )
+            .bind(dev_id)
+            .bind(policy.webcam_interval_secs.map(|v| v as i32))
+            .bind(policy.screenshot_interval_secs.map(|v| v as i32))
+            .bind(policy.burst_count as i32)
+            .bind(policy.burst_interval_secs as i32)
+            .bind(policy.input_cooldown_secs as i32)
+            .bind(policy.max_uploads_per_hour as i32)
+            .execute(&mut **conn)
+            .await?;
+        }
+    }
+    Ok(())
+}
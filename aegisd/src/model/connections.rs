@@ -0,0 +1,129 @@
+//! Log of device websocket connections, kept as evidence of where a device was seen from
+
+use crate::db::DbConnection;
+use aegislib::command::admin::ConnectionRecord;
+use anyhow::Result;
+use chrono::{NaiveDateTime, Utc};
+
+#[derive(sqlx::FromRow)]
+pub struct Connection {
+    pub connected_at: NaiveDateTime,
+    pub disconnected_at: Option<NaiveDateTime>,
+    pub remote_addr: String,
+}
+
+impl From<Connection> for ConnectionRecord {
+    fn from(c: Connection) -> Self {
+        Self {
+            connected_at_timestamp: c.connected_at.and_utc().timestamp() as u64,
+            disconnected_at_timestamp: c.disconnected_at.map(|t| t.and_utc().timestamp() as u64),
+            remote_addr: c.remote_addr,
+        }
+    }
+}
+
+/// Returns the ID to pass to [`close`] when the device disconnects
+pub async fn open(conn: &mut DbConnection, dev_id: i32, remote_addr: &str) -> Result<i32> {
+    let now = Utc::now().naive_utc();
+    let id = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_scalar!(
+                "INSERT INTO device_connection (dev_id, connected_at, remote_addr)
+                 VALUES ($1, $2, $3) RETURNING id",
+                dev_id,
+                now,
+                remote_addr
+            )
+            .fetch_one(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_scalar(
+                "INSERT INTO device_connection (dev_id, connected_at, remote_addr)
+                 VALUES ($1, $2, $3) RETURNING id",
+            )
+            .bind(dev_id)
+            .bind(now)
+            .bind(remote_addr)
+            .fetch_one(&mut **conn)
+            .await?
+        }
+    };
+    Ok(id)
+}
+
+pub async fn close(conn: &mut DbConnection, id: i32) -> Result<()> {
+    let now = Utc::now().naive_utc();
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "UPDATE device_connection SET disconnected_at = $2 WHERE id = $1",
+                id,
+                now
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query("UPDATE device_connection SET disconnected_at = $2 WHERE id = $1")
+                .bind(id)
+                .bind(now)
+                .execute(&mut **conn)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Oldest first
+pub async fn list_for_device(conn: &mut DbConnection, dev_id: i32) -> Result<Vec<Connection>> {
+    let connections = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                Connection,
+                "SELECT connected_at, disconnected_at, remote_addr FROM device_connection
+                 WHERE dev_id = $1 ORDER BY connected_at, id",
+                dev_id
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT connected_at, disconnected_at, remote_addr FROM device_connection
+                 WHERE dev_id = $1 ORDER BY connected_at, id",
+            )
+            .bind(dev_id)
+            .fetch_all(&mut **conn)
+            .await?
+        }
+    };
+    Ok(connections)
+}
+
+/// When each device that isn't archived was last connected, devices that never were are left out
+pub async fn last_seen(conn: &mut DbConnection) -> Result<Vec<(i32, String, NaiveDateTime)>> {
+    let last_seen = match conn {
+        DbConnection::Postgres(conn) => sqlx::query!(
+            r#"SELECT d.id, d.name,
+                      MAX(COALESCE(c.disconnected_at, c.connected_at)) as "last_seen!"
+               FROM device d JOIN device_connection c ON c.dev_id = d.id
+               WHERE NOT d.pending AND d.archived_at IS NULL GROUP BY d.id, d.name"#
+        )
+        .fetch_all(&mut **conn)
+        .await?
+        .into_iter()
+        .map(|r| (r.id, r.name, r.last_seen))
+        .collect(),
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT d.id, d.name, MAX(COALESCE(c.disconnected_at, c.connected_at))
+                 FROM device d JOIN device_connection c ON c.dev_id = d.id
+                 WHERE NOT d.pending AND d.archived_at IS NULL GROUP BY d.id, d.name",
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+    };
+    Ok(last_seen)
+}
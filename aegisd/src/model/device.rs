@@ -1,30 +1,63 @@
+use crate::db::{with_conn, DbConnection};
 use crate::handler::device::DeviceId;
-use aegislib::command::device::StatusReply;
+use crate::model::status_history;
+use aegislib::command::admin::{ImportedDevice, SetDeviceInfoArg};
+use aegislib::command::device::{HardwareInfo, StatusReply};
 use anyhow::{bail, Result};
 use base64::prelude::*;
 use chrono::{DateTime, NaiveDateTime, Utc};
-use sqlx::{Connection, PgConnection};
+use sqlx::Connection;
 
+#[derive(sqlx::FromRow)]
 pub struct Device {
     pub id: i32,
     pub created_at: NaiveDateTime,
     pub name: String,
     pub pubkey: String,
     pub pending: bool,
+    /// The token the device registered with, if any
+    pub enrollment_token_id: Option<i32>,
+    pub owner: Option<String>,
+    pub contact: Option<String>,
+    pub notes: Option<String>,
+    pub serial_number: Option<String>,
+    pub hardware_model: Option<String>,
+    /// As reported by the device
+    pub active_profile: Option<String>,
+    /// Set once the device is archived, it can't authenticate anymore
+    pub archived_at: Option<NaiveDateTime>,
 }
 
 impl Device {
-    pub async fn insert(self, db: &mut PgConnection) -> sqlx::Result<()> {
-        sqlx::query!(
-            "INSERT INTO device (created_at, name, pubkey, pending)
-             VALUES ($1, $2, $3, $4)",
-            self.created_at,
-            self.name,
-            self.pubkey,
-            self.pending
-        )
-        .execute(db)
-        .await?;
+    pub async fn insert(self, db: &mut DbConnection) -> sqlx::Result<()> {
+        match db {
+            DbConnection::Postgres(conn) => {
+                sqlx::query!(
+                    "INSERT INTO device (created_at, name, pubkey, pending, enrollment_token_id)
+                     VALUES ($1, $2, $3, $4, $5)",
+                    self.created_at,
+                    self.name,
+                    self.pubkey,
+                    self.pending,
+                    self.enrollment_token_id
+                )
+                .execute(&mut **conn)
+                .await?;
+            }
+            DbConnection::Sqlite(conn) => {
+                sqlx::query(
+                    "INSERT INTO device (created_at, name, pubkey, pending, enrollment_token_id)
+                     VALUES ($1, $2, $3, $4, $5)",
+                )
+                .bind(self.created_at)
+                .bind(self.name)
+                .bind(self.pubkey)
+                .bind(self.pending)
+                .bind(self.enrollment_token_id)
+                .execute(&mut **conn)
+                .await?;
+            }
+        }
         Ok(())
     }
 }
@@ -33,21 +66,41 @@ pub struct PendingDevice {
     pub created_at: NaiveDateTime,
     pub name: String,
     pub pubkey: String,
+    pub enrollment_token_id: Option<i32>,
 }
 
 impl PendingDevice {
-    pub async fn insert(self, db: &mut PgConnection) -> sqlx::Result<()> {
+    pub async fn insert(self, db: &mut DbConnection) -> sqlx::Result<()> {
         let device = Device {
             id: 0,
             created_at: self.created_at,
             name: self.name,
             pubkey: self.pubkey,
             pending: true,
+            enrollment_token_id: self.enrollment_token_id,
+            owner: None,
+            contact: None,
+            notes: None,
+            serial_number: None,
+            hardware_model: None,
+            active_profile: None,
+            archived_at: None,
         };
         device.insert(db).await
     }
 }
 
+impl From<Device> for PendingDevice {
+    fn from(dev: Device) -> Self {
+        Self {
+            created_at: dev.created_at,
+            name: dev.name,
+            pubkey: dev.pubkey,
+            enrollment_token_id: dev.enrollment_token_id,
+        }
+    }
+}
+
 impl From<PendingDevice> for aegislib::command::admin::PendingDevice {
     fn from(dev: PendingDevice) -> Self {
         Self {
@@ -58,6 +111,16 @@ impl From<PendingDevice> for aegislib::command::admin::PendingDevice {
     }
 }
 
+impl From<Device> for aegislib::command::admin::ArchivedDevice {
+    fn from(dev: Device) -> Self {
+        let archived_at = dev.archived_at.unwrap_or_default();
+        Self {
+            archived_at: DateTime::<Utc>::from_naive_utc_and_offset(archived_at, Utc).into(),
+            device: dev.into(),
+        }
+    }
+}
+
 impl From<Device> for aegislib::command::admin::RegisteredDevice {
     fn from(dev: Device) -> Self {
         Self {
@@ -65,6 +128,12 @@ impl From<Device> for aegislib::command::admin::RegisteredDevice {
             created_at: DateTime::<Utc>::from_naive_utc_and_offset(dev.created_at, Utc).into(),
             name: dev.name,
             pubkey: dev.pubkey,
+            owner: dev.owner,
+            contact: dev.contact,
+            notes: dev.notes,
+            serial_number: dev.serial_number,
+            hardware_model: dev.hardware_model,
+            active_profile: dev.active_profile,
         }
     }
 }
@@ -90,140 +159,647 @@ impl From<Status> for StatusReply {
     }
 }
 
-impl Status {
-    pub async fn insert(self, db: &mut PgConnection) -> sqlx::Result<()> {
-        sqlx::query!(
-            "INSERT INTO device_status
-             VALUES ($1, $2, $3, $4, $5)",
-            self.dev_id,
-            self.updated_at,
-            self.vt_locked,
-            self.ssh_locked,
-            self.draw_decoy
+pub async fn list_pending(conn: &mut DbConnection) -> Result<Vec<PendingDevice>> {
+    let devices = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                Device,
+                "SELECT id, created_at, name, pubkey, pending, enrollment_token_id,
+                        owner, contact, notes, serial_number, hardware_model, active_profile, archived_at
+                 FROM device WHERE pending = TRUE"
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as("SELECT * FROM device WHERE pending = TRUE")
+                .fetch_all(&mut **conn)
+                .await?
+        }
+    };
+    Ok(devices.into_iter().map(PendingDevice::from).collect())
+}
+
+pub async fn list_pending_for_token(
+    conn: &mut DbConnection,
+    token_id: i32,
+) -> Result<Vec<PendingDevice>> {
+    let devices = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                Device,
+                "SELECT id, created_at, name, pubkey, pending, enrollment_token_id,
+                        owner, contact, notes, serial_number, hardware_model, active_profile, archived_at
+                 FROM device WHERE pending = TRUE AND enrollment_token_id = $1",
+                token_id
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as("SELECT * FROM device WHERE pending = TRUE AND enrollment_token_id = $1")
+                .bind(token_id)
+                .fetch_all(&mut **conn)
+                .await?
+        }
+    };
+    Ok(devices.into_iter().map(PendingDevice::from).collect())
+}
+
+/// Devices queued by a token have their own queue, only the others count against the cap
+pub async fn count_pending(conn: &mut DbConnection) -> Result<i64> {
+    let count =
+        match conn {
+            DbConnection::Postgres(conn) => sqlx::query_scalar!(
+                "SELECT COUNT(*) FROM device WHERE pending = TRUE AND enrollment_token_id IS NULL"
+            )
+            .fetch_one(&mut **conn)
+            .await?
+            .unwrap_or(0),
+            DbConnection::Sqlite(conn) => sqlx::query_scalar(
+                "SELECT COUNT(*) FROM device WHERE pending = TRUE AND enrollment_token_id IS NULL",
+            )
+            .fetch_one(&mut **conn)
+            .await?,
+        };
+    Ok(count)
+}
+
+pub async fn delete_pending(conn: &mut DbConnection, name: &str) -> Result<()> {
+    let rows_affected = match conn {
+        DbConnection::Postgres(conn) => sqlx::query!(
+            "DELETE FROM device WHERE pending = TRUE AND name = $1",
+            name
         )
-        .execute(db)
-        .await?;
-        Ok(())
+        .execute(&mut **conn)
+        .await?
+        .rows_affected(),
+        DbConnection::Sqlite(conn) => {
+            sqlx::query("DELETE FROM device WHERE pending = TRUE AND name = $1")
+                .bind(name)
+                .execute(&mut **conn)
+                .await?
+                .rows_affected()
+        }
+    };
+    if rows_affected != 1 {
+        debug_assert_eq!(rows_affected, 0); // name is UNIQUE
+        bail!("Pending device '{}' not found", name);
     }
+    Ok(())
 }
 
-pub async fn list_pending(conn: &mut PgConnection) -> Result<Vec<PendingDevice>> {
-    let record = sqlx::query!("SELECT * FROM device WHERE pending = TRUE")
-        .fetch_all(conn)
-        .await?;
-    Ok(record
-        .into_iter()
-        .map(|r| PendingDevice {
-            created_at: r.created_at,
-            name: r.name,
-            pubkey: r.pubkey,
-        })
-        .collect())
-}
-
-pub async fn count_pending(conn: &mut PgConnection) -> Result<i64> {
-    let record = sqlx::query!("SELECT COUNT(*) FROM device WHERE pending = TRUE")
-        .fetch_one(conn)
-        .await?;
-    Ok(record.count.unwrap_or(0))
+pub async fn confirm_pending(conn: &mut DbConnection, name: &str) -> Result<()> {
+    let now = Utc::now().naive_utc();
+    match conn {
+        DbConnection::Postgres(conn) => {
+            let mut tx = conn.begin().await?;
+            let dev_id = sqlx::query_scalar!(
+                "UPDATE device SET pending = FALSE WHERE name = $1 AND pending = TRUE
+                 RETURNING id",
+                name
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "INSERT INTO device_status (dev_id, updated_at) VALUES ($1, $2)",
+                dev_id,
+                now
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            let mut tx = conn.begin().await?;
+            let dev_id: i32 = sqlx::query_scalar(
+                "UPDATE device SET pending = FALSE WHERE name = $1 AND pending = TRUE
+                 RETURNING id",
+            )
+            .bind(name)
+            .fetch_one(&mut *tx)
+            .await?;
+            sqlx::query("INSERT INTO device_status (dev_id, updated_at) VALUES ($1, $2)")
+                .bind(dev_id)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        }
+    }
+    Ok(())
 }
 
-pub async fn delete_pending(conn: &mut PgConnection, name: &str) -> Result<()> {
-    let result = sqlx::query!(
-        "DELETE FROM device WHERE pending = TRUE AND name = $1",
-        name
-    )
-    .execute(conn)
-    .await?;
-    if result.rows_affected() != 1 {
-        debug_assert_eq!(result.rows_affected(), 0); // name is UNIQUE
-        bail!("Pending device '{}' not found", name);
+/// Registers devices whose keys were generated ahead of time, as if an admin confirmed them.
+/// Returns the names of the devices that were skipped because the name or key is already taken.
+pub async fn import(conn: &mut DbConnection, devices: Vec<ImportedDevice>) -> Result<Vec<String>> {
+    let now = Utc::now().naive_utc();
+    let mut conflicts = Vec::new();
+    match conn {
+        DbConnection::Postgres(conn) => {
+            let mut tx = conn.begin().await?;
+            for dev in devices {
+                let dev_id = sqlx::query_scalar!(
+                    "INSERT INTO device (created_at, name, pubkey, pending) VALUES ($1, $2, $3, FALSE)
+                     ON CONFLICT DO NOTHING RETURNING id",
+                    now,
+                    dev.name,
+                    dev.pubkey
+                )
+                .fetch_optional(&mut *tx)
+                .await?;
+                let Some(dev_id) = dev_id else {
+                    conflicts.push(dev.name);
+                    continue;
+                };
+                sqlx::query!(
+                    "INSERT INTO device_status (dev_id, updated_at) VALUES ($1, $2)",
+                    dev_id,
+                    now
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            let mut tx = conn.begin().await?;
+            for dev in devices {
+                let dev_id: Option<i32> = sqlx::query_scalar(
+                    "INSERT INTO device (created_at, name, pubkey, pending) VALUES ($1, $2, $3, FALSE)
+                     ON CONFLICT DO NOTHING RETURNING id",
+                )
+                .bind(now)
+                .bind(&dev.name)
+                .bind(dev.pubkey)
+                .fetch_optional(&mut *tx)
+                .await?;
+                let Some(dev_id) = dev_id else {
+                    conflicts.push(dev.name);
+                    continue;
+                };
+                sqlx::query("INSERT INTO device_status (dev_id, updated_at) VALUES ($1, $2)")
+                    .bind(dev_id)
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+        }
+    }
+    Ok(conflicts)
+}
+
+pub async fn list_registered(conn: &mut DbConnection) -> Result<Vec<Device>> {
+    let devices = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                Device,
+                "SELECT id, created_at, name, pubkey, pending, enrollment_token_id,
+                        owner, contact, notes, serial_number, hardware_model, active_profile, archived_at
+                 FROM device WHERE pending = FALSE AND archived_at IS NULL"
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as("SELECT * FROM device WHERE pending = FALSE AND archived_at IS NULL")
+                .fetch_all(&mut **conn)
+                .await?
+        }
+    };
+    Ok(devices)
+}
+
+pub async fn list_archived(conn: &mut DbConnection) -> Result<Vec<Device>> {
+    let devices = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                Device,
+                "SELECT id, created_at, name, pubkey, pending, enrollment_token_id,
+                        owner, contact, notes, serial_number, hardware_model, active_profile, archived_at
+                 FROM device WHERE archived_at IS NOT NULL ORDER BY archived_at"
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT * FROM device WHERE archived_at IS NOT NULL ORDER BY archived_at",
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+    };
+    Ok(devices)
+}
+
+/// Revokes the device's key, but keeps everything it ever sent us
+pub async fn archive(conn: &mut DbConnection, dev_id: i32) -> Result<()> {
+    let now = Utc::now().naive_utc();
+    let rows_affected = match conn {
+        DbConnection::Postgres(conn) => sqlx::query!(
+            "UPDATE device SET archived_at = $2 WHERE id = $1 AND archived_at IS NULL",
+            dev_id,
+            now
+        )
+        .execute(&mut **conn)
+        .await?
+        .rows_affected(),
+        DbConnection::Sqlite(conn) => {
+            sqlx::query("UPDATE device SET archived_at = $2 WHERE id = $1 AND archived_at IS NULL")
+                .bind(dev_id)
+                .bind(now)
+                .execute(&mut **conn)
+                .await?
+                .rows_affected()
+        }
+    };
+    if rows_affected != 1 {
+        bail!("Device is already archived");
     }
     Ok(())
 }
 
-pub async fn confirm_pending(conn: &mut PgConnection, name: &str) -> Result<()> {
-    let mut tx = conn.begin().await?;
-    let result = sqlx::query!(
-        "UPDATE device SET pending = FALSE WHERE name = $1 AND pending = TRUE
-         RETURNING id",
-        name
-    )
-    .fetch_one(&mut *tx)
-    .await?;
-    Status {
-        dev_id: result.id,
-        updated_at: Utc::now().naive_utc(),
-        vt_locked: false,
-        ssh_locked: false,
-        draw_decoy: false,
+/// Lets an archived device authenticate with its old key again
+pub async fn restore(conn: &mut DbConnection, dev_id: i32) -> Result<()> {
+    let rows_affected = match conn {
+        DbConnection::Postgres(conn) => sqlx::query!(
+            "UPDATE device SET archived_at = NULL WHERE id = $1 AND archived_at IS NOT NULL",
+            dev_id
+        )
+        .execute(&mut **conn)
+        .await?
+        .rows_affected(),
+        DbConnection::Sqlite(conn) => sqlx::query(
+            "UPDATE device SET archived_at = NULL WHERE id = $1 AND archived_at IS NOT NULL",
+        )
+        .bind(dev_id)
+        .execute(&mut **conn)
+        .await?
+        .rows_affected(),
+    };
+    if rows_affected != 1 {
+        bail!("Device is not archived");
     }
-    .insert(&mut tx)
-    .await?;
-    tx.commit().await?;
     Ok(())
 }
 
-pub async fn list_registered(conn: &mut PgConnection) -> Result<Vec<Device>> {
-    let record = sqlx::query!("SELECT * FROM device WHERE pending = FALSE")
-        .fetch_all(conn)
-        .await?;
-    Ok(record
-        .into_iter()
-        .map(|r| Device {
-            id: r.id,
-            created_at: r.created_at,
-            name: r.name,
-            pubkey: r.pubkey,
-            pending: r.pending,
-        })
-        .collect())
-}
-
-pub async fn delete_registered(conn: &mut PgConnection, name: &str) -> Result<()> {
-    let result = sqlx::query!(
-        "DELETE FROM device WHERE pending = FALSE AND name = $1",
-        name
-    )
-    .execute(conn)
-    .await?;
-    if result.rows_affected() != 1 {
-        debug_assert_eq!(result.rows_affected(), 0); // name is UNIQUE
-        bail!("Device '{}' not found", name);
+/// Deletes an archived device for good, along with its events and pictures
+pub async fn purge_archived(conn: &mut DbConnection, dev_id: i32) -> Result<()> {
+    let rows_affected = match conn {
+        DbConnection::Postgres(conn) => sqlx::query!(
+            "DELETE FROM device WHERE archived_at IS NOT NULL AND id = $1",
+            dev_id
+        )
+        .execute(&mut **conn)
+        .await?
+        .rows_affected(),
+        DbConnection::Sqlite(conn) => {
+            sqlx::query("DELETE FROM device WHERE archived_at IS NOT NULL AND id = $1")
+                .bind(dev_id)
+                .execute(&mut **conn)
+                .await?
+                .rows_affected()
+        }
+    };
+    if rows_affected != 1 {
+        bail!("Device {} is not archived", dev_id);
     }
     Ok(())
 }
 
+pub async fn get_archived_dev_id_by_name(conn: &mut DbConnection, name: &str) -> Result<i32> {
+    let id = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_scalar!(
+                "SELECT id FROM device WHERE archived_at IS NOT NULL AND name = $1",
+                name
+            )
+            .fetch_optional(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_scalar("SELECT id FROM device WHERE archived_at IS NOT NULL AND name = $1")
+                .bind(name)
+                .fetch_optional(&mut **conn)
+                .await?
+        }
+    };
+    match id {
+        Some(id) => Ok(id),
+        None => bail!("Archived device '{}' not found", name),
+    }
+}
+
 pub async fn get_dev_id_by_pk(
-    conn: &mut PgConnection,
+    conn: &mut DbConnection,
     pubkey: &ed25519_dalek::VerifyingKey,
 ) -> Result<i32> {
     let pubkey = BASE64_URL_SAFE_NO_PAD.encode(pubkey.as_ref());
-    let id = sqlx::query_scalar!(
-        "SELECT id FROM device WHERE pending = FALSE AND pubkey = $1",
-        pubkey
-    )
-    .fetch_one(conn)
-    .await?;
+    let id = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_scalar!(
+                "SELECT id FROM device
+                 WHERE pending = FALSE AND archived_at IS NULL AND pubkey = $1",
+                pubkey
+            )
+            .fetch_one(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => sqlx::query_scalar(
+            "SELECT id FROM device WHERE pending = FALSE AND archived_at IS NULL AND pubkey = $1",
+        )
+        .bind(pubkey)
+        .fetch_one(&mut **conn)
+        .await?,
+    };
+    Ok(id)
+}
+
+/// Registered or archived, but not pending
+pub async fn get_by_id(conn: &mut DbConnection, dev_id: i32) -> Result<Device> {
+    let device = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                Device,
+                "SELECT id, created_at, name, pubkey, pending, enrollment_token_id,
+                        owner, contact, notes, serial_number, hardware_model, active_profile, archived_at
+                 FROM device WHERE pending = FALSE AND id = $1",
+                dev_id
+            )
+            .fetch_one(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as("SELECT * FROM device WHERE pending = FALSE AND id = $1")
+                .bind(dev_id)
+                .fetch_one(&mut **conn)
+                .await?
+        }
+    };
+    Ok(device)
+}
+
+pub async fn get_dev_id_by_name(conn: &mut DbConnection, name: &str) -> Result<i32> {
+    let id = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_scalar!(
+                "SELECT id FROM device WHERE pending = FALSE AND name = $1",
+                name
+            )
+            .fetch_one(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_scalar("SELECT id FROM device WHERE pending = FALSE AND name = $1")
+                .bind(name)
+                .fetch_one(&mut **conn)
+                .await?
+        }
+    };
     Ok(id)
 }
 
-pub async fn get_dev_id_by_name(conn: &mut PgConnection, name: &str) -> Result<i32> {
-    let id = sqlx::query_scalar!(
-        "SELECT id FROM device WHERE pending = FALSE AND name = $1",
-        name
+pub async fn get_name_by_id(conn: &mut DbConnection, dev_id: i32) -> Result<String> {
+    let name = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_scalar!("SELECT name FROM device WHERE id = $1", dev_id)
+                .fetch_one(&mut **conn)
+                .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_scalar("SELECT name FROM device WHERE id = $1")
+                .bind(dev_id)
+                .fetch_one(&mut **conn)
+                .await?
+        }
+    };
+    Ok(name)
+}
+
+pub async fn get_pubkey_by_id(conn: &mut DbConnection, dev_id: i32) -> Result<String> {
+    let pubkey = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_scalar!("SELECT pubkey FROM device WHERE id = $1", dev_id)
+                .fetch_one(&mut **conn)
+                .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_scalar("SELECT pubkey FROM device WHERE id = $1")
+                .bind(dev_id)
+                .fetch_one(&mut **conn)
+                .await?
+        }
+    };
+    Ok(pubkey)
+}
+
+/// Fails if a device, pending or not, already has the new name
+pub async fn rename(conn: &mut DbConnection, dev_id: i32, new_name: &str) -> Result<()> {
+    let result = match conn {
+        DbConnection::Postgres(conn) => sqlx::query!(
+            "UPDATE device SET name = $2 WHERE id = $1",
+            dev_id,
+            new_name
+        )
+        .execute(&mut **conn)
+        .await
+        .map(|_| ()),
+        DbConnection::Sqlite(conn) => sqlx::query("UPDATE device SET name = $2 WHERE id = $1")
+            .bind(dev_id)
+            .bind(new_name)
+            .execute(&mut **conn)
+            .await
+            .map(|_| ()),
+    };
+    match result {
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            bail!("A device named '{}' already exists", new_name)
+        }
+        result => result?,
+    };
+    Ok(())
+}
+
+/// Sets the given text columns, None leaves a column alone and an empty string clears it.
+/// Returns whether anything was set.
+async fn update_text_fields(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    fields: &[(&str, Option<&String>)],
+) -> Result<bool> {
+    let fields: Vec<_> = fields
+        .iter()
+        .filter_map(|(column, val)| Some((column, (*val)?)))
+        .collect();
+    if fields.is_empty() {
+        return Ok(false);
+    }
+    let assignments = fields
+        .iter()
+        .enumerate()
+        .map(|(i, (column, _))| format!("{column} = NULLIF(${}, '')", i + 2))
+        .collect::<Vec<_>>()
+        .join(",");
+    let query = &format!("UPDATE device SET {assignments} WHERE id = $1");
+    with_conn!(conn, |conn| {
+        let mut query = sqlx::query(query).bind(dev_id);
+        for (_, val) in &fields {
+            query = query.bind(val.as_str());
+        }
+        query.execute(&mut **conn).await?;
+    });
+    Ok(true)
+}
+
+/// Returns whether the arg changed anything
+pub async fn set_info(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    arg: &SetDeviceInfoArg,
+) -> Result<bool> {
+    update_text_fields(
+        conn,
+        dev_id,
+        &[
+            ("owner", arg.owner.as_ref()),
+            ("contact", arg.contact.as_ref()),
+            ("notes", arg.notes.as_ref()),
+            ("serial_number", arg.serial_number.as_ref()),
+            ("hardware_model", arg.hardware_model.as_ref()),
+        ],
+    )
+    .await
+}
+
+/// Stores what the device could read, a value it couldn't read doesn't erase the current one
+pub async fn report_hardware(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    info: &HardwareInfo,
+) -> Result<()> {
+    fn non_empty(val: &Option<String>) -> Option<&String> {
+        val.as_ref().filter(|val| !val.is_empty())
+    }
+    update_text_fields(
+        conn,
+        dev_id,
+        &[
+            ("serial_number", non_empty(&info.serial_number)),
+            ("hardware_model", non_empty(&info.hardware_model)),
+        ],
     )
-    .fetch_one(conn)
     .await?;
-    Ok(id)
+    Ok(())
+}
+
+/// Stores the protection profile the device says is active, None if there's none
+pub async fn report_profile(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    name: Option<&str>,
+) -> Result<()> {
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "UPDATE device SET active_profile = $2 WHERE id = $1",
+                dev_id,
+                name
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query("UPDATE device SET active_profile = $2 WHERE id = $1")
+                .bind(dev_id)
+                .bind(name)
+                .execute(&mut **conn)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the device's key, unless it changed since the caller read `current_pubkey`
+pub async fn rotate_key(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    current_pubkey: &str,
+    new_pubkey: &str,
+) -> Result<()> {
+    let rows_affected = match conn {
+        DbConnection::Postgres(conn) => sqlx::query!(
+            "UPDATE device SET pubkey = $3 WHERE id = $1 AND pubkey = $2",
+            dev_id,
+            current_pubkey,
+            new_pubkey
+        )
+        .execute(&mut **conn)
+        .await?
+        .rows_affected(),
+        DbConnection::Sqlite(conn) => {
+            sqlx::query("UPDATE device SET pubkey = $3 WHERE id = $1 AND pubkey = $2")
+                .bind(dev_id)
+                .bind(current_pubkey)
+                .bind(new_pubkey)
+                .execute(&mut **conn)
+                .await?
+                .rows_affected()
+        }
+    };
+    if rows_affected != 1 {
+        bail!("The device key was changed concurrently");
+    }
+    Ok(())
+}
+
+pub async fn set_stolen(conn: &mut DbConnection, dev_id: i32, stolen: bool) -> Result<()> {
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "UPDATE device SET stolen = $2 WHERE id = $1",
+                dev_id,
+                stolen
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query("UPDATE device SET stolen = $2 WHERE id = $1")
+                .bind(dev_id)
+                .bind(stolen)
+                .execute(&mut **conn)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn is_stolen(conn: &mut DbConnection, dev_id: i32) -> Result<bool> {
+    let stolen = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_scalar!("SELECT stolen FROM device WHERE id = $1", dev_id)
+                .fetch_one(&mut **conn)
+                .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_scalar("SELECT stolen FROM device WHERE id = $1")
+                .bind(dev_id)
+                .fetch_one(&mut **conn)
+                .await?
+        }
+    };
+    Ok(stolen)
 }
 
 pub async fn update_status(
-    conn: &mut PgConnection,
+    conn: &mut DbConnection,
     dev_id: i32,
     vt_locked: Option<bool>,
     ssh_locked: Option<bool>,
     draw_decoy: Option<bool>,
+    changed_by: &str,
 ) -> Result<Status> {
     let mut fields = vec!["dev_id=dev_id".to_owned()];
     if let Some(val) = vt_locked {
@@ -237,39 +813,56 @@ pub async fn update_status(
     }
 
     // Only if we actually updated something, set updated_at
-    if fields.len() != 1 {
-        fields.push("updated_at=timezone('utc', now())".to_owned())
+    let updated = fields.len() != 1;
+    if updated {
+        fields.push("updated_at = $2".to_owned())
     }
 
     let fields = fields.join(",");
     let query = &format!("UPDATE device_status SET {fields} WHERE dev_id = $1 RETURNING *");
-    let result = sqlx::query_as::<_, Status>(query)
-        .bind(dev_id)
-        .fetch_one(conn)
-        .await?;
+    let result = with_conn!(conn, |conn| {
+        let mut query = sqlx::query_as::<_, Status>(query).bind(dev_id);
+        if updated {
+            query = query.bind(Utc::now().naive_utc());
+        }
+        query.fetch_one(&mut **conn).await?
+    });
+    if updated {
+        status_history::record(conn, &result, changed_by).await?;
+    }
     Ok(result)
 }
 
-pub async fn get_status(conn: &mut PgConnection, dev_id: i32) -> Result<Status> {
-    let result = sqlx::query_as!(
-        Status,
-        "SELECT * FROM device_status WHERE dev_id = $1",
-        dev_id
-    )
-    .fetch_one(conn)
-    .await?;
+pub async fn get_status(conn: &mut DbConnection, dev_id: i32) -> Result<Status> {
+    let result = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                Status,
+                "SELECT * FROM device_status WHERE dev_id = $1",
+                dev_id
+            )
+            .fetch_one(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as("SELECT * FROM device_status WHERE dev_id = $1")
+                .bind(dev_id)
+                .fetch_one(&mut **conn)
+                .await?
+        }
+    };
     Ok(result)
 }
 
 #[cfg(test)]
 pub mod test {
     use super::{confirm_pending, PendingDevice};
+    use crate::db::DbConnection;
     use crate::error::Result;
     use chrono::Utc;
-    use sqlx::PgConnection;
 
     pub async fn insert_test_pending_device(
-        db: &mut PgConnection,
+        db: &mut DbConnection,
         device_pk: String,
         name: String,
     ) -> Result<()> {
@@ -277,6 +870,7 @@ pub mod test {
             created_at: Utc::now().naive_utc(),
             name,
             pubkey: device_pk,
+            enrollment_token_id: None,
         }
         .insert(db)
         .await?;
@@ -284,7 +878,7 @@ pub mod test {
     }
 
     pub async fn insert_test_device(
-        db: &mut PgConnection,
+        db: &mut DbConnection,
         device_pk: String,
         name: String,
     ) -> Result<()> {
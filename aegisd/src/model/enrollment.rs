@@ -0,0 +1,201 @@
+//! Tokens admins hand out so devices can register without waiting in the public queue.
+//! Only a hash of each token is stored, the token itself is shown once when it is created.
+
+use crate::db::DbConnection;
+use aegislib::crypto::sha256_hex;
+use anyhow::{bail, Result};
+use base64::prelude::*;
+use chrono::{NaiveDateTime, Utc};
+
+const TOKEN_LEN: usize = 32;
+
+#[derive(sqlx::FromRow)]
+pub struct EnrollmentToken {
+    pub id: i32,
+    pub label: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub auto_approve: bool,
+    pub pending_devices: i64,
+}
+
+impl From<EnrollmentToken> for aegislib::command::admin::EnrollmentToken {
+    fn from(token: EnrollmentToken) -> Self {
+        Self {
+            id: token.id,
+            label: token.label,
+            created_at_timestamp: token.created_at.and_utc().timestamp() as u64,
+            expires_at_timestamp: token.expires_at.map(|t| t.and_utc().timestamp() as u64),
+            max_uses: token.max_uses.map(|n| n as u32),
+            uses: token.uses as u32,
+            auto_approve: token.auto_approve,
+            pending_devices: token.pending_devices as u32,
+        }
+    }
+}
+
+/// A token that was accepted for one registration
+pub struct Enrollment {
+    pub token_id: i32,
+    pub auto_approve: bool,
+}
+
+/// Returns the new token's ID and the token to give to devices
+pub async fn create(
+    conn: &mut DbConnection,
+    label: &str,
+    expires_at: Option<NaiveDateTime>,
+    max_uses: Option<i32>,
+    auto_approve: bool,
+) -> Result<(i32, String)> {
+    let mut token = [0u8; TOKEN_LEN];
+    getrandom::getrandom(&mut token)?;
+    let token = BASE64_URL_SAFE_NO_PAD.encode(token);
+    let token_hash = sha256_hex(token.as_bytes());
+    let now = Utc::now().naive_utc();
+    let id = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_scalar!(
+                "INSERT INTO enrollment_token
+                 (token_hash, label, created_at, expires_at, max_uses, auto_approve)
+                 VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+                token_hash,
+                label,
+                now,
+                expires_at,
+                max_uses,
+                auto_approve
+            )
+            .fetch_one(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_scalar(
+                "INSERT INTO enrollment_token
+                 (token_hash, label, created_at, expires_at, max_uses, auto_approve)
+                 VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+            )
+            .bind(token_hash)
+            .bind(label)
+            .bind(now)
+            .bind(expires_at)
+            .bind(max_uses)
+            .bind(auto_approve)
+            .fetch_one(&mut **conn)
+            .await?
+        }
+    };
+    Ok((id, token))
+}
+
+/// Uses up one registration on the token, unless it is unknown, expired or exhausted
+pub async fn consume(conn: &mut DbConnection, token: &str) -> Result<Option<Enrollment>> {
+    let token_hash = sha256_hex(token.as_bytes());
+    let now = Utc::now().naive_utc();
+    let row = match conn {
+        DbConnection::Postgres(conn) => sqlx::query!(
+            "UPDATE enrollment_token SET uses = uses + 1
+             WHERE token_hash = $1
+               AND (expires_at IS NULL OR expires_at > $2)
+               AND (max_uses IS NULL OR uses < max_uses)
+             RETURNING id, auto_approve",
+            token_hash,
+            now
+        )
+        .fetch_optional(&mut **conn)
+        .await?
+        .map(|row| (row.id, row.auto_approve)),
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "UPDATE enrollment_token SET uses = uses + 1
+             WHERE token_hash = $1
+               AND (expires_at IS NULL OR expires_at > $2)
+               AND (max_uses IS NULL OR uses < max_uses)
+             RETURNING id, auto_approve",
+            )
+            .bind(token_hash)
+            .bind(now)
+            .fetch_optional(&mut **conn)
+            .await?
+        }
+    };
+    Ok(row.map(|(token_id, auto_approve)| Enrollment {
+        token_id,
+        auto_approve,
+    }))
+}
+
+/// Gives back a use taken by a registration that didn't go through
+pub async fn refund(conn: &mut DbConnection, token_id: i32) -> Result<()> {
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "UPDATE enrollment_token SET uses = uses - 1 WHERE id = $1 AND uses > 0",
+                token_id
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query("UPDATE enrollment_token SET uses = uses - 1 WHERE id = $1 AND uses > 0")
+                .bind(token_id)
+                .execute(&mut **conn)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn list(conn: &mut DbConnection) -> Result<Vec<EnrollmentToken>> {
+    let tokens = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                EnrollmentToken,
+                r#"SELECT t.id, t.label, t.created_at, t.expires_at, t.max_uses, t.uses,
+                          t.auto_approve,
+                          (SELECT COUNT(*) FROM device d
+                           WHERE d.enrollment_token_id = t.id AND d.pending = TRUE)
+                              AS "pending_devices!"
+                   FROM enrollment_token t ORDER BY t.id"#
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT t.id, t.label, t.created_at, t.expires_at, t.max_uses, t.uses,
+                        t.auto_approve,
+                        (SELECT COUNT(*) FROM device d
+                         WHERE d.enrollment_token_id = t.id AND d.pending = TRUE)
+                            AS pending_devices
+                 FROM enrollment_token t ORDER BY t.id",
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+    };
+    Ok(tokens)
+}
+
+/// Devices that registered with the token are kept, pending or not
+pub async fn delete(conn: &mut DbConnection, id: i32) -> Result<()> {
+    let rows_affected = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!("DELETE FROM enrollment_token WHERE id = $1", id)
+                .execute(&mut **conn)
+                .await?
+                .rows_affected()
+        }
+        DbConnection::Sqlite(conn) => sqlx::query("DELETE FROM enrollment_token WHERE id = $1")
+            .bind(id)
+            .execute(&mut **conn)
+            .await?
+            .rows_affected(),
+    };
+    if rows_affected != 1 {
+        bail!("Enrollment token {} not found", id);
+    }
+    Ok(())
+}
@@ -1,11 +1,15 @@
+use crate::db::DbConnection;
+use crate::live::publish_for_device;
+use crate::model::page::{split_page, PageCursor};
+use aegislib::command::admin::{LiveEvent, RetentionPolicy};
 use aegislib::command::device::{DeviceEvent, EventLogLevel};
 use anyhow::{bail, Result};
-use chrono::{DateTime, NaiveDateTime};
-use sqlx::PgConnection;
+use chrono::{DateTime, Duration, NaiveDateTime};
 
+/// In order of severity, the discriminant is the `level_rank` column of SQLite databases
 #[derive(Copy, Clone, Debug, Eq, PartialEq, sqlx::Type)]
 #[sqlx(type_name = "event_log_level", rename_all = "snake_case")]
-enum DbEventLogLevel {
+pub enum DbEventLogLevel {
     Trace,
     Debug,
     Info,
@@ -40,7 +44,6 @@ impl From<EventLogLevel> for DbEventLogLevel {
 #[derive(sqlx::FromRow)]
 struct DbDeviceEvent {
     #[sqlx(default)]
-    #[allow(unused)]
     id: i32,
     #[allow(unused)]
     dev_id: i32,
@@ -59,38 +62,216 @@ impl From<DbDeviceEvent> for DeviceEvent {
     }
 }
 
-pub async fn insert(conn: &mut PgConnection, dev_id: i32, event: DeviceEvent) -> Result<()> {
-    sqlx::query!(
-        r#"INSERT INTO device_event (dev_id, created_at, level, message) VALUES ($1, $2, $3, $4)"#,
-        dev_id,
-        DateTime::from_timestamp(event.timestamp as i64, 0)
-            .unwrap()
-            .naive_utc()
-            .into(),
-        DbEventLogLevel::from(event.level) as _,
-        &event.message
-    )
-    .execute(conn)
-    .await?;
+pub async fn insert(conn: &mut DbConnection, dev_id: i32, event: DeviceEvent) -> Result<()> {
+    let created_at = DateTime::from_timestamp(event.timestamp as i64, 0)
+        .unwrap()
+        .naive_utc();
+    let level = DbEventLogLevel::from(event.level);
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                r#"INSERT INTO device_event (dev_id, created_at, level, message) VALUES ($1, $2, $3, $4)"#,
+                dev_id,
+                created_at,
+                level as _,
+                &event.message
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query(
+                "INSERT INTO device_event (dev_id, created_at, level, message) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(dev_id)
+            .bind(created_at)
+            .bind(level)
+            .bind(&event.message)
+            .execute(&mut **conn)
+            .await?;
+        }
+    }
+    publish_for_device(conn, dev_id, |dev_name| LiveEvent::Logged {
+        dev_name,
+        event,
+    })
+    .await;
     Ok(())
 }
 
-pub async fn get_for_device(conn: &mut PgConnection, dev_id: i32) -> Result<Vec<DeviceEvent>> {
-    let record = sqlx::query_as!(
-        DbDeviceEvent,
-        r#"SELECT id, dev_id, created_at, level as "level: _", message FROM device_event WHERE dev_id = $1"#,
-        dev_id
-    )
-        .fetch_all(conn)
-        .await?;
-    Ok(record.into_iter().map(Into::into).collect())
+pub async fn get_for_device(conn: &mut DbConnection, dev_id: i32) -> Result<Vec<DeviceEvent>> {
+    let records = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                DbDeviceEvent,
+                r#"SELECT id, dev_id, created_at, level as "level: _", message FROM device_event WHERE dev_id = $1"#,
+                dev_id
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT id, dev_id, created_at, level, message FROM device_event WHERE dev_id = $1",
+            )
+            .bind(dev_id)
+            .fetch_all(&mut **conn)
+            .await?
+        }
+    };
+    Ok(records.into_iter().map(Into::into).collect())
+}
+
+pub struct EventFilter {
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+    pub min_level: Option<EventLogLevel>,
+    /// Case-insensitive substring of the message
+    pub search: Option<String>,
+    pub after: Option<PageCursor>,
+    pub limit: i64,
+}
+
+/// Escapes LIKE wildcards, so that searches are plain substrings
+fn like_pattern(search: &str) -> String {
+    let escaped = search
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{escaped}%")
 }
 
-pub async fn delete_for_device(conn: &mut PgConnection, dev_id: i32) -> Result<()> {
-    let result = sqlx::query!("DELETE FROM device_event WHERE dev_id = $1", dev_id)
-        .execute(conn)
-        .await?;
-    if result.rows_affected() == 0 {
+/// A page of events matching `filter`, and the cursor of the next page if there is one
+pub async fn list_for_device(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    filter: EventFilter,
+) -> Result<(Vec<DeviceEvent>, Option<PageCursor>)> {
+    let min_level = filter.min_level.map(DbEventLogLevel::from);
+    let pattern = filter.search.as_deref().map(like_pattern);
+    let mut records = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                DbDeviceEvent,
+                r#"SELECT id, dev_id, created_at, level as "level: _", message FROM device_event
+                   WHERE dev_id = $1
+                     AND ($2::timestamp IS NULL OR created_at >= $2)
+                     AND ($3::timestamp IS NULL OR created_at < $3)
+                     AND ($4::event_log_level IS NULL OR level >= $4)
+                     AND ($5::text IS NULL OR message ILIKE $5)
+                     AND ($6::timestamp IS NULL OR (created_at, id) > ($6, $7))
+                   ORDER BY created_at, id
+                   LIMIT $8"#,
+                dev_id,
+                filter.since,
+                filter.until,
+                min_level as _,
+                pattern,
+                filter.after.map(|c| c.created_at),
+                filter.after.map(|c| c.id),
+                filter.limit + 1,
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            // SQLite's LIKE is only case-insensitive for ASCII
+            sqlx::query_as(
+                r#"SELECT id, dev_id, created_at, level, message FROM device_event
+                   WHERE dev_id = $1
+                     AND ($2 IS NULL OR created_at >= $2)
+                     AND ($3 IS NULL OR created_at < $3)
+                     AND ($4 IS NULL OR level_rank >= $4)
+                     AND ($5 IS NULL OR message LIKE $5 ESCAPE '\')
+                     AND ($6 IS NULL OR (created_at, id) > ($6, $7))
+                   ORDER BY created_at, id
+                   LIMIT $8"#,
+            )
+            .bind(dev_id)
+            .bind(filter.since)
+            .bind(filter.until)
+            .bind(min_level.map(|l| l as i32))
+            .bind(pattern)
+            .bind(filter.after.map(|c| c.created_at))
+            .bind(filter.after.map(|c| c.id))
+            .bind(filter.limit + 1)
+            .fetch_all(&mut **conn)
+            .await?
+        }
+    };
+    let next = split_page(&mut records, filter.limit, |e| PageCursor {
+        created_at: e.created_at,
+        id: e.id,
+    });
+    Ok((records.into_iter().map(Into::into).collect(), next))
+}
+
+/// Deletes events past the retention limits, returns how many were deleted
+pub async fn prune(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    policy: &RetentionPolicy,
+    now: NaiveDateTime,
+) -> Result<u64> {
+    let cutoff = policy
+        .event_max_age_days
+        .map(|days| now - Duration::days(days as i64));
+    let min_level = policy.event_min_level.map(DbEventLogLevel::from);
+    let max_count = policy.event_max_count.map(i64::from);
+    let result = match conn {
+        DbConnection::Postgres(conn) => sqlx::query!(
+            r#"DELETE FROM device_event
+                   WHERE dev_id = $1
+                     AND (($2::timestamp IS NOT NULL AND created_at < $2)
+                       OR ($3::event_log_level IS NOT NULL AND level < $3)
+                       OR ($4::bigint IS NOT NULL AND id IN (
+                           SELECT id FROM device_event WHERE dev_id = $1
+                           ORDER BY created_at DESC, id DESC
+                           OFFSET $4)))"#,
+            dev_id,
+            cutoff,
+            min_level as _,
+            max_count,
+        )
+        .execute(&mut **conn)
+        .await?
+        .rows_affected(),
+        DbConnection::Sqlite(conn) => sqlx::query(
+            r#"DELETE FROM device_event
+                   WHERE dev_id = $1
+                     AND (($2 IS NOT NULL AND created_at < $2)
+                       OR ($3 IS NOT NULL AND level_rank < $3)
+                       OR ($4 IS NOT NULL AND id IN (
+                           SELECT id FROM device_event WHERE dev_id = $1
+                           ORDER BY created_at DESC, id DESC
+                           LIMIT -1 OFFSET $4)))"#,
+        )
+        .bind(dev_id)
+        .bind(cutoff)
+        .bind(min_level.map(|l| l as i32))
+        .bind(max_count)
+        .execute(&mut **conn)
+        .await?
+        .rows_affected(),
+    };
+    Ok(result)
+}
+
+pub async fn delete_for_device(conn: &mut DbConnection, dev_id: i32) -> Result<()> {
+    let rows_affected = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!("DELETE FROM device_event WHERE dev_id = $1", dev_id)
+                .execute(&mut **conn)
+                .await?
+                .rows_affected()
+        }
+        DbConnection::Sqlite(conn) => sqlx::query("DELETE FROM device_event WHERE dev_id = $1")
+            .bind(dev_id)
+            .execute(&mut **conn)
+            .await?
+            .rows_affected(),
+    };
+    if rows_affected == 0 {
         bail!("Device {} has no stored events", dev_id);
     }
     Ok(())
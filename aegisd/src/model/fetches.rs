@@ -0,0 +1,680 @@
+//! Files fetched from devices, stored in chunks as the devices send them
+
+use crate::db::DbConnection;
+use crate::model::pics::store::{stores, Backend, Blob, PictureStores};
+use aegislib::command::admin::{FetchedFile, FileFetchInfo};
+use aegislib::command::device::{FileChunk, FileFetchFinished};
+use aegislib::command::server::FileFetch;
+use anyhow::{bail, Result};
+use chrono::NaiveDateTime;
+use sqlx::Connection;
+use tracing::warn;
+
+#[derive(sqlx::FromRow)]
+struct DbFileFetch {
+    id: i32,
+    fetch_id: i64,
+    /// Bincode list of the requested paths
+    paths: Vec<u8>,
+    encrypted: bool,
+    requested_at: NaiveDateTime,
+    finished_at: Option<NaiveDateTime>,
+}
+
+#[derive(sqlx::FromRow)]
+struct DbFetchedFile {
+    id: i32,
+    file_fetch: i32,
+    path: String,
+    size: i64,
+    chunks: i32,
+    complete: bool,
+    error: Option<String>,
+}
+
+impl From<&DbFetchedFile> for FetchedFile {
+    fn from(f: &DbFetchedFile) -> Self {
+        Self {
+            path: f.path.clone(),
+            size: f.size as u64,
+            chunks: f.chunks as u32,
+            complete: f.complete,
+            error: f.error.clone(),
+        }
+    }
+}
+
+pub async fn insert(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    fetch: &FileFetch,
+    requested_at: NaiveDateTime,
+) -> Result<()> {
+    let fetch_id = fetch.fetch_id as i64;
+    let paths = bincode::serialize(&fetch.paths)?;
+    let encrypted = fetch.encrypt_to.is_some();
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "INSERT INTO device_file_fetch (dev_id, fetch_id, paths, encrypted, requested_at)
+                 VALUES ($1, $2, $3, $4, $5)",
+                dev_id,
+                fetch_id,
+                paths,
+                encrypted,
+                requested_at
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query(
+                "INSERT INTO device_file_fetch (dev_id, fetch_id, paths, encrypted, requested_at)
+                 VALUES ($1, $2, $3, $4, $5)",
+            )
+            .bind(dev_id)
+            .bind(fetch_id)
+            .bind(paths)
+            .bind(encrypted)
+            .bind(requested_at)
+            .execute(&mut **conn)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(sqlx::FromRow)]
+struct PendingFetch {
+    id: i32,
+    received_bytes: i64,
+}
+
+/// A fetch the device hasn't finished yet
+async fn pending_fetch(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    fetch_id: u64,
+) -> Result<PendingFetch> {
+    let db_fetch_id = fetch_id as i64;
+    let pending = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                PendingFetch,
+                "SELECT id, received_bytes FROM device_file_fetch
+                 WHERE dev_id = $1 AND fetch_id = $2 AND finished_at IS NULL",
+                dev_id,
+                db_fetch_id
+            )
+            .fetch_optional(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT id, received_bytes FROM device_file_fetch
+                 WHERE dev_id = $1 AND fetch_id = $2 AND finished_at IS NULL",
+            )
+            .bind(dev_id)
+            .bind(db_fetch_id)
+            .fetch_optional(&mut **conn)
+            .await?
+        }
+    };
+    match pending {
+        Some(pending) => Ok(pending),
+        None => bail!("No pending file fetch {fetch_id}"),
+    }
+}
+
+async fn find_file(
+    conn: &mut DbConnection,
+    file_fetch: i32,
+    path: &str,
+) -> Result<Option<DbFetchedFile>> {
+    Ok(match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                DbFetchedFile,
+                "SELECT id, file_fetch, path, size, chunks, complete, error
+                 FROM device_fetched_file WHERE file_fetch = $1 AND path = $2",
+                file_fetch,
+                path
+            )
+            .fetch_optional(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT id, file_fetch, path, size, chunks, complete, error
+                 FROM device_fetched_file WHERE file_fetch = $1 AND path = $2",
+            )
+            .bind(file_fetch)
+            .bind(path)
+            .fetch_optional(&mut **conn)
+            .await?
+        }
+    })
+}
+
+async fn insert_file(conn: &mut DbConnection, file_fetch: i32, path: &str) -> Result<i32> {
+    Ok(match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_scalar!(
+                "INSERT INTO device_fetched_file (file_fetch, path) VALUES ($1, $2) RETURNING id",
+                file_fetch,
+                path
+            )
+            .fetch_one(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_scalar(
+                "INSERT INTO device_fetched_file (file_fetch, path) VALUES ($1, $2) RETURNING id",
+            )
+            .bind(file_fetch)
+            .bind(path)
+            .fetch_one(&mut **conn)
+            .await?
+        }
+    })
+}
+
+async fn set_file_error(conn: &mut DbConnection, id: i32, error: &str) -> Result<()> {
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "UPDATE device_fetched_file SET error = $2 WHERE id = $1",
+                id,
+                error
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query("UPDATE device_fetched_file SET error = $2 WHERE id = $1")
+                .bind(id)
+                .bind(error)
+                .execute(&mut **conn)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Appends the next chunk of a file, fails unless it is the one we expect and the fetch stays
+/// under [`FileFetch::MAX_TOTAL_LEN`]. The data goes to the active picture store before the rows
+/// are written, so no transaction waits on the store.
+pub async fn store_chunk(conn: &mut DbConnection, dev_id: i32, chunk: &FileChunk) -> Result<()> {
+    let pending = pending_fetch(conn, dev_id, chunk.fetch_id).await?;
+    let file = find_file(conn, pending.id, &chunk.path).await?;
+    let expected = match &file {
+        None => chunk.seq == 0,
+        Some(file) => !file.complete && file.error.is_none() && file.chunks as u32 == chunk.seq,
+    };
+    if !expected {
+        bail!("Unexpected chunk {} of {}", chunk.seq, chunk.path);
+    }
+    let id = match file {
+        Some(file) => file.id,
+        None => insert_file(conn, pending.id, &chunk.path).await?,
+    };
+    let len = chunk.data.len() as i64;
+    let max_len = FileFetch::MAX_TOTAL_LEN as i64;
+    if pending.received_bytes + len > max_len {
+        let error = format!("Over the limit of {max_len} bytes per file fetch");
+        set_file_error(conn, id, &error).await?;
+        bail!("{error}, refusing chunk {} of {}", chunk.seq, chunk.path);
+    }
+
+    let stores = stores();
+    let active = stores.active();
+    let store = stores.get(active)?;
+    let seq = chunk.seq as i32;
+    if let Some(store) = store {
+        store
+            .put(Blob::FetchedChunk { file: id, seq }, &chunk.data)
+            .await?;
+    }
+    let data = store.is_none().then_some(&chunk.data);
+    let size = chunk.size as i64;
+    // Checks the limit again, in case another chunk of the fetch got in first
+    let reserved = match conn {
+        DbConnection::Postgres(conn) => {
+            let mut tx = conn.begin().await?;
+            let reserved = sqlx::query!(
+                "UPDATE device_file_fetch SET received_bytes = received_bytes + $2
+                 WHERE id = $1 AND received_bytes + $2 <= $3",
+                pending.id,
+                len,
+                max_len
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+                == 1;
+            if reserved {
+                sqlx::query!(
+                    "INSERT INTO device_fetched_chunk (file, seq, data, backend)
+                     VALUES ($1, $2, $3, $4)",
+                    id,
+                    seq,
+                    data,
+                    active as _
+                )
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query!(
+                    "UPDATE device_fetched_file SET chunks = chunks + 1, size = $2, complete = $3
+                     WHERE id = $1",
+                    id,
+                    size,
+                    chunk.last
+                )
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+            }
+            reserved
+        }
+        DbConnection::Sqlite(conn) => {
+            let mut tx = conn.begin().await?;
+            let reserved = sqlx::query(
+                "UPDATE device_file_fetch SET received_bytes = received_bytes + $2
+                 WHERE id = $1 AND received_bytes + $2 <= $3",
+            )
+            .bind(pending.id)
+            .bind(len)
+            .bind(max_len)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+                == 1;
+            if reserved {
+                sqlx::query(
+                    "INSERT INTO device_fetched_chunk (file, seq, data, backend)
+                     VALUES ($1, $2, $3, $4)",
+                )
+                .bind(id)
+                .bind(seq)
+                .bind(data)
+                .bind(active)
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query(
+                    "UPDATE device_fetched_file SET chunks = chunks + 1, size = $2, complete = $3
+                     WHERE id = $1",
+                )
+                .bind(id)
+                .bind(size)
+                .bind(chunk.last)
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+            }
+            reserved
+        }
+    };
+    if !reserved {
+        delete_data(
+            &stores,
+            &[StoredChunk {
+                file: id,
+                seq,
+                backend: active,
+            }],
+        )
+        .await;
+        bail!(
+            "Over the limit of {max_len} bytes per file fetch, refusing chunk {} of {}",
+            chunk.seq,
+            chunk.path
+        );
+    }
+    Ok(())
+}
+
+/// Records the paths the device couldn't send, no more chunks are accepted after this
+pub async fn finish(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    finished: &FileFetchFinished,
+    finished_at: NaiveDateTime,
+) -> Result<()> {
+    let file_fetch = pending_fetch(conn, dev_id, finished.fetch_id).await?.id;
+    match conn {
+        DbConnection::Postgres(conn) => {
+            let mut tx = conn.begin().await?;
+            for failure in &finished.failures {
+                sqlx::query!(
+                    "INSERT INTO device_fetched_file (file_fetch, path, error) VALUES ($1, $2, $3)
+                     ON CONFLICT (file_fetch, path) DO UPDATE SET error = excluded.error",
+                    file_fetch,
+                    failure.path,
+                    failure.error
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            sqlx::query!(
+                "UPDATE device_file_fetch SET finished_at = $2 WHERE id = $1",
+                file_fetch,
+                finished_at
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            let mut tx = conn.begin().await?;
+            for failure in &finished.failures {
+                sqlx::query(
+                    "INSERT INTO device_fetched_file (file_fetch, path, error) VALUES ($1, $2, $3)
+                     ON CONFLICT (file_fetch, path) DO UPDATE SET error = excluded.error",
+                )
+                .bind(file_fetch)
+                .bind(&failure.path)
+                .bind(&failure.error)
+                .execute(&mut *tx)
+                .await?;
+            }
+            sqlx::query("UPDATE device_file_fetch SET finished_at = $2 WHERE id = $1")
+                .bind(file_fetch)
+                .bind(finished_at)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Oldest first, with the progress of each file
+pub async fn list_for_device(conn: &mut DbConnection, dev_id: i32) -> Result<Vec<FileFetchInfo>> {
+    let (fetches, files): (Vec<DbFileFetch>, Vec<DbFetchedFile>) = match conn {
+        DbConnection::Postgres(conn) => {
+            let fetches = sqlx::query_as!(
+                DbFileFetch,
+                "SELECT id, fetch_id, paths, encrypted, requested_at, finished_at
+                 FROM device_file_fetch WHERE dev_id = $1 ORDER BY requested_at, id",
+                dev_id
+            )
+            .fetch_all(&mut **conn)
+            .await?;
+            let files = sqlx::query_as!(
+                DbFetchedFile,
+                "SELECT f.id, f.file_fetch, f.path, f.size, f.chunks, f.complete, f.error
+                 FROM device_fetched_file f JOIN device_file_fetch x ON x.id = f.file_fetch
+                 WHERE x.dev_id = $1 ORDER BY f.id",
+                dev_id
+            )
+            .fetch_all(&mut **conn)
+            .await?;
+            (fetches, files)
+        }
+        DbConnection::Sqlite(conn) => {
+            let fetches = sqlx::query_as(
+                "SELECT id, fetch_id, paths, encrypted, requested_at, finished_at
+                 FROM device_file_fetch WHERE dev_id = $1 ORDER BY requested_at, id",
+            )
+            .bind(dev_id)
+            .fetch_all(&mut **conn)
+            .await?;
+            let files = sqlx::query_as(
+                "SELECT f.id, f.file_fetch, f.path, f.size, f.chunks, f.complete, f.error
+                 FROM device_fetched_file f JOIN device_file_fetch x ON x.id = f.file_fetch
+                 WHERE x.dev_id = $1 ORDER BY f.id",
+            )
+            .bind(dev_id)
+            .fetch_all(&mut **conn)
+            .await?;
+            (fetches, files)
+        }
+    };
+    fetches
+        .into_iter()
+        .map(|fetch| {
+            Ok(FileFetchInfo {
+                fetch_id: fetch.fetch_id as u64,
+                paths: bincode::deserialize(&fetch.paths)?,
+                encrypted: fetch.encrypted,
+                requested_at_timestamp: fetch.requested_at.and_utc().timestamp() as u64,
+                finished_at_timestamp: fetch.finished_at.map(|t| t.and_utc().timestamp() as u64),
+                files: files
+                    .iter()
+                    .filter(|f| f.file_fetch == fetch.id)
+                    .map(Into::into)
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+#[derive(sqlx::FromRow)]
+struct DbChunk {
+    file: i32,
+    /// Only set when the chunk is stored in the database
+    data: Option<Vec<u8>>,
+    backend: Backend,
+}
+
+pub async fn get_chunk(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    fetch_id: u64,
+    path: &str,
+    seq: u32,
+) -> Result<Vec<u8>> {
+    let db_fetch_id = fetch_id as i64;
+    let db_seq = seq as i32;
+    let chunk = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                DbChunk,
+                r#"SELECT c.file, c.data, c.backend as "backend: _" FROM device_fetched_chunk c
+                   JOIN device_fetched_file f ON f.id = c.file
+                   JOIN device_file_fetch x ON x.id = f.file_fetch
+                   WHERE x.dev_id = $1 AND x.fetch_id = $2 AND f.path = $3 AND c.seq = $4"#,
+                dev_id,
+                db_fetch_id,
+                path,
+                db_seq
+            )
+            .fetch_optional(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT c.file, c.data, c.backend FROM device_fetched_chunk c
+                 JOIN device_fetched_file f ON f.id = c.file
+                 JOIN device_file_fetch x ON x.id = f.file_fetch
+                 WHERE x.dev_id = $1 AND x.fetch_id = $2 AND f.path = $3 AND c.seq = $4",
+            )
+            .bind(dev_id)
+            .bind(db_fetch_id)
+            .bind(path)
+            .bind(db_seq)
+            .fetch_optional(&mut **conn)
+            .await?
+        }
+    };
+    let Some(chunk) = chunk else {
+        bail!("No chunk {seq} of {path} in file fetch {fetch_id}");
+    };
+    let stores = stores();
+    match (chunk.data, stores.get(chunk.backend)?) {
+        (_, Some(store)) => {
+            let blob = Blob::FetchedChunk {
+                file: chunk.file,
+                seq: db_seq,
+            };
+            store.get(blob).await
+        }
+        (Some(data), None) => Ok(data),
+        (None, None) => bail!("Chunk {seq} of {path} in file fetch {fetch_id} has no data"),
+    }
+}
+
+/// A chunk whose data may be in a picture store
+#[derive(sqlx::FromRow)]
+struct StoredChunk {
+    file: i32,
+    seq: i32,
+    backend: Backend,
+}
+
+/// The chunks of the device's fetches, or only of `fetch_id`
+async fn stored_chunks(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    fetch_id: Option<i64>,
+) -> Result<Vec<StoredChunk>> {
+    Ok(match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                StoredChunk,
+                r#"SELECT c.file, c.seq, c.backend as "backend: _" FROM device_fetched_chunk c
+                   JOIN device_fetched_file f ON f.id = c.file
+                   JOIN device_file_fetch x ON x.id = f.file_fetch
+                   WHERE x.dev_id = $1 AND ($2::bigint IS NULL OR x.fetch_id = $2)"#,
+                dev_id,
+                fetch_id
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT c.file, c.seq, c.backend FROM device_fetched_chunk c
+                 JOIN device_fetched_file f ON f.id = c.file
+                 JOIN device_file_fetch x ON x.id = f.file_fetch
+                 WHERE x.dev_id = $1 AND ($2 IS NULL OR x.fetch_id = $2)",
+            )
+            .bind(dev_id)
+            .bind(fetch_id)
+            .fetch_all(&mut **conn)
+            .await?
+        }
+    })
+}
+
+/// Deletes chunk data from the stores once the rows are gone. A failure only leaks the data.
+async fn delete_data(stores: &PictureStores, chunks: &[StoredChunk]) {
+    for chunk in chunks {
+        let blob = Blob::FetchedChunk {
+            file: chunk.file,
+            seq: chunk.seq,
+        };
+        let result = match stores.get(chunk.backend) {
+            Ok(Some(store)) => store.delete(blob).await,
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!("Failed to delete data of {blob}: {e}");
+        }
+    }
+}
+
+/// Deletes the fetch with all its files
+pub async fn delete(conn: &mut DbConnection, dev_id: i32, fetch_id: u64) -> Result<()> {
+    let db_fetch_id = fetch_id as i64;
+    let chunks = stored_chunks(conn, dev_id, Some(db_fetch_id)).await?;
+    let deleted = match conn {
+        DbConnection::Postgres(conn) => sqlx::query!(
+            "DELETE FROM device_file_fetch WHERE dev_id = $1 AND fetch_id = $2",
+            dev_id,
+            db_fetch_id
+        )
+        .execute(&mut **conn)
+        .await?
+        .rows_affected(),
+        DbConnection::Sqlite(conn) => {
+            sqlx::query("DELETE FROM device_file_fetch WHERE dev_id = $1 AND fetch_id = $2")
+                .bind(dev_id)
+                .bind(db_fetch_id)
+                .execute(&mut **conn)
+                .await?
+                .rows_affected()
+        }
+    };
+    if deleted == 0 {
+        bail!("No file fetch {fetch_id}");
+    }
+    delete_data(&stores(), &chunks).await;
+    Ok(())
+}
+
+/// Deletes every fetch of the device, when it is purged
+pub async fn delete_all_for_device(conn: &mut DbConnection, dev_id: i32) -> Result<()> {
+    let chunks = stored_chunks(conn, dev_id, None).await?;
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!("DELETE FROM device_file_fetch WHERE dev_id = $1", dev_id)
+                .execute(&mut **conn)
+                .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query("DELETE FROM device_file_fetch WHERE dev_id = $1")
+                .bind(dev_id)
+                .execute(&mut **conn)
+                .await?;
+        }
+    }
+    delete_data(&stores(), &chunks).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{get_chunk, insert, list_for_device, store_chunk};
+    use crate::db::{with_conn, DbPool};
+    use crate::error::Result;
+    use crate::model::device::get_dev_id_by_name;
+    use crate::model::device::test::insert_test_device;
+    use aegisd_handler_macros::db_test;
+    use aegislib::command::device::FileChunk;
+    use aegislib::command::server::FileFetch;
+    use chrono::Utc;
+
+    #[db_test]
+    async fn fetch_byte_limit(db: DbPool) -> Result<()> {
+        let conn = &mut db.acquire().await?;
+        insert_test_device(conn, "pk".into(), "test".into()).await?;
+        let dev_id = get_dev_id_by_name(conn, "test").await?;
+        let fetch = FileFetch {
+            fetch_id: 5,
+            paths: vec!["/srv".into()],
+            encrypt_to: None,
+        };
+        insert(conn, dev_id, &fetch, Utc::now().naive_utc()).await?;
+        let chunk = |path: &str, len| FileChunk {
+            fetch_id: 5,
+            path: path.into(),
+            seq: 0,
+            size: len as u64,
+            data: vec![1; len],
+            last: true,
+        };
+        store_chunk(conn, dev_id, &chunk("/srv/a", 16)).await?;
+        assert_eq!(get_chunk(conn, dev_id, 5, "/srv/a", 0).await?, vec![1; 16]);
+
+        // Pretend the device already sent almost all it may
+        let received = (FileFetch::MAX_TOTAL_LEN - 8) as i64;
+        with_conn!(conn, |c| {
+            sqlx::query("UPDATE device_file_fetch SET received_bytes = $1")
+                .bind(received)
+                .execute(&mut **c)
+                .await?;
+        });
+        assert!(store_chunk(conn, dev_id, &chunk("/srv/b", 16))
+            .await
+            .is_err());
+        store_chunk(conn, dev_id, &chunk("/srv/c", 8)).await?;
+
+        let files = list_for_device(conn, dev_id).await?.pop().unwrap().files;
+        assert!(files[1].error.is_some() && files[1].chunks == 0);
+        assert!(files[2].complete && files[2].error.is_none());
+        assert!(get_chunk(conn, dev_id, 5, "/srv/b", 0).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,376 @@
+use crate::db::DbConnection;
+use anyhow::Result;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::Connection;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "notification_trigger", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    PictureUploaded,
+    DeviceConfirmed,
+    LockedDeviceOnline,
+    ForgedSignature,
+    PendingRegistration,
+}
+
+impl Display for Trigger {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Trigger::PictureUploaded => "picture uploaded",
+            Trigger::DeviceConfirmed => "device confirmed",
+            Trigger::LockedDeviceOnline => "locked device online",
+            Trigger::ForgedSignature => "forged signature",
+            Trigger::PendingRegistration => "pending registration",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Trigger {
+    /// Comes in bursts, repeats for the same device are sent as a single notification
+    pub fn is_coalesced(self) -> bool {
+        matches!(self, Trigger::ForgedSignature)
+    }
+}
+
+pub struct Notification {
+    pub id: i32,
+    pub created_at: NaiveDateTime,
+    pub trigger: Trigger,
+    pub device: Option<String>,
+    pub message: String,
+}
+
+/// A queued delivery of a notification to one sink
+pub struct PendingDelivery {
+    pub id: i32,
+    pub sink: String,
+    pub attempts: i32,
+    pub notification: Notification,
+}
+
+pub async fn insert(
+    conn: &mut DbConnection,
+    created_at: NaiveDateTime,
+    trigger: Trigger,
+    device: Option<&str>,
+    message: &str,
+) -> Result<()> {
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "INSERT INTO notification (created_at, trigger, device, message) VALUES ($1, $2, $3, $4)",
+                created_at,
+                trigger as _,
+                device,
+                message
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query(
+                "INSERT INTO notification (created_at, trigger, device, message) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(created_at)
+            .bind(trigger)
+            .bind(device)
+            .bind(message)
+            .execute(&mut **conn)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(sqlx::FromRow)]
+struct NewNotification {
+    id: i32,
+    trigger: Trigger,
+    device: Option<String>,
+    message: String,
+}
+
+/// Queues a delivery to each sink returned by `sinks_for` for every new notification.
+/// New [`Trigger::is_coalesced`] notifications wait until `coalesce_window` has passed since
+/// the last one for their device was queued, then only the latest of them is sent, counting
+/// the others.
+pub async fn dispatch_new(
+    conn: &mut DbConnection,
+    now: NaiveDateTime,
+    coalesce_window: chrono::Duration,
+    sinks_for: impl Fn(Trigger) -> Vec<String>,
+) -> Result<usize> {
+    let since = now - coalesce_window;
+    let (new, recent): (Vec<NewNotification>, Vec<(Trigger, Option<String>)>) = match conn {
+        DbConnection::Postgres(conn) => {
+            let new = sqlx::query_as!(
+                NewNotification,
+                r#"SELECT id, trigger as "trigger: _", device, message
+                   FROM notification WHERE NOT dispatched ORDER BY id"#
+            )
+            .fetch_all(&mut **conn)
+            .await?;
+            let recent = sqlx::query!(
+                r#"SELECT DISTINCT trigger as "trigger: Trigger", device
+                   FROM notification WHERE dispatched AND created_at > $1"#,
+                since
+            )
+            .fetch_all(&mut **conn)
+            .await?;
+            let recent = recent.into_iter().map(|r| (r.trigger, r.device)).collect();
+            (new, recent)
+        }
+        DbConnection::Sqlite(conn) => {
+            let new = sqlx::query_as(
+                "SELECT id, trigger, device, message
+                 FROM notification WHERE NOT dispatched ORDER BY id",
+            )
+            .fetch_all(&mut **conn)
+            .await?;
+            let recent = sqlx::query_as(
+                "SELECT DISTINCT trigger, device
+                 FROM notification WHERE dispatched AND created_at > $1",
+            )
+            .bind(since)
+            .fetch_all(&mut **conn)
+            .await?;
+            (new, recent)
+        }
+    };
+
+    let mut dispatched = Vec::new();
+    let mut bursts: HashMap<(Trigger, Option<String>), Vec<NewNotification>> = HashMap::new();
+    for notification in new {
+        if notification.trigger.is_coalesced() {
+            let key = (notification.trigger, notification.device.clone());
+            bursts.entry(key).or_default().push(notification);
+        } else {
+            dispatched.push((notification.id, notification.trigger));
+        }
+    }
+    let mut merged = Vec::new();
+    let mut counted = Vec::new();
+    for (key, mut burst) in bursts {
+        if recent.contains(&key) {
+            continue;
+        }
+        let latest = burst.pop().expect("Empty burst");
+        if !burst.is_empty() {
+            let message = format!("{} (and {} more like it)", latest.message, burst.len());
+            counted.push((latest.id, message));
+            merged.extend(burst.iter().map(|n| n.id));
+        }
+        dispatched.push((latest.id, latest.trigger));
+    }
+    let ids: Vec<i32> = dispatched.iter().map(|&(id, _)| id).collect();
+
+    match conn {
+        DbConnection::Postgres(conn) => {
+            let mut tx = conn.begin().await?;
+            sqlx::query!("DELETE FROM notification WHERE id = ANY($1)", &merged)
+                .execute(&mut *tx)
+                .await?;
+            for (id, message) in &counted {
+                sqlx::query!(
+                    "UPDATE notification SET message = $2 WHERE id = $1",
+                    id,
+                    message
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            sqlx::query!(
+                "UPDATE notification SET dispatched = TRUE WHERE id = ANY($1)",
+                &ids
+            )
+            .execute(&mut *tx)
+            .await?;
+            for &(id, trigger) in &dispatched {
+                for sink in sinks_for(trigger) {
+                    sqlx::query!(
+                        "INSERT INTO notification_delivery (notification_id, sink, next_attempt_at)
+                         VALUES ($1, $2, $3)",
+                        id,
+                        sink,
+                        now
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+            }
+            tx.commit().await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            let mut tx = conn.begin().await?;
+            sqlx::query("DELETE FROM notification WHERE id IN (SELECT value FROM json_each($1))")
+                .bind(serde_json::to_string(&merged)?)
+                .execute(&mut *tx)
+                .await?;
+            for (id, message) in &counted {
+                sqlx::query("UPDATE notification SET message = $2 WHERE id = $1")
+                    .bind(id)
+                    .bind(message)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            sqlx::query(
+                "UPDATE notification SET dispatched = TRUE
+                 WHERE id IN (SELECT value FROM json_each($1))",
+            )
+            .bind(serde_json::to_string(&ids)?)
+            .execute(&mut *tx)
+            .await?;
+            for &(id, trigger) in &dispatched {
+                for sink in sinks_for(trigger) {
+                    sqlx::query(
+                        "INSERT INTO notification_delivery (notification_id, sink, next_attempt_at)
+                         VALUES ($1, $2, $3)",
+                    )
+                    .bind(id)
+                    .bind(sink)
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await?;
+                }
+            }
+            tx.commit().await?;
+        }
+    }
+    Ok(dispatched.len())
+}
+
+#[derive(sqlx::FromRow)]
+struct DbPendingDelivery {
+    id: i32,
+    sink: String,
+    attempts: i32,
+    notification_id: i32,
+    created_at: NaiveDateTime,
+    trigger: Trigger,
+    device: Option<String>,
+    message: String,
+}
+
+/// Deliveries to the given sinks whose next attempt is due
+pub async fn get_due_deliveries(
+    conn: &mut DbConnection,
+    now: NaiveDateTime,
+    sinks: &[String],
+    limit: i64,
+) -> Result<Vec<PendingDelivery>> {
+    let records = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                DbPendingDelivery,
+                r#"SELECT d.id, d.sink, d.attempts, n.id as notification_id, n.created_at,
+                          n.trigger as "trigger: _", n.device, n.message
+                   FROM notification_delivery d JOIN notification n ON n.id = d.notification_id
+                   WHERE d.next_attempt_at <= $1 AND d.sink = ANY($2)
+                   ORDER BY d.next_attempt_at LIMIT $3"#,
+                now,
+                sinks,
+                limit
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT d.id, d.sink, d.attempts, n.id as notification_id, n.created_at,
+                        n.trigger, n.device, n.message
+                 FROM notification_delivery d JOIN notification n ON n.id = d.notification_id
+                 WHERE d.next_attempt_at <= $1 AND d.sink IN (SELECT value FROM json_each($2))
+                 ORDER BY d.next_attempt_at LIMIT $3",
+            )
+            .bind(now)
+            .bind(serde_json::to_string(sinks)?)
+            .bind(limit)
+            .fetch_all(&mut **conn)
+            .await?
+        }
+    };
+    Ok(records
+        .into_iter()
+        .map(|r: DbPendingDelivery| PendingDelivery {
+            id: r.id,
+            sink: r.sink,
+            attempts: r.attempts,
+            notification: Notification {
+                id: r.notification_id,
+                created_at: r.created_at,
+                trigger: r.trigger,
+                device: r.device,
+                message: r.message,
+            },
+        })
+        .collect())
+}
+
+pub async fn mark_delivered(conn: &mut DbConnection, id: i32, now: NaiveDateTime) -> Result<()> {
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "UPDATE notification_delivery
+                 SET attempts = attempts + 1, next_attempt_at = NULL, delivered_at = $2, last_error = NULL
+                 WHERE id = $1",
+                id,
+                now
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query(
+                "UPDATE notification_delivery
+                 SET attempts = attempts + 1, next_attempt_at = NULL, delivered_at = $2, last_error = NULL
+                 WHERE id = $1",
+            )
+            .bind(id)
+            .bind(now)
+            .execute(&mut **conn)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Records a failed attempt. A `next_attempt_at` of `None` gives up on the delivery.
+pub async fn mark_failed(
+    conn: &mut DbConnection,
+    id: i32,
+    error: &str,
+    next_attempt_at: Option<NaiveDateTime>,
+) -> Result<()> {
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "UPDATE notification_delivery
+                 SET attempts = attempts + 1, next_attempt_at = $2, last_error = $3
+                 WHERE id = $1",
+                id,
+                next_attempt_at,
+                error
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query(
+                "UPDATE notification_delivery
+                 SET attempts = attempts + 1, next_attempt_at = $2, last_error = $3
+                 WHERE id = $1",
+            )
+            .bind(id)
+            .bind(next_attempt_at)
+            .bind(error)
+            .execute(&mut **conn)
+            .await?;
+        }
+    }
+    Ok(())
+}
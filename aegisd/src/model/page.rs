@@ -0,0 +1,87 @@
+//! Keyset pagination over `(created_at, id)`, which stays stable while rows are inserted
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDateTime};
+
+/// Position after the last row of a page. Clients only pass it back as an opaque string.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PageCursor {
+    pub created_at: NaiveDateTime,
+    pub id: i32,
+}
+
+impl PageCursor {
+    pub fn encode(&self) -> String {
+        format!(
+            "{}.{}",
+            self.created_at.and_utc().timestamp_micros(),
+            self.id
+        )
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid page cursor");
+        let (micros, id) = cursor.split_once('.').ok_or_else(invalid)?;
+        let micros = micros.parse().map_err(|_| invalid())?;
+        Ok(Self {
+            created_at: DateTime::from_timestamp_micros(micros)
+                .ok_or_else(invalid)?
+                .naive_utc(),
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// The requested page size, or `default`, capped to `max`
+pub fn page_limit(requested: Option<u32>, default: u32, max: u32) -> i64 {
+    requested.unwrap_or(default).clamp(1, max) as i64
+}
+
+pub fn naive_from_timestamp(timestamp: u64) -> Result<NaiveDateTime> {
+    i64::try_from(timestamp)
+        .ok()
+        .and_then(|t| DateTime::from_timestamp(t, 0))
+        .map(|t| t.naive_utc())
+        .ok_or_else(|| anyhow!("Invalid timestamp {timestamp}"))
+}
+
+/// Splits off the extra row fetched past `limit`, returning the cursor for the next page if any
+pub fn split_page<T>(
+    rows: &mut Vec<T>,
+    limit: i64,
+    cursor_of: impl Fn(&T) -> PageCursor,
+) -> Option<PageCursor> {
+    if rows.len() as i64 <= limit {
+        return None;
+    }
+    rows.truncate(limit as usize);
+    rows.last().map(cursor_of)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cursor_roundtrip() {
+        let cursor = PageCursor {
+            created_at: naive_from_timestamp(1_650_000_000).unwrap(),
+            id: 42,
+        };
+        assert_eq!(PageCursor::decode(&cursor.encode()).unwrap(), cursor);
+        assert!(PageCursor::decode("garbage").is_err());
+        assert!(PageCursor::decode("1.x").is_err());
+    }
+
+    #[test]
+    fn split_pages() {
+        let cursor_of = |&id: &i32| PageCursor {
+            created_at: naive_from_timestamp(0).unwrap(),
+            id,
+        };
+        let mut rows = vec![1, 2, 3];
+        assert_eq!(split_page(&mut rows, 2, cursor_of).unwrap().id, 2);
+        assert_eq!(rows, [1, 2]);
+        assert_eq!(split_page(&mut rows, 2, cursor_of), None);
+    }
+}
@@ -1,7 +1,55 @@
-use aegislib::command::admin::StoredCameraPicture;
+pub mod filesystem;
+pub mod s3;
+pub mod store;
+
+use crate::db::DbConnection;
+use crate::model::page::{split_page, PageCursor};
+use aegislib::command::admin::{
+    ExportedPicture, PictureInfo, RetentionPolicy, StoredCameraPicture,
+};
+use aegislib::command::device::CaptureTrigger;
+use aegislib::crypto::sha256_hex;
 use anyhow::{bail, Result};
-use chrono::NaiveDateTime;
-use sqlx::PgConnection;
+use chrono::{Duration, NaiveDateTime};
+use store::{stores, Backend, Blob, PictureStores};
+use tracing::warn;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "capture_trigger", rename_all = "snake_case")]
+pub enum DbCaptureTrigger {
+    Unknown,
+    InputWhileLocked,
+    Periodic,
+    Scheduled,
+    Screenshot,
+    Requested,
+}
+
+impl From<DbCaptureTrigger> for CaptureTrigger {
+    fn from(t: DbCaptureTrigger) -> Self {
+        match t {
+            DbCaptureTrigger::Unknown => Self::Unknown,
+            DbCaptureTrigger::InputWhileLocked => Self::InputWhileLocked,
+            DbCaptureTrigger::Periodic => Self::Periodic,
+            DbCaptureTrigger::Scheduled => Self::Scheduled,
+            DbCaptureTrigger::Screenshot => Self::Screenshot,
+            DbCaptureTrigger::Requested => Self::Requested,
+        }
+    }
+}
+
+impl From<CaptureTrigger> for DbCaptureTrigger {
+    fn from(t: CaptureTrigger) -> Self {
+        match t {
+            CaptureTrigger::Unknown => Self::Unknown,
+            CaptureTrigger::InputWhileLocked => Self::InputWhileLocked,
+            CaptureTrigger::Periodic => Self::Periodic,
+            CaptureTrigger::Scheduled => Self::Scheduled,
+            CaptureTrigger::Screenshot => Self::Screenshot,
+            CaptureTrigger::Requested => Self::Requested,
+        }
+    }
+}
 
 #[derive(sqlx::FromRow)]
 pub struct DeviceCameraPicture {
@@ -9,6 +57,9 @@ pub struct DeviceCameraPicture {
     pub dev_id: i32,
     pub created_at: NaiveDateTime,
     pub jpeg_data: Vec<u8>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub trigger: DbCaptureTrigger,
 }
 
 impl From<DeviceCameraPicture> for StoredCameraPicture {
@@ -21,40 +72,781 @@ impl From<DeviceCameraPicture> for StoredCameraPicture {
 }
 
 impl DeviceCameraPicture {
-    pub async fn insert(self, db: &mut PgConnection) -> sqlx::Result<()> {
-        sqlx::query!(
-            "INSERT INTO device_cam_pics (dev_id, created_at, jpeg_data)
-             VALUES ($1, $2, $3)",
-            self.dev_id,
-            self.created_at,
-            self.jpeg_data,
-        )
-        .execute(db)
-        .await?;
+    /// Inserts the picture with its data in the database, then moves the data to the active
+    /// backend the way [`migrate_to_active`] does, so no transaction waits on the store.
+    /// If the store fails, the data stays in the database until the next migration.
+    pub async fn insert(self, db: &mut DbConnection) -> Result<()> {
+        self.insert_into(db, &stores()).await
+    }
+
+    async fn insert_into(self, db: &mut DbConnection, stores: &PictureStores) -> Result<()> {
+        let active = stores.active();
+        let store = stores.get(active)?;
+        let size = self.jpeg_data.len() as i32;
+        let sha256 = sha256_hex(&self.jpeg_data);
+        let id = match &mut *db {
+            DbConnection::Postgres(conn) => {
+                sqlx::query_scalar!(
+                    "INSERT INTO device_cam_pics
+                         (dev_id, created_at, jpeg_data, width, height, trigger, size, sha256, backend)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                     RETURNING id",
+                    self.dev_id,
+                    self.created_at,
+                    self.jpeg_data,
+                    self.width,
+                    self.height,
+                    self.trigger as _,
+                    size,
+                    sha256,
+                    Backend::Postgres as _,
+                )
+                .fetch_one(&mut **conn)
+                .await?
+            }
+            DbConnection::Sqlite(conn) => {
+                sqlx::query_scalar(
+                    "INSERT INTO device_cam_pics
+                         (dev_id, created_at, jpeg_data, width, height, trigger, size, sha256, backend)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                     RETURNING id",
+                )
+                .bind(self.dev_id)
+                .bind(self.created_at)
+                .bind(&self.jpeg_data)
+                .bind(self.width)
+                .bind(self.height)
+                .bind(self.trigger)
+                .bind(size)
+                .bind(sha256)
+                .bind(Backend::Postgres)
+                .fetch_one(&mut **conn)
+                .await?
+            }
+        };
+        let Some(store) = store else {
+            return Ok(());
+        };
+        if let Err(e) = store.put(Blob::Picture(id), &self.jpeg_data).await {
+            warn!("Keeping camera picture {id} in the database, failed to store it: {e}");
+            return Ok(());
+        }
+        let moved = match db {
+            DbConnection::Postgres(conn) => sqlx::query!(
+                "UPDATE device_cam_pics SET backend = $2, jpeg_data = NULL
+                 WHERE id = $1 AND backend = $3",
+                id,
+                active as _,
+                Backend::Postgres as _,
+            )
+            .execute(&mut **conn)
+            .await?
+            .rows_affected(),
+            DbConnection::Sqlite(conn) => sqlx::query(
+                "UPDATE device_cam_pics SET backend = $2, jpeg_data = NULL
+                 WHERE id = $1 AND backend = $3",
+            )
+            .bind(id)
+            .bind(active)
+            .bind(Backend::Postgres)
+            .execute(&mut **conn)
+            .await?
+            .rows_affected(),
+        };
+        // The picture was deleted while we stored it
+        if moved == 0 {
+            delete_data(
+                stores,
+                &[DeletedPicture {
+                    id,
+                    backend: active,
+                }],
+            )
+            .await;
+        }
         Ok(())
     }
 }
 
+/// A picture's row, `jpeg_data` is only set when the data is stored in the database
+#[derive(sqlx::FromRow)]
+struct DbPicture {
+    id: i32,
+    dev_id: i32,
+    created_at: NaiveDateTime,
+    jpeg_data: Option<Vec<u8>>,
+    width: Option<i32>,
+    height: Option<i32>,
+    trigger: DbCaptureTrigger,
+    sha256: String,
+    backend: Backend,
+}
+
+impl DbPicture {
+    async fn load(self, stores: &PictureStores) -> Result<DeviceCameraPicture> {
+        let jpeg_data = match (self.jpeg_data, stores.get(self.backend)?) {
+            (Some(data), _) => data,
+            (None, Some(store)) => {
+                let data = store.get(Blob::Picture(self.id)).await?;
+                if sha256_hex(&data) != self.sha256 {
+                    bail!("Camera picture {} is corrupted in its store", self.id);
+                }
+                data
+            }
+            (None, None) => bail!("Picture {} has no data in the database", self.id),
+        };
+        Ok(DeviceCameraPicture {
+            id: self.id,
+            dev_id: self.dev_id,
+            created_at: self.created_at,
+            jpeg_data,
+            width: self.width,
+            height: self.height,
+            trigger: self.trigger,
+        })
+    }
+}
+
+async fn load_all(rows: Vec<DbPicture>) -> Result<Vec<DeviceCameraPicture>> {
+    let stores = stores();
+    let mut pictures = Vec::with_capacity(rows.len());
+    for row in rows {
+        pictures.push(row.load(&stores).await?);
+    }
+    Ok(pictures)
+}
+
+#[derive(sqlx::FromRow)]
+struct DeletedPicture {
+    id: i32,
+    backend: Backend,
+}
+
+/// Deletes the data of pictures whose rows are already gone. A failure only leaks the data.
+async fn delete_data(stores: &PictureStores, deleted: &[DeletedPicture]) {
+    for pic in deleted {
+        let result = match stores.get(pic.backend) {
+            Ok(Some(store)) => store.delete(Blob::Picture(pic.id)).await,
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!("Failed to delete data of camera picture {}: {e}", pic.id);
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+pub struct DbPictureInfo {
+    pub id: i32,
+    pub created_at: NaiveDateTime,
+    pub size: i32,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub trigger: DbCaptureTrigger,
+}
+
+impl From<DbPictureInfo> for PictureInfo {
+    fn from(p: DbPictureInfo) -> Self {
+        PictureInfo {
+            id: p.id,
+            created_at_timestamp: p.created_at.and_utc().timestamp() as u64,
+            size: p.size as u64,
+            width: p.width.map(|w| w as u32),
+            height: p.height.map(|h| h as u32),
+            trigger: p.trigger.into(),
+        }
+    }
+}
+
 pub async fn get_for_device(
-    conn: &mut PgConnection,
+    conn: &mut DbConnection,
     dev_id: i32,
 ) -> Result<Vec<DeviceCameraPicture>> {
-    let record = sqlx::query_as!(
-        DeviceCameraPicture,
-        "SELECT * FROM device_cam_pics WHERE dev_id = $1",
-        dev_id
-    )
-    .fetch_all(conn)
-    .await?;
-    Ok(record)
-}
-
-pub async fn delete_for_device(conn: &mut PgConnection, dev_id: i32) -> Result<()> {
-    let result = sqlx::query!("DELETE FROM device_cam_pics WHERE dev_id = $1", dev_id)
-        .execute(conn)
-        .await?;
-    if result.rows_affected() == 0 {
+    let rows =
+        match conn {
+            DbConnection::Postgres(conn) => sqlx::query_as!(
+                DbPicture,
+                r#"SELECT id, dev_id, created_at, jpeg_data, width, height, trigger as "trigger: _",
+                          sha256, backend as "backend: _"
+                   FROM device_cam_pics WHERE dev_id = $1"#,
+                dev_id
+            )
+            .fetch_all(&mut **conn)
+            .await?,
+            DbConnection::Sqlite(conn) => sqlx::query_as(
+                "SELECT id, dev_id, created_at, jpeg_data, width, height, trigger, sha256, backend
+                 FROM device_cam_pics WHERE dev_id = $1",
+            )
+            .bind(dev_id)
+            .fetch_all(&mut **conn)
+            .await?,
+        };
+    load_all(rows).await
+}
+
+pub struct PictureFilter {
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+    pub after: Option<PageCursor>,
+    pub limit: i64,
+}
+
+/// A page of pictures matching `filter`, and the cursor of the next page if there is one
+pub async fn list_for_device(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    filter: PictureFilter,
+) -> Result<(Vec<DeviceCameraPicture>, Option<PageCursor>)> {
+    let mut rows: Vec<DbPicture> =
+        match conn {
+            DbConnection::Postgres(conn) => sqlx::query_as!(
+                DbPicture,
+                r#"SELECT id, dev_id, created_at, jpeg_data, width, height, trigger as "trigger: _",
+                          sha256, backend as "backend: _"
+                   FROM device_cam_pics
+                   WHERE dev_id = $1
+                     AND ($2::timestamp IS NULL OR created_at >= $2)
+                     AND ($3::timestamp IS NULL OR created_at < $3)
+                     AND ($4::timestamp IS NULL OR (created_at, id) > ($4, $5))
+                   ORDER BY created_at, id
+                   LIMIT $6"#,
+                dev_id,
+                filter.since,
+                filter.until,
+                filter.after.map(|c| c.created_at),
+                filter.after.map(|c| c.id),
+                filter.limit + 1,
+            )
+            .fetch_all(&mut **conn)
+            .await?,
+            DbConnection::Sqlite(conn) => sqlx::query_as(
+                "SELECT id, dev_id, created_at, jpeg_data, width, height, trigger, sha256, backend
+                 FROM device_cam_pics
+                 WHERE dev_id = $1
+                   AND ($2 IS NULL OR created_at >= $2)
+                   AND ($3 IS NULL OR created_at < $3)
+                   AND ($4 IS NULL OR (created_at, id) > ($4, $5))
+                 ORDER BY created_at, id
+                 LIMIT $6",
+            )
+            .bind(dev_id)
+            .bind(filter.since)
+            .bind(filter.until)
+            .bind(filter.after.map(|c| c.created_at))
+            .bind(filter.after.map(|c| c.id))
+            .bind(filter.limit + 1)
+            .fetch_all(&mut **conn)
+            .await?,
+        };
+    let next = split_page(&mut rows, filter.limit, |p| PageCursor {
+        created_at: p.created_at,
+        id: p.id,
+    });
+    Ok((load_all(rows).await?, next))
+}
+
+/// Like [`list_for_device`], without loading the pictures themselves
+pub async fn list_info_for_device(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    filter: PictureFilter,
+) -> Result<(Vec<DbPictureInfo>, Option<PageCursor>)> {
+    let mut records: Vec<DbPictureInfo> = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                DbPictureInfo,
+                r#"SELECT id, created_at, size, width, height,
+                          trigger as "trigger: _"
+                   FROM device_cam_pics
+                   WHERE dev_id = $1
+                     AND ($2::timestamp IS NULL OR created_at >= $2)
+                     AND ($3::timestamp IS NULL OR created_at < $3)
+                     AND ($4::timestamp IS NULL OR (created_at, id) > ($4, $5))
+                   ORDER BY created_at, id
+                   LIMIT $6"#,
+                dev_id,
+                filter.since,
+                filter.until,
+                filter.after.map(|c| c.created_at),
+                filter.after.map(|c| c.id),
+                filter.limit + 1,
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT id, created_at, size, width, height, trigger
+                 FROM device_cam_pics
+                 WHERE dev_id = $1
+                   AND ($2 IS NULL OR created_at >= $2)
+                   AND ($3 IS NULL OR created_at < $3)
+                   AND ($4 IS NULL OR (created_at, id) > ($4, $5))
+                 ORDER BY created_at, id
+                 LIMIT $6",
+            )
+            .bind(dev_id)
+            .bind(filter.since)
+            .bind(filter.until)
+            .bind(filter.after.map(|c| c.created_at))
+            .bind(filter.after.map(|c| c.id))
+            .bind(filter.limit + 1)
+            .fetch_all(&mut **conn)
+            .await?
+        }
+    };
+    let next = split_page(&mut records, filter.limit, |p| PageCursor {
+        created_at: p.created_at,
+        id: p.id,
+    });
+    Ok((records, next))
+}
+
+#[derive(sqlx::FromRow)]
+struct DbExportedPicture {
+    id: i32,
+    created_at: NaiveDateTime,
+    size: i32,
+    width: Option<i32>,
+    height: Option<i32>,
+    trigger: DbCaptureTrigger,
+    sha256: String,
+}
+
+impl From<DbExportedPicture> for ExportedPicture {
+    fn from(p: DbExportedPicture) -> Self {
+        let info = DbPictureInfo {
+            id: p.id,
+            created_at: p.created_at,
+            size: p.size,
+            width: p.width,
+            height: p.height,
+            trigger: p.trigger,
+        };
+        ExportedPicture {
+            info: info.into(),
+            sha256: p.sha256,
+        }
+    }
+}
+
+/// Every picture of the device with the hash of its data, oldest first
+pub async fn export_info_for_device(
+    conn: &mut DbConnection,
+    dev_id: i32,
+) -> Result<Vec<ExportedPicture>> {
+    let records: Vec<DbExportedPicture> = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                DbExportedPicture,
+                r#"SELECT id, created_at, size, width, height, trigger as "trigger: _", sha256
+                   FROM device_cam_pics WHERE dev_id = $1 ORDER BY created_at, id"#,
+                dev_id
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT id, created_at, size, width, height, trigger, sha256
+                 FROM device_cam_pics WHERE dev_id = $1 ORDER BY created_at, id",
+            )
+            .bind(dev_id)
+            .fetch_all(&mut **conn)
+            .await?
+        }
+    };
+    Ok(records.into_iter().map(Into::into).collect())
+}
+
+pub async fn get_by_id(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    id: i32,
+) -> Result<DeviceCameraPicture> {
+    let row: Option<DbPicture> =
+        match conn {
+            DbConnection::Postgres(conn) => sqlx::query_as!(
+                DbPicture,
+                r#"SELECT id, dev_id, created_at, jpeg_data, width, height, trigger as "trigger: _",
+                          sha256, backend as "backend: _"
+                   FROM device_cam_pics WHERE dev_id = $1 AND id = $2"#,
+                dev_id,
+                id
+            )
+            .fetch_optional(&mut **conn)
+            .await?,
+            DbConnection::Sqlite(conn) => sqlx::query_as(
+                "SELECT id, dev_id, created_at, jpeg_data, width, height, trigger, sha256, backend
+                 FROM device_cam_pics WHERE dev_id = $1 AND id = $2",
+            )
+            .bind(dev_id)
+            .bind(id)
+            .fetch_optional(&mut **conn)
+            .await?,
+        };
+    match row {
+        Some(row) => row.load(&stores()).await,
+        None => bail!("Device {} has no camera picture {}", dev_id, id),
+    }
+}
+
+pub async fn delete_by_id(conn: &mut DbConnection, dev_id: i32, id: i32) -> Result<()> {
+    let deleted: Vec<DeletedPicture> =
+        match conn {
+            DbConnection::Postgres(conn) => {
+                sqlx::query_as!(
+                    DeletedPicture,
+                    r#"DELETE FROM device_cam_pics WHERE dev_id = $1 AND id = $2
+                   RETURNING id, backend as "backend: _""#,
+                    dev_id,
+                    id
+                )
+                .fetch_all(&mut **conn)
+                .await?
+            }
+            DbConnection::Sqlite(conn) => sqlx::query_as(
+                "DELETE FROM device_cam_pics WHERE dev_id = $1 AND id = $2 RETURNING id, backend",
+            )
+            .bind(dev_id)
+            .bind(id)
+            .fetch_all(&mut **conn)
+            .await?,
+        };
+    if deleted.is_empty() {
+        bail!("Device {} has no camera picture {}", dev_id, id);
+    }
+    delete_data(&stores(), &deleted).await;
+    Ok(())
+}
+
+/// Deletes pictures past the retention limits, returns how many were deleted
+pub async fn prune(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    policy: &RetentionPolicy,
+    now: NaiveDateTime,
+) -> Result<u64> {
+    let cutoff = policy
+        .picture_max_age_days
+        .map(|days| now - Duration::days(days as i64));
+    let max_count = policy.picture_max_count.map(i64::from);
+    let max_bytes = policy.picture_max_bytes.map(|b| b as i64);
+    let deleted: Vec<DeletedPicture> = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                DeletedPicture,
+                r#"DELETE FROM device_cam_pics
+                   WHERE id IN (
+                       SELECT id FROM (
+                           SELECT id, created_at,
+                                  ROW_NUMBER() OVER newest_first AS position,
+                                  SUM(size) OVER newest_first AS newer_bytes
+                           FROM device_cam_pics
+                           WHERE dev_id = $1
+                           WINDOW newest_first AS (ORDER BY created_at DESC, id DESC)
+                       ) p
+                       WHERE ($2::timestamp IS NOT NULL AND created_at < $2)
+                          OR ($3::bigint IS NOT NULL AND position > $3)
+                          OR ($4::bigint IS NOT NULL AND newer_bytes > $4))
+                   RETURNING id, backend as "backend: _""#,
+                dev_id,
+                cutoff,
+                max_count,
+                max_bytes,
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "DELETE FROM device_cam_pics
+                 WHERE id IN (
+                     SELECT id FROM (
+                         SELECT id, created_at,
+                                ROW_NUMBER() OVER newest_first AS position,
+                                SUM(size) OVER newest_first AS newer_bytes
+                         FROM device_cam_pics
+                         WHERE dev_id = $1
+                         WINDOW newest_first AS (ORDER BY created_at DESC, id DESC)
+                     ) p
+                     WHERE ($2 IS NOT NULL AND created_at < $2)
+                        OR ($3 IS NOT NULL AND position > $3)
+                        OR ($4 IS NOT NULL AND newer_bytes > $4))
+                 RETURNING id, backend",
+            )
+            .bind(dev_id)
+            .bind(cutoff)
+            .bind(max_count)
+            .bind(max_bytes)
+            .fetch_all(&mut **conn)
+            .await?
+        }
+    };
+    delete_data(&stores(), &deleted).await;
+    Ok(deleted.len() as u64)
+}
+
+pub async fn delete_for_device(conn: &mut DbConnection, dev_id: i32) -> Result<()> {
+    if delete_all_for_device(conn, dev_id).await? == 0 {
         bail!("Device {} has no stored camera pictures", dev_id);
     }
     Ok(())
 }
+
+/// Also deletes the data kept outside the database, which deleting the device wouldn't
+pub async fn delete_all_for_device(conn: &mut DbConnection, dev_id: i32) -> Result<u64> {
+    let deleted: Vec<DeletedPicture> = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                DeletedPicture,
+                r#"DELETE FROM device_cam_pics WHERE dev_id = $1 RETURNING id, backend as "backend: _""#,
+                dev_id
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as("DELETE FROM device_cam_pics WHERE dev_id = $1 RETURNING id, backend")
+                .bind(dev_id)
+                .fetch_all(&mut **conn)
+                .await?
+        }
+    };
+    delete_data(&stores(), &deleted).await;
+    Ok(deleted.len() as u64)
+}
+
+/// How many pictures the device uploaded after `since`
+pub async fn count_since(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    since: NaiveDateTime,
+) -> Result<i64> {
+    let count = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_scalar!(
+                r#"SELECT COUNT(*) AS "count!" FROM device_cam_pics
+                   WHERE dev_id = $1 AND created_at > $2"#,
+                dev_id,
+                since
+            )
+            .fetch_one(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_scalar(
+                "SELECT COUNT(*) FROM device_cam_pics WHERE dev_id = $1 AND created_at > $2",
+            )
+            .bind(dev_id)
+            .bind(since)
+            .fetch_one(&mut **conn)
+            .await?
+        }
+    };
+    Ok(count)
+}
+
+pub async fn total_size(conn: &mut DbConnection) -> Result<i64> {
+    let size = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_scalar!(
+                r#"SELECT COALESCE(SUM(size), 0)::BIGINT AS "size!" FROM device_cam_pics"#
+            )
+            .fetch_one(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_scalar("SELECT COALESCE(SUM(size), 0) FROM device_cam_pics")
+                .fetch_one(&mut **conn)
+                .await?
+        }
+    };
+    Ok(size)
+}
+
+/// Moves pictures stored in other backends to the active one, returns how many were moved
+pub async fn migrate_to_active(conn: &mut DbConnection, stores: &PictureStores) -> Result<u64> {
+    const BATCH_SIZE: i64 = 100;
+    let active = stores.active();
+    let target = stores.get(active)?;
+    let mut moved = 0;
+    let mut after = 0;
+    loop {
+        let rows: Vec<DbPicture> = match &mut *conn {
+            DbConnection::Postgres(conn) => sqlx::query_as!(
+                DbPicture,
+                r#"SELECT id, dev_id, created_at, jpeg_data, width, height, trigger as "trigger: _",
+                              sha256, backend as "backend: _"
+                       FROM device_cam_pics
+                       WHERE backend != $1 AND id > $2
+                       ORDER BY id
+                       LIMIT $3"#,
+                active as _,
+                after,
+                BATCH_SIZE,
+            )
+            .fetch_all(&mut **conn)
+            .await?,
+            DbConnection::Sqlite(conn) => sqlx::query_as(
+                "SELECT id, dev_id, created_at, jpeg_data, width, height, trigger, sha256, backend
+                     FROM device_cam_pics
+                     WHERE backend != $1 AND id > $2
+                     ORDER BY id
+                     LIMIT $3",
+            )
+            .bind(active)
+            .bind(after)
+            .bind(BATCH_SIZE)
+            .fetch_all(&mut **conn)
+            .await?,
+        };
+        let Some(last) = rows.last() else {
+            return Ok(moved);
+        };
+        after = last.id;
+        for row in rows {
+            let (id, source) = (row.id, row.backend);
+            let data = row.load(stores).await?.jpeg_data;
+            if let Some(store) = target {
+                store.put(Blob::Picture(id), &data).await?;
+            }
+            let jpeg_data = target.is_none().then_some(data);
+            match &mut *conn {
+                DbConnection::Postgres(conn) => {
+                    sqlx::query!(
+                        "UPDATE device_cam_pics SET backend = $2, jpeg_data = $3 WHERE id = $1",
+                        id,
+                        active as _,
+                        jpeg_data,
+                    )
+                    .execute(&mut **conn)
+                    .await?;
+                }
+                DbConnection::Sqlite(conn) => {
+                    sqlx::query(
+                        "UPDATE device_cam_pics SET backend = $2, jpeg_data = $3 WHERE id = $1",
+                    )
+                    .bind(id)
+                    .bind(active)
+                    .bind(jpeg_data)
+                    .execute(&mut **conn)
+                    .await?;
+                }
+            }
+            delete_data(
+                stores,
+                &[DeletedPicture {
+                    id,
+                    backend: source,
+                }],
+            )
+            .await;
+            moved += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::store::{Backend, PictureStores};
+    use super::{get_for_device, migrate_to_active, DbCaptureTrigger, DeviceCameraPicture};
+    use crate::config::{FilesystemStoreConfig, PictureStoreConfig};
+    use crate::db::{with_conn, DbPool};
+    use crate::error::Result;
+    use crate::model::device::get_dev_id_by_name;
+    use crate::model::device::test::insert_test_device;
+    use aegisd_handler_macros::db_test;
+    use chrono::Utc;
+
+    #[db_test]
+    async fn insert_into_store(db: DbPool) -> Result<()> {
+        let conn = &mut db.acquire().await?;
+        insert_test_device(conn, "pk".into(), "test".into()).await?;
+        let dev_id = get_dev_id_by_name(conn, "test").await?;
+        let dir = tempfile::tempdir().unwrap();
+        let stores = PictureStores::new(&PictureStoreConfig {
+            backend: Backend::Filesystem,
+            filesystem: Some(FilesystemStoreConfig {
+                path: dir.path().to_owned(),
+            }),
+            s3: None,
+        })?;
+        DeviceCameraPicture {
+            id: 0,
+            dev_id,
+            created_at: Utc::now().naive_utc(),
+            jpeg_data: vec![7; 16],
+            width: None,
+            height: None,
+            trigger: DbCaptureTrigger::Unknown,
+        }
+        .insert_into(conn, &stores)
+        .await?;
+
+        // Only the store has the data once the insert returns
+        let rows: Vec<(Option<Vec<u8>>, Backend)> = with_conn!(conn, |c| {
+            sqlx::query_as("SELECT jpeg_data, backend FROM device_cam_pics")
+                .fetch_all(&mut **c)
+                .await?
+        });
+        assert_eq!(rows, vec![(None, Backend::Filesystem)]);
+        assert_eq!(std::fs::read_dir(dir.path().join("0")).unwrap().count(), 1);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn migrate_between_backends(db: DbPool) -> Result<()> {
+        let conn = &mut db.acquire().await?;
+        insert_test_device(conn, "pk".into(), "test".into()).await?;
+        let dev_id = get_dev_id_by_name(conn, "test").await?;
+        for i in 0..3u8 {
+            DeviceCameraPicture {
+                id: 0,
+                dev_id,
+                created_at: Utc::now().naive_utc(),
+                jpeg_data: vec![i; 16],
+                width: None,
+                height: None,
+                trigger: DbCaptureTrigger::Unknown,
+            }
+            .insert(conn)
+            .await?;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = PictureStoreConfig {
+            backend: Backend::Filesystem,
+            filesystem: Some(FilesystemStoreConfig {
+                path: dir.path().to_owned(),
+            }),
+            s3: None,
+        };
+        let filesystem = PictureStores::new(&config)?;
+        assert_eq!(migrate_to_active(conn, &filesystem).await?, 3);
+        assert_eq!(migrate_to_active(conn, &filesystem).await?, 0);
+        let rows: Vec<(Option<Vec<u8>>, Backend)> = with_conn!(conn, |c| {
+            sqlx::query_as("SELECT jpeg_data, backend FROM device_cam_pics")
+                .fetch_all(&mut **c)
+                .await?
+        });
+        assert!(rows
+            .iter()
+            .all(|(data, backend)| data.is_none() && *backend == Backend::Filesystem));
+        assert_eq!(std::fs::read_dir(dir.path().join("0")).unwrap().count(), 3);
+
+        config.backend = Backend::Postgres;
+        let postgres = PictureStores::new(&config)?;
+        assert_eq!(migrate_to_active(conn, &postgres).await?, 3);
+        assert_eq!(std::fs::read_dir(dir.path().join("0")).unwrap().count(), 0);
+        let mut data: Vec<_> = get_for_device(conn, dev_id)
+            .await?
+            .into_iter()
+            .map(|p| p.jpeg_data)
+            .collect();
+        data.sort();
+        assert_eq!(data, vec![vec![0; 16], vec![1; 16], vec![2; 16]]);
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use crate::config::FilesystemStoreConfig;
+use crate::model::pics::store::{Blob, PictureStore};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// One file per picture, grouped in directories of a thousand pictures.
+/// Fetched files get a directory each, with a file per chunk.
+pub struct FilesystemStore {
+    root: PathBuf,
+}
+
+impl FilesystemStore {
+    pub fn new(config: &FilesystemStoreConfig) -> Self {
+        Self {
+            root: config.path.clone(),
+        }
+    }
+
+    fn path(&self, blob: Blob) -> PathBuf {
+        match blob {
+            Blob::Picture(id) => self
+                .root
+                .join((id / 1000).to_string())
+                .join(format!("{id}.jpg")),
+            Blob::FetchedChunk { file, seq } => self
+                .root
+                .join("fetches")
+                .join((file / 1000).to_string())
+                .join(file.to_string())
+                .join(format!("{seq}.bin")),
+        }
+    }
+}
+
+#[async_trait]
+impl PictureStore for FilesystemStore {
+    async fn put(&self, blob: Blob, data: &[u8]) -> Result<()> {
+        let path = self.path(blob);
+        let dir = path.parent().expect("Blob paths have a parent");
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        // Readers never see a partial picture
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        tokio::fs::write(&tmp_path, data)
+            .await
+            .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        Ok(())
+    }
+
+    async fn get(&self, blob: Blob) -> Result<Vec<u8>> {
+        let path = self.path(blob);
+        tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))
+    }
+
+    async fn delete(&self, blob: Blob) -> Result<()> {
+        match tokio::fs::remove_file(self.path(blob)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FilesystemStore;
+    use crate::config::FilesystemStoreConfig;
+    use crate::error::Result;
+    use crate::model::pics::store::{Blob, PictureStore};
+
+    #[tokio::test]
+    async fn roundtrip() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FilesystemStore::new(&FilesystemStoreConfig {
+            path: dir.path().to_owned(),
+        });
+
+        let picture = Blob::Picture(1234);
+        store.put(picture, b"picture").await?;
+        assert!(dir.path().join("1/1234.jpg").is_file());
+        assert_eq!(store.get(picture).await?, b"picture");
+        store.put(picture, b"replaced").await?;
+        assert_eq!(store.get(picture).await?, b"replaced");
+
+        store.delete(picture).await?;
+        assert!(store.get(picture).await.is_err());
+        store.delete(picture).await?;
+
+        let chunk = Blob::FetchedChunk { file: 1234, seq: 2 };
+        store.put(chunk, b"chunk").await?;
+        assert!(dir.path().join("fetches/1/1234/2.bin").is_file());
+        assert_eq!(store.get(chunk).await?, b"chunk");
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+//! Where the data of camera pictures lives. The `device_cam_pics` row always holds the
+//! metadata and a SHA-256 of the data, and records which backend has the data, so pictures
+//! stay readable after the configured backend changes and until they're migrated.
+//! Chunks of files fetched from devices are stored the same way.
+
+use crate::config::PictureStoreConfig;
+use crate::model::pics::filesystem::FilesystemStore;
+use crate::model::pics::s3::S3Store;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, RwLock};
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash, sqlx::Type, Deserialize)]
+#[sqlx(type_name = "picture_backend", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// In the `jpeg_data` column, next to the metadata, whichever the database is
+    #[default]
+    Postgres,
+    Filesystem,
+    /// Any S3-compatible object storage
+    S3,
+}
+
+/// What the stores keep, each kind under its own names
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Blob {
+    Picture(i32),
+    /// By `device_fetched_file` ID and chunk number
+    FetchedChunk {
+        file: i32,
+        seq: i32,
+    },
+}
+
+impl Display for Blob {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Blob::Picture(id) => write!(f, "picture {id}"),
+            Blob::FetchedChunk { file, seq } => write!(f, "chunk {seq} of fetched file {file}"),
+        }
+    }
+}
+
+/// Stores picture data outside the database
+#[async_trait]
+pub trait PictureStore: Send + Sync {
+    async fn put(&self, blob: Blob, data: &[u8]) -> Result<()>;
+    async fn get(&self, blob: Blob) -> Result<Vec<u8>>;
+    /// Deleting a blob that isn't stored is not an error
+    async fn delete(&self, blob: Blob) -> Result<()>;
+}
+
+/// The configured backends, new pictures go to the active one
+pub struct PictureStores {
+    active: Backend,
+    filesystem: Option<FilesystemStore>,
+    s3: Option<S3Store>,
+}
+
+impl Default for PictureStores {
+    fn default() -> Self {
+        Self {
+            active: Backend::Postgres,
+            filesystem: None,
+            s3: None,
+        }
+    }
+}
+
+impl PictureStores {
+    pub fn new(config: &PictureStoreConfig) -> Result<Self> {
+        Ok(Self {
+            active: config.backend,
+            filesystem: config.filesystem.as_ref().map(FilesystemStore::new),
+            s3: config.s3.as_ref().map(S3Store::new).transpose()?,
+        })
+    }
+
+    pub fn active(&self) -> Backend {
+        self.active
+    }
+
+    /// The store of `backend`, None when the data is kept in the database itself
+    pub fn get(&self, backend: Backend) -> Result<Option<&dyn PictureStore>> {
+        Ok(match backend {
+            Backend::Postgres => None,
+            Backend::Filesystem => match &self.filesystem {
+                Some(store) => Some(store),
+                None => bail!("Pictures are stored in the filesystem, but it isn't configured"),
+            },
+            Backend::S3 => match &self.s3 {
+                Some(store) => Some(store),
+                None => bail!("Pictures are stored in S3, but it isn't configured"),
+            },
+        })
+    }
+}
+
+lazy_static! {
+    static ref STORES: RwLock<Arc<PictureStores>> = Default::default();
+}
+
+/// Replaces the picture stores, called once at startup
+pub fn set_stores(stores: PictureStores) {
+    *STORES.write().unwrap() = Arc::new(stores);
+}
+
+pub fn stores() -> Arc<PictureStores> {
+    STORES.read().unwrap().clone()
+}
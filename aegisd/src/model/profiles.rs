@@ -0,0 +1,143 @@
+//! Protection profiles that admins apply to devices, see [`ProtectionProfile`]
+
+use crate::db::DbConnection;
+use aegislib::command::admin::ProtectionProfile;
+use anyhow::{bail, Result};
+
+#[derive(sqlx::FromRow)]
+pub struct Profile {
+    pub name: String,
+    pub vt_locked: bool,
+    pub ssh_locked: bool,
+    pub draw_decoy: bool,
+    pub capture_interval_secs: Option<i32>,
+    pub telemetry_interval_secs: Option<i32>,
+}
+
+impl From<Profile> for ProtectionProfile {
+    fn from(p: Profile) -> Self {
+        Self {
+            name: p.name,
+            vt_locked: p.vt_locked,
+            ssh_locked: p.ssh_locked,
+            draw_decoy: p.draw_decoy,
+            capture_interval_secs: p.capture_interval_secs.map(|secs| secs as u32),
+            telemetry_interval_secs: p.telemetry_interval_secs.map(|secs| secs as u32),
+        }
+    }
+}
+
+pub async fn list(conn: &mut DbConnection) -> Result<Vec<ProtectionProfile>> {
+    let profiles: Vec<Profile> = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                Profile,
+                "SELECT name, vt_locked, ssh_locked, draw_decoy, capture_interval_secs,
+                        telemetry_interval_secs
+                 FROM protection_profile ORDER BY name"
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as("SELECT * FROM protection_profile ORDER BY name")
+                .fetch_all(&mut **conn)
+                .await?
+        }
+    };
+    Ok(profiles.into_iter().map(Into::into).collect())
+}
+
+pub async fn get(conn: &mut DbConnection, name: &str) -> Result<ProtectionProfile> {
+    let profile: Option<Profile> = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                Profile,
+                "SELECT name, vt_locked, ssh_locked, draw_decoy, capture_interval_secs,
+                        telemetry_interval_secs
+                 FROM protection_profile WHERE name = $1",
+                name
+            )
+            .fetch_optional(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as("SELECT * FROM protection_profile WHERE name = $1")
+                .bind(name)
+                .fetch_optional(&mut **conn)
+                .await?
+        }
+    };
+    match profile {
+        Some(profile) => Ok(profile.into()),
+        None => bail!("No protection profile named '{name}'"),
+    }
+}
+
+/// Creates the profile, or replaces the one with the same name
+pub async fn upsert(conn: &mut DbConnection, profile: &ProtectionProfile) -> Result<()> {
+    let capture_interval_secs = profile.capture_interval_secs.map(|secs| secs as i32);
+    let telemetry_interval_secs = profile.telemetry_interval_secs.map(|secs| secs as i32);
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "INSERT INTO protection_profile
+                 (name, vt_locked, ssh_locked, draw_decoy, capture_interval_secs,
+                  telemetry_interval_secs)
+                 VALUES ($1, $2, $3, $4, $5, $6)
+                 ON CONFLICT (name) DO UPDATE SET
+                 vt_locked = $2, ssh_locked = $3, draw_decoy = $4, capture_interval_secs = $5,
+                 telemetry_interval_secs = $6",
+                profile.name,
+                profile.vt_locked,
+                profile.ssh_locked,
+                profile.draw_decoy,
+                capture_interval_secs,
+                telemetry_interval_secs
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query(
+                "INSERT INTO protection_profile
+                 (name, vt_locked, ssh_locked, draw_decoy, capture_interval_secs,
+                  telemetry_interval_secs)
+                 VALUES ($1, $2, $3, $4, $5, $6)
+                 ON CONFLICT (name) DO UPDATE SET
+                 vt_locked = $2, ssh_locked = $3, draw_decoy = $4, capture_interval_secs = $5,
+                 telemetry_interval_secs = $6",
+            )
+            .bind(&profile.name)
+            .bind(profile.vt_locked)
+            .bind(profile.ssh_locked)
+            .bind(profile.draw_decoy)
+            .bind(capture_interval_secs)
+            .bind(telemetry_interval_secs)
+            .execute(&mut **conn)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Devices keep running a deleted profile until another one is applied
+pub async fn delete(conn: &mut DbConnection, name: &str) -> Result<()> {
+    let rows_affected = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!("DELETE FROM protection_profile WHERE name = $1", name)
+                .execute(&mut **conn)
+                .await?
+                .rows_affected()
+        }
+        DbConnection::Sqlite(conn) => sqlx::query("DELETE FROM protection_profile WHERE name = $1")
+            .bind(name)
+            .execute(&mut **conn)
+            .await?
+            .rows_affected(),
+    };
+    if rows_affected != 1 {
+        bail!("No protection profile named '{name}'");
+    }
+    Ok(())
+}
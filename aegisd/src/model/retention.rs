@@ -0,0 +1,201 @@
+use crate::db::DbConnection;
+use crate::model::events::DbEventLogLevel;
+use aegislib::command::admin::RetentionPolicy;
+use anyhow::Result;
+
+#[derive(sqlx::FromRow)]
+struct DbRetentionPolicy {
+    event_max_age_days: Option<i32>,
+    event_max_count: Option<i32>,
+    event_min_level: Option<DbEventLogLevel>,
+    picture_max_age_days: Option<i32>,
+    picture_max_count: Option<i32>,
+    picture_max_bytes: Option<i64>,
+}
+
+impl From<DbRetentionPolicy> for RetentionPolicy {
+    fn from(p: DbRetentionPolicy) -> Self {
+        Self {
+            event_max_age_days: p.event_max_age_days.map(|v| v as u32),
+            event_max_count: p.event_max_count.map(|v| v as u32),
+            event_min_level: p.event_min_level.map(Into::into),
+            picture_max_age_days: p.picture_max_age_days.map(|v| v as u32),
+            picture_max_count: p.picture_max_count.map(|v| v as u32),
+            picture_max_bytes: p.picture_max_bytes.map(|v| v as u64),
+        }
+    }
+}
+
+/// A device and the policy it overrides the server's with, if any
+pub struct DeviceRetention {
+    pub dev_id: i32,
+    pub stolen: bool,
+    pub policy: Option<RetentionPolicy>,
+}
+
+pub async fn get_override(conn: &mut DbConnection, dev_id: i32) -> Result<Option<RetentionPolicy>> {
+    let record = match conn {
+        DbConnection::Postgres(conn) => sqlx::query_as!(
+            DbRetentionPolicy,
+            r#"SELECT event_max_age_days, event_max_count, event_min_level as "event_min_level: _",
+                          picture_max_age_days, picture_max_count, picture_max_bytes
+                   FROM device_retention WHERE dev_id = $1"#,
+            dev_id
+        )
+        .fetch_optional(&mut **conn)
+        .await?,
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT event_max_age_days, event_max_count, event_min_level,
+                        picture_max_age_days, picture_max_count, picture_max_bytes
+                 FROM device_retention WHERE dev_id = $1",
+            )
+            .bind(dev_id)
+            .fetch_optional(&mut **conn)
+            .await?
+        }
+    };
+    Ok(record.map(Into::into))
+}
+
+/// Replaces the device's override, or removes it when `policy` is None
+pub async fn set_override(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    policy: Option<RetentionPolicy>,
+) -> Result<()> {
+    let policy = match policy {
+        Some(policy) => policy,
+        None => {
+            match conn {
+                DbConnection::Postgres(conn) => {
+                    sqlx::query!("DELETE FROM device_retention WHERE dev_id = $1", dev_id)
+                        .execute(&mut **conn)
+                        .await?;
+                }
+                DbConnection::Sqlite(conn) => {
+                    sqlx::query("DELETE FROM device_retention WHERE dev_id = $1")
+                        .bind(dev_id)
+                        .execute(&mut **conn)
+                        .await?;
+                }
+            }
+            return Ok(());
+        }
+    };
+    let event_min_level = policy.event_min_level.map(DbEventLogLevel::from);
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                r#"INSERT INTO device_retention
+                   (dev_id, event_max_age_days, event_max_count, event_min_level,
+                    picture_max_age_days, picture_max_count, picture_max_bytes)
+                   VALUES ($1, $2, $3, $4, $5, $6, $7)
+                   ON CONFLICT (dev_id) DO UPDATE SET
+                       event_max_age_days = EXCLUDED.event_max_age_days,
+                       event_max_count = EXCLUDED.event_max_count,
+                       event_min_level = EXCLUDED.event_min_level,
+                       picture_max_age_days = EXCLUDED.picture_max_age_days,
+                       picture_max_count = EXCLUDED.picture_max_count,
+                       picture_max_bytes = EXCLUDED.picture_max_bytes"#,
+                dev_id,
+                policy.event_max_age_days.map(|v| v as i32),
+                policy.event_max_count.map(|v| v as i32),
+                event_min_level as _,
+                policy.picture_max_age_days.map(|v| v as i32),
+                policy.picture_max_count.map(|v| v as i32),
+                policy.picture_max_bytes.map(|v| v as i64),
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query(
+                "INSERT INTO device_retention
+                 (dev_id, event_max_age_days, event_max_count, event_min_level,
+                  picture_max_age_days, picture_max_count, picture_max_bytes)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)
+                 ON CONFLICT (dev_id) DO UPDATE SET
+                     event_max_age_days = EXCLUDED.event_max_age_days,
+                     event_max_count = EXCLUDED.event_max_count,
+                     event_min_level = EXCLUDED.event_min_level,
+                     picture_max_age_days = EXCLUDED.picture_max_age_days,
+                     picture_max_count = EXCLUDED.picture_max_count,
+                     picture_max_bytes = EXCLUDED.picture_max_bytes",
+            )
+            .bind(dev_id)
+            .bind(policy.event_max_age_days.map(|v| v as i32))
+            .bind(policy.event_max_count.map(|v| v as i32))
+            .bind(event_min_level)
+            .bind(policy.picture_max_age_days.map(|v| v as i32))
+            .bind(policy.picture_max_count.map(|v| v as i32))
+            .bind(policy.picture_max_bytes.map(|v| v as i64))
+            .execute(&mut **conn)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(sqlx::FromRow)]
+struct DbDeviceRetention {
+    id: i32,
+    stolen: bool,
+    override_id: Option<i32>,
+    #[sqlx(flatten)]
+    policy: DbRetentionPolicy,
+}
+
+/// Every device, pending or not, with its retention override
+pub async fn list_devices(conn: &mut DbConnection) -> Result<Vec<DeviceRetention>> {
+    let records: Vec<DbDeviceRetention> = match conn {
+        DbConnection::Postgres(conn) => {
+            let records = sqlx::query!(
+                r#"SELECT d.id as "id!", d.stolen as "stolen!", r.dev_id as "override_id?",
+                          r.event_max_age_days as "event_max_age_days?",
+                          r.event_max_count as "event_max_count?",
+                          r.event_min_level as "event_min_level?: DbEventLogLevel",
+                          r.picture_max_age_days as "picture_max_age_days?",
+                          r.picture_max_count as "picture_max_count?",
+                          r.picture_max_bytes as "picture_max_bytes?"
+                   FROM device d LEFT JOIN device_retention r ON r.dev_id = d.id"#
+            )
+            .fetch_all(&mut **conn)
+            .await?;
+            records
+                .into_iter()
+                .map(|r| DbDeviceRetention {
+                    id: r.id,
+                    stolen: r.stolen,
+                    override_id: r.override_id,
+                    policy: DbRetentionPolicy {
+                        event_max_age_days: r.event_max_age_days,
+                        event_max_count: r.event_max_count,
+                        event_min_level: r.event_min_level,
+                        picture_max_age_days: r.picture_max_age_days,
+                        picture_max_count: r.picture_max_count,
+                        picture_max_bytes: r.picture_max_bytes,
+                    },
+                })
+                .collect()
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT d.id, d.stolen, r.dev_id as override_id,
+                        r.event_max_age_days, r.event_max_count, r.event_min_level,
+                        r.picture_max_age_days, r.picture_max_count, r.picture_max_bytes
+                 FROM device d LEFT JOIN device_retention r ON r.dev_id = d.id",
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+    };
+    Ok(records
+        .into_iter()
+        .map(|r| DeviceRetention {
+            dev_id: r.id,
+            stolen: r.stolen,
+            policy: r.override_id.map(|_| r.policy.into()),
+        })
+        .collect())
+}
@@ -0,0 +1,420 @@
+//! Automation rules and their execution log, see [`crate::rules`]
+
+use crate::db::DbConnection;
+use aegislib::command::admin::{AutomationRule, RuleExecution};
+use anyhow::{bail, Result};
+use chrono::{Duration, NaiveDateTime};
+
+#[derive(sqlx::FromRow)]
+struct DbRule {
+    name: String,
+    dev_name: Option<String>,
+    /// Bincode [`aegislib::command::admin::RuleTrigger`]
+    trigger: Vec<u8>,
+    threshold: i32,
+    window_secs: i32,
+    /// Bincode [`aegislib::command::admin::RuleAction`]
+    action: Vec<u8>,
+    enabled: bool,
+    dry_run: bool,
+}
+
+impl TryFrom<DbRule> for AutomationRule {
+    type Error = anyhow::Error;
+
+    fn try_from(r: DbRule) -> Result<Self> {
+        Ok(Self {
+            name: r.name,
+            dev_name: r.dev_name,
+            trigger: bincode::deserialize(&r.trigger)?,
+            threshold: r.threshold as u32,
+            window_secs: r.window_secs as u32,
+            action: bincode::deserialize(&r.action)?,
+            enabled: r.enabled,
+            dry_run: r.dry_run,
+        })
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct DbRuleExecution {
+    rule_name: String,
+    dev_name: String,
+    executed_at: NaiveDateTime,
+    dry_run: bool,
+    action: Vec<u8>,
+    error: Option<String>,
+}
+
+impl TryFrom<DbRuleExecution> for RuleExecution {
+    type Error = anyhow::Error;
+
+    fn try_from(e: DbRuleExecution) -> Result<Self> {
+        Ok(Self {
+            rule_name: e.rule_name,
+            dev_name: e.dev_name,
+            executed_at_timestamp: e.executed_at.and_utc().timestamp() as u64,
+            dry_run: e.dry_run,
+            action: bincode::deserialize(&e.action)?,
+            error: e.error,
+        })
+    }
+}
+
+pub async fn list(conn: &mut DbConnection) -> Result<Vec<AutomationRule>> {
+    let rules: Vec<DbRule> = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                DbRule,
+                r#"SELECT r.name, d.name as "dev_name?", r.trigger, r.threshold, r.window_secs,
+                          r.action, r.enabled, r.dry_run
+                   FROM automation_rule r LEFT JOIN device d ON d.id = r.dev_id
+                   ORDER BY r.name"#
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT r.name, d.name as dev_name, r.trigger, r.threshold, r.window_secs,
+                        r.action, r.enabled, r.dry_run
+                 FROM automation_rule r LEFT JOIN device d ON d.id = r.dev_id
+                 ORDER BY r.name",
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+    };
+    rules.into_iter().map(TryInto::try_into).collect()
+}
+
+/// The enabled rules that apply to the device
+pub async fn list_enabled_for_device(
+    conn: &mut DbConnection,
+    dev_id: i32,
+) -> Result<Vec<AutomationRule>> {
+    let rules: Vec<DbRule> = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                DbRule,
+                r#"SELECT r.name, d.name as "dev_name?", r.trigger, r.threshold, r.window_secs,
+                          r.action, r.enabled, r.dry_run
+                   FROM automation_rule r LEFT JOIN device d ON d.id = r.dev_id
+                   WHERE r.enabled AND (r.dev_id IS NULL OR r.dev_id = $1)
+                   ORDER BY r.name"#,
+                dev_id
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT r.name, d.name as dev_name, r.trigger, r.threshold, r.window_secs,
+                        r.action, r.enabled, r.dry_run
+                 FROM automation_rule r LEFT JOIN device d ON d.id = r.dev_id
+                 WHERE r.enabled AND (r.dev_id IS NULL OR r.dev_id = $1)
+                 ORDER BY r.name",
+            )
+            .bind(dev_id)
+            .fetch_all(&mut **conn)
+            .await?
+        }
+    };
+    rules.into_iter().map(TryInto::try_into).collect()
+}
+
+/// Creates the rule, or replaces the one with the same name and forgets its past matches
+pub async fn upsert(
+    conn: &mut DbConnection,
+    rule: &AutomationRule,
+    dev_id: Option<i32>,
+) -> Result<()> {
+    let trigger = bincode::serialize(&rule.trigger)?;
+    let action = bincode::serialize(&rule.action)?;
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "INSERT INTO automation_rule
+                 (name, dev_id, trigger, threshold, window_secs, action, enabled, dry_run)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                 ON CONFLICT (name) DO UPDATE SET
+                     dev_id = EXCLUDED.dev_id,
+                     trigger = EXCLUDED.trigger,
+                     threshold = EXCLUDED.threshold,
+                     window_secs = EXCLUDED.window_secs,
+                     action = EXCLUDED.action,
+                     enabled = EXCLUDED.enabled,
+                     dry_run = EXCLUDED.dry_run",
+                rule.name,
+                dev_id,
+                trigger,
+                rule.threshold as i32,
+                rule.window_secs as i32,
+                action,
+                rule.enabled,
+                rule.dry_run
+            )
+            .execute(&mut **conn)
+            .await?;
+            sqlx::query!(
+                "DELETE FROM automation_rule_hit WHERE rule_name = $1",
+                rule.name
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query(
+                "INSERT INTO automation_rule
+                 (name, dev_id, trigger, threshold, window_secs, action, enabled, dry_run)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                 ON CONFLICT (name) DO UPDATE SET
+                     dev_id = EXCLUDED.dev_id,
+                     trigger = EXCLUDED.trigger,
+                     threshold = EXCLUDED.threshold,
+                     window_secs = EXCLUDED.window_secs,
+                     action = EXCLUDED.action,
+                     enabled = EXCLUDED.enabled,
+                     dry_run = EXCLUDED.dry_run",
+            )
+            .bind(&rule.name)
+            .bind(dev_id)
+            .bind(trigger)
+            .bind(rule.threshold as i32)
+            .bind(rule.window_secs as i32)
+            .bind(action)
+            .bind(rule.enabled)
+            .bind(rule.dry_run)
+            .execute(&mut **conn)
+            .await?;
+            sqlx::query("DELETE FROM automation_rule_hit WHERE rule_name = $1")
+                .bind(&rule.name)
+                .execute(&mut **conn)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// The log of its executions is kept
+pub async fn delete(conn: &mut DbConnection, name: &str) -> Result<()> {
+    let rows_affected = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!("DELETE FROM automation_rule WHERE name = $1", name)
+                .execute(&mut **conn)
+                .await?
+                .rows_affected()
+        }
+        DbConnection::Sqlite(conn) => sqlx::query("DELETE FROM automation_rule WHERE name = $1")
+            .bind(name)
+            .execute(&mut **conn)
+            .await?
+            .rows_affected(),
+    };
+    if rows_affected != 1 {
+        bail!("No automation rule named '{name}'");
+    }
+    Ok(())
+}
+
+/// Records that the rule's trigger matched on the device.
+/// Returns whether the rule should fire, in which case its matches start over.
+pub async fn record_hit(
+    conn: &mut DbConnection,
+    rule: &AutomationRule,
+    dev_id: i32,
+    now: NaiveDateTime,
+) -> Result<bool> {
+    if rule.threshold <= 1 {
+        return Ok(true);
+    }
+    let expired = now - Duration::seconds(rule.window_secs as i64);
+    let hits = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "DELETE FROM automation_rule_hit
+                 WHERE rule_name = $1 AND dev_id = $2 AND hit_at <= $3",
+                rule.name,
+                dev_id,
+                expired
+            )
+            .execute(&mut **conn)
+            .await?;
+            sqlx::query!(
+                "INSERT INTO automation_rule_hit (rule_name, dev_id, hit_at) VALUES ($1, $2, $3)",
+                rule.name,
+                dev_id,
+                now
+            )
+            .execute(&mut **conn)
+            .await?;
+            sqlx::query_scalar!(
+                r#"SELECT COUNT(*) as "count!" FROM automation_rule_hit
+                   WHERE rule_name = $1 AND dev_id = $2"#,
+                rule.name,
+                dev_id
+            )
+            .fetch_one(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query(
+                "DELETE FROM automation_rule_hit
+                 WHERE rule_name = $1 AND dev_id = $2 AND hit_at <= $3",
+            )
+            .bind(&rule.name)
+            .bind(dev_id)
+            .bind(expired)
+            .execute(&mut **conn)
+            .await?;
+            sqlx::query(
+                "INSERT INTO automation_rule_hit (rule_name, dev_id, hit_at) VALUES ($1, $2, $3)",
+            )
+            .bind(&rule.name)
+            .bind(dev_id)
+            .bind(now)
+            .execute(&mut **conn)
+            .await?;
+            sqlx::query_scalar(
+                "SELECT COUNT(*) FROM automation_rule_hit WHERE rule_name = $1 AND dev_id = $2",
+            )
+            .bind(&rule.name)
+            .bind(dev_id)
+            .fetch_one(&mut **conn)
+            .await?
+        }
+    };
+    if hits < rule.threshold as i64 {
+        return Ok(false);
+    }
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "DELETE FROM automation_rule_hit WHERE rule_name = $1 AND dev_id = $2",
+                rule.name,
+                dev_id
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query("DELETE FROM automation_rule_hit WHERE rule_name = $1 AND dev_id = $2")
+                .bind(&rule.name)
+                .bind(dev_id)
+                .execute(&mut **conn)
+                .await?;
+        }
+    }
+    Ok(true)
+}
+
+pub async fn log_execution(
+    conn: &mut DbConnection,
+    rule: &AutomationRule,
+    dev_id: i32,
+    executed_at: NaiveDateTime,
+    error: Option<&str>,
+) -> Result<()> {
+    let action = bincode::serialize(&rule.action)?;
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "INSERT INTO automation_rule_execution
+                 (rule_name, dev_id, executed_at, dry_run, action, error)
+                 VALUES ($1, $2, $3, $4, $5, $6)",
+                rule.name,
+                dev_id,
+                executed_at,
+                rule.dry_run,
+                action,
+                error
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query(
+                "INSERT INTO automation_rule_execution
+                 (rule_name, dev_id, executed_at, dry_run, action, error)
+                 VALUES ($1, $2, $3, $4, $5, $6)",
+            )
+            .bind(&rule.name)
+            .bind(dev_id)
+            .bind(executed_at)
+            .bind(rule.dry_run)
+            .bind(action)
+            .bind(error)
+            .execute(&mut **conn)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the rule fired on the device after `since`
+pub async fn executed_since(
+    conn: &mut DbConnection,
+    rule_name: &str,
+    dev_id: i32,
+    since: NaiveDateTime,
+) -> Result<bool> {
+    let executed = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_scalar!(
+                r#"SELECT EXISTS (SELECT 1 FROM automation_rule_execution
+                   WHERE rule_name = $1 AND dev_id = $2 AND executed_at > $3) as "exists!""#,
+                rule_name,
+                dev_id,
+                since
+            )
+            .fetch_one(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_scalar(
+                "SELECT EXISTS (SELECT 1 FROM automation_rule_execution
+                 WHERE rule_name = $1 AND dev_id = $2 AND executed_at > $3)",
+            )
+            .bind(rule_name)
+            .bind(dev_id)
+            .bind(since)
+            .fetch_one(&mut **conn)
+            .await?
+        }
+    };
+    Ok(executed)
+}
+
+/// Most recent first
+pub async fn list_executions(
+    conn: &mut DbConnection,
+    rule_name: Option<&str>,
+    limit: i64,
+) -> Result<Vec<RuleExecution>> {
+    let executions: Vec<DbRuleExecution> = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                DbRuleExecution,
+                "SELECT e.rule_name, d.name as dev_name, e.executed_at, e.dry_run, e.action, e.error
+                 FROM automation_rule_execution e JOIN device d ON d.id = e.dev_id
+                 WHERE $1::text IS NULL OR e.rule_name = $1
+                 ORDER BY e.executed_at DESC, e.id DESC LIMIT $2",
+                rule_name,
+                limit
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => sqlx::query_as(
+            "SELECT e.rule_name, d.name as dev_name, e.executed_at, e.dry_run, e.action, e.error
+                 FROM automation_rule_execution e JOIN device d ON d.id = e.dev_id
+                 WHERE $1 IS NULL OR e.rule_name = $1
+                 ORDER BY e.executed_at DESC, e.id DESC LIMIT $2",
+        )
+        .bind(rule_name)
+        .bind(limit)
+        .fetch_all(&mut **conn)
+        .await?,
+    };
+    executions.into_iter().map(TryInto::try_into).collect()
+}
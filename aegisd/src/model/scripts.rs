@@ -0,0 +1,207 @@
+//! Scripts sent to devices, and the results they reported
+
+use crate::db::DbConnection;
+use aegislib::command::admin::{ScriptOutput, ScriptRun};
+use aegislib::command::device::ScriptResult;
+use aegislib::command::server::Script;
+use anyhow::{bail, Result};
+use chrono::NaiveDateTime;
+
+#[derive(sqlx::FromRow)]
+struct DbScriptRun {
+    run_id: i64,
+    source: String,
+    requested_at: NaiveDateTime,
+    finished_at: Option<NaiveDateTime>,
+    exit_code: Option<i32>,
+    timed_out: bool,
+}
+
+impl From<DbScriptRun> for ScriptRun {
+    fn from(r: DbScriptRun) -> Self {
+        Self {
+            run_id: r.run_id as u64,
+            source: r.source,
+            requested_at_timestamp: r.requested_at.and_utc().timestamp() as u64,
+            finished_at_timestamp: r.finished_at.map(|t| t.and_utc().timestamp() as u64),
+            exit_code: r.exit_code,
+            timed_out: r.timed_out,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct DbScriptOutput {
+    run_id: i64,
+    source: String,
+    requested_at: NaiveDateTime,
+    finished_at: Option<NaiveDateTime>,
+    exit_code: Option<i32>,
+    timed_out: bool,
+    stdout: Option<Vec<u8>>,
+    stderr: Option<Vec<u8>>,
+    truncated: bool,
+}
+
+impl From<DbScriptOutput> for ScriptOutput {
+    fn from(o: DbScriptOutput) -> Self {
+        Self {
+            run: ScriptRun::from(DbScriptRun {
+                run_id: o.run_id,
+                source: o.source,
+                requested_at: o.requested_at,
+                finished_at: o.finished_at,
+                exit_code: o.exit_code,
+                timed_out: o.timed_out,
+            }),
+            stdout: o.stdout.unwrap_or_default(),
+            stderr: o.stderr.unwrap_or_default(),
+            truncated: o.truncated,
+        }
+    }
+}
+
+pub async fn insert(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    script: &Script,
+    requested_at: NaiveDateTime,
+) -> Result<()> {
+    let run_id = script.run_id as i64;
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "INSERT INTO device_script_run (dev_id, run_id, source, requested_at)
+                 VALUES ($1, $2, $3, $4)",
+                dev_id,
+                run_id,
+                script.source,
+                requested_at
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query(
+                "INSERT INTO device_script_run (dev_id, run_id, source, requested_at)
+                 VALUES ($1, $2, $3, $4)",
+            )
+            .bind(dev_id)
+            .bind(run_id)
+            .bind(&script.source)
+            .bind(requested_at)
+            .execute(&mut **conn)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Fails unless the script was sent to this device and has no result yet
+pub async fn record_result(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    result: &ScriptResult,
+    finished_at: NaiveDateTime,
+) -> Result<()> {
+    let run_id = result.run_id as i64;
+    let updated = match conn {
+        DbConnection::Postgres(conn) => sqlx::query!(
+            "UPDATE device_script_run
+             SET finished_at = $3, exit_code = $4, timed_out = $5, stdout = $6, stderr = $7,
+                 truncated = $8
+             WHERE dev_id = $1 AND run_id = $2 AND finished_at IS NULL",
+            dev_id,
+            run_id,
+            finished_at,
+            result.exit_code,
+            result.timed_out,
+            result.stdout,
+            result.stderr,
+            result.truncated
+        )
+        .execute(&mut **conn)
+        .await?
+        .rows_affected(),
+        DbConnection::Sqlite(conn) => sqlx::query(
+            "UPDATE device_script_run
+             SET finished_at = $3, exit_code = $4, timed_out = $5, stdout = $6, stderr = $7,
+                 truncated = $8
+             WHERE dev_id = $1 AND run_id = $2 AND finished_at IS NULL",
+        )
+        .bind(dev_id)
+        .bind(run_id)
+        .bind(finished_at)
+        .bind(result.exit_code)
+        .bind(result.timed_out)
+        .bind(&result.stdout)
+        .bind(&result.stderr)
+        .bind(result.truncated)
+        .execute(&mut **conn)
+        .await?
+        .rows_affected(),
+    };
+    if updated == 0 {
+        bail!("No pending script run {}", result.run_id);
+    }
+    Ok(())
+}
+
+/// Oldest first, without their output
+pub async fn list_for_device(conn: &mut DbConnection, dev_id: i32) -> Result<Vec<ScriptRun>> {
+    let runs: Vec<DbScriptRun> = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                DbScriptRun,
+                "SELECT run_id, source, requested_at, finished_at, exit_code, timed_out
+                 FROM device_script_run WHERE dev_id = $1 ORDER BY requested_at, id",
+                dev_id
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT run_id, source, requested_at, finished_at, exit_code, timed_out
+                 FROM device_script_run WHERE dev_id = $1 ORDER BY requested_at, id",
+            )
+            .bind(dev_id)
+            .fetch_all(&mut **conn)
+            .await?
+        }
+    };
+    Ok(runs.into_iter().map(Into::into).collect())
+}
+
+pub async fn get_output(conn: &mut DbConnection, dev_id: i32, run_id: u64) -> Result<ScriptOutput> {
+    let db_run_id = run_id as i64;
+    let output: Option<DbScriptOutput> = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                DbScriptOutput,
+                "SELECT run_id, source, requested_at, finished_at, exit_code, timed_out,
+                        stdout, stderr, truncated
+                 FROM device_script_run WHERE dev_id = $1 AND run_id = $2",
+                dev_id,
+                db_run_id
+            )
+            .fetch_optional(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT run_id, source, requested_at, finished_at, exit_code, timed_out,
+                        stdout, stderr, truncated
+                 FROM device_script_run WHERE dev_id = $1 AND run_id = $2",
+            )
+            .bind(dev_id)
+            .bind(db_run_id)
+            .fetch_optional(&mut **conn)
+            .await?
+        }
+    };
+    match output {
+        Some(output) => Ok(output.into()),
+        None => bail!("No script run {run_id}"),
+    }
+}
@@ -0,0 +1,90 @@
+//! Every status a device went through, `device_status` only keeps the current one
+
+use crate::db::DbConnection;
+use crate::model::device::Status;
+use aegislib::command::admin::StatusHistoryEntry;
+use anyhow::Result;
+use chrono::NaiveDateTime;
+
+#[derive(sqlx::FromRow)]
+pub struct StatusChange {
+    pub changed_at: NaiveDateTime,
+    pub changed_by: String,
+    pub vt_locked: bool,
+    pub ssh_locked: bool,
+    pub draw_decoy: bool,
+}
+
+impl From<StatusChange> for StatusHistoryEntry {
+    fn from(s: StatusChange) -> Self {
+        Self {
+            changed_at_timestamp: s.changed_at.and_utc().timestamp() as u64,
+            changed_by: s.changed_by,
+            vt_locked: s.vt_locked,
+            ssh_locked: s.ssh_locked,
+            draw_decoy: s.draw_decoy,
+        }
+    }
+}
+
+/// `changed_by` is "admin", or the scheduled change that was applied
+pub async fn record(conn: &mut DbConnection, status: &Status, changed_by: &str) -> Result<()> {
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "INSERT INTO device_status_history
+                 (dev_id, changed_at, changed_by, vt_locked, ssh_locked, draw_decoy)
+                 VALUES ($1, $2, $3, $4, $5, $6)",
+                status.dev_id,
+                status.updated_at,
+                changed_by,
+                status.vt_locked,
+                status.ssh_locked,
+                status.draw_decoy
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query(
+                "INSERT INTO device_status_history
+                 (dev_id, changed_at, changed_by, vt_locked, ssh_locked, draw_decoy)
+                 VALUES ($1, $2, $3, $4, $5, $6)",
+            )
+            .bind(status.dev_id)
+            .bind(status.updated_at)
+            .bind(changed_by)
+            .bind(status.vt_locked)
+            .bind(status.ssh_locked)
+            .bind(status.draw_decoy)
+            .execute(&mut **conn)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Oldest first
+pub async fn list_for_device(conn: &mut DbConnection, dev_id: i32) -> Result<Vec<StatusChange>> {
+    let history =
+        match conn {
+            DbConnection::Postgres(conn) => {
+                sqlx::query_as!(
+                StatusChange,
+                "SELECT changed_at, changed_by, vt_locked, ssh_locked, draw_decoy FROM device_status_history
+                 WHERE dev_id = $1 ORDER BY changed_at, id",
+                dev_id
+            )
+                .fetch_all(&mut **conn)
+                .await?
+            }
+            DbConnection::Sqlite(conn) => sqlx::query_as(
+                "SELECT changed_at, changed_by, vt_locked, ssh_locked, draw_decoy FROM device_status_history
+                 WHERE dev_id = $1 ORDER BY changed_at, id",
+            )
+            .bind(dev_id)
+            .fetch_all(&mut **conn)
+            .await?,
+        };
+    Ok(history)
+}
@@ -0,0 +1,220 @@
+//! Status changes waiting for the [`crate::scheduler::StatusScheduler`]
+
+use crate::db::DbConnection;
+use aegislib::command::admin::ScheduledStatusChange;
+use aegislib::command::signed::{SignedCommand, StatusChange};
+use anyhow::{bail, Result};
+use chrono::NaiveDateTime;
+
+#[derive(sqlx::FromRow)]
+pub struct ScheduledStatus {
+    pub id: i32,
+    pub dev_id: i32,
+    pub apply_at: NaiveDateTime,
+    pub repeat_daily: bool,
+    pub is_expiry: bool,
+    pub vt_locked: Option<bool>,
+    pub ssh_locked: Option<bool>,
+    pub draw_decoy: Option<bool>,
+    /// Bincode [`SignedCommand`]
+    pub command: Option<Vec<u8>>,
+}
+
+impl ScheduledStatus {
+    pub fn new(
+        dev_id: i32,
+        apply_at: NaiveDateTime,
+        change: StatusChange,
+        command: Option<&SignedCommand>,
+    ) -> Self {
+        Self {
+            id: 0,
+            dev_id,
+            apply_at,
+            repeat_daily: false,
+            is_expiry: false,
+            vt_locked: change.vt_locked,
+            ssh_locked: change.ssh_locked,
+            draw_decoy: change.draw_decoy,
+            command: command.map(|c| bincode::serialize(c).unwrap()),
+        }
+    }
+
+    pub fn change(&self) -> StatusChange {
+        StatusChange {
+            vt_locked: self.vt_locked,
+            ssh_locked: self.ssh_locked,
+            draw_decoy: self.draw_decoy,
+        }
+    }
+
+    pub fn signed_command(&self) -> Result<Option<SignedCommand>> {
+        Ok(self
+            .command
+            .as_deref()
+            .map(bincode::deserialize)
+            .transpose()?)
+    }
+
+    /// Written in the status history, and in events
+    pub fn changed_by(&self) -> String {
+        match self.is_expiry {
+            true => format!("expiry #{}", self.id),
+            false => format!("schedule #{}", self.id),
+        }
+    }
+
+    /// Returns the new ID, `self.id` is ignored
+    pub async fn insert(self, conn: &mut DbConnection) -> Result<i32> {
+        let id = match conn {
+            DbConnection::Postgres(conn) => {
+                sqlx::query_scalar!(
+                    "INSERT INTO device_status_schedule
+                     (dev_id, apply_at, repeat_daily, is_expiry, vt_locked, ssh_locked, draw_decoy, command)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id",
+                    self.dev_id,
+                    self.apply_at,
+                    self.repeat_daily,
+                    self.is_expiry,
+                    self.vt_locked,
+                    self.ssh_locked,
+                    self.draw_decoy,
+                    self.command
+                )
+                .fetch_one(&mut **conn)
+                .await?
+            }
+            DbConnection::Sqlite(conn) => {
+                sqlx::query_scalar(
+                    "INSERT INTO device_status_schedule
+                     (dev_id, apply_at, repeat_daily, is_expiry, vt_locked, ssh_locked, draw_decoy, command)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id",
+                )
+                .bind(self.dev_id)
+                .bind(self.apply_at)
+                .bind(self.repeat_daily)
+                .bind(self.is_expiry)
+                .bind(self.vt_locked)
+                .bind(self.ssh_locked)
+                .bind(self.draw_decoy)
+                .bind(self.command)
+                .fetch_one(&mut **conn)
+                .await?
+            }
+        };
+        Ok(id)
+    }
+}
+
+impl From<ScheduledStatus> for ScheduledStatusChange {
+    fn from(s: ScheduledStatus) -> Self {
+        Self {
+            id: s.id,
+            apply_at_timestamp: s.apply_at.and_utc().timestamp() as u64,
+            repeat_daily: s.repeat_daily,
+            is_expiry: s.is_expiry,
+            vt_locked: s.vt_locked,
+            ssh_locked: s.ssh_locked,
+            draw_decoy: s.draw_decoy,
+        }
+    }
+}
+
+/// Soonest first
+pub async fn list_for_device(conn: &mut DbConnection, dev_id: i32) -> Result<Vec<ScheduledStatus>> {
+    let scheduled = match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query_as!(
+                ScheduledStatus,
+                "SELECT id, dev_id, apply_at, repeat_daily, is_expiry, vt_locked, ssh_locked,
+                        draw_decoy, command
+                 FROM device_status_schedule WHERE dev_id = $1 ORDER BY apply_at, id",
+                dev_id
+            )
+            .fetch_all(&mut **conn)
+            .await?
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query_as(
+                "SELECT * FROM device_status_schedule WHERE dev_id = $1 ORDER BY apply_at, id",
+            )
+            .bind(dev_id)
+            .fetch_all(&mut **conn)
+            .await?
+        }
+    };
+    Ok(scheduled)
+}
+
+/// Changes that should have been applied by `now`, oldest first
+pub async fn list_due(conn: &mut DbConnection, now: NaiveDateTime) -> Result<Vec<ScheduledStatus>> {
+    let due =
+        match conn {
+            DbConnection::Postgres(conn) => {
+                sqlx::query_as!(
+                    ScheduledStatus,
+                    "SELECT id, dev_id, apply_at, repeat_daily, is_expiry, vt_locked, ssh_locked,
+                        draw_decoy, command
+                 FROM device_status_schedule WHERE apply_at <= $1 ORDER BY apply_at, id",
+                    now
+                )
+                .fetch_all(&mut **conn)
+                .await?
+            }
+            DbConnection::Sqlite(conn) => sqlx::query_as(
+                "SELECT * FROM device_status_schedule WHERE apply_at <= $1 ORDER BY apply_at, id",
+            )
+            .bind(now)
+            .fetch_all(&mut **conn)
+            .await?,
+        };
+    Ok(due)
+}
+
+pub async fn reschedule(conn: &mut DbConnection, id: i32, apply_at: NaiveDateTime) -> Result<()> {
+    match conn {
+        DbConnection::Postgres(conn) => {
+            sqlx::query!(
+                "UPDATE device_status_schedule SET apply_at = $2 WHERE id = $1",
+                id,
+                apply_at
+            )
+            .execute(&mut **conn)
+            .await?;
+        }
+        DbConnection::Sqlite(conn) => {
+            sqlx::query("UPDATE device_status_schedule SET apply_at = $2 WHERE id = $1")
+                .bind(id)
+                .bind(apply_at)
+                .execute(&mut **conn)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Fails if the device has no such scheduled change
+pub async fn delete(conn: &mut DbConnection, dev_id: i32, id: i32) -> Result<()> {
+    let rows_affected = match conn {
+        DbConnection::Postgres(conn) => sqlx::query!(
+            "DELETE FROM device_status_schedule WHERE id = $1 AND dev_id = $2",
+            id,
+            dev_id
+        )
+        .execute(&mut **conn)
+        .await?
+        .rows_affected(),
+        DbConnection::Sqlite(conn) => {
+            sqlx::query("DELETE FROM device_status_schedule WHERE id = $1 AND dev_id = $2")
+                .bind(id)
+                .bind(dev_id)
+                .execute(&mut **conn)
+                .await?
+                .rows_affected()
+        }
+    };
+    if rows_affected != 1 {
+        bail!("No scheduled status change #{id} for this device");
+    }
+    Ok(())
+}
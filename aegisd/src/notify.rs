@@ -0,0 +1,443 @@
+//! Outbound notifications for security events.
+//! Handlers only record a notification in the database, the [`Notifier`] running in the
+//! background fans it out to the configured sinks and retries failed deliveries.
+mod email;
+mod webhook;
+
+use crate::config::Config;
+use crate::db::{DbConnection, DbPool};
+use crate::model::device::get_name_by_id;
+use crate::model::notifications::{self, PendingDelivery, Trigger};
+use anyhow::Result;
+use chrono::Utc;
+use email::EmailSink;
+use lazy_static::lazy_static;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+use webhook::WebhookSink;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DELIVERY_BATCH_SIZE: i64 = 32;
+const MAX_ATTEMPTS: i32 = 8;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// How long repeated forged signature notifications for a device are held back and counted
+const COALESCE_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+lazy_static! {
+    static ref NEW_NOTIFICATION: Notify = Notify::new();
+}
+
+/// Records a notification for delivery. Never fails the caller, errors are only logged.
+pub async fn notify(
+    conn: &mut DbConnection,
+    trigger: Trigger,
+    device: Option<&str>,
+    message: &str,
+) {
+    let now = Utc::now().naive_utc();
+    match notifications::insert(conn, now, trigger, device, message).await {
+        Ok(()) => NEW_NOTIFICATION.notify_one(),
+        Err(e) => warn!("Failed to record {trigger} notification: {e}"),
+    }
+}
+
+/// Same as [`notify`], for a registered device
+pub async fn notify_device(conn: &mut DbConnection, trigger: Trigger, dev_id: i32, message: &str) {
+    let name = get_name_by_id(conn, dev_id).await.ok();
+    notify(conn, trigger, name.as_deref(), message).await
+}
+
+/// Delay before the next attempt, or None when we should give up
+fn retry_delay(attempts: i32) -> Option<Duration> {
+    if attempts >= MAX_ATTEMPTS {
+        return None;
+    }
+    Some(FIRST_RETRY_DELAY * 2u32.pow(attempts.max(1) as u32 - 1))
+}
+
+struct Sink {
+    name: String,
+    triggers: Option<Vec<Trigger>>,
+    kind: SinkKind,
+}
+
+enum SinkKind {
+    Webhook(WebhookSink),
+    Email(EmailSink),
+}
+
+impl Sink {
+    fn wants(&self, trigger: Trigger) -> bool {
+        match &self.triggers {
+            Some(triggers) => triggers.contains(&trigger),
+            None => true,
+        }
+    }
+
+    async fn deliver(&self, delivery: &PendingDelivery) -> Result<()> {
+        match &self.kind {
+            SinkKind::Webhook(sink) => sink.deliver(delivery).await,
+            SinkKind::Email(sink) => sink.deliver(&delivery.notification).await,
+        }
+    }
+}
+
+pub struct Notifier {
+    db: DbPool,
+    sinks: Vec<Sink>,
+}
+
+impl Notifier {
+    pub fn new(db: DbPool, config: &Config) -> Result<Self> {
+        let mut sinks = Vec::new();
+        for webhook in &config.webhook {
+            sinks.push(Sink {
+                name: webhook.name.clone(),
+                triggers: webhook.triggers.clone(),
+                kind: SinkKind::Webhook(WebhookSink::new(webhook)?),
+            });
+        }
+        for email in &config.email {
+            sinks.push(Sink {
+                name: email.name.clone(),
+                triggers: email.triggers.clone(),
+                kind: SinkKind::Email(EmailSink::new(email)?),
+            });
+        }
+        Ok(Self { db, sinks })
+    }
+
+    /// Delivers notifications in the background until the server exits
+    pub fn spawn(self) {
+        info!(sinks = self.sinks.len(), "Starting notifier");
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.run_once().await {
+                    warn!("Failed to process notifications: {e}");
+                }
+                select! {
+                    _ = NEW_NOTIFICATION.notified() => {},
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {},
+                }
+            }
+        });
+    }
+
+    /// Queues deliveries for new notifications, then attempts all the deliveries that are due
+    pub async fn run_once(&self) -> Result<()> {
+        let conn = &mut self.db.acquire().await?;
+        let window = chrono::Duration::from_std(COALESCE_WINDOW).unwrap();
+        notifications::dispatch_new(conn, Utc::now().naive_utc(), window, |trigger| {
+            self.sinks
+                .iter()
+                .filter(|s| s.wants(trigger))
+                .map(|s| s.name.clone())
+                .collect()
+        })
+        .await?;
+
+        let sink_names: Vec<String> = self.sinks.iter().map(|s| s.name.clone()).collect();
+        loop {
+            let now = Utc::now().naive_utc();
+            let due =
+                notifications::get_due_deliveries(conn, now, &sink_names, DELIVERY_BATCH_SIZE)
+                    .await?;
+            let batch_len = due.len();
+            for delivery in due {
+                self.attempt(conn, delivery).await?;
+            }
+            if batch_len < DELIVERY_BATCH_SIZE as usize {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn attempt(&self, conn: &mut DbConnection, delivery: PendingDelivery) -> Result<()> {
+        let sink = self
+            .sinks
+            .iter()
+            .find(|s| s.name == delivery.sink)
+            .expect("Due delivery for unknown sink");
+        let result = tokio::time::timeout(DELIVERY_TIMEOUT, sink.deliver(&delivery)).await;
+        let error = match result {
+            Ok(Ok(())) => {
+                return notifications::mark_delivered(conn, delivery.id, Utc::now().naive_utc())
+                    .await
+            }
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "Timed out".to_owned(),
+        };
+
+        let attempts = delivery.attempts + 1;
+        let next_attempt_at = retry_delay(attempts)
+            .map(|delay| Utc::now().naive_utc() + chrono::Duration::from_std(delay).unwrap());
+        if next_attempt_at.is_some() {
+            warn!(
+                sink = sink.name,
+                attempts, "Notification delivery failed: {error}"
+            );
+        } else {
+            warn!(
+                sink = sink.name,
+                attempts, "Giving up on notification delivery: {error}"
+            );
+        }
+        notifications::mark_failed(conn, delivery.id, &error, next_attempt_at).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::webhook::{signature, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+    use super::*;
+    use crate::config::{EmailConfig, SmtpSecurity, WebhookConfig};
+    use crate::db::with_conn;
+    use crate::error;
+    use aegisd_handler_macros::db_test;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+    use std::net::{SocketAddr, TcpListener};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::sync::mpsc::{channel, Receiver};
+
+    /// Local webhook receiver that answers every request with `status`
+    fn serve_webhook(status: StatusCode) -> (SocketAddr, Receiver<(HeaderMap, Vec<u8>)>) {
+        let (tx, rx) = channel(8);
+        let app = Router::new().route(
+            "/hook",
+            post(
+                move |headers: HeaderMap, body: axum::body::Bytes| async move {
+                    tx.send((headers, body.to_vec())).await.unwrap();
+                    status
+                },
+            ),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app.into_make_service()));
+        (addr, rx)
+    }
+
+    /// Local SMTP server that accepts a single message and returns its DATA
+    async fn serve_smtp() -> (SocketAddr, tokio::task::JoinHandle<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            write.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            let mut data = String::new();
+            let mut in_data = false;
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if in_data {
+                    if line == "." {
+                        in_data = false;
+                        write.write_all(b"250 queued\r\n").await.unwrap();
+                    } else {
+                        data.push_str(&line);
+                        data.push('\n');
+                    }
+                    continue;
+                }
+                let reply: &[u8] = match line.get(..4).map(|c| c.to_ascii_uppercase()) {
+                    Some(c) if c == "DATA" => {
+                        in_data = true;
+                        b"354 go ahead\r\n"
+                    }
+                    Some(c) if c == "QUIT" => {
+                        write.write_all(b"221 bye\r\n").await.unwrap();
+                        break;
+                    }
+                    _ => b"250 ok\r\n",
+                };
+                write.write_all(reply).await.unwrap();
+            }
+            data
+        });
+        (addr, handle)
+    }
+
+    fn webhook_config(addr: SocketAddr, triggers: Option<Vec<Trigger>>) -> Config {
+        let root_key = ed25519_dalek::SigningKey::generate(&mut rand::thread_rng());
+        let mut config = Config::test_config(root_key.verifying_key());
+        config.webhook.push(WebhookConfig {
+            name: "hook".to_owned(),
+            url: format!("http://{addr}/hook"),
+            secret: "secret".to_owned(),
+            triggers,
+        });
+        config
+    }
+
+    async fn delivery_state(db: &DbPool) -> error::Result<(i32, bool, Option<String>)> {
+        let row: (i32, Option<chrono::NaiveDateTime>, Option<String>) =
+            with_conn!(&mut db.acquire().await?, |c| {
+                sqlx::query_as(
+                    "SELECT attempts, delivered_at, last_error FROM notification_delivery",
+                )
+                .fetch_one(&mut **c)
+                .await?
+            });
+        Ok((row.0, row.1.is_some(), row.2))
+    }
+
+    #[test]
+    fn retry_delays() {
+        assert_eq!(retry_delay(1), Some(FIRST_RETRY_DELAY));
+        assert_eq!(retry_delay(3), Some(FIRST_RETRY_DELAY * 4));
+        assert_eq!(retry_delay(MAX_ATTEMPTS), None);
+    }
+
+    #[db_test]
+    async fn webhook_delivery(db: DbPool) -> error::Result<()> {
+        let (addr, mut requests) = serve_webhook(StatusCode::OK);
+        let notifier = Notifier::new(db.clone(), &webhook_config(addr, None))?;
+        let conn = &mut db.acquire().await?;
+        notify(conn, Trigger::PictureUploaded, Some("laptop"), "Smile").await;
+        notifier.run_once().await?;
+
+        let (headers, body) = requests.recv().await.unwrap();
+        let timestamp = headers[TIMESTAMP_HEADER].to_str().unwrap();
+        let expected = format!("sha256={}", signature(b"secret", timestamp, &body));
+        assert_eq!(headers[SIGNATURE_HEADER].to_str().unwrap(), expected);
+        let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(payload["trigger"], "picture_uploaded");
+        assert_eq!(payload["device"], "laptop");
+        assert_eq!(payload["message"], "Smile");
+
+        assert_eq!(delivery_state(&db).await?, (1, true, None));
+        Ok(())
+    }
+
+    #[db_test]
+    async fn webhook_failure_is_retried_later(db: DbPool) -> error::Result<()> {
+        let (addr, mut requests) = serve_webhook(StatusCode::INTERNAL_SERVER_ERROR);
+        let notifier = Notifier::new(db.clone(), &webhook_config(addr, None))?;
+        let conn = &mut db.acquire().await?;
+        notify(conn, Trigger::ForgedSignature, None, "Forged").await;
+        notifier.run_once().await?;
+        assert!(requests.recv().await.is_some());
+
+        let (attempts, delivered, error) = delivery_state(&db).await?;
+        assert_eq!((attempts, delivered), (1, false));
+        assert!(error.unwrap().contains("500"));
+
+        // The retry is not due yet
+        notifier.run_once().await?;
+        assert!(requests.try_recv().is_err());
+        assert_eq!(delivery_state(&db).await?.0, 1);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn forged_signatures_are_coalesced(db: DbPool) -> error::Result<()> {
+        let (addr, mut requests) = serve_webhook(StatusCode::OK);
+        let notifier = Notifier::new(db.clone(), &webhook_config(addr, None))?;
+        let conn = &mut db.acquire().await?;
+        for i in 0..3 {
+            let message = format!("Forged {i}");
+            notify(conn, Trigger::ForgedSignature, Some("laptop"), &message).await;
+        }
+        notifier.run_once().await?;
+        let (_, body) = requests.recv().await.unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(payload["message"], "Forged 2 (and 2 more like it)");
+
+        // Held back for the window, other devices and triggers are not
+        notify(conn, Trigger::ForgedSignature, Some("laptop"), "Forged 3").await;
+        notify(conn, Trigger::ForgedSignature, Some("laptop"), "Forged 4").await;
+        notify(conn, Trigger::ForgedSignature, Some("desktop"), "Forged").await;
+        notify(conn, Trigger::PictureUploaded, Some("laptop"), "Smile").await;
+        notifier.run_once().await?;
+        let mut messages = Vec::new();
+        for _ in 0..2 {
+            let (_, body) = requests.recv().await.unwrap();
+            let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            messages.push(payload["message"].as_str().unwrap().to_owned());
+        }
+        messages.sort();
+        assert_eq!(messages, ["Forged", "Smile"]);
+        assert!(requests.try_recv().is_err());
+
+        let window = chrono::Duration::from_std(COALESCE_WINDOW).unwrap();
+        let later = Utc::now().naive_utc() + window;
+        let dispatched =
+            notifications::dispatch_new(conn, later, window, |_| vec!["hook".into()]).await?;
+        assert_eq!(dispatched, 1);
+        let messages: Vec<String> = with_conn!(conn, |c| {
+            sqlx::query_scalar("SELECT message FROM notification ORDER BY id")
+                .fetch_all(&mut **c)
+                .await?
+        });
+        assert_eq!(
+            messages,
+            [
+                "Forged 2 (and 2 more like it)",
+                "Forged 4 (and 1 more like it)",
+                "Forged",
+                "Smile"
+            ]
+        );
+        Ok(())
+    }
+
+    #[db_test]
+    async fn sink_trigger_filter(db: DbPool) -> error::Result<()> {
+        let (addr, mut requests) = serve_webhook(StatusCode::OK);
+        let config = webhook_config(addr, Some(vec![Trigger::DeviceConfirmed]));
+        let notifier = Notifier::new(db.clone(), &config)?;
+        let conn = &mut db.acquire().await?;
+        notify(conn, Trigger::PictureUploaded, Some("laptop"), "Smile").await;
+        notifier.run_once().await?;
+
+        assert!(requests.try_recv().is_err());
+        let deliveries: i64 = with_conn!(conn, |c| {
+            sqlx::query_scalar("SELECT COUNT(*) FROM notification_delivery")
+                .fetch_one(&mut **c)
+                .await?
+        });
+        assert_eq!(deliveries, 0);
+        Ok(())
+    }
+
+    #[db_test]
+    async fn email_delivery(db: DbPool) -> error::Result<()> {
+        let (addr, message) = serve_smtp().await;
+        let root_key = ed25519_dalek::SigningKey::generate(&mut rand::thread_rng());
+        let mut config = Config::test_config(root_key.verifying_key());
+        config.email.push(EmailConfig {
+            name: "mail".to_owned(),
+            smtp_host: addr.ip().to_string(),
+            smtp_port: Some(addr.port()),
+            smtp_security: SmtpSecurity::None,
+            username: None,
+            password: None,
+            from: "aegisd@example.com".to_owned(),
+            to: vec!["admin@example.com".to_owned()],
+            triggers: None,
+        });
+        let notifier = Notifier::new(db.clone(), &config)?;
+        let conn = &mut db.acquire().await?;
+        notify(
+            conn,
+            Trigger::DeviceConfirmed,
+            Some("laptop"),
+            "Device confirmed",
+        )
+        .await;
+        notifier.run_once().await?;
+
+        let message = message.await.unwrap();
+        assert!(message.contains("Subject: [aegis] device confirmed: laptop"));
+        assert!(message.contains("To: admin@example.com"));
+        assert!(message.contains("Device confirmed"));
+        assert_eq!(delivery_state(&db).await?, (1, true, None));
+        Ok(())
+    }
+}
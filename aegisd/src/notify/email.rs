@@ -0,0 +1,61 @@
+use crate::config::{EmailConfig, SmtpSecurity};
+use crate::model::notifications::Notification;
+use anyhow::Result;
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+pub struct EmailSink {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+}
+
+impl EmailSink {
+    pub fn new(config: &EmailConfig) -> Result<Self> {
+        let host = config.smtp_host.as_str();
+        let mut builder = match config.smtp_security {
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+            SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+            SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+        };
+        if let Some(port) = config.smtp_port {
+            builder = builder.port(port);
+        }
+        if let (Some(user), Some(pass)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(user.clone(), pass.clone()));
+        }
+        Ok(Self {
+            transport: builder.build(),
+            from: config.from.parse()?,
+            to: config
+                .to
+                .iter()
+                .map(|to| to.parse())
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub async fn deliver(&self, notification: &Notification) -> Result<()> {
+        let subject = match &notification.device {
+            Some(device) => format!("[aegis] {}: {device}", notification.trigger),
+            None => format!("[aegis] {}", notification.trigger),
+        };
+        let body = format!(
+            "{}\n\nDevice: {}\nTime: {} UTC\n",
+            notification.message,
+            notification.device.as_deref().unwrap_or("-"),
+            notification.created_at.format("%Y-%m-%d %H:%M:%S"),
+        );
+        let mut message = Message::builder()
+            .from(self.from.clone())
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+        self.transport.send(message.body(body)?).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+use crate::config::WebhookConfig;
+use crate::model::notifications::{PendingDelivery, Trigger};
+use aegislib::crypto::hex;
+use anyhow::{bail, Result};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+pub const DELIVERY_HEADER: &str = "x-aegis-delivery";
+pub const TIMESTAMP_HEADER: &str = "x-aegis-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-aegis-signature";
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    id: i32,
+    trigger: Trigger,
+    device: Option<&'a str>,
+    message: &'a str,
+    timestamp: i64,
+}
+
+pub struct WebhookSink {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    secret: Vec<u8>,
+}
+
+/// Hex HMAC-SHA256 of `<timestamp>.<body>`, so receivers can also reject replays
+pub fn signature(secret: &[u8], timestamp: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    hex(&mac.finalize().into_bytes())
+}
+
+impl WebhookSink {
+    pub fn new(config: &WebhookConfig) -> Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder().use_rustls_tls().build()?,
+            url: config.url.parse()?,
+            secret: config.secret.as_bytes().to_vec(),
+        })
+    }
+
+    pub async fn deliver(&self, delivery: &PendingDelivery) -> Result<()> {
+        let notification = &delivery.notification;
+        let body = serde_json::to_vec(&WebhookPayload {
+            id: notification.id,
+            trigger: notification.trigger,
+            device: notification.device.as_deref(),
+            message: &notification.message,
+            timestamp: notification.created_at.and_utc().timestamp(),
+        })?;
+        let timestamp = Utc::now().timestamp().to_string();
+        let signature = signature(&self.secret, &timestamp, &body);
+
+        let resp = self
+            .client
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(DELIVERY_HEADER, delivery.id.to_string())
+            .header(TIMESTAMP_HEADER, timestamp)
+            .header(SIGNATURE_HEADER, format!("sha256={signature}"))
+            .body(body)
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            bail!("Webhook returned {}", resp.status());
+        }
+        Ok(())
+    }
+}
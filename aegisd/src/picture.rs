@@ -0,0 +1,58 @@
+//! Decoding of uploaded camera pictures. Devices send whatever their webcam produced,
+//! so nothing here assumes the data is a valid JPEG.
+
+use anyhow::Result;
+use image::codecs::jpeg::JpegEncoder;
+use image::io::Reader as ImageReader;
+use image::ImageFormat;
+use std::io::Cursor;
+
+/// Longest side of generated thumbnails, in pixels
+pub const THUMBNAIL_SIZE: u32 = 256;
+const THUMBNAIL_QUALITY: u8 = 80;
+
+/// Width and height, read from the JPEG header without decoding the picture
+pub fn dimensions(jpeg_data: &[u8]) -> Option<(u32, u32)> {
+    ImageReader::with_format(Cursor::new(jpeg_data), ImageFormat::Jpeg)
+        .into_dimensions()
+        .ok()
+}
+
+/// A JPEG scaled down to fit in [`THUMBNAIL_SIZE`], keeping the aspect ratio.
+/// Decoding is slow for large pictures, call this from a blocking task.
+pub fn thumbnail(jpeg_data: &[u8]) -> Result<Vec<u8>> {
+    let image = image::load_from_memory_with_format(jpeg_data, ImageFormat::Jpeg)?;
+    let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).into_rgb8();
+    let mut data = Vec::new();
+    JpegEncoder::new_with_quality(&mut data, THUMBNAIL_QUALITY).encode_image(&thumbnail)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use image::RgbImage;
+
+    pub fn test_jpeg(width: u32, height: u32) -> Vec<u8> {
+        let image = RgbImage::from_fn(width, height, |x, y| image::Rgb([x as u8, y as u8, 0]));
+        let mut data = Vec::new();
+        JpegEncoder::new(&mut data).encode_image(&image).unwrap();
+        data
+    }
+
+    #[test]
+    fn picture_dimensions() {
+        assert_eq!(dimensions(&test_jpeg(64, 48)), Some((64, 48)));
+        assert_eq!(dimensions(b"not a jpeg"), None);
+    }
+
+    #[test]
+    fn thumbnail_keeps_aspect_ratio() {
+        let thumb = thumbnail(&test_jpeg(1024, 512)).unwrap();
+        assert_eq!(
+            dimensions(&thumb),
+            Some((THUMBNAIL_SIZE, THUMBNAIL_SIZE / 2))
+        );
+        assert!(thumbnail(b"not a jpeg").is_err());
+    }
+}
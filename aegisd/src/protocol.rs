@@ -0,0 +1,49 @@
+//! Server side of the protocol negotiation, see `aegislib::protocol`
+
+pub mod legacy;
+
+use crate::error::{Error, Result};
+use aegislib::command::admin::GetPicturesArg;
+use aegislib::protocol::{PeerProtocol, CAPABILITIES_HEADER, PROTOCOL_VERSION_HEADER};
+use axum::response::Response;
+use http::{HeaderMap, HeaderValue, StatusCode};
+use serde::de::DeserializeOwned;
+use std::io::Read;
+
+/// Reads the protocol a client advertised, rejecting clients older than `min_version`
+pub fn check_peer_protocol(headers: &HeaderMap, min_version: u32) -> Result<PeerProtocol> {
+    let protocol = parse_peer_protocol(headers)?;
+    protocol
+        .check_min_version(min_version)
+        .map_err(|e| Error::Response(StatusCode::UPGRADE_REQUIRED, e.to_string()))?;
+    Ok(protocol)
+}
+
+pub fn parse_peer_protocol(headers: &HeaderMap) -> Result<PeerProtocol> {
+    PeerProtocol::from_header_values(
+        headers.get(PROTOCOL_VERSION_HEADER).map(|v| v.as_bytes()),
+        headers.get(CAPABILITIES_HEADER).map(|v| v.as_bytes()),
+    )
+    .map_err(|e| Error::Response(StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+/// Tells clients which protocol we speak, on every response
+pub async fn add_protocol_headers<B>(mut resp: Response<B>) -> Response<B> {
+    for (name, value) in PeerProtocol::current().header_values() {
+        let value = HeaderValue::from_str(&value).expect("Invalid protocol header value");
+        resp.headers_mut().insert(name, value);
+    }
+    resp
+}
+
+/// The argument of an admin handler that also takes the admin's protocol
+pub trait AdminArg: DeserializeOwned {
+    /// Reads the argument the way admins of `protocol` send it, the same for all by default
+    fn read(body: impl Read, _protocol: &PeerProtocol) -> bincode::Result<Self> {
+        bincode::deserialize_from(body)
+    }
+}
+
+impl AdminArg for () {}
+impl AdminArg for String {}
+impl AdminArg for GetPicturesArg {}
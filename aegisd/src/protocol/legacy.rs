@@ -0,0 +1,193 @@
+//! Older shapes of admin arguments and replies, for admins whose protocol predates a change to
+//! them. Payloads are positional bincode, so an older admin can't skip fields it doesn't know.
+
+use crate::protocol::AdminArg;
+use aegislib::command::admin::{
+    ArchivedDevice, ConnectionRecord, DeviceExport, ExportedPicture, RegisteredDevice,
+    SetStatusArg, SignedArg, SignedStatusArg, StatusHistoryEntry,
+};
+use aegislib::command::device::{DeviceEvent, StatusReply};
+use aegislib::protocol::{
+    PeerProtocol, HARDWARE_INFO_VERSION, PROTECTION_PROFILE_VERSION, STATUS_SCHEDULE_VERSION,
+};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::time::SystemTime;
+
+/// [`SetStatusArg`] before scheduled and expiring changes
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetStatusArgV6 {
+    pub dev_name: String,
+    pub vt_locked: Option<bool>,
+    pub ssh_locked: Option<bool>,
+    pub draw_decoy: Option<bool>,
+}
+
+/// Older admins send a `SignedArg<SetStatusArgV6>`, for a change that applies right away
+impl AdminArg for SignedStatusArg {
+    fn read(body: impl Read, protocol: &PeerProtocol) -> bincode::Result<Self> {
+        if protocol.version >= STATUS_SCHEDULE_VERSION {
+            return bincode::deserialize_from(body);
+        }
+        let SignedArg { arg, command }: SignedArg<SetStatusArgV6> =
+            bincode::deserialize_from(body)?;
+        Ok(SignedStatusArg {
+            arg: SetStatusArg {
+                dev_name: arg.dev_name,
+                vt_locked: arg.vt_locked,
+                ssh_locked: arg.ssh_locked,
+                draw_decoy: arg.draw_decoy,
+                schedule: None,
+                expires_after_secs: None,
+            },
+            command,
+            revert: None,
+        })
+    }
+}
+
+/// [`StatusHistoryEntry`] before it recorded who made the change
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatusHistoryEntryV6 {
+    pub changed_at_timestamp: u64,
+    pub vt_locked: bool,
+    pub ssh_locked: bool,
+    pub draw_decoy: bool,
+}
+
+/// A [`StatusHistoryEntry`] in the shape the admin's protocol version expects
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum StatusHistoryReply {
+    V6(StatusHistoryEntryV6),
+    Current(StatusHistoryEntry),
+}
+
+impl StatusHistoryReply {
+    pub fn new(entry: StatusHistoryEntry, version: u32) -> Self {
+        if version >= STATUS_SCHEDULE_VERSION {
+            return Self::Current(entry);
+        }
+        Self::V6(StatusHistoryEntryV6 {
+            changed_at_timestamp: entry.changed_at_timestamp,
+            vt_locked: entry.vt_locked,
+            ssh_locked: entry.ssh_locked,
+            draw_decoy: entry.draw_decoy,
+        })
+    }
+}
+
+/// [`RegisteredDevice`] before device metadata
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegisteredDeviceV3 {
+    pub id: i32,
+    pub created_at: SystemTime,
+    pub name: String,
+    pub pubkey: String,
+}
+
+/// [`RegisteredDevice`] before protection profiles
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegisteredDeviceV6 {
+    pub id: i32,
+    pub created_at: SystemTime,
+    pub name: String,
+    pub pubkey: String,
+    pub owner: Option<String>,
+    pub contact: Option<String>,
+    pub notes: Option<String>,
+    pub serial_number: Option<String>,
+    pub hardware_model: Option<String>,
+}
+
+/// A [`RegisteredDevice`] in the shape the admin's protocol version expects
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum RegisteredDeviceReply {
+    V3(RegisteredDeviceV3),
+    V6(RegisteredDeviceV6),
+    Current(RegisteredDevice),
+}
+
+impl RegisteredDeviceReply {
+    pub fn new(device: RegisteredDevice, version: u32) -> Self {
+        if version >= PROTECTION_PROFILE_VERSION {
+            return Self::Current(device);
+        }
+        if version < HARDWARE_INFO_VERSION {
+            return Self::V3(RegisteredDeviceV3 {
+                id: device.id,
+                created_at: device.created_at,
+                name: device.name,
+                pubkey: device.pubkey,
+            });
+        }
+        Self::V6(RegisteredDeviceV6 {
+            id: device.id,
+            created_at: device.created_at,
+            name: device.name,
+            pubkey: device.pubkey,
+            owner: device.owner,
+            contact: device.contact,
+            notes: device.notes,
+            serial_number: device.serial_number,
+            hardware_model: device.hardware_model,
+        })
+    }
+}
+
+/// Same fields as [`ArchivedDevice`], with the device in the admin's shape
+#[derive(Serialize)]
+pub struct ArchivedDeviceReply {
+    pub device: RegisteredDeviceReply,
+    pub archived_at: SystemTime,
+}
+
+impl ArchivedDeviceReply {
+    pub fn new(archived: ArchivedDevice, version: u32) -> Self {
+        Self {
+            device: RegisteredDeviceReply::new(archived.device, version),
+            archived_at: archived.archived_at,
+        }
+    }
+}
+
+/// Same fields as [`DeviceExport`], with the device and status history in the admin's shape
+#[derive(Serialize)]
+pub struct DeviceExportReply {
+    pub device: RegisteredDeviceReply,
+    pub archived_at: Option<SystemTime>,
+    pub exported_at: SystemTime,
+    pub status: StatusReply,
+    pub status_history: Vec<StatusHistoryReply>,
+    pub connections: Vec<ConnectionRecord>,
+    pub events: Vec<DeviceEvent>,
+    pub pictures: Vec<ExportedPicture>,
+}
+
+impl DeviceExportReply {
+    /// Also leaves out the capture triggers the admin doesn't know
+    pub fn new(export: DeviceExport, version: u32) -> Self {
+        Self {
+            device: RegisteredDeviceReply::new(export.device, version),
+            archived_at: export.archived_at,
+            exported_at: export.exported_at,
+            status: export.status,
+            status_history: export
+                .status_history
+                .into_iter()
+                .map(|entry| StatusHistoryReply::new(entry, version))
+                .collect(),
+            connections: export.connections,
+            events: export.events,
+            pictures: export
+                .pictures
+                .into_iter()
+                .map(|mut picture| {
+                    picture.info.trigger = picture.info.trigger.for_version(version);
+                    picture
+                })
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,397 @@
+//! Limits on the unauthenticated endpoints and on device websockets, and temporary bans for
+//! addresses that keep sending forged signatures
+
+use crate::config::RateLimitConfig;
+use crate::error::{bail, Result};
+use crate::listener::UNIX_SOCKET_PEER_ADDR;
+use crate::metrics::{record_ban, record_rate_limited, RateLimit};
+use aegislib::crypto::VerifyingKey;
+use axum::extract::{ConnectInfo, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use dashmap::DashMap;
+use http::{Request, StatusCode};
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::warn;
+
+/// How often buckets that refilled completely and bans that ended are forgotten
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct TokenBucket {
+    capacity: f64,
+    per_sec: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, per_sec: f64) -> Self {
+        Self {
+            capacity: capacity as f64,
+            per_sec,
+            tokens: capacity as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Takes a token if there is one left
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < 1. {
+            return false;
+        }
+        self.tokens -= 1.;
+        true
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/// One token bucket per address or key
+struct KeyedLimiter<K> {
+    capacity: u32,
+    per_sec: f64,
+    buckets: DashMap<K, TokenBucket>,
+}
+
+impl<K: Eq + Hash> KeyedLimiter<K> {
+    /// Allows `count` events in a burst, refilled over `period`. None if `count` is 0.
+    fn new(count: u32, period: Duration) -> Option<Self> {
+        if count == 0 || period.is_zero() {
+            return None;
+        }
+        Some(Self {
+            capacity: count,
+            per_sec: count as f64 / period.as_secs_f64(),
+            buckets: DashMap::new(),
+        })
+    }
+
+    fn check(&self, key: K) -> bool {
+        self.buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(self.capacity, self.per_sec))
+            .try_take(Instant::now())
+    }
+
+    /// A full bucket is the same as a new one
+    fn sweep(&self, now: Instant) {
+        self.buckets.retain(|_, bucket| !bucket.is_full(now));
+    }
+}
+
+/// What we limit an address by. Anyone with an IPv6 address usually has the whole /64.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => {
+            let prefix = u128::from(ip) & !(u64::MAX as u128);
+            IpAddr::V6(Ipv6Addr::from(prefix))
+        }
+        ip => ip,
+    }
+}
+
+struct Limits {
+    config: RateLimitConfig,
+    register: Option<KeyedLimiter<IpAddr>>,
+    websocket: Option<KeyedLimiter<IpAddr>>,
+    websocket_key: Option<KeyedLimiter<[u8; 32]>>,
+    forged_signatures: Option<KeyedLimiter<IpAddr>>,
+    banned_until: DashMap<IpAddr, Instant>,
+}
+
+#[derive(Clone)]
+pub struct RateLimits(Arc<Limits>);
+
+impl Limits {
+    fn sweep(&self, now: Instant) {
+        for limiter in [&self.register, &self.websocket, &self.forged_signatures]
+            .into_iter()
+            .flatten()
+        {
+            limiter.sweep(now);
+        }
+        if let Some(limiter) = &self.websocket_key {
+            limiter.sweep(now);
+        }
+        self.banned_until.retain(|_, until| *until > now);
+    }
+}
+
+impl RateLimits {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let minute = Duration::from_secs(60);
+        Self(Arc::new(Limits {
+            config: config.clone(),
+            register: KeyedLimiter::new(config.register_per_minute, minute),
+            websocket: KeyedLimiter::new(config.websocket_per_minute, minute),
+            websocket_key: KeyedLimiter::new(config.websocket_per_key_per_minute, minute),
+            forged_signatures: KeyedLimiter::new(
+                config.ban_after_forged_signatures,
+                Self::ban_duration(config),
+            ),
+            banned_until: DashMap::new(),
+        }))
+    }
+
+    /// Forgets idle clients periodically, for as long as the limits are used
+    pub fn spawn_sweeper(&self) {
+        let limits: Weak<Limits> = Arc::downgrade(&self.0);
+        tokio::spawn(async move {
+            let mut ticks = interval(SWEEP_INTERVAL);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                match limits.upgrade() {
+                    Some(limits) => limits.sweep(Instant::now()),
+                    None => return,
+                }
+            }
+        });
+    }
+
+    fn ban_duration(config: &RateLimitConfig) -> Duration {
+        Duration::from_secs(config.ban_minutes as u64 * 60)
+    }
+
+    /// Unix socket peers are a reverse proxy, every client behind it would share our limits
+    fn is_exempt(&self, addr: SocketAddr) -> bool {
+        addr == UNIX_SOCKET_PEER_ADDR || self.0.config.exempt.contains(&addr.ip())
+    }
+
+    pub fn is_banned(&self, addr: SocketAddr) -> bool {
+        let now = Instant::now();
+        let ip = client_key(addr.ip());
+        match self.0.banned_until.get(&ip).map(|until| *until) {
+            Some(until) if until > now => true,
+            Some(_) => {
+                self.0.banned_until.remove_if(&ip, |_, until| *until <= now);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Called by everything that checks signatures, bans the address once it sent too many
+    pub fn record_forged_signature(&self, addr: SocketAddr) {
+        let forged_signatures = match &self.0.forged_signatures {
+            Some(limiter) if !self.is_exempt(addr) => limiter,
+            _ => return,
+        };
+        let ip = client_key(addr.ip());
+        if forged_signatures.check(ip) {
+            return;
+        }
+        let ban_duration = Self::ban_duration(&self.0.config);
+        if self
+            .0
+            .banned_until
+            .insert(ip, Instant::now() + ban_duration)
+            .is_none()
+        {
+            let minutes = self.0.config.ban_minutes;
+            warn!(%ip, minutes, "Banning address for forged signatures");
+            record_ban();
+        }
+    }
+
+    fn check(&self, limiter: &Option<KeyedLimiter<IpAddr>>, addr: SocketAddr) -> bool {
+        match limiter {
+            Some(limiter) if !self.is_exempt(addr) => limiter.check(client_key(addr.ip())),
+            _ => true,
+        }
+    }
+
+    /// Charged once a websocket proved it holds the device key, so that knowing a device's
+    /// public key isn't enough to use up its connections
+    pub fn check_device_key(&self, addr: SocketAddr, device_pk: &VerifyingKey) -> bool {
+        match &self.0.websocket_key {
+            Some(limiter) if !self.is_exempt(addr) => limiter.check(device_pk.to_bytes()),
+            _ => true,
+        }
+    }
+
+    /// Each websocket gets its own bucket, so a busy device doesn't slow down the others
+    pub fn ws_message_bucket(&self) -> Option<TokenBucket> {
+        let config = &self.0.config;
+        if config.ws_messages_per_second == 0 {
+            return None;
+        }
+        let burst = config.ws_message_burst.max(1);
+        Some(TokenBucket::new(
+            burst,
+            config.ws_messages_per_second as f64,
+        ))
+    }
+}
+
+pub fn remote_addr<B>(req: &Request<B>) -> SocketAddr {
+    match req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => *addr,
+        None => (Ipv4Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+/// Turns away every request from a banned address
+pub async fn reject_banned<B>(
+    State(limits): State<RateLimits>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response> {
+    if limits.is_banned(remote_addr(&req)) {
+        record_rate_limited(RateLimit::Banned);
+        bail!(StatusCode::FORBIDDEN, "Temporarily banned");
+    }
+    Ok(next.run(req).await)
+}
+
+pub async fn limit_register<B>(
+    State(limits): State<RateLimits>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response> {
+    let remote_addr = remote_addr(&req);
+    if !limits.check(&limits.0.register, remote_addr) {
+        warn!(%remote_addr, "Too many registrations");
+        record_rate_limited(RateLimit::Register);
+        bail!(StatusCode::TOO_MANY_REQUESTS, "Too many registrations");
+    }
+    Ok(next.run(req).await)
+}
+
+/// Device keys are limited by the websocket itself, see [`RateLimits::check_device_key`]
+pub async fn limit_websocket<B>(
+    State(limits): State<RateLimits>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response> {
+    let remote_addr = remote_addr(&req);
+    if !limits.check(&limits.0.websocket, remote_addr) {
+        warn!(%remote_addr, "Too many websocket connections");
+        record_rate_limited(RateLimit::Websocket);
+        bail!(StatusCode::TOO_MANY_REQUESTS, "Too many connections");
+    }
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, 1.);
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+        assert!(!bucket.try_take(start + Duration::from_millis(500)));
+        assert!(bucket.try_take(start + Duration::from_millis(1000)));
+        assert!(!bucket.try_take(start + Duration::from_millis(1000)));
+        assert!(bucket.is_full(start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn ban_after_forged_signatures() {
+        let config = RateLimitConfig {
+            ban_after_forged_signatures: 2,
+            exempt: vec![Ipv4Addr::LOCALHOST.into()],
+            ..Default::default()
+        };
+        let limits = RateLimits::new(&config);
+        let attacker: SocketAddr = (Ipv4Addr::new(192, 0, 2, 1), 1234).into();
+        let proxy: SocketAddr = (Ipv4Addr::LOCALHOST, 1234).into();
+        for _ in 0..2 {
+            limits.record_forged_signature(attacker);
+            limits.record_forged_signature(proxy);
+            limits.record_forged_signature(UNIX_SOCKET_PEER_ADDR);
+        }
+        assert!(!limits.is_banned(attacker));
+        limits.record_forged_signature(attacker);
+        limits.record_forged_signature(proxy);
+        limits.record_forged_signature(UNIX_SOCKET_PEER_ADDR);
+        assert!(limits.is_banned(attacker));
+        assert!(!limits.is_banned(proxy));
+        assert!(!limits.is_banned(UNIX_SOCKET_PEER_ADDR));
+    }
+
+    #[test]
+    fn unix_socket_peers_are_exempt() {
+        let config = RateLimitConfig {
+            register_per_minute: 1,
+            ..Default::default()
+        };
+        let limits = RateLimits::new(&config);
+        for _ in 0..10 {
+            assert!(limits.check(&limits.0.register, UNIX_SOCKET_PEER_ADDR));
+        }
+        let local_tcp = (Ipv4Addr::LOCALHOST, 1234).into();
+        assert!(limits.check(&limits.0.register, local_tcp));
+        assert!(!limits.check(&limits.0.register, local_tcp));
+    }
+
+    #[test]
+    fn limits_can_be_disabled() {
+        let config = RateLimitConfig {
+            register_per_minute: 0,
+            ws_messages_per_second: 0,
+            ..Default::default()
+        };
+        let limits = RateLimits::new(&config);
+        let addr = (Ipv4Addr::new(192, 0, 2, 1), 1234).into();
+        for _ in 0..100 {
+            assert!(limits.check(&limits.0.register, addr));
+        }
+        assert!(limits.ws_message_bucket().is_none());
+    }
+
+    #[test]
+    fn ipv6_limited_by_prefix() {
+        let config = RateLimitConfig {
+            register_per_minute: 2,
+            ..Default::default()
+        };
+        let limits = RateLimits::new(&config);
+        let ip = |last: u16| (IpAddr::from([0x2001, 0xdb8, 0, 1, 0, 0, 0, last]), 1234).into();
+        assert!(limits.check(&limits.0.register, ip(1)));
+        assert!(limits.check(&limits.0.register, ip(2)));
+        assert!(!limits.check(&limits.0.register, ip(3)));
+        let other_prefix = (IpAddr::from([0x2001, 0xdb8, 0, 2, 0, 0, 0, 1]), 1234).into();
+        assert!(limits.check(&limits.0.register, other_prefix));
+    }
+
+    #[test]
+    fn sweep_forgets_idle_clients() {
+        let config = RateLimitConfig {
+            register_per_minute: 2,
+            ..Default::default()
+        };
+        let limits = RateLimits::new(&config);
+        let register = limits.0.register.as_ref().unwrap();
+        let idle = (Ipv4Addr::new(192, 0, 2, 1), 1234).into();
+        let busy = (Ipv4Addr::new(192, 0, 2, 2), 1234).into();
+        limits.check(&limits.0.register, idle);
+        let later = Instant::now() + Duration::from_secs(60);
+        limits.0.sweep(later);
+        assert!(register.buckets.is_empty());
+
+        limits.check(&limits.0.register, busy);
+        limits.check(&limits.0.register, busy);
+        limits.0.sweep(Instant::now());
+        assert_eq!(register.buckets.len(), 1);
+    }
+}
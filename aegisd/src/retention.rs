@@ -0,0 +1,184 @@
+//! Background pruning of stored events and pictures, following the server-wide
+//! [`RetentionConfig`] or a device's own policy. Stolen devices are never pruned,
+//! everything they sent may become evidence.
+
+use crate::config::RetentionConfig;
+use crate::db::DbPool;
+use crate::metrics::record_stored_picture_bytes;
+use crate::model::{events, pics, retention};
+use aegislib::command::admin::RetentionPolicy;
+use aegislib::command::device::{DeviceEvent, EventLogLevel};
+use anyhow::Result;
+use chrono::{NaiveDateTime, Utc};
+use std::time::Duration;
+use tracing::{info, warn};
+
+const DEFAULT_INTERVAL_MINUTES: u32 = 60;
+
+pub struct Retention {
+    db: DbPool,
+    policy: RetentionPolicy,
+    interval: Duration,
+}
+
+impl Retention {
+    pub fn new(db: DbPool, config: &RetentionConfig) -> Self {
+        let minutes = config.interval_minutes.unwrap_or(DEFAULT_INTERVAL_MINUTES);
+        Self {
+            db,
+            policy: config.policy(),
+            interval: Duration::from_secs(minutes as u64 * 60),
+        }
+    }
+
+    /// Prunes periodically until the server exits
+    pub fn spawn(self) {
+        info!(policy = ?self.policy, "Starting retention task");
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.run_once(Utc::now().naive_utc()).await {
+                    warn!("Failed to apply retention policies: {e}");
+                }
+                tokio::time::sleep(self.interval).await;
+            }
+        });
+    }
+
+    pub async fn run_once(&self, now: NaiveDateTime) -> Result<()> {
+        let conn = &mut self.db.acquire().await?;
+        for device in retention::list_devices(conn).await? {
+            if device.stolen {
+                continue;
+            }
+            let policy = device.policy.as_ref().unwrap_or(&self.policy);
+            if *policy == RetentionPolicy::default() {
+                continue;
+            }
+            let pruned_events = events::prune(conn, device.dev_id, policy, now).await?;
+            let pruned_pics = pics::prune(conn, device.dev_id, policy, now).await?;
+            if pruned_events == 0 && pruned_pics == 0 {
+                continue;
+            }
+            info!(
+                dev_id = device.dev_id,
+                pruned_events, pruned_pics, "Applied retention policy"
+            );
+            let event = DeviceEvent {
+                timestamp: now.and_utc().timestamp() as u64,
+                level: EventLogLevel::Info,
+                message: format!(
+                    "Retention policy pruned {pruned_events} events and {pruned_pics} pictures"
+                ),
+            };
+            let _ = events::insert(conn, device.dev_id, event).await;
+        }
+        // Summing every picture is too slow to do on each scrape
+        record_stored_picture_bytes(pics::total_size(conn).await?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::DbConnection;
+    use crate::error::Result;
+    use crate::model::device::test::insert_test_device;
+    use crate::model::device::{get_dev_id_by_name, set_stolen};
+    use crate::model::page::naive_from_timestamp;
+    use crate::model::pics::{DbCaptureTrigger, DeviceCameraPicture};
+    use aegisd_handler_macros::db_test;
+    use aegislib::crypto::random_sign_keypair;
+    use base64::prelude::*;
+
+    const DAY: u64 = 24 * 3600;
+    const NOW: u64 = 100 * DAY;
+
+    async fn device_with_data(conn: &mut DbConnection, name: &str) -> Result<i32> {
+        let pk = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
+        insert_test_device(conn, pk, name.into()).await?;
+        let dev_id = get_dev_id_by_name(conn, name).await?;
+        // One event and one 1kB picture per day over the last 10 days, oldest first
+        for age in (0..10).rev() {
+            let event = DeviceEvent {
+                timestamp: NOW - age * DAY,
+                level: match age % 2 {
+                    0 => EventLogLevel::Warn,
+                    _ => EventLogLevel::Debug,
+                },
+                message: format!("{age} days ago"),
+            };
+            events::insert(conn, dev_id, event).await?;
+            DeviceCameraPicture {
+                id: 0,
+                dev_id,
+                created_at: naive_from_timestamp(NOW - age * DAY)?,
+                jpeg_data: vec![age as u8; 1000],
+                width: None,
+                height: None,
+                trigger: DbCaptureTrigger::Unknown,
+            }
+            .insert(conn)
+            .await?;
+        }
+        Ok(dev_id)
+    }
+
+    async fn stored_ages(conn: &mut DbConnection, dev_id: i32) -> Result<(Vec<u64>, Vec<u8>)> {
+        let events = events::get_for_device(conn, dev_id).await?;
+        let mut event_ages: Vec<_> = events
+            .iter()
+            .filter(|e| !e.message.starts_with("Retention"))
+            .map(|e| (NOW - e.timestamp) / DAY)
+            .collect();
+        event_ages.sort();
+        let mut pic_ages: Vec<_> = pics::get_for_device(conn, dev_id)
+            .await?
+            .iter()
+            .map(|p| p.jpeg_data[0])
+            .collect();
+        pic_ages.sort();
+        Ok((event_ages, pic_ages))
+    }
+
+    #[db_test]
+    async fn prune(db: DbPool) -> Result<()> {
+        let conn = &mut db.acquire().await?;
+        let global = device_with_data(conn, "global").await?;
+        let custom = device_with_data(conn, "custom").await?;
+        let stolen = device_with_data(conn, "stolen").await?;
+        set_stolen(conn, stolen, true).await?;
+        let custom_policy = RetentionPolicy {
+            event_max_count: Some(3),
+            picture_max_bytes: Some(2500),
+            ..Default::default()
+        };
+        retention::set_override(conn, custom, Some(custom_policy)).await?;
+
+        let config = RetentionConfig {
+            event_max_age_days: Some(7),
+            event_min_level: Some(EventLogLevel::Info),
+            picture_max_count: Some(4),
+            ..Default::default()
+        };
+        let retention = Retention::new(db.clone(), &config);
+        retention.run_once(naive_from_timestamp(NOW)?).await?;
+
+        assert_eq!(
+            stored_ages(conn, global).await?,
+            (vec![0, 2, 4, 6], vec![0, 1, 2, 3])
+        );
+        assert_eq!(
+            stored_ages(conn, custom).await?,
+            (vec![0, 1, 2], vec![0, 1])
+        );
+        let (event_ages, pic_ages) = stored_ages(conn, stolen).await?;
+        assert_eq!((event_ages.len(), pic_ages.len()), (10, 10));
+
+        let log = events::get_for_device(conn, global).await?;
+        assert!(log
+            .iter()
+            .any(|e| e.message == "Retention policy pruned 6 events and 6 pictures"));
+        Ok(())
+    }
+}
@@ -0,0 +1,409 @@
+//! Automation rules, admin-defined reactions to what devices do, see [`AutomationRule`].
+//! Handlers report what happened with [`evaluate`], and the [`RuleRunner`] notices devices that
+//! stay offline. Every execution is logged, dry-run rules are only logged.
+
+use crate::db::{DbConnection, DbPool};
+use crate::handler::device::DeviceId;
+use crate::model::{connections, device, events, rules};
+use crate::ws::{push_status, ws_for_device};
+use aegislib::command::admin::{AutomationRule, RuleAction, RuleTrigger};
+use aegislib::command::device::{CaptureTrigger, DeviceEvent, EventLogLevel, StatusReply};
+use aegislib::command::server::{PowerCommand, ServerCommand};
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDateTime, Utc};
+use std::time::Duration;
+use tracing::{info, warn};
+
+const INTERVAL: Duration = Duration::from_secs(60);
+
+/// Something a device did that rules may react to
+pub enum RuleEvent<'a> {
+    Logged(&'a DeviceEvent),
+    Picture(CaptureTrigger),
+    Connected { locked: bool, new_address: bool },
+    Disconnected,
+}
+
+impl RuleEvent<'_> {
+    fn matches(&self, trigger: &RuleTrigger) -> bool {
+        match (trigger, self) {
+            (
+                RuleTrigger::Event {
+                    min_level,
+                    contains,
+                },
+                RuleEvent::Logged(event),
+            ) => {
+                event.level >= *min_level
+                    && contains
+                        .as_ref()
+                        .is_none_or(|text| event.message.contains(text.as_str()))
+            }
+            (RuleTrigger::Picture { trigger }, RuleEvent::Picture(picture_trigger)) => {
+                trigger.is_none_or(|t| t == *picture_trigger)
+            }
+            (
+                RuleTrigger::Connected {
+                    while_locked,
+                    new_address,
+                },
+                RuleEvent::Connected {
+                    locked,
+                    new_address: is_new,
+                },
+            ) => (!while_locked || *locked) && (!new_address || *is_new),
+            (RuleTrigger::Disconnected, RuleEvent::Disconnected) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Runs the device's rules that match `event`. Never fails the caller, errors are only logged.
+pub async fn evaluate(conn: &mut DbConnection, dev_id: i32, event: RuleEvent<'_>) {
+    if let Err(e) = try_evaluate(conn, dev_id, event).await {
+        warn!(dev_id, "Failed to evaluate automation rules: {e}");
+    }
+}
+
+async fn try_evaluate(conn: &mut DbConnection, dev_id: i32, event: RuleEvent<'_>) -> Result<()> {
+    let now = Utc::now().naive_utc();
+    for rule in rules::list_enabled_for_device(conn, dev_id).await? {
+        if !event.matches(&rule.trigger) {
+            continue;
+        }
+        if rules::record_hit(conn, &rule, dev_id, now).await? {
+            execute(conn, &rule, dev_id, now).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn execute(
+    conn: &mut DbConnection,
+    rule: &AutomationRule,
+    dev_id: i32,
+    now: NaiveDateTime,
+) -> Result<()> {
+    let result = match rule.dry_run {
+        true => Ok(()),
+        false => run_action(conn, rule, dev_id).await,
+    };
+    let error = result.err().map(|e| e.to_string());
+    rules::log_execution(conn, rule, dev_id, now, error.as_deref()).await?;
+    info!(
+        dev_id,
+        rule = rule.name,
+        dry_run = rule.dry_run,
+        "Executed automation rule"
+    );
+    let (level, message) = match (&error, rule.dry_run) {
+        (_, true) => (
+            EventLogLevel::Info,
+            format!(
+                "Rule '{}' would have run {:?} (dry run)",
+                rule.name, rule.action
+            ),
+        ),
+        (None, false) => (
+            EventLogLevel::Warn,
+            format!("Rule '{}' ran {:?}", rule.name, rule.action),
+        ),
+        (Some(e), false) => (
+            EventLogLevel::Error,
+            format!("Rule '{}' failed to run {:?}: {e}", rule.name, rule.action),
+        ),
+    };
+    let event = DeviceEvent {
+        timestamp: now.and_utc().timestamp() as u64,
+        level,
+        message,
+    };
+    let _ = events::insert(conn, dev_id, event).await;
+    Ok(())
+}
+
+async fn run_action(conn: &mut DbConnection, rule: &AutomationRule, dev_id: i32) -> Result<()> {
+    let send = |cmd: ServerCommand| async move {
+        let ws =
+            ws_for_device(DeviceId(dev_id)).ok_or_else(|| anyhow!("Device is not connected"))?;
+        ws.send(cmd).await
+    };
+    match &rule.action {
+        RuleAction::Lock { .. } => {
+            let change = rule.action.status_change().unwrap();
+            let changed_by = format!("rule '{}'", rule.name);
+            let status: StatusReply = device::update_status(
+                conn,
+                dev_id,
+                change.vt_locked,
+                change.ssh_locked,
+                change.draw_decoy,
+                &changed_by,
+            )
+            .await?
+            .into();
+            push_status(DeviceId(dev_id), status.into(), None).await;
+        }
+        RuleAction::Poweroff => send(PowerCommand::Poweroff.into()).await?,
+        RuleAction::Capture => send(ServerCommand::Capture).await?,
+        RuleAction::MarkStolen => device::set_stolen(conn, dev_id, true).await?,
+    }
+    Ok(())
+}
+
+/// Fires `Offline` rules, which no device event can trigger
+pub struct RuleRunner {
+    db: DbPool,
+}
+
+impl RuleRunner {
+    pub fn new(db: DbPool) -> Self {
+        Self { db }
+    }
+
+    /// Checks for offline devices periodically until the server exits
+    pub fn spawn(self) {
+        info!("Starting automation rule runner");
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.run_once(Utc::now().naive_utc()).await {
+                    warn!("Failed to run automation rules for offline devices: {e}");
+                }
+                tokio::time::sleep(INTERVAL).await;
+            }
+        });
+    }
+
+    pub async fn run_once(&self, now: NaiveDateTime) -> Result<()> {
+        let conn = &mut self.db.acquire().await?;
+        let offline_rules: Vec<_> = rules::list(conn)
+            .await?
+            .into_iter()
+            .filter(|rule| rule.enabled && matches!(rule.trigger, RuleTrigger::Offline { .. }))
+            .collect();
+        if offline_rules.is_empty() {
+            return Ok(());
+        }
+        for (dev_id, dev_name, last_seen) in connections::last_seen(conn).await? {
+            if ws_for_device(DeviceId(dev_id)).is_some() {
+                continue;
+            }
+            for rule in &offline_rules {
+                let RuleTrigger::Offline { secs } = rule.trigger else {
+                    continue;
+                };
+                if rule.dev_name.as_ref().is_some_and(|name| *name != dev_name) {
+                    continue;
+                }
+                let offline_for = (now - last_seen).to_std().unwrap_or_default();
+                if offline_for < Duration::from_secs(secs as u64) {
+                    continue;
+                }
+                // Once per disconnection
+                if rules::executed_since(conn, &rule.name, dev_id, last_seen).await? {
+                    continue;
+                }
+                execute(conn, rule, dev_id, now).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::Result;
+    use crate::model::device::test::insert_test_device;
+    use crate::model::device::{get_dev_id_by_name, get_status, is_stolen};
+    use crate::server::{make_test_server, serve_test_server};
+    use aegisd_handler_macros::db_test;
+    use aegislib::client::{ClientConfig, DeviceClient};
+    use aegislib::crypto::random_sign_keypair;
+    use base64::prelude::*;
+    use tokio::sync::mpsc::channel;
+
+    fn rule(name: &str, trigger: RuleTrigger, action: RuleAction) -> AutomationRule {
+        AutomationRule {
+            name: name.into(),
+            dev_name: None,
+            trigger,
+            threshold: 1,
+            window_secs: 0,
+            action,
+            enabled: true,
+            dry_run: false,
+        }
+    }
+
+    #[test]
+    fn matching() {
+        let event = DeviceEvent {
+            timestamp: 0,
+            level: EventLogLevel::Warn,
+            message: "Failed login attempt".into(),
+        };
+        let event_trigger = |min_level, contains: Option<&str>| RuleTrigger::Event {
+            min_level,
+            contains: contains.map(Into::into),
+        };
+        let logged = RuleEvent::Logged(&event);
+        assert!(logged.matches(&event_trigger(EventLogLevel::Info, None)));
+        assert!(logged.matches(&event_trigger(EventLogLevel::Warn, Some("login"))));
+        assert!(!logged.matches(&event_trigger(EventLogLevel::Error, None)));
+        assert!(!logged.matches(&event_trigger(EventLogLevel::Info, Some("sudo"))));
+        assert!(!logged.matches(&RuleTrigger::Disconnected));
+
+        let picture = RuleEvent::Picture(CaptureTrigger::Screenshot);
+        assert!(picture.matches(&RuleTrigger::Picture { trigger: None }));
+        assert!(!picture.matches(&RuleTrigger::Picture {
+            trigger: Some(CaptureTrigger::InputWhileLocked)
+        }));
+
+        let connected = |while_locked, new_address| RuleTrigger::Connected {
+            while_locked,
+            new_address,
+        };
+        let unlocked_known = RuleEvent::Connected {
+            locked: false,
+            new_address: false,
+        };
+        assert!(unlocked_known.matches(&connected(false, false)));
+        assert!(!unlocked_known.matches(&connected(true, false)));
+        assert!(!unlocked_known.matches(&connected(false, true)));
+        assert!(!RuleEvent::Disconnected.matches(&RuleTrigger::Offline { secs: 1 }));
+    }
+
+    #[db_test]
+    async fn threshold_and_dry_run(db: DbPool) -> Result<()> {
+        let conn = &mut db.acquire().await?;
+        let pk = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
+        insert_test_device(conn, pk, "test".into()).await?;
+        let dev_id = get_dev_id_by_name(conn, "test").await?;
+
+        let screenshots = RuleTrigger::Picture {
+            trigger: Some(CaptureTrigger::Screenshot),
+        };
+        let mut stolen = rule("stolen", screenshots, RuleAction::MarkStolen);
+        stolen.threshold = 2;
+        stolen.window_secs = 600;
+        rules::upsert(conn, &stolen, Some(dev_id)).await?;
+        let lock = RuleAction::Lock {
+            vt_locked: true,
+            ssh_locked: false,
+            draw_decoy: false,
+        };
+        let mut dry_lock = rule("dry lock", RuleTrigger::Picture { trigger: None }, lock);
+        dry_lock.dry_run = true;
+        rules::upsert(conn, &dry_lock, None).await?;
+
+        evaluate(conn, dev_id, RuleEvent::Picture(CaptureTrigger::Scheduled)).await;
+        evaluate(conn, dev_id, RuleEvent::Picture(CaptureTrigger::Screenshot)).await;
+        assert!(!is_stolen(conn, dev_id).await?);
+        evaluate(conn, dev_id, RuleEvent::Picture(CaptureTrigger::Screenshot)).await;
+        assert!(is_stolen(conn, dev_id).await?);
+        assert!(!get_status(conn, dev_id).await?.vt_locked);
+
+        let executions = rules::list_executions(conn, None, 10).await?;
+        let summary: Vec<_> = executions
+            .iter()
+            .map(|e| (e.rule_name.as_str(), e.dry_run, e.error.is_none()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("stolen", false, true),
+                ("dry lock", true, true),
+                ("dry lock", true, true),
+                ("dry lock", true, true),
+            ]
+        );
+        let stolen_only = rules::list_executions(conn, Some("stolen"), 10).await?;
+        assert_eq!(stolen_only.len(), 1);
+        assert_eq!(stolen_only[0].dev_name, "test");
+        Ok(())
+    }
+
+    #[db_test]
+    async fn poweroff_after_repeated_input(db: DbPool) -> Result<()> {
+        let server = make_test_server(db.clone()).await?;
+        let addr = serve_test_server(db.clone(), &server.config).await?;
+        let conn = &mut db.acquire().await?;
+        // Websocket tests running alongside share the connection map, with their first devices
+        for name in ["spare", "spare2", "spare3", "spare4"] {
+            let pk = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
+            insert_test_device(conn, pk, name.into()).await?;
+        }
+        let device_key = random_sign_keypair();
+        let pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key());
+        insert_test_device(conn, pk, "test".into()).await?;
+        let dev_id = get_dev_id_by_name(conn, "test").await?;
+        let client_config = ClientConfig {
+            server_addr: addr.to_string(),
+            use_tls: false,
+            use_rest: false,
+            extra_ca_certs_pem: vec![],
+            pinned_server_cert_pem: None,
+            pinned_spki_sha256: None,
+            server_public_key: None,
+        };
+        let (event_tx, mut event_rx) = channel(1);
+        let _device = DeviceClient::new(&client_config, device_key, Some(event_tx))
+            .await
+            .map_err(|(_, e)| anyhow!(e))?;
+
+        let input = RuleTrigger::Picture {
+            trigger: Some(CaptureTrigger::InputWhileLocked),
+        };
+        let mut poweroff = rule("poweroff", input, RuleAction::Poweroff);
+        poweroff.threshold = 3;
+        poweroff.window_secs = 600;
+        rules::upsert(conn, &poweroff, None).await?;
+        for _ in 0..3 {
+            let event = RuleEvent::Picture(CaptureTrigger::InputWhileLocked);
+            evaluate(conn, dev_id, event).await;
+        }
+        let received = tokio::time::timeout(Duration::from_secs(5), event_rx.recv())
+            .await
+            .unwrap();
+        match received {
+            Some(ServerCommand::PowerCommand(PowerCommand::Poweroff)) => {}
+            other => panic!("Unexpected server command: {other:?}"),
+        }
+        let executions = rules::list_executions(conn, None, 10).await?;
+        assert_eq!(executions.len(), 1);
+        assert!(executions[0].error.is_none());
+        Ok(())
+    }
+
+    #[db_test]
+    async fn offline_fires_once(db: DbPool) -> Result<()> {
+        let conn = &mut db.acquire().await?;
+        // Websocket tests running alongside share the connection map, with their first device
+        for name in ["spare", "test"] {
+            let pk = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
+            insert_test_device(conn, pk, name.into()).await?;
+        }
+        let dev_id = get_dev_id_by_name(conn, "test").await?;
+        let id = connections::open(conn, dev_id, "127.0.0.1:1234").await?;
+        connections::close(conn, id).await?;
+
+        let offline = RuleTrigger::Offline { secs: 3600 };
+        rules::upsert(conn, &rule("capture", offline, RuleAction::Capture), None).await?;
+        let runner = RuleRunner::new(db.clone());
+        let now = Utc::now().naive_utc();
+        runner.run_once(now).await?;
+        assert!(rules::list_executions(conn, None, 10).await?.is_empty());
+
+        let later = now + chrono::Duration::hours(2);
+        runner.run_once(later).await?;
+        runner.run_once(later + chrono::Duration::hours(1)).await?;
+        let executions = rules::list_executions(conn, None, 10).await?;
+        assert_eq!(executions.len(), 1);
+        assert_eq!(
+            executions[0].error.as_deref(),
+            Some("Device is not connected")
+        );
+        Ok(())
+    }
+}
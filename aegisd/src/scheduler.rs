@@ -0,0 +1,454 @@
+//! Background task applying scheduled status changes once they're due, and pushing the new
+//! status to connected devices. Changes that unlock carry a command the admin signed for that
+//! time, which devices only accept for [`MAX_COMMAND_AGE`]. Those wait for their device to be
+//! connected, it also gets them when reconnecting, and the stored status only changes once
+//! the command is relayed. Past that age they are dropped, the device stays as it was.
+
+use crate::db::{DbConnection, DbPool};
+use crate::handler::device::DeviceId;
+use crate::model::status_schedule::{self, ScheduledStatus};
+use crate::model::{device, events};
+use crate::ws::{push_status, ws_for_device};
+use aegislib::command::device::{DeviceEvent, EventLogLevel, StatusReply};
+use aegislib::command::server::StatusUpdate;
+use aegislib::command::signed::MAX_COMMAND_AGE;
+use anyhow::{bail, Result};
+use chrono::{Days, NaiveDateTime, NaiveTime, Timelike, Utc};
+use lazy_static::lazy_static;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+const INTERVAL: Duration = Duration::from_secs(30);
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+lazy_static! {
+    /// The scheduler and reconnecting devices must not relay the same change twice
+    static ref APPLYING: Mutex<()> = Mutex::new(());
+}
+
+/// The first time strictly after `after` that is `minute_of_day` minutes past midnight UTC
+pub fn next_daily(minute_of_day: u16, after: NaiveDateTime) -> Result<NaiveDateTime> {
+    if minute_of_day >= MINUTES_PER_DAY {
+        bail!("Invalid time of day: {minute_of_day} minutes after midnight");
+    }
+    let time =
+        NaiveTime::from_hms_opt(minute_of_day as u32 / 60, minute_of_day as u32 % 60, 0).unwrap();
+    let today = after.date().and_time(time);
+    Ok(match today > after {
+        true => today,
+        false => today + Days::new(1),
+    })
+}
+
+pub struct StatusScheduler {
+    db: DbPool,
+}
+
+impl StatusScheduler {
+    pub fn new(db: DbPool) -> Self {
+        Self { db }
+    }
+
+    /// Applies due changes periodically until the server exits
+    pub fn spawn(self) {
+        info!("Starting status scheduler");
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.run_once(Utc::now().naive_utc()).await {
+                    warn!("Failed to apply scheduled status changes: {e}");
+                }
+                tokio::time::sleep(INTERVAL).await;
+            }
+        });
+    }
+
+    pub async fn run_once(&self, now: NaiveDateTime) -> Result<()> {
+        let conn = &mut self.db.acquire().await?;
+        let _applying = APPLYING.lock().await;
+        for scheduled in status_schedule::list_due(conn, now).await? {
+            process(conn, &scheduled, now).await;
+        }
+        Ok(())
+    }
+}
+
+/// Relays the due changes of a device that just connected. Never fails the caller.
+pub async fn relay_due(conn: &mut DbConnection, dev_id: i32, now: NaiveDateTime) {
+    let _applying = APPLYING.lock().await;
+    let due = match status_schedule::list_due(conn, now).await {
+        Ok(due) => due,
+        Err(e) => {
+            warn!(dev_id, "Failed to list due status changes: {e}");
+            return;
+        }
+    };
+    for scheduled in due.iter().filter(|s| s.dev_id == dev_id) {
+        process(conn, scheduled, now).await;
+    }
+}
+
+/// Applies a due change and removes it, unless it waits for its device. A change that fails
+/// is retried until its command is too old to relay anyway, then given up.
+async fn process(conn: &mut DbConnection, scheduled: &ScheduledStatus, now: NaiveDateTime) {
+    let overdue = (now - scheduled.apply_at).to_std().unwrap_or_default() > MAX_COMMAND_AGE;
+    match apply(conn, scheduled, overdue, now).await {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) if !overdue => {
+            warn!(
+                id = scheduled.id,
+                dev_id = scheduled.dev_id,
+                "Failed to apply scheduled status change, retrying: {e}"
+            );
+            return;
+        }
+        Err(e) => {
+            warn!(
+                id = scheduled.id,
+                dev_id = scheduled.dev_id,
+                "Giving up on scheduled status change: {e}"
+            );
+            let message = format!("Failed to apply {}: {e}", scheduled.changed_by());
+            log_event(conn, scheduled.dev_id, EventLogLevel::Error, message, now).await;
+        }
+    }
+    if let Err(e) = finish(conn, scheduled, now).await {
+        warn!(
+            id = scheduled.id,
+            dev_id = scheduled.dev_id,
+            "Failed to remove applied status change: {e}"
+        );
+    }
+}
+
+/// Returns false if the change has to wait for its device to connect
+async fn apply(
+    conn: &mut DbConnection,
+    scheduled: &ScheduledStatus,
+    overdue: bool,
+    now: NaiveDateTime,
+) -> Result<bool> {
+    let dev_id = scheduled.dev_id;
+    let changed_by = scheduled.changed_by();
+    let change = scheduled.change();
+    if change.only_locks() {
+        let command = if overdue {
+            None
+        } else {
+            scheduled.signed_command()?
+        };
+        let status = update_status(conn, scheduled, now).await?;
+        push_status(DeviceId(dev_id), status.into(), command).await;
+        return Ok(true);
+    }
+
+    if overdue {
+        warn!(
+            dev_id,
+            "Too late to relay the signed command of {changed_by}"
+        );
+        let message = format!(
+            "Dropped {changed_by}, the device wasn't connected until its signed command expired"
+        );
+        log_event(conn, dev_id, EventLogLevel::Warn, message, now).await;
+        return Ok(true);
+    }
+    let Some(command) = scheduled.signed_command()? else {
+        bail!("{changed_by} unlocks without a signed command");
+    };
+    if ws_for_device(DeviceId(dev_id)).is_none() {
+        return Ok(false);
+    }
+    let current: StatusReply = device::get_status(conn, dev_id).await?.into();
+    let status = change.apply_to(StatusUpdate::from(current));
+    if !push_status(DeviceId(dev_id), status, Some(command)).await {
+        return Ok(false);
+    }
+    update_status(conn, scheduled, now).await?;
+    Ok(true)
+}
+
+async fn update_status(
+    conn: &mut DbConnection,
+    scheduled: &ScheduledStatus,
+    now: NaiveDateTime,
+) -> Result<StatusReply> {
+    let changed_by = scheduled.changed_by();
+    let change = scheduled.change();
+    let status: StatusReply = device::update_status(
+        conn,
+        scheduled.dev_id,
+        change.vt_locked,
+        change.ssh_locked,
+        change.draw_decoy,
+        &changed_by,
+    )
+    .await?
+    .into();
+    info!(
+        dev_id = scheduled.dev_id,
+        changed_by, "Applied scheduled status change"
+    );
+    let message = format!("Status updated by {changed_by}: {status:?}");
+    log_event(conn, scheduled.dev_id, EventLogLevel::Info, message, now).await;
+    Ok(status)
+}
+
+async fn log_event(
+    conn: &mut DbConnection,
+    dev_id: i32,
+    level: EventLogLevel,
+    message: String,
+    now: NaiveDateTime,
+) {
+    let event = DeviceEvent {
+        timestamp: now.and_utc().timestamp() as u64,
+        level,
+        message,
+    };
+    let _ = events::insert(conn, dev_id, event).await;
+}
+
+/// Removes a change that's done with, or moves a daily one to its next day
+async fn finish(
+    conn: &mut DbConnection,
+    scheduled: &ScheduledStatus,
+    now: NaiveDateTime,
+) -> Result<()> {
+    if scheduled.repeat_daily {
+        let minute_of_day = scheduled.apply_at.num_seconds_from_midnight() / 60;
+        let next = next_daily(minute_of_day as u16, now)?;
+        status_schedule::reschedule(conn, scheduled.id, next).await
+    } else {
+        status_schedule::delete(conn, scheduled.dev_id, scheduled.id).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::Result;
+    use crate::model::device::test::insert_test_device;
+    use crate::model::device::{get_dev_id_by_name, get_status};
+    use crate::model::page::naive_from_timestamp;
+    use crate::model::status_history;
+    use crate::server::{make_test_server, serve_test_server};
+    use aegisd_handler_macros::db_test;
+    use aegislib::client::{ClientConfig, DeviceClient};
+    use aegislib::command::server::ServerCommand;
+    use aegislib::command::signed::{AdminCommand, SignedCommand, StatusChange};
+    use aegislib::crypto::random_sign_keypair;
+    use anyhow::anyhow;
+    use base64::prelude::*;
+    use tokio::sync::mpsc::channel;
+
+    const HOUR: u64 = 3600;
+    /// A day at midnight
+    const NOW: u64 = 20_000 * 24 * HOUR;
+
+    #[test]
+    fn daily() {
+        let midnight = naive_from_timestamp(NOW).unwrap();
+        let at = |minutes: u64| naive_from_timestamp(NOW + minutes * 60).unwrap();
+        assert_eq!(next_daily(22 * 60, midnight).unwrap(), at(22 * 60));
+        assert_eq!(next_daily(0, midnight).unwrap(), at(24 * 60));
+        assert_eq!(next_daily(30, at(22 * 60)).unwrap(), at(24 * 60 + 30));
+        assert!(next_daily(24 * 60, midnight).is_err());
+    }
+
+    #[db_test]
+    async fn applies_due_changes(db: DbPool) -> Result<()> {
+        let conn = &mut db.acquire().await?;
+        let pk = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
+        insert_test_device(conn, pk, "test".into()).await?;
+        let dev_id = get_dev_id_by_name(conn, "test").await?;
+        let lock = |vt_locked, ssh_locked| StatusChange {
+            vt_locked,
+            ssh_locked,
+            draw_decoy: None,
+        };
+
+        let now = naive_from_timestamp(NOW)?;
+        let once = ScheduledStatus::new(dev_id, now, lock(Some(true), None), None)
+            .insert(conn)
+            .await?;
+        let mut nightly = ScheduledStatus::new(dev_id, now, lock(None, Some(true)), None);
+        nightly.repeat_daily = true;
+        let nightly = nightly.insert(conn).await?;
+        let mut expiry = ScheduledStatus::new(
+            dev_id,
+            naive_from_timestamp(NOW + 2 * HOUR)?,
+            lock(Some(false), None),
+            None,
+        );
+        expiry.is_expiry = true;
+        let expiry = expiry.insert(conn).await?;
+
+        let scheduler = StatusScheduler::new(db.clone());
+        scheduler.run_once(now).await?;
+        let status = get_status(conn, dev_id).await?;
+        assert!(status.vt_locked && status.ssh_locked);
+        let pending: Vec<_> = status_schedule::list_for_device(conn, dev_id)
+            .await?
+            .into_iter()
+            .map(|s| (s.id, s.apply_at))
+            .collect();
+        assert_eq!(
+            pending,
+            [
+                (expiry, naive_from_timestamp(NOW + 2 * HOUR)?),
+                (nightly, naive_from_timestamp(NOW + 24 * HOUR)?),
+            ]
+        );
+
+        // The device never connected to get the unlock, it's dropped without changing anything
+        scheduler
+            .run_once(naive_from_timestamp(NOW + 3 * HOUR)?)
+            .await?;
+        let status = get_status(conn, dev_id).await?;
+        assert!(status.vt_locked && status.ssh_locked);
+        let pending: Vec<_> = status_schedule::list_for_device(conn, dev_id)
+            .await?
+            .into_iter()
+            .map(|s| s.id)
+            .collect();
+        assert_eq!(pending, [nightly]);
+        let changed_by: Vec<_> = status_history::list_for_device(conn, dev_id)
+            .await?
+            .into_iter()
+            .map(|s| s.changed_by)
+            .collect();
+        assert_eq!(
+            changed_by,
+            [format!("schedule #{once}"), format!("schedule #{nightly}")]
+        );
+        let events = events::get_for_device(conn, dev_id).await?;
+        assert!(events
+            .iter()
+            .any(|e| e.message.starts_with(&format!("Dropped expiry #{expiry}"))));
+        Ok(())
+    }
+
+    #[db_test]
+    async fn unlock_waits_for_device(db: DbPool) -> Result<()> {
+        let server = make_test_server(db.clone()).await?;
+        let addr = serve_test_server(db.clone(), &server.config).await?;
+        let conn = &mut db.acquire().await?;
+        // Websocket tests running alongside share the connection map, with their first devices
+        for name in ["spare", "spare2", "spare3", "spare4", "spare5"] {
+            let pk = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
+            insert_test_device(conn, pk, name.into()).await?;
+        }
+        let device_key = random_sign_keypair();
+        let device_pk = BASE64_URL_SAFE_NO_PAD.encode(device_key.verifying_key());
+        insert_test_device(conn, device_pk.clone(), "test".into()).await?;
+        let dev_id = get_dev_id_by_name(conn, "test").await?;
+        device::update_status(conn, dev_id, Some(true), None, None, "admin").await?;
+
+        let now = Utc::now().naive_utc();
+        let unlock = StatusChange {
+            vt_locked: Some(false),
+            ssh_locked: None,
+            draw_decoy: None,
+        };
+        let command = SignedCommand::sign_at(
+            &server.root_key,
+            device_pk,
+            AdminCommand::SetStatus(unlock),
+            now.and_utc().timestamp() as u64,
+        );
+        let id = ScheduledStatus::new(dev_id, now, unlock, Some(&command))
+            .insert(conn)
+            .await?;
+        StatusScheduler::new(db.clone()).run_once(now).await?;
+        assert!(get_status(conn, dev_id).await?.vt_locked);
+        assert_eq!(
+            status_schedule::list_for_device(conn, dev_id).await?.len(),
+            1
+        );
+
+        let client_config = ClientConfig {
+            server_addr: addr.to_string(),
+            use_tls: false,
+            use_rest: false,
+            extra_ca_certs_pem: vec![],
+            pinned_server_cert_pem: None,
+            pinned_spki_sha256: None,
+            server_public_key: None,
+        };
+        let (event_tx, mut event_rx) = channel(1);
+        let _device = DeviceClient::new(&client_config, device_key, Some(event_tx))
+            .await
+            .map_err(|(_, e)| anyhow!(e))?;
+        let received = tokio::time::timeout(Duration::from_secs(5), event_rx.recv())
+            .await
+            .unwrap();
+        match received {
+            Some(ServerCommand::Signed(signed)) => assert_eq!(signed.signature, command.signature),
+            other => panic!("Unexpected server command: {other:?}"),
+        }
+        // The status is stored right after relaying
+        for _ in 0..50 {
+            if !get_status(conn, dev_id).await?.vt_locked {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(!get_status(conn, dev_id).await?.vt_locked);
+        let changed_by = status_history::list_for_device(conn, dev_id).await?;
+        assert_eq!(
+            changed_by.last().unwrap().changed_by,
+            format!("schedule #{id}")
+        );
+        assert!(status_schedule::list_for_device(conn, dev_id)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[db_test]
+    async fn failing_change_is_skipped(db: DbPool) -> Result<()> {
+        let conn = &mut db.acquire().await?;
+        let pk = BASE64_URL_SAFE_NO_PAD.encode(random_sign_keypair().verifying_key());
+        insert_test_device(conn, pk, "test".into()).await?;
+        let dev_id = get_dev_id_by_name(conn, "test").await?;
+
+        let now = naive_from_timestamp(NOW)?;
+        let unlock = StatusChange {
+            vt_locked: Some(false),
+            ssh_locked: None,
+            draw_decoy: None,
+        };
+        let mut broken = ScheduledStatus::new(dev_id, now, unlock, None);
+        broken.command = Some(vec![1, 2, 3]);
+        let broken = broken.insert(conn).await?;
+        let lock = StatusChange {
+            vt_locked: None,
+            ssh_locked: Some(true),
+            draw_decoy: None,
+        };
+        ScheduledStatus::new(dev_id, now, lock, None)
+            .insert(conn)
+            .await?;
+
+        let scheduler = StatusScheduler::new(db.clone());
+        scheduler.run_once(now).await?;
+        assert!(get_status(conn, dev_id).await?.ssh_locked);
+        let pending: Vec<_> = status_schedule::list_for_device(conn, dev_id)
+            .await?
+            .into_iter()
+            .map(|s| s.id)
+            .collect();
+        assert_eq!(pending, [broken]);
+
+        // Retried until the command would be too old anyway
+        scheduler
+            .run_once(naive_from_timestamp(NOW + HOUR)?)
+            .await?;
+        assert!(status_schedule::list_for_device(conn, dev_id)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+}
@@ -1,29 +1,74 @@
-use crate::config::Config;
+use crate::channel::ServerKey;
+use crate::config::{Config, MetricsConfig};
+use crate::db::DbPool;
 use crate::handler::admin::admin_handler_iter;
 use crate::handler::device::device_handler_iter;
-use crate::handler::root::{health, register, websocket_upgrade};
+use crate::handler::root::{health, ready, register, websocket_upgrade};
+use crate::listener::{ConnectionAcceptor, Listener, TlsCertificates};
+use crate::live::subscribe;
+use crate::metrics::{metrics, restrict_scrapers, track_requests};
 use crate::middleware::{AdminAuthLayer, DeviceAuthLayer};
+use crate::notify::Notifier;
+use crate::protocol::add_protocol_headers;
+use crate::ratelimit::{limit_register, limit_websocket, reject_banned, RateLimits};
+use crate::retention::Retention;
+use crate::rules::RuleRunner;
+use crate::scheduler::StatusScheduler;
 use anyhow::Result;
+use axum::middleware::from_fn_with_state;
 use axum::routing::{get, post, Router};
-use sqlx::PgPool;
+use axum::Extension;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tower::ServiceBuilder;
+use tower_http::compression::predicate::{DefaultPredicate, NotForContentType, Predicate};
 use tower_http::compression::CompressionLayer;
 use tower_http::trace::{DefaultMakeSpan, DefaultOnRequest, TraceLayer};
-use tracing::{info, Level};
+use tracing::{error, info, Level};
 
-pub async fn make_router(db: PgPool, config: &Config) -> Result<Router> {
+pub async fn make_router(db: DbPool, config: &Config) -> Result<Router> {
+    let server_key = match &config.server_key_path {
+        Some(path) => ServerKey::load_or_create(path)?,
+        None => ServerKey(None),
+    };
+    let limits = RateLimits::new(&config.rate_limit);
+    limits.spawn_sweeper();
     let mut app = Router::new()
         .route("/health", get(health))
-        .route("/ws/:device_pk", get(websocket_upgrade))
-        .route("/register/:device_pk/name/:name", post(register))
+        .route("/health/live", get(health))
+        .route("/health/ready", get(ready))
+        .route(
+            "/ws/:device_pk",
+            get(websocket_upgrade).layer(
+                ServiceBuilder::new()
+                    .layer(from_fn_with_state(limits.clone(), limit_websocket))
+                    .layer(Extension(server_key))
+                    .layer(Extension(limits.clone())),
+            ),
+        )
+        .route(
+            "/register/:device_pk/name/:name",
+            post(register).layer(
+                ServiceBuilder::new()
+                    .layer(from_fn_with_state(limits.clone(), limit_register))
+                    .layer(Extension(config.registration.clone())),
+            ),
+        )
         .with_state::<()>(db.clone());
+    if config.metrics.listen.is_empty() {
+        app = app.merge(metrics_router(db.clone(), &config.metrics));
+    }
 
     let admin_router = admin_handler_iter()
         .fold(Router::new(), |router, handler| {
             router.route(handler.path, post(handler.http_handler))
         })
-        .layer(AdminAuthLayer::new(config.clone()))
+        .route("/subscribe", post(subscribe))
+        .layer(AdminAuthLayer::new(
+            config.clone(),
+            db.clone(),
+            limits.clone(),
+        ))
         .with_state(db.clone());
     app = app.nest("/admin", admin_router);
 
@@ -31,28 +76,82 @@ pub async fn make_router(db: PgPool, config: &Config) -> Result<Router> {
         .fold(Router::new(), |router, handler| {
             router.route(handler.path, post(handler.http_handler))
         })
-        .layer(DeviceAuthLayer::new(db.clone()))
+        .layer(DeviceAuthLayer::new(db.clone(), limits.clone()))
         .with_state(db);
     app = app.nest("/device/:device_pk", device_router);
 
-    app = app.layer(
-        ServiceBuilder::new()
-            .layer(
-                TraceLayer::new_for_http()
-                    .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
-                    .on_request(DefaultOnRequest::new().level(Level::INFO)),
-            )
-            .layer(CompressionLayer::new()),
-    );
+    app = app
+        .route_layer(axum::middleware::from_fn(track_requests))
+        .route_layer(from_fn_with_state(limits, reject_banned))
+        .layer(
+            ServiceBuilder::new()
+                .layer(
+                    TraceLayer::new_for_http()
+                        .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+                        .on_request(DefaultOnRequest::new().level(Level::INFO)),
+                )
+                // Compression buffers server-sent events, admins would never see them
+                .layer(CompressionLayer::new().compress_when(
+                    DefaultPredicate::new().and(NotForContentType::const_new("text/event-stream")),
+                ))
+                .layer(axum::middleware::map_response(add_protocol_headers)),
+        );
 
     Ok(app)
 }
 
-pub async fn run_server(db: PgPool, config: &Config) -> Result<()> {
-    let app = make_router(db, config).await?;
-    let fut = axum::Server::bind(&SocketAddr::new([0, 0, 0, 0].into(), config.port))
+/// `/metrics`, only for the addresses in `[metrics] allow` if there are any
+fn metrics_router(db: DbPool, config: &MetricsConfig) -> Router {
+    let mut router = Router::new().route("/metrics", get(metrics));
+    if !config.allow.is_empty() {
+        let allow = Arc::new(config.allow.clone());
+        router = router.route_layer(from_fn_with_state(allow, restrict_scrapers));
+    }
+    router.with_state(db)
+}
+
+pub async fn run_server(db: DbPool, config: &Config) -> Result<()> {
+    Notifier::new(db.clone(), config)?.spawn();
+    Retention::new(db.clone(), &config.retention).spawn();
+    StatusScheduler::new(db.clone()).spawn();
+    RuleRunner::new(db.clone()).spawn();
+    let app = make_router(db.clone(), config).await?;
+
+    let tls = match &config.tls {
+        Some(tls_config) => {
+            let certs = TlsCertificates::load(tls_config)?;
+            certs.reload_on_sighup()?;
+            Some(certs)
+        }
+        None => None,
+    };
+    let mut listeners = Vec::new();
+    for listen_config in config.listeners() {
+        let listener = Listener::bind(&listen_config, tls.as_ref()).await?;
+        info!(%listener, "Listening");
+        listeners.push(listener);
+    }
+
+    if !config.metrics.listen.is_empty() {
+        let mut metrics_listeners = Vec::new();
+        for listen_config in &config.metrics.listen {
+            let listener = Listener::bind(listen_config, tls.as_ref()).await?;
+            info!(%listener, "Serving metrics");
+            metrics_listeners.push(listener);
+        }
+        let metrics_app = metrics_router(db, &config.metrics);
+        let metrics_fut = axum::Server::builder(ConnectionAcceptor::new(metrics_listeners))
+            .serve(metrics_app.into_make_service_with_connect_info::<SocketAddr>());
+        tokio::spawn(async move {
+            if let Err(e) = metrics_fut.await {
+                error!("Metrics server failed: {e}");
+            }
+        });
+    }
+
+    let fut = axum::Server::builder(ConnectionAcceptor::new(listeners))
         .serve(app.into_make_service_with_connect_info::<SocketAddr>());
-    info!(port = config.port, "Server ready");
+    info!("Server ready");
     fut.await?;
     Ok(())
 }
@@ -64,8 +163,21 @@ pub struct TestServer {
     pub root_key: ed25519_dalek::SigningKey,
 }
 
+/// Runs a real aegisd on a local port, returns its address
+#[cfg(test)]
+pub async fn serve_test_server(db: DbPool, config: &Config) -> Result<SocketAddr> {
+    let app = make_router(db, config).await?;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+    tokio::spawn(server);
+    Ok(addr)
+}
+
 #[cfg(test)]
-pub async fn make_test_server(db: PgPool) -> Result<TestServer> {
+pub async fn make_test_server(db: DbPool) -> Result<TestServer> {
     let root_key = ed25519_dalek::SigningKey::generate(&mut rand::thread_rng());
     let config = Config::test_config(root_key.verifying_key());
     let app = make_router(db, &config).await?;
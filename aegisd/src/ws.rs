@@ -1,21 +1,40 @@
+use crate::db::DbPool;
 use crate::error::Error;
 use crate::handler::device::{device_handler_iter, DeviceHandlerFn, DeviceId};
-use aegislib::command::server::ServerCommand;
+use crate::live::publish_for_device;
+use crate::metrics::{
+    record_rate_limited, record_request, record_send_failure, record_signature_failure, RateLimit,
+    SignatureClient,
+};
+use crate::model::notifications::Trigger;
+use crate::model::{connections, device};
+use crate::notify::notify_device;
+use crate::ratelimit::{RateLimits, TokenBucket};
+use crate::rules::{self, RuleEvent};
+use crate::scheduler;
+use aegislib::command::admin::LiveEvent;
+use aegislib::command::server::{ServerCommand, StatusUpdate};
+use aegislib::command::signed::SignedCommand;
+use aegislib::crypto::channel::{Opener, Sealer};
 use aegislib::crypto::check_signature;
-use anyhow::anyhow;
+use aegislib::protocol::{Capability, PeerProtocol};
+use anyhow::{anyhow, bail};
 use async_stream::stream;
 use axum::body::Bytes;
 use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
 use base64::prelude::*;
+use chrono::Utc;
 use dashmap::DashMap;
 use ed25519_dalek::VerifyingKey;
 use futures::pin_mut;
 use futures::StreamExt;
-use sqlx::PgPool;
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::select;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::Notify;
 use tracing::{error, info, warn};
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
@@ -30,15 +49,104 @@ lazy_static::lazy_static! {
         m
     };
 
-    static ref WS_CLIENT_MAP: DashMap<DeviceId, Sender<ServerCommand>> = DashMap::new();
+    static ref WS_CLIENT_MAP: DashMap<DeviceId, DeviceWs> = DashMap::new();
 }
 
-pub fn ws_for_device(dev_id: DeviceId) -> Option<Sender<ServerCommand>> {
+/// Send side of a connected device's websocket
+#[derive(Clone)]
+pub struct DeviceWs {
+    tx: Sender<ServerCommand>,
+    disconnect: Arc<Notify>,
+    pub protocol: PeerProtocol,
+}
+
+impl DeviceWs {
+    /// Fails if the device didn't advertise the capability to understand this command
+    pub fn check_supported(&self, cmd: &ServerCommand) -> anyhow::Result<()> {
+        let capability = cmd.required_capability();
+        if !self.protocol.supports(capability) {
+            bail!(
+                "Device does not support {capability} (protocol version {})",
+                self.protocol.version
+            );
+        }
+        Ok(())
+    }
+
+    pub async fn send(&self, cmd: ServerCommand) -> anyhow::Result<()> {
+        self.check_supported(&cmd)?;
+        self.tx.send(cmd).await.map_err(|_| {
+            record_send_failure();
+            anyhow!("Websocket send queue closed")
+        })
+    }
+}
+
+pub fn ws_for_device(dev_id: DeviceId) -> Option<DeviceWs> {
     WS_CLIENT_MAP.get(&dev_id).map(|a| a.clone())
 }
 
+/// Sends a device its new status if it is connected. Devices that check signatures get the
+/// admin's `command` instead, they only accept unsigned updates that lock further.
+/// Returns whether it was sent.
+pub async fn push_status(
+    dev_id: DeviceId,
+    status: StatusUpdate,
+    command: Option<SignedCommand>,
+) -> bool {
+    let Some(ws) = ws_for_device(dev_id) else {
+        return false;
+    };
+    let server_cmd = match command {
+        Some(command) if ws.protocol.supports(Capability::SignedCommands) => {
+            ServerCommand::Signed(command)
+        }
+        _ => status.into(),
+    };
+    match ws.send(server_cmd).await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(
+                "Failed to send status update to websocket for device {}: {e}",
+                dev_id.0
+            );
+            false
+        }
+    }
+}
+
+/// Closes the device's websocket, if it is connected. Returns whether it was.
+pub fn disconnect_device(dev_id: DeviceId) -> bool {
+    match WS_CLIENT_MAP.remove(&dev_id) {
+        Some((_, ws)) => {
+            ws.disconnect.notify_one();
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn connected_device_count() -> usize {
+    WS_CLIENT_MAP.len()
+}
+
+/// Sends a data message, sealed if the device set up an encrypted channel
+async fn send_data(
+    ws: &mut WebSocket,
+    sealer: &mut Option<Sealer>,
+    data: Vec<u8>,
+) -> Result<(), Error> {
+    let data = match sealer {
+        Some(sealer) => sealer.seal(&data),
+        None => data,
+    };
+    ws.send(Message::Binary(data)).await?;
+    Ok(())
+}
+
 async fn send_response(
     ws: &mut WebSocket,
+    sealer: &mut Option<Sealer>,
     ok: bool,
     msg_id: &[u8],
     payload: &[u8],
@@ -50,45 +158,171 @@ async fn send_response(
         msg.extend_from_slice(b" err ");
     };
     msg.extend_from_slice(payload);
-    ws.send(Message::Binary(msg)).await?;
-    Ok(())
+    send_data(ws, sealer, msg).await
 }
 
-async fn send_server_command(ws: &mut WebSocket, cmd: ServerCommand) -> Result<(), Error> {
+async fn send_server_command(
+    ws: &mut WebSocket,
+    sealer: &mut Option<Sealer>,
+    cmd: ServerCommand,
+) -> Result<(), Error> {
     // WS server message format: <handler> <payload>
     let mut payload = b"server_command ".to_vec();
     bincode::serialize_into(&mut payload, &cmd).unwrap();
-    ws.send(Message::Binary(payload)).await?;
-    Ok(())
+    send_data(ws, sealer, payload).await
 }
 
 pub struct WsConn {
-    db: PgPool,
+    db: DbPool,
     device_pk: VerifyingKey,
     device_id: DeviceId,
+    protocol: PeerProtocol,
+    sealer: Option<Sealer>,
+    opener: Option<Opener>,
     last_heartbeat: Instant,
+    remote_addr: SocketAddr,
     remote_addr_untrusted: String,
+    limits: RateLimits,
+    message_bucket: Option<TokenBucket>,
+    /// Whether this connection was charged to its device key's rate limit yet
+    key_rate_limited: bool,
 }
 
 impl WsConn {
     pub fn new(
-        db: PgPool,
+        db: DbPool,
         device_pk: VerifyingKey,
         device_id: DeviceId,
-        remote_addr_untrusted: String,
+        protocol: PeerProtocol,
+        channel: Option<(Sealer, Opener)>,
+        remote_addr: SocketAddr,
+        limits: RateLimits,
     ) -> WsConn {
+        let (sealer, opener) = channel.unzip();
+        let message_bucket = limits.ws_message_bucket();
         WsConn {
             db,
             device_pk,
             device_id,
+            protocol,
+            sealer,
+            opener,
             last_heartbeat: Instant::now(),
-            remote_addr_untrusted,
+            remote_addr,
+            remote_addr_untrusted: remote_addr.to_string(),
+            limits,
+            message_bucket,
+            key_rate_limited: false,
         }
     }
 
     pub async fn handle(mut self, mut ws: WebSocket) -> Result<(), Error> {
         let (send_queue_tx, mut send_queue_rx) = tokio::sync::mpsc::channel(4);
-        WS_CLIENT_MAP.insert(self.device_id, send_queue_tx);
+        let disconnect = Arc::new(Notify::new());
+        WS_CLIENT_MAP.insert(
+            self.device_id,
+            DeviceWs {
+                tx: send_queue_tx.clone(),
+                disconnect: disconnect.clone(),
+                protocol: self.protocol.clone(),
+            },
+        );
+        self.publish_connection_change(true).await;
+        if let Ok(mut conn) = self.db.acquire().await {
+            scheduler::relay_due(&mut conn, self.device_id.0, Utc::now().naive_utc()).await;
+        }
+        self.evaluate_connected_rules().await;
+        let connection_id = self.open_connection_log().await;
+        let result = self.run(&mut ws, &mut send_queue_rx, &disconnect).await;
+        if let Some(id) = connection_id {
+            self.close_connection_log(id).await;
+        }
+        if let Ok(mut conn) = self.db.acquire().await {
+            rules::evaluate(&mut conn, self.device_id.0, RuleEvent::Disconnected).await;
+        }
+        // A newer connection from the same device may have replaced our entry already
+        WS_CLIENT_MAP.remove_if(&self.device_id, |_, dev_ws| {
+            dev_ws.tx.same_channel(&send_queue_tx)
+        });
+        self.publish_connection_change(false).await;
+        result
+    }
+
+    /// Must run before this connection is logged, so its address doesn't count as known
+    async fn evaluate_connected_rules(&self) {
+        let Ok(mut conn) = self.db.acquire().await else {
+            return;
+        };
+        let dev_id = self.device_id.0;
+        let locked = match device::get_status(&mut conn, dev_id).await {
+            Ok(status) => status.vt_locked || status.ssh_locked,
+            Err(_) => false,
+        };
+        let new_address = match connections::list_for_device(&mut conn, dev_id).await {
+            Ok(previous) => !previous.iter().any(|connection| {
+                connection
+                    .remote_addr
+                    .parse::<SocketAddr>()
+                    .is_ok_and(|addr| addr.ip() == self.remote_addr.ip())
+            }),
+            Err(_) => false,
+        };
+        let event = RuleEvent::Connected {
+            locked,
+            new_address,
+        };
+        rules::evaluate(&mut conn, dev_id, event).await;
+    }
+
+    async fn open_connection_log(&self) -> Option<i32> {
+        let mut conn = self.db.acquire().await.ok()?;
+        let remote_addr = &self.remote_addr_untrusted;
+        match connections::open(&mut conn, self.device_id.0, remote_addr).await {
+            Ok(id) => Some(id),
+            Err(e) => {
+                error!(remote_addr, "Failed to log device connection: {e}");
+                None
+            }
+        }
+    }
+
+    async fn close_connection_log(&self, id: i32) {
+        let Ok(mut conn) = self.db.acquire().await else {
+            return;
+        };
+        if let Err(e) = connections::close(&mut conn, id).await {
+            error!(
+                remote_addr = &self.remote_addr_untrusted,
+                "Failed to log device disconnection: {e}"
+            );
+        }
+    }
+
+    async fn publish_connection_change(&self, connected: bool) {
+        let mut conn = match self.db.acquire().await {
+            Ok(conn) => conn,
+            Err(_) => return,
+        };
+        let timestamp = Utc::now().timestamp() as u64;
+        publish_for_device(&mut conn, self.device_id.0, |dev_name| match connected {
+            true => LiveEvent::Connected {
+                dev_name,
+                timestamp,
+            },
+            false => LiveEvent::Disconnected {
+                dev_name,
+                timestamp,
+            },
+        })
+        .await
+    }
+
+    async fn run(
+        &mut self,
+        ws: &mut WebSocket,
+        send_queue_rx: &mut Receiver<ServerCommand>,
+        disconnect: &Notify,
+    ) -> Result<(), Error> {
         let heartbeat = stream! {
             loop {
                 tokio::time::sleep(HEARTBEAT_INTERVAL).await;
@@ -103,7 +337,16 @@ impl WsConn {
                 },
                 msg = send_queue_rx.recv() => {
                     let msg = msg.ok_or_else(|| anyhow!("Send queue tx dropped!"))?;
-                    send_server_command(&mut ws, msg).await?;
+                    send_server_command(ws, &mut self.sealer, msg).await?;
+                },
+                _ = disconnect.notified() => {
+                    info!(remote_addr = &self.remote_addr_untrusted, "Device key revoked, disconnecting");
+                    let close_msg = CloseFrame {
+                        code: close_code::POLICY,
+                        reason: "Device key revoked".into(),
+                    };
+                    let _ = ws.send(Message::Close(Some(close_msg))).await;
+                    break;
                 },
                 msg = ws.recv() => {
                     let msg = match msg {
@@ -117,7 +360,7 @@ impl WsConn {
                             break;
                         }
                     };
-                    if let Err(close_msg) = self.handle_ws_msg(&mut ws, msg).await {
+                    if let Err(close_msg) = self.handle_ws_msg(ws, msg).await {
                         let _ = ws.send(Message::Close(close_msg)).await;
                         break;
                     }
@@ -138,8 +381,14 @@ impl WsConn {
         msg: Message,
     ) -> Result<(), Option<CloseFrame<'static>>> {
         match msg {
-            Message::Text(payload) => self.handle_message_data(ws, payload.into_bytes()).await,
-            Message::Binary(payload) => self.handle_message_data(ws, payload).await,
+            Message::Text(payload) => {
+                self.check_message_rate()?;
+                self.handle_sealed_data(ws, payload.into_bytes()).await
+            }
+            Message::Binary(payload) => {
+                self.check_message_rate()?;
+                self.handle_sealed_data(ws, payload).await
+            }
             Message::Ping(msg) => {
                 self.last_heartbeat = Instant::now();
                 ws.send(Message::Pong(msg)).await.map_err(|e| {
@@ -162,8 +411,44 @@ impl WsConn {
         }
     }
 
+    fn check_message_rate(&mut self) -> Result<(), Option<CloseFrame<'static>>> {
+        let bucket = match &mut self.message_bucket {
+            Some(bucket) => bucket,
+            None => return Ok(()),
+        };
+        if bucket.try_take(Instant::now()) {
+            return Ok(());
+        }
+        let remote_addr = &self.remote_addr_untrusted;
+        warn!(%remote_addr, "Too many websocket messages, closing");
+        record_rate_limited(RateLimit::WebsocketMessage);
+        Err(Some(CloseFrame {
+            code: close_code::AGAIN,
+            reason: "Too many messages".into(),
+        }))
+    }
+
+    async fn handle_sealed_data(
+        &mut self,
+        ws: &mut WebSocket,
+        payload: Vec<u8>,
+    ) -> Result<(), Option<CloseFrame<'static>>> {
+        let payload = match &mut self.opener {
+            None => payload,
+            Some(opener) => opener.open(&payload).map_err(|e| {
+                let remote_addr = self.remote_addr_untrusted.as_str();
+                warn!(%remote_addr, "Invalid encrypted websocket message: {e}");
+                Some(CloseFrame {
+                    code: close_code::POLICY,
+                    reason: "Invalid encrypted message".into(),
+                })
+            })?,
+        };
+        self.handle_message_data(ws, payload).await
+    }
+
     async fn handle_message_data(
-        &self,
+        &mut self,
         ws: &mut WebSocket,
         raw_payload: Vec<u8>,
     ) -> Result<(), Option<CloseFrame<'static>>> {
@@ -210,17 +495,40 @@ impl WsConn {
         // msg_id is actually also a randomized signature!
         if !check_signature(&self.device_pk, &signature, handler.as_bytes(), data) {
             warn!(%remote_addr, %handler, "Invalid websocket message signature");
+            record_signature_failure(SignatureClient::DeviceWebsocket);
+            self.limits.record_forged_signature(self.remote_addr);
+            if let Ok(mut conn) = self.db.acquire().await {
+                let msg = format!("Forged websocket message signature from {remote_addr}");
+                notify_device(&mut conn, Trigger::ForgedSignature, self.device_id.0, &msg).await;
+            }
             return Err(Some(CloseFrame {
                 code: close_code::POLICY,
                 reason: "invalid signature".into(),
             }));
         }
+        // Only now do we know the device holds its key, so nobody else can use up its connections
+        if !self.key_rate_limited {
+            self.key_rate_limited = true;
+            if !self
+                .limits
+                .check_device_key(self.remote_addr, &self.device_pk)
+            {
+                warn!(%remote_addr, "Too many websocket connections for the same device key");
+                record_rate_limited(RateLimit::WebsocketKey);
+                return Err(Some(CloseFrame {
+                    code: close_code::AGAIN,
+                    reason: "Too many connections".into(),
+                }));
+            }
+        }
 
-        let handler = match HANDLER_MAP.get(handler) {
-            Some(handler) => handler,
+        let start = Instant::now();
+        let (path, handler) = match HANDLER_MAP.get_key_value(handler) {
+            Some((path, handler)) => (format!("ws/{path}"), handler),
             _ => {
                 warn!(%remote_addr, "Websocket handler not found: {handler}");
-                send_response(ws, false, msg_id, b"handler not found")
+                record_request("ws/unknown", "not_found", start);
+                send_response(ws, &mut self.sealer, false, msg_id, b"handler not found")
                     .await
                     .map_err(|_| None)?;
                 return Ok(());
@@ -230,16 +538,29 @@ impl WsConn {
         let db = self.db.clone();
         let dev_id = self.device_id;
         let data = raw_payload.slice_ref(data);
-        match handler(db, dev_id, data).await {
-            Ok(reply) => send_response(ws, true, msg_id, &reply).await,
-            Err(e) => send_response(ws, false, msg_id, format!("{e}").as_bytes()).await,
+        let result = handler(db, dev_id, data).await;
+        record_request(&path, if result.is_ok() { "ok" } else { "err" }, start);
+        // This connection was authenticated with the old key, the device reconnects with its new one
+        let rotated_key = path == "ws/rotate_key" && result.is_ok();
+        match result {
+            Ok(reply) => send_response(ws, &mut self.sealer, true, msg_id, &reply).await,
+            Err(e) => {
+                let msg = format!("{e}");
+                send_response(ws, &mut self.sealer, false, msg_id, msg.as_bytes()).await
+            }
+        }
+        .map_err(|e| {
+            Some(CloseFrame {
+                code: close_code::ERROR,
+                reason: format!("Failed to send handler response: {e}").into(),
+            })
+        })?;
+        if rotated_key {
+            return Err(Some(CloseFrame {
+                code: close_code::NORMAL,
+                reason: "Device key rotated".into(),
+            }));
         }
-            .map_err(|e| {
-                Some(CloseFrame {
-                    code: close_code::ERROR,
-                    reason: format!("Failed to send handler response: {e}").into(),
-                })
-            })?;
         Ok(())
     }
 }
@@ -1,7 +1,12 @@
 mod api_client;
 
+use crate::protocol::{
+    PeerProtocol, CAPABILITIES_HEADER, ENROLLMENT_TOKEN_HEADER, MIN_SERVER_PROTOCOL_VERSION,
+    PROTOCOL_VERSION_HEADER,
+};
 pub use api_client::*;
 use base64::prelude::*;
+use reqwest::header::HeaderMap;
 pub use reqwest::StatusCode;
 use thiserror::Error;
 
@@ -11,12 +16,18 @@ pub use dev_client::*;
 mod admin_client;
 pub use admin_client::*;
 
+mod live_events;
+pub use live_events::*;
+
 mod rest_client;
 pub use rest_client::*;
 
 mod ws_client;
 pub use ws_client::*;
 
+mod tls;
+pub use tls::read_pem;
+
 #[derive(Debug, Clone, Error)]
 #[error("Client HTTP error {}: {}", u16::from(*.code), .message.as_deref().unwrap_or_else(|| .code.canonical_reason().unwrap_or("<unknown status code>"))
 )]
@@ -35,33 +46,79 @@ pub enum ClientError {
     Other(#[from] anyhow::Error),
 }
 
+impl ClientError {
+    /// Whether the server refused us, as opposed to us failing to reach it or to finish a request
+    pub fn is_forbidden(&self) -> bool {
+        matches!(self, ClientError::Http(e) if e.code == StatusCode::FORBIDDEN)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ClientConfig {
     pub server_addr: String,
     pub use_tls: bool,
     pub use_rest: bool,
+    /// PEM certificates of CAs trusted in addition to the webpki roots
+    pub extra_ca_certs_pem: Vec<String>,
+    /// If set, the server must present exactly this PEM certificate
+    pub pinned_server_cert_pem: Option<String>,
+    /// If set, the server's public key must have this SHA-256 (base64 of the DER SPKI)
+    pub pinned_spki_sha256: Option<String>,
+    /// The server's channel key (URL-safe base64). If set, device websockets are end-to-end
+    /// encrypted with the server, whether or not TLS is in the way
+    pub server_public_key: Option<String>,
+}
+
+impl ClientConfig {
+    /// A reqwest client that verifies the server like every other connection we make
+    pub(crate) fn http_client(&self) -> Result<reqwest::Client, ClientError> {
+        let tls_config = tls::tls_client_config(self)?;
+        Ok(reqwest::Client::builder()
+            .use_preconfigured_tls((*tls_config).clone())
+            .build()
+            .map_err(anyhow::Error::from)?)
+    }
+}
+
+/// Reads the protocol the server advertised in its response, and checks we still speak it
+pub(crate) fn check_server_protocol(headers: &HeaderMap) -> Result<PeerProtocol, ClientError> {
+    let protocol = PeerProtocol::from_header_values(
+        headers.get(PROTOCOL_VERSION_HEADER).map(|v| v.as_bytes()),
+        headers.get(CAPABILITIES_HEADER).map(|v| v.as_bytes()),
+    )
+    .map_err(anyhow::Error::from)?;
+    protocol
+        .check_min_version(MIN_SERVER_PROTOCOL_VERSION)
+        .map_err(anyhow::Error::from)?;
+    Ok(protocol)
 }
 
+/// Registers a device pending confirmation by an admin. With an enrollment token, the server
+/// may confirm it right away or queue it with the other devices of the token.
 pub async fn register_device(
     config: &ClientConfig,
     name: &str,
     pk: &ed25519_dalek::VerifyingKey,
+    enrollment_token: Option<&str>,
 ) -> Result<(), ClientError> {
     let pk = BASE64_URL_SAFE_NO_PAD.encode(pk);
-    let client = reqwest::Client::new();
+    let client = config.http_client()?;
     let proto = if config.use_tls {
         "https://"
     } else {
         "http://"
     };
-    let reply = client
-        .post(format!(
-            "{proto}{addr}/register/{pk}/name/{name}",
-            addr = &config.server_addr
-        ))
-        .send()
-        .await
-        .map_err(anyhow::Error::from)?;
+    let mut request = client.post(format!(
+        "{proto}{addr}/register/{pk}/name/{name}",
+        addr = &config.server_addr
+    ));
+    for (name, value) in PeerProtocol::current().header_values() {
+        request = request.header(name, value);
+    }
+    if let Some(token) = enrollment_token {
+        request = request.header(ENROLLMENT_TOKEN_HEADER, token);
+    }
+    let reply = request.send().await.map_err(anyhow::Error::from)?;
     if reply.status().as_u16() == StatusCode::CONFLICT {
         return Err(ClientError::Http(ClientHttpError {
             code: StatusCode::CONFLICT,
@@ -74,5 +131,6 @@ pub async fn register_device(
             message: reply.text().await.ok(),
         }));
     }
+    check_server_protocol(reply.headers())?;
     Ok(())
 }
@@ -1,27 +1,51 @@
-use crate::client::{ApiClient, ClientConfig, RestClient};
+use crate::client::{ApiClient, ClientConfig, LiveEventStream, RestClient};
 use crate::command::admin::{
-    PendingDevice, RegisteredDevice, SendPowerCommandArg, SetStatusArg, StoredCameraPicture,
+    ApplyProfileArg, ArchivedDevice, AutomationRule, CreateEnrollmentTokenArg,
+    CreatedEnrollmentToken, DeviceExport, DeviceRetention, EnrollmentToken, EventPage,
+    FetchFilesArg, FetchedChunkArg, FetchedFile, FileFetchIdArg, FileFetchInfo, GetEventsArg,
+    GetPicturesArg, ImportDevicesReply, ImportedDevice, ListRuleExecutionsArg, PendingDevice,
+    PictureIdArg, PictureInfoPage, PicturePage, ProtectionProfile, RegisteredDevice,
+    RenameDeviceArg, RuleExecution, RunScriptArg, ScheduledStatusChange, ScriptOutput, ScriptRun,
+    ScriptRunIdArg, SendPowerCommandArg, SetCapturePolicyArg, SetDeviceInfoArg, SetRetentionArg,
+    SetStatusArg, SetStolenArg, SignedArg, SignedStatusArg, StatusHistoryEntry, StatusSchedule,
+    StatusScheduleIdArg, StoredCameraPicture, SubscribeArg,
 };
 use crate::command::device::{DeviceEvent, StatusReply};
-use crate::command::server::PowerCommand;
+use crate::command::server::{CapturePolicy, FileFetch, PowerCommand, Script};
+use crate::command::signed::{AdminCommand, SignedCommand};
+use crate::crypto::evidence::EvidenceBundle;
+use crate::crypto::sealed::{self, FileOpener};
 use crate::crypto::{randomized_signature, RootKeys};
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub struct AdminClient {
     client: RestClient,
     key: ed25519_dalek::SigningKey,
+    /// Opens the files that devices seal for us
+    enc: chacha20poly1305::Key,
+}
+
+/// IDs the admin picks for what devices report back, they fit the server's signed columns
+fn random_id() -> Result<u64> {
+    let mut id = [0u8; 8];
+    getrandom::getrandom(&mut id)?;
+    Ok(u64::from_le_bytes(id) >> 1)
 }
 
 impl AdminClient {
     pub async fn new(config: &ClientConfig, keys: &RootKeys) -> Result<Self> {
-        let client = RestClient::new_client(config).await;
+        let client = RestClient::new_client(config).await?;
         Ok(AdminClient {
             client,
             // No Clone, because let's frustrate people until they decide to use libsodium instead :(
             // Yes, we make a copy of a key. Hope no one dumps my ram before both copies get zeroed...
             key: ed25519_dalek::SigningKey::from_bytes(&keys.sig.to_bytes()),
+            enc: keys.enc,
         })
     }
 
@@ -37,6 +61,26 @@ impl AdminClient {
         Ok(bincode::deserialize(&reply)?)
     }
 
+    /// Signs `command` for the device named `dev_name`, so it can check the command came from us
+    async fn device_pubkey(&mut self, dev_name: &str) -> Result<String> {
+        let device = self
+            .list_registered()
+            .await?
+            .into_iter()
+            .find(|d| d.name == dev_name)
+            .ok_or_else(|| anyhow!("Device {dev_name} not found"))?;
+        Ok(device.pubkey)
+    }
+
+    async fn sign_command(
+        &mut self,
+        dev_name: &str,
+        command: AdminCommand,
+    ) -> Result<SignedCommand> {
+        let pubkey = self.device_pubkey(dev_name).await?;
+        Ok(SignedCommand::sign(&self.key, pubkey, command))
+    }
+
     pub async fn list_pending(&mut self) -> Result<Vec<PendingDevice>> {
         self.do_request("list_pending_devices", ()).await
     }
@@ -49,23 +93,212 @@ impl AdminClient {
         self.do_request("confirm_pending_device", name).await
     }
 
+    pub async fn create_enrollment_token(
+        &mut self,
+        arg: CreateEnrollmentTokenArg,
+    ) -> Result<CreatedEnrollmentToken> {
+        self.do_request("create_enrollment_token", arg).await
+    }
+
+    pub async fn list_enrollment_tokens(&mut self) -> Result<Vec<EnrollmentToken>> {
+        self.do_request("list_enrollment_tokens", ()).await
+    }
+
+    /// Devices that already registered with the token stay registered or pending
+    pub async fn delete_enrollment_token(&mut self, id: i32) -> Result<()> {
+        self.do_request("delete_enrollment_token", id).await
+    }
+
+    /// The devices waiting for confirmation after registering with the token
+    pub async fn list_token_pending(&mut self, id: i32) -> Result<Vec<PendingDevice>> {
+        self.do_request("list_token_pending_devices", id).await
+    }
+
+    pub async fn import_devices(
+        &mut self,
+        devices: Vec<ImportedDevice>,
+    ) -> Result<ImportDevicesReply> {
+        self.do_request("import_devices", devices).await
+    }
+
     pub async fn list_registered(&mut self) -> Result<Vec<RegisteredDevice>> {
         self.do_request("list_registered_devices", ()).await
     }
 
-    pub async fn delete_registered(&mut self, name: String) -> Result<()> {
-        self.do_request("delete_registered_device", name).await
+    /// Works for archived devices too
+    pub async fn export_device(&mut self, name: String) -> Result<DeviceExport> {
+        self.do_request("export_device", name).await
+    }
+
+    /// Signs the bundle with our key, see [`crate::crypto::evidence::verify`]
+    pub fn sign_evidence(&self, bundle: EvidenceBundle) -> BTreeMap<String, Vec<u8>> {
+        bundle.sign(&self.key)
+    }
+
+    pub async fn list_archived(&mut self) -> Result<Vec<ArchivedDevice>> {
+        self.do_request("list_archived_devices", ()).await
+    }
+
+    /// Revokes the device's key and disconnects it, but keeps its pictures and events
+    pub async fn archive_device(&mut self, name: String) -> Result<()> {
+        self.do_request("archive_device", name).await
+    }
+
+    pub async fn restore_device(&mut self, name: String) -> Result<()> {
+        self.do_request("restore_device", name).await
+    }
+
+    /// Deletes an archived device with all its pictures and events, this can't be undone
+    pub async fn purge_device(&mut self, name: String) -> Result<()> {
+        self.do_request("purge_device", name).await
+    }
+
+    pub async fn rename_device(&mut self, dev_name: String, new_name: String) -> Result<()> {
+        let arg = RenameDeviceArg { dev_name, new_name };
+        self.do_request("rename_device", arg).await
     }
 
+    pub async fn set_device_info(&mut self, arg: SetDeviceInfoArg) -> Result<()> {
+        self.do_request("set_device_info", arg).await
+    }
+
+    /// Scheduled changes return the current status, they're only applied later
     pub async fn set_status(&mut self, arg: SetStatusArg) -> Result<StatusReply> {
+        let pubkey = self.device_pubkey(&arg.dev_name).await?;
+        let applies_at = match arg.schedule {
+            Some(StatusSchedule::At { timestamp }) => timestamp,
+            _ => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        };
+        let change = arg.change();
+        let command = SignedCommand::sign_at(
+            &self.key,
+            pubkey.clone(),
+            AdminCommand::SetStatus(change),
+            applies_at,
+        );
+        let revert = arg.expires_after_secs.map(|secs| {
+            let revert = AdminCommand::SetStatus(change.inverted());
+            SignedCommand::sign_at(&self.key, pubkey, revert, applies_at + secs)
+        });
+        let arg = SignedStatusArg {
+            arg,
+            command,
+            revert,
+        };
         self.do_request("set_status", arg).await
     }
 
+    /// Oldest first
+    pub async fn status_history(&mut self, dev_name: String) -> Result<Vec<StatusHistoryEntry>> {
+        self.do_request("status_history", dev_name).await
+    }
+
+    pub async fn list_status_schedules(
+        &mut self,
+        dev_name: String,
+    ) -> Result<Vec<ScheduledStatusChange>> {
+        self.do_request("list_status_schedules", dev_name).await
+    }
+
+    pub async fn cancel_status_schedule(&mut self, arg: StatusScheduleIdArg) -> Result<()> {
+        self.do_request("cancel_status_schedule", arg).await
+    }
+
+    pub async fn list_profiles(&mut self) -> Result<Vec<ProtectionProfile>> {
+        self.do_request("list_profiles", ()).await
+    }
+
+    /// Creates the profile, or replaces the one with the same name.
+    /// Devices running the old version keep it until it is applied again.
+    pub async fn set_profile(&mut self, profile: ProtectionProfile) -> Result<()> {
+        self.do_request("set_profile", profile).await
+    }
+
+    pub async fn delete_profile(&mut self, name: String) -> Result<()> {
+        self.do_request("delete_profile", name).await
+    }
+
+    /// Applies the profile's status, and starts its behaviors on the device if it's connected
+    pub async fn apply_profile(&mut self, dev_name: String, name: String) -> Result<StatusReply> {
+        let profile = self
+            .list_profiles()
+            .await?
+            .into_iter()
+            .find(|p| p.name == name)
+            .ok_or_else(|| anyhow!("Protection profile {name} not found"))?;
+        let pubkey = self.device_pubkey(&dev_name).await?;
+        let profile_command = SignedCommand::sign(
+            &self.key,
+            pubkey.clone(),
+            AdminCommand::SetProfile(Some(profile.device_profile())),
+        );
+        let status_command = SignedCommand::sign(
+            &self.key,
+            pubkey,
+            AdminCommand::SetStatus(profile.status_change()),
+        );
+        let arg = ApplyProfileArg {
+            dev_name,
+            profile: Some(name),
+            profile_command,
+            status_command: Some(status_command),
+        };
+        self.do_request("apply_profile", arg).await
+    }
+
+    /// Stops the behaviors of the device's active profile, its status is left as it is
+    pub async fn clear_profile(&mut self, dev_name: String) -> Result<StatusReply> {
+        let profile_command = self
+            .sign_command(&dev_name, AdminCommand::SetProfile(None))
+            .await?;
+        let arg = ApplyProfileArg {
+            dev_name,
+            profile: None,
+            profile_command,
+            status_command: None,
+        };
+        self.do_request("apply_profile", arg).await
+    }
+
     pub async fn delete_device_camera_pictures(&mut self, dev_name: String) -> Result<()> {
         self.do_request("delete_device_camera_pictures", dev_name)
             .await
     }
 
+    pub async fn list_device_camera_pictures(
+        &mut self,
+        arg: GetPicturesArg,
+    ) -> Result<PicturePage> {
+        self.do_request("list_device_camera_pictures", arg).await
+    }
+
+    pub async fn list_device_picture_info(
+        &mut self,
+        arg: GetPicturesArg,
+    ) -> Result<PictureInfoPage> {
+        self.do_request("list_device_picture_info", arg).await
+    }
+
+    pub async fn get_device_camera_picture(
+        &mut self,
+        arg: PictureIdArg,
+    ) -> Result<StoredCameraPicture> {
+        self.do_request("get_device_camera_picture", arg).await
+    }
+
+    /// A small JPEG preview of a picture, generated by the server
+    pub async fn get_device_picture_thumbnail(
+        &mut self,
+        arg: PictureIdArg,
+    ) -> Result<StoredCameraPicture> {
+        self.do_request("get_device_picture_thumbnail", arg).await
+    }
+
+    pub async fn delete_device_camera_picture(&mut self, arg: PictureIdArg) -> Result<()> {
+        self.do_request("delete_device_camera_picture", arg).await
+    }
+
+    /// Every stored picture at once, prefer [`Self::list_device_camera_pictures`]
     pub async fn get_device_camera_pictures(
         &mut self,
         dev_name: String,
@@ -74,24 +307,199 @@ impl AdminClient {
             .await
     }
 
+    pub async fn get_device_retention(&mut self, dev_name: String) -> Result<DeviceRetention> {
+        self.do_request("get_device_retention", dev_name).await
+    }
+
+    pub async fn set_device_retention(&mut self, arg: SetRetentionArg) -> Result<()> {
+        self.do_request("set_device_retention", arg).await
+    }
+
+    /// Stolen devices keep all their events and pictures until they are recovered
+    pub async fn set_device_stolen(&mut self, arg: SetStolenArg) -> Result<()> {
+        self.do_request("set_device_stolen", arg).await
+    }
+
+    pub async fn get_capture_policy(&mut self, dev_name: String) -> Result<CapturePolicy> {
+        self.do_request("get_capture_policy", dev_name).await
+    }
+
+    /// Connected devices get the new policy right away, the others when they reconnect
+    pub async fn set_capture_policy(&mut self, arg: SetCapturePolicyArg) -> Result<()> {
+        self.do_request("set_capture_policy", arg).await
+    }
+
+    pub async fn list_rules(&mut self) -> Result<Vec<AutomationRule>> {
+        self.do_request("list_rules", ()).await
+    }
+
+    /// Creates the rule, or replaces the one with the same name
+    pub async fn set_rule(&mut self, rule: AutomationRule) -> Result<()> {
+        self.do_request("set_rule", rule).await
+    }
+
+    pub async fn delete_rule(&mut self, name: String) -> Result<()> {
+        self.do_request("delete_rule", name).await
+    }
+
+    pub async fn list_rule_executions(
+        &mut self,
+        arg: ListRuleExecutionsArg,
+    ) -> Result<Vec<RuleExecution>> {
+        self.do_request("list_rule_executions", arg).await
+    }
+
     pub async fn send_power_command(&mut self, dev_name: String, cmd: PowerCommand) -> Result<()> {
-        self.do_request(
-            "send_power_command",
-            SendPowerCommandArg {
-                dev_name,
-                command: cmd,
-            },
-        )
-        .await
+        let command = self
+            .sign_command(&dev_name, AdminCommand::Power(cmd))
+            .await?;
+        let arg = SendPowerCommandArg {
+            dev_name,
+            command: cmd,
+        };
+        self.do_request("send_power_command", SignedArg { arg, command })
+            .await
+    }
+
+    /// Sends a signed script to a connected device, returns the ID its result is stored under
+    pub async fn run_script(
+        &mut self,
+        dev_name: String,
+        source: String,
+        timeout_secs: u32,
+        env: Vec<(String, String)>,
+    ) -> Result<u64> {
+        let script = Script {
+            run_id: random_id()?,
+            source,
+            timeout_secs,
+            env,
+        };
+        script.check()?;
+        let command = self
+            .sign_command(&dev_name, AdminCommand::RunScript(script.clone()))
+            .await?;
+        let arg = RunScriptArg { dev_name, script };
+        let run_id = arg.script.run_id;
+        let () = self
+            .do_request("run_script", SignedArg { arg, command })
+            .await?;
+        Ok(run_id)
+    }
+
+    pub async fn list_script_runs(&mut self, dev_name: String) -> Result<Vec<ScriptRun>> {
+        self.do_request("list_script_runs", dev_name).await
+    }
+
+    pub async fn get_script_output(&mut self, arg: ScriptRunIdArg) -> Result<ScriptOutput> {
+        self.do_request("get_script_output", arg).await
+    }
+
+    /// Asks a connected device for files under its allowed prefixes, returns the fetch's ID.
+    /// With `encrypt`, the device seals them so only our root key can open them.
+    pub async fn fetch_files(
+        &mut self,
+        dev_name: String,
+        paths: Vec<String>,
+        encrypt: bool,
+    ) -> Result<u64> {
+        let fetch = FileFetch {
+            fetch_id: random_id()?,
+            paths,
+            encrypt_to: encrypt.then(|| sealed::recipient_public_key(&self.enc)),
+        };
+        fetch.check()?;
+        let command = self
+            .sign_command(&dev_name, AdminCommand::FetchFiles(fetch.clone()))
+            .await?;
+        let arg = FetchFilesArg { dev_name, fetch };
+        let fetch_id = arg.fetch.fetch_id;
+        let () = self
+            .do_request("fetch_files", SignedArg { arg, command })
+            .await?;
+        Ok(fetch_id)
+    }
+
+    pub async fn list_file_fetches(&mut self, dev_name: String) -> Result<Vec<FileFetchInfo>> {
+        self.do_request("list_file_fetches", dev_name).await
+    }
+
+    /// Deletes the fetch and every file received for it
+    pub async fn delete_file_fetch(&mut self, arg: FileFetchIdArg) -> Result<()> {
+        self.do_request("delete_file_fetch", arg).await
+    }
+
+    /// Writes a completely received file to `out` one chunk at a time, opening it if sealed
+    pub async fn download_fetched_file(
+        &mut self,
+        dev_name: &str,
+        fetch: &FileFetchInfo,
+        file: &FetchedFile,
+        out: &mut impl Write,
+    ) -> Result<()> {
+        if !file.complete {
+            bail!("{} wasn't completely received", file.path);
+        }
+        let mut opener = None;
+        for seq in 0..file.chunks {
+            let arg = FetchedChunkArg {
+                dev_name: dev_name.to_owned(),
+                fetch_id: fetch.fetch_id,
+                path: file.path.clone(),
+                seq,
+            };
+            let mut data: Vec<u8> = self.do_request("get_fetched_chunk", arg).await?;
+            if fetch.encrypted {
+                if seq == 0 {
+                    if data.len() < sealed::HEADER_LEN {
+                        bail!("{} is too short to be sealed", file.path);
+                    }
+                    let chunk = data.split_off(sealed::HEADER_LEN);
+                    opener = Some(FileOpener::new(
+                        &self.enc,
+                        &data,
+                        fetch.fetch_id,
+                        &file.path,
+                    )?);
+                    data = chunk;
+                }
+                let opener = opener.as_mut().unwrap();
+                data = opener.open(&data, seq + 1 == file.chunks)?;
+            }
+            out.write_all(&data)?;
+        }
+        Ok(())
+    }
+
+    /// The device must be connected, it generates its new key and registers it by itself
+    pub async fn request_key_rotation(&mut self, dev_name: String) -> Result<()> {
+        self.do_request("request_key_rotation", dev_name).await
     }
 
     pub async fn delete_device_events(&mut self, dev_name: String) -> Result<()> {
         self.do_request("delete_device_events", dev_name).await
     }
 
+    pub async fn list_device_events(&mut self, arg: GetEventsArg) -> Result<EventPage> {
+        self.do_request("list_device_events", arg).await
+    }
+
+    /// Every stored event at once, prefer [`Self::list_device_events`]
     pub async fn get_device_events(&mut self, dev_name: String) -> Result<Vec<DeviceEvent>> {
         self.do_request("get_device_events", dev_name).await
     }
+
+    /// Streams live events matching `filter` until the connection drops
+    pub async fn subscribe(&mut self, filter: SubscribeArg) -> Result<LiveEventStream> {
+        let route = "/admin/subscribe";
+        let payload = bincode::serialize(&filter)?;
+        let signature = randomized_signature(&self.key, route.as_bytes(), &payload);
+        let response = self
+            .client
+            .request_stream(route, &signature, payload)
+            .await?;
+        Ok(LiveEventStream::new(response))
+    }
 }
 
 #[cfg(test)]
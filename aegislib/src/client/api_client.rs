@@ -1,9 +1,17 @@
+use crate::client::ClientError;
+use crate::protocol::PeerProtocol;
 use async_trait::async_trait;
 use bytes::Bytes;
-use crate::client::ClientError;
 
 #[async_trait]
 pub trait ApiClient {
-    async fn request(&mut self, handler: &str, signature: &[u8], payload: Vec<u8>)
-        -> Result<Bytes, ClientError>;
+    async fn request(
+        &mut self,
+        handler: &str,
+        signature: &[u8],
+        payload: Vec<u8>,
+    ) -> Result<Bytes, ClientError>;
+
+    /// What the server told us it speaks, once we've heard from it
+    fn server_protocol(&self) -> Option<&PeerProtocol>;
 }
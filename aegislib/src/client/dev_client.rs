@@ -1,9 +1,16 @@
 use crate::client::{ApiClient, ClientConfig, ClientError, RestClient, WsClient};
 use crate::command::device::{
-    DeviceEvent, StatusArg, StatusReply, StoreCameraPictureArg, StoreCameraPictureReply,
+    CaptureTrigger, DeviceEvent, FileChunk, FileFetchFinished, HardwareInfo, RotateKeyArg,
+    RotateKeyReply, ScriptResult, StatusArg, StatusReply, StoreCameraPictureArg,
+    StoreCameraPictureReply, UploadCameraPictureArg,
 };
-use crate::command::server::ServerCommand;
+use crate::command::server::{CapturePolicy, ServerCommand};
 use crate::crypto::randomized_signature;
+use crate::protocol::{
+    PeerProtocol, CAPTURE_POLICY_VERSION, FILE_FETCH_VERSION, HARDWARE_INFO_VERSION,
+    KEY_ROTATION_VERSION, PROTECTION_PROFILE_VERSION, SCRIPT_RESULT_VERSION,
+    UPLOAD_CAMERA_PICTURE_VERSION,
+};
 use anyhow::{anyhow, Error};
 use base64::prelude::*;
 use serde::de::DeserializeOwned;
@@ -25,12 +32,7 @@ impl DeviceClient {
         key: ed25519_dalek::SigningKey,
         event_tx: Option<Sender<ServerCommand>>,
     ) -> Result<Self, (ed25519_dalek::SigningKey, ClientError)> {
-        let api_base = if config.use_rest {
-            let dev_pk = BASE64_URL_SAFE_NO_PAD.encode(key.verifying_key());
-            format!("/device/{dev_pk}/")
-        } else {
-            String::new()
-        };
+        let api_base = Self::api_base(config, &key);
         let client = match Self::build_client(config, &key, event_tx.clone()).await {
             Ok(c) => c,
             Err(e) => return Err((key, e)),
@@ -44,6 +46,15 @@ impl DeviceClient {
         })
     }
 
+    fn api_base(config: &ClientConfig, key: &ed25519_dalek::SigningKey) -> String {
+        if config.use_rest {
+            let dev_pk = BASE64_URL_SAFE_NO_PAD.encode(key.verifying_key());
+            format!("/device/{dev_pk}/")
+        } else {
+            String::new()
+        }
+    }
+
     async fn build_client(
         config: &ClientConfig,
         key: &ed25519_dalek::SigningKey,
@@ -53,7 +64,12 @@ impl DeviceClient {
             if event_tx.is_some() {
                 return Err(anyhow!("Cannot receive events if config.use_rest is true").into());
             }
-            Box::new(RestClient::new_client(config).await)
+            if config.server_public_key.is_some() {
+                return Err(
+                    anyhow!("The encrypted channel requires config.use_rest to be false").into(),
+                );
+            }
+            Box::new(RestClient::new_client(config).await?)
         } else {
             match WsClient::new_device_client(config, key, event_tx).await {
                 Err(e) => return Err(e),
@@ -101,19 +117,111 @@ impl DeviceClient {
             .map_err(|e| anyhow!("do_request: Failed to deserialize reply: {}", e))?)
     }
 
+    pub fn server_protocol(&self) -> Option<&PeerProtocol> {
+        self.client.server_protocol()
+    }
+
     pub async fn status(&mut self) -> Result<StatusReply, ClientError> {
         self.do_request("status", StatusArg {}).await
     }
 
+    /// Uploads a picture, the trigger is dropped if the server is too old to store it
     pub async fn store_camera_picture(
         &mut self,
         jpeg_data: Vec<u8>,
+        trigger: CaptureTrigger,
     ) -> Result<StoreCameraPictureReply, ClientError> {
-        self.do_request("store_camera_picture", StoreCameraPictureArg { jpeg_data })
-            .await
+        let server_version = self.server_protocol().map(|p| p.version);
+        let trigger = trigger.for_version(server_version.unwrap_or(0));
+        if server_version.unwrap_or(0) >= UPLOAD_CAMERA_PICTURE_VERSION {
+            let arg = UploadCameraPictureArg { jpeg_data, trigger };
+            self.do_request("upload_camera_picture", arg).await
+        } else {
+            self.do_request("store_camera_picture", StoreCameraPictureArg { jpeg_data })
+                .await
+        }
     }
 
     pub async fn log_event(&mut self, event: DeviceEvent) -> Result<(), ClientError> {
         self.do_request("log_event", event).await
     }
+
+    /// Tells the server about our hardware, does nothing if it is too old to store it
+    pub async fn report_hardware(&mut self, info: HardwareInfo) -> Result<(), ClientError> {
+        let server_version = self.server_protocol().map(|p| p.version);
+        if server_version.unwrap_or(0) < HARDWARE_INFO_VERSION {
+            return Ok(());
+        }
+        self.do_request("report_hardware", info).await
+    }
+
+    /// Our capture policy, None if the server is too old to have one
+    pub async fn capture_policy(&mut self) -> Result<Option<CapturePolicy>, ClientError> {
+        let server_version = self.server_protocol().map(|p| p.version);
+        if server_version.unwrap_or(0) < CAPTURE_POLICY_VERSION {
+            return Ok(None);
+        }
+        Ok(Some(self.do_request("capture_policy", ()).await?))
+    }
+
+    /// Reports what a signed script did
+    pub async fn upload_script_result(&mut self, result: ScriptResult) -> Result<(), ClientError> {
+        let server_version = self.server_protocol().map(|p| p.version);
+        if server_version.unwrap_or(0) < SCRIPT_RESULT_VERSION {
+            return Err(anyhow!("The server is too old to store script results").into());
+        }
+        self.do_request("script_result", result).await
+    }
+
+    /// Sends the next chunk of a file an admin fetched
+    pub async fn upload_file_chunk(&mut self, chunk: FileChunk) -> Result<(), ClientError> {
+        self.check_file_fetch_support()?;
+        self.do_request("file_chunk", chunk).await
+    }
+
+    /// Tells the server we sent every file of a fetch that we could
+    pub async fn finish_file_fetch(
+        &mut self,
+        finished: FileFetchFinished,
+    ) -> Result<(), ClientError> {
+        self.check_file_fetch_support()?;
+        self.do_request("file_fetch_finished", finished).await
+    }
+
+    fn check_file_fetch_support(&self) -> Result<(), ClientError> {
+        let server_version = self.server_protocol().map(|p| p.version);
+        if server_version.unwrap_or(0) < FILE_FETCH_VERSION {
+            return Err(anyhow!("The server is too old to store fetched files").into());
+        }
+        Ok(())
+    }
+
+    /// Tells the server which protection profile is active, does nothing if it is too old
+    pub async fn report_profile(&mut self, name: Option<String>) -> Result<(), ClientError> {
+        let server_version = self.server_protocol().map(|p| p.version);
+        if server_version.unwrap_or(0) < PROTECTION_PROFILE_VERSION {
+            return Ok(());
+        }
+        self.do_request("report_profile", name).await
+    }
+
+    /// Asks the server to replace our key with `new_key`, and switches to it once it agreed.
+    /// The old key stays in use if the request fails.
+    pub async fn rotate_key(
+        &mut self,
+        new_key: ed25519_dalek::SigningKey,
+    ) -> Result<(), ClientError> {
+        let server_version = self.server_protocol().map(|p| p.version);
+        if server_version.unwrap_or(0) < KEY_ROTATION_VERSION {
+            return Err(anyhow!("The server is too old to rotate device keys").into());
+        }
+        let arg = RotateKeyArg::new(&self.key.verifying_key(), &new_key);
+        let RotateKeyReply {} = self.do_request("rotate_key", arg).await?;
+
+        // The server closes websockets authenticated with the old key
+        self.key = new_key;
+        self.api_base = Self::api_base(&self.config, &self.key);
+        self.client = Self::build_client(&self.config, &self.key, self.event_tx.clone()).await?;
+        Ok(())
+    }
 }
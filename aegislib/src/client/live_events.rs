@@ -0,0 +1,82 @@
+use crate::command::admin::LiveEvent;
+use anyhow::{anyhow, Result};
+use base64::prelude::*;
+
+/// Server-sent events from aegisd's `/admin/subscribe` endpoint.
+/// Each event's data is a base64 bincode `LiveEvent`, comment lines are keep-alives.
+pub struct LiveEventStream {
+    response: reqwest::Response,
+    buf: Vec<u8>,
+}
+
+impl LiveEventStream {
+    pub(crate) fn new(response: reqwest::Response) -> Self {
+        Self {
+            response,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Waits for the next event. Returns None once the server closed the stream.
+    pub async fn next(&mut self) -> Result<Option<LiveEvent>> {
+        loop {
+            while let Some(end) = find_frame_end(&self.buf) {
+                let frame: Vec<u8> = self.buf.drain(..end + 2).collect();
+                if let Some(event) = parse_frame(&frame[..end])? {
+                    return Ok(Some(event));
+                }
+            }
+            match self.response.chunk().await? {
+                Some(chunk) => self.buf.extend_from_slice(&chunk),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+fn find_frame_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|w| w == b"\n\n")
+}
+
+fn parse_frame(frame: &[u8]) -> Result<Option<LiveEvent>> {
+    let mut data = Vec::new();
+    for line in frame.split(|&c| c == b'\n') {
+        if let Some(value) = line.strip_prefix(b"data:") {
+            data.extend_from_slice(value.strip_prefix(b" ").unwrap_or(value));
+        }
+    }
+    if data.is_empty() {
+        return Ok(None);
+    }
+    let data = BASE64_URL_SAFE_NO_PAD
+        .decode(&data)
+        .map_err(|e| anyhow!("Invalid live event encoding: {e}"))?;
+    Ok(Some(bincode::deserialize(&data)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_frames() {
+        let event = LiveEvent::Connected {
+            dev_name: "laptop".into(),
+            timestamp: 42,
+        };
+        let data = BASE64_URL_SAFE_NO_PAD.encode(bincode::serialize(&event).unwrap());
+        let stream = format!(":\n\ndata: {data}\n\n");
+
+        let end = find_frame_end(stream.as_bytes()).unwrap();
+        assert!(parse_frame(&stream.as_bytes()[..end]).unwrap().is_none());
+        let rest = &stream.as_bytes()[end + 2..];
+        let end = find_frame_end(rest).unwrap();
+        match parse_frame(&rest[..end]).unwrap() {
+            Some(LiveEvent::Connected {
+                dev_name,
+                timestamp: 42,
+            }) => assert_eq!(dev_name, "laptop"),
+            e => panic!("Unexpected event {e:?}"),
+        }
+    }
+}
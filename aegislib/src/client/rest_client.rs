@@ -1,4 +1,5 @@
-use crate::client::{ApiClient, ClientConfig, ClientError, ClientHttpError};
+use crate::client::{check_server_protocol, ApiClient, ClientConfig, ClientError, ClientHttpError};
+use crate::protocol::PeerProtocol;
 use anyhow::{Error, Result};
 use async_trait::async_trait;
 use base64::prelude::*;
@@ -8,19 +9,61 @@ use reqwest::Client;
 pub struct RestClient {
     base_url: String,
     client: Client,
+    server_protocol: Option<PeerProtocol>,
 }
 
 impl RestClient {
-    pub async fn new_client(config: &ClientConfig) -> Self {
+    /// Sends a signed request and returns the response without reading its body,
+    /// for endpoints that keep streaming
+    pub(crate) async fn request_stream(
+        &mut self,
+        handler: &str,
+        signature: &[u8],
+        payload: Vec<u8>,
+    ) -> Result<reqwest::Response, ClientError> {
+        let reply = self.send(handler, signature, payload).await?;
+        self.server_protocol = Some(check_server_protocol(reply.headers())?);
+        Ok(reply)
+    }
+
+    async fn send(
+        &self,
+        handler: &str,
+        signature: &[u8],
+        payload: Vec<u8>,
+    ) -> Result<reqwest::Response, ClientError> {
+        let signature = BASE64_URL_SAFE_NO_PAD.encode(signature);
+
+        let url = format!("{}{}", &self.base_url, handler);
+        let mut request = self.client.post(url).bearer_auth(signature);
+        for (name, value) in PeerProtocol::current().header_values() {
+            request = request.header(name, value);
+        }
+        let reply = request.body(payload).send().await.map_err(Error::from)?;
+        if !reply.status().is_success() {
+            return Err(ClientHttpError {
+                code: reply.status(),
+                message: reply.text().await.ok(),
+            }
+            .into());
+        }
+        Ok(reply)
+    }
+
+    pub async fn new_client(config: &ClientConfig) -> Result<Self, ClientError> {
         let proto = if config.use_tls {
             "https://"
         } else {
             "http://"
         };
         let base_url = format!("{}{}", proto, &config.server_addr);
-        let client = Client::new();
+        let client = config.http_client()?;
 
-        Self { base_url, client }
+        Ok(Self {
+            base_url,
+            client,
+            server_protocol: None,
+        })
     }
 }
 
@@ -32,24 +75,12 @@ impl ApiClient for RestClient {
         signature: &[u8],
         payload: Vec<u8>,
     ) -> Result<Bytes, ClientError> {
-        let signature = BASE64_URL_SAFE_NO_PAD.encode(signature);
-
-        let url = format!("{}{}", &self.base_url, handler);
-        let reply = self
-            .client
-            .post(url)
-            .bearer_auth(signature)
-            .body(payload)
-            .send()
-            .await
-            .map_err(Error::from)?;
-        if !reply.status().is_success() {
-            return Err(ClientHttpError {
-                code: reply.status(),
-                message: reply.text().await.ok(),
-            }
-            .into());
-        }
+        let reply = self.send(handler, signature, payload).await?;
+        self.server_protocol = Some(check_server_protocol(reply.headers())?);
         Ok(reply.bytes().await.map_err(Error::from)?)
     }
+
+    fn server_protocol(&self) -> Option<&PeerProtocol> {
+        self.server_protocol.as_ref()
+    }
 }
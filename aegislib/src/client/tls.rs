@@ -0,0 +1,270 @@
+//! TLS settings shared by every connection to the server (REST, websocket and registration)
+
+use crate::client::ClientConfig;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::prelude::*;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, OwnedTrustAnchor, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Builds the rustls config for `config`: webpki roots plus our extra CAs, and the pins if any
+pub(crate) fn tls_client_config(config: &ClientConfig) -> Result<Arc<rustls::ClientConfig>> {
+    let tls_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PinningVerifier::new(config)?))
+        .with_no_client_auth();
+    Ok(Arc::new(tls_config))
+}
+
+/// Reads a PEM file named in a config, for `ClientConfig::extra_ca_certs_pem` and the pinned cert
+pub fn read_pem(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))
+}
+
+fn parse_pem_certs(pem: &str) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut pem.as_bytes()).context("Invalid PEM certificate")?;
+    if certs.is_empty() {
+        bail!("No certificate found in PEM");
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+struct PinningVerifier {
+    webpki: WebPkiVerifier,
+    /// The server must present exactly this certificate, which we then trust without a CA
+    pinned_cert: Option<Certificate>,
+    /// The server certificate must chain to a trusted CA, and have this public key
+    pinned_spki_sha256: Option<[u8; 32]>,
+}
+
+impl PinningVerifier {
+    fn new(config: &ClientConfig) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        for pem in &config.extra_ca_certs_pem {
+            for cert in parse_pem_certs(pem)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| anyhow!("Invalid extra CA certificate: {e}"))?;
+            }
+        }
+
+        let pinned_cert = match &config.pinned_server_cert_pem {
+            Some(pem) => Some(
+                parse_pem_certs(pem)?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| anyhow!("Pinned server certificate is empty"))?,
+            ),
+            None => None,
+        };
+        let pinned_spki_sha256 = match &config.pinned_spki_sha256 {
+            Some(hash) => Some(
+                BASE64_STANDARD
+                    .decode(hash.trim())
+                    .ok()
+                    .and_then(|hash| <[u8; 32]>::try_from(hash).ok())
+                    .ok_or_else(|| anyhow!("Pinned SPKI hash must be a base64 SHA-256"))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            webpki: WebPkiVerifier::new(roots, None),
+            pinned_cert,
+            pinned_spki_sha256,
+        })
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(pinned_cert) = &self.pinned_cert {
+            if end_entity != pinned_cert {
+                return Err(rustls::Error::General(
+                    "Server certificate does not match the pinned certificate".into(),
+                ));
+            }
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        let verified = self.webpki.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        if let Some(pinned_hash) = &self.pinned_spki_sha256 {
+            let spki = subject_public_key_info(&end_entity.0).ok_or_else(|| {
+                rustls::Error::General("Failed to parse server certificate".into())
+            })?;
+            if Sha256::digest(spki).as_slice() != pinned_hash {
+                return Err(rustls::Error::General(
+                    "Server public key does not match the pinned SPKI hash".into(),
+                ));
+            }
+        }
+        Ok(verified)
+    }
+}
+
+struct DerElement<'a> {
+    tag: u8,
+    /// The whole element, header included
+    raw: &'a [u8],
+    contents: &'a [u8],
+    /// What follows the element in the input
+    rest: &'a [u8],
+}
+
+/// Splits the next DER element off `input`
+fn next_der_element(input: &[u8]) -> Option<DerElement<'_>> {
+    let (&tag, after_tag) = input.split_first()?;
+    let (&len_byte, after_len) = after_tag.split_first()?;
+    let (len, after_len) = if len_byte < 0x80 {
+        (len_byte as usize, after_len)
+    } else {
+        let num_bytes = (len_byte & 0x7f) as usize;
+        if num_bytes == 0 || num_bytes > 4 || after_len.len() < num_bytes {
+            return None;
+        }
+        let len = after_len[..num_bytes]
+            .iter()
+            .fold(0usize, |len, &b| (len << 8) | b as usize);
+        (len, &after_len[num_bytes..])
+    };
+    if after_len.len() < len {
+        return None;
+    }
+    let header_len = input.len() - after_len.len();
+    Some(DerElement {
+        tag,
+        raw: &input[..header_len + len],
+        contents: &after_len[..len],
+        rest: &after_len[len..],
+    })
+}
+
+/// The DER SubjectPublicKeyInfo of an X.509 certificate, the same input as `openssl pkey -pubin`
+fn subject_public_key_info(cert_der: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const EXPLICIT_VERSION: u8 = 0xa0;
+
+    let cert = next_der_element(cert_der).filter(|e| e.tag == SEQUENCE)?;
+    let tbs = next_der_element(cert.contents).filter(|e| e.tag == SEQUENCE)?;
+    let mut fields = tbs.contents;
+    if fields.first() == Some(&EXPLICIT_VERSION) {
+        fields = next_der_element(fields)?.rest;
+    }
+    // Skip serialNumber, signature, issuer, validity and subject
+    for _ in 0..5 {
+        fields = next_der_element(fields)?.rest;
+    }
+    let spki = next_der_element(fields).filter(|e| e.tag == SEQUENCE)?;
+    Some(spki.raw)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CA_PEM: &str = include_str!("../../../aegisd/testdata/ca.crt");
+    const SERVER_PEM: &str = include_str!("../../../aegisd/testdata/localhost.crt");
+    /// openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary
+    const SERVER_SPKI_SHA256: &str = "mglhLlCzJKdVmrdpSD3pIk1pk1VCxo/nzMyeGOnTdyc=";
+
+    fn config() -> ClientConfig {
+        ClientConfig {
+            server_addr: "localhost".into(),
+            use_tls: true,
+            use_rest: true,
+            extra_ca_certs_pem: vec![],
+            pinned_server_cert_pem: None,
+            pinned_spki_sha256: None,
+            server_public_key: None,
+        }
+    }
+
+    fn verify(config: &ClientConfig) -> Result<(), rustls::Error> {
+        let server_cert = parse_pem_certs(SERVER_PEM).unwrap().remove(0);
+        PinningVerifier::new(config)
+            .unwrap()
+            .verify_server_cert(
+                &server_cert,
+                &[],
+                &ServerName::try_from("localhost").unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+            .map(|_| ())
+    }
+
+    #[test]
+    fn extra_ca() {
+        assert!(verify(&config()).is_err());
+        let config = ClientConfig {
+            extra_ca_certs_pem: vec![CA_PEM.into()],
+            ..config()
+        };
+        assert!(verify(&config).is_ok());
+    }
+
+    #[test]
+    fn pinned_certificate() {
+        let pinned = ClientConfig {
+            pinned_server_cert_pem: Some(SERVER_PEM.into()),
+            ..config()
+        };
+        assert!(verify(&pinned).is_ok());
+
+        let wrong_pin = ClientConfig {
+            extra_ca_certs_pem: vec![CA_PEM.into()],
+            pinned_server_cert_pem: Some(CA_PEM.into()),
+            ..config()
+        };
+        assert!(verify(&wrong_pin).is_err());
+    }
+
+    #[test]
+    fn pinned_spki() {
+        let cert = parse_pem_certs(SERVER_PEM).unwrap().remove(0);
+        let spki = subject_public_key_info(&cert.0).unwrap();
+        assert_eq!(
+            BASE64_STANDARD.encode(Sha256::digest(spki)),
+            SERVER_SPKI_SHA256
+        );
+
+        let pinned = ClientConfig {
+            extra_ca_certs_pem: vec![CA_PEM.into()],
+            pinned_spki_sha256: Some(SERVER_SPKI_SHA256.into()),
+            ..config()
+        };
+        assert!(verify(&pinned).is_ok());
+
+        let wrong_pin = ClientConfig {
+            pinned_spki_sha256: Some(BASE64_STANDARD.encode([0u8; 32])),
+            ..pinned
+        };
+        assert!(verify(&wrong_pin).is_err());
+    }
+}
@@ -1,6 +1,10 @@
+use crate::client::tls::tls_client_config;
 use crate::client::ClientError::WebsocketDisconnected;
-use crate::client::{ApiClient, ClientConfig, ClientError, ClientHttpError};
+use crate::client::{check_server_protocol, ApiClient, ClientConfig, ClientError, ClientHttpError};
 use crate::command::server::ServerCommand;
+use crate::crypto::channel::{ClientHandshake, Opener, Sealer, CHANNEL_HEADER};
+use crate::crypto::public_key_from_base64;
+use crate::protocol::PeerProtocol;
 use anyhow::{anyhow, bail, Error, Result};
 use async_trait::async_trait;
 use base64::prelude::*;
@@ -15,8 +19,10 @@ use tokio::sync::mpsc::error::TryRecvError;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::sync::Mutex;
 use tokio::time::timeout;
-use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
 use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{connect_async_tls_with_config, Connector};
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use tracing::{debug, error, warn};
 
@@ -33,10 +39,43 @@ enum WsReceivedMessage {
     RequestReply(WsRequestReply),
 }
 
+/// Everything needed to (re)open the websocket
+#[derive(Clone)]
+struct WsConnectParams {
+    url: String,
+    connector: Connector,
+    device_key: ed25519_dalek::SigningKey,
+    /// If set, we only talk to the server over an encrypted channel with this server key
+    server_pk: Option<ed25519_dalek::VerifyingKey>,
+}
+
+struct WsConnection {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    server_protocol: PeerProtocol,
+    channel: Option<(Sealer, Opener)>,
+}
+
+/// Write half of the websocket, seals data messages when we have an encrypted channel
+struct WsWriter {
+    sink: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
+    sealer: Option<Sealer>,
+}
+
+impl WsWriter {
+    async fn send_data(&mut self, data: Vec<u8>) -> Result<(), WsError> {
+        let data = match &mut self.sealer {
+            Some(sealer) => sealer.seal(&data),
+            None => data,
+        };
+        self.sink.send(Message::Binary(data)).await
+    }
+}
+
 pub struct WsClient {
-    write: Arc<Mutex<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>>,
+    write: Arc<Mutex<WsWriter>>,
     request_tx: Sender<Vec<u8>>,
     response_rx: Receiver<Result<Bytes, ClientError>>,
+    server_protocol: PeerProtocol,
 }
 
 impl WsClient {
@@ -48,48 +87,108 @@ impl WsClient {
     ) -> Result<Self, ClientError> {
         let pk = BASE64_URL_SAFE_NO_PAD.encode(key.verifying_key());
         let proto = if config.use_tls { "wss://" } else { "ws://" };
-        let ws_url = format!("{}{}/ws/{}", proto, &config.server_addr, pk);
-        let ws_stream = Self::connect(&ws_url).await?;
-        debug!("WsClient: WebSocket handshake completed");
+        let server_pk = match &config.server_public_key {
+            Some(key) => Some(public_key_from_base64(key)?),
+            None => None,
+        };
+        let params = WsConnectParams {
+            url: format!("{}{}/ws/{}", proto, &config.server_addr, pk),
+            connector: Connector::Rustls(tls_client_config(config)?),
+            device_key: key.clone(),
+            server_pk,
+        };
+        let conn = Self::connect(&params).await?;
+        debug!(
+            encrypted = conn.channel.is_some(),
+            "WsClient: WebSocket handshake completed"
+        );
 
-        let (write, read) = ws_stream.split();
-        let write = Arc::new(Mutex::new(write));
+        let (sink, read) = conn.stream.split();
+        let (sealer, opener) = conn.channel.unzip();
+        let write = Arc::new(Mutex::new(WsWriter { sink, sealer }));
         let (request_tx, request_rx) = channel(1);
         let (response_tx, response_rx) = channel(1);
 
         {
             let write = write.clone();
             spawn(async move {
-                Self::recv_messages(read, request_rx, response_tx, event_tx, ws_url, write).await
+                Self::recv_messages(
+                    read,
+                    opener,
+                    request_rx,
+                    response_tx,
+                    event_tx,
+                    params,
+                    write,
+                )
+                .await
             });
         }
         Ok(WsClient {
             write,
             request_tx,
             response_rx,
+            server_protocol: conn.server_protocol,
         })
     }
 
-    async fn connect(
-        ws_url: &str,
-    ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, ClientError> {
-        Ok(match connect_async(ws_url).await {
+    async fn connect(params: &WsConnectParams) -> Result<WsConnection, ClientError> {
+        let mut request = params
+            .url
+            .as_str()
+            .into_client_request()
+            .map_err(Error::from)?;
+        for (name, value) in PeerProtocol::current().header_values() {
+            let value = HeaderValue::from_str(&value).map_err(Error::from)?;
+            request.headers_mut().insert(name, value);
+        }
+        let handshake = params
+            .server_pk
+            .map(|_| ClientHandshake::start(&params.device_key));
+        if let Some(handshake) = &handshake {
+            let value = HeaderValue::from_str(&handshake.header_value()).map_err(Error::from)?;
+            request.headers_mut().insert(CHANNEL_HEADER, value);
+        }
+        let connector = params.connector.clone();
+        let connected = connect_async_tls_with_config(request, None, false, Some(connector));
+        let (ws_stream, response) = match connected.await {
             Err(WsError::Http(err)) => {
                 return Err(ClientHttpError {
                     code: err.status(),
-                    message: None,
+                    message: err
+                        .body()
+                        .as_ref()
+                        .map(|body| String::from_utf8_lossy(body).into_owned()),
                 }
                 .into())
             }
             Err(e) => return Err(ClientError::Other(Error::from(e))),
-            Ok((ws_stream, _)) => ws_stream,
+            Ok(connected) => connected,
+        };
+        let server_protocol = check_server_protocol(response.headers())?;
+        let channel = match (handshake, &params.server_pk) {
+            (Some(handshake), Some(server_pk)) => {
+                // Never fall back to cleartext, that's exactly what an attacker would want
+                let server_hello = response
+                    .headers()
+                    .get(CHANNEL_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                    .ok_or_else(|| anyhow!("Server did not set up the encrypted channel"))?;
+                Some(handshake.finish(server_pk, server_hello)?)
+            }
+            _ => None,
+        };
+        Ok(WsConnection {
+            stream: ws_stream,
+            server_protocol,
+            channel,
         })
     }
 
-    async fn connect_loop_forever(ws_url: &str) -> WebSocketStream<MaybeTlsStream<TcpStream>> {
+    async fn connect_loop_forever(params: &WsConnectParams) -> WsConnection {
         loop {
-            match Self::connect(ws_url).await {
-                Ok(s) => break s,
+            match Self::connect(params).await {
+                Ok(conn) => break conn,
                 Err(e) => {
                     warn!("WsClient: Failed to connect to websocket: {e}");
                     tokio::time::sleep(Duration::from_secs(1)).await;
@@ -98,13 +197,30 @@ impl WsClient {
         }
     }
 
+    /// Opens a new websocket (with a new channel handshake), and swaps the writer to it
+    async fn reconnect(
+        params: &WsConnectParams,
+        write: &Mutex<WsWriter>,
+    ) -> (
+        SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+        Option<Opener>,
+    ) {
+        let conn = Self::connect_loop_forever(params).await;
+        debug!("WsClient: WebSocket reconnected");
+        let (sink, read) = conn.stream.split();
+        let (sealer, opener) = conn.channel.unzip();
+        *write.lock().await = WsWriter { sink, sealer };
+        (read, opener)
+    }
+
     async fn recv_messages(
         mut read_stream: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+        mut opener: Option<Opener>,
         mut request_rx: Receiver<Vec<u8>>,
         response_tx: Sender<Result<Bytes, ClientError>>,
         event_tx: Option<Sender<ServerCommand>>,
-        ws_connect_url: String,
-        write: Arc<Mutex<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>>,
+        params: WsConnectParams,
+        write: Arc<Mutex<WsWriter>>,
     ) {
         let mut last_ping_time = Instant::now();
         let mut last_request_id = None;
@@ -116,11 +232,7 @@ impl WsClient {
                 Err(_) => {
                     if Instant::now().duration_since(last_ping_time) >= PING_TIMEOUT {
                         warn!("WsClient: Websocket ping timeout");
-                        let ws_stream = Self::connect_loop_forever(&ws_connect_url).await;
-                        debug!("WsClient: WebSocket reconnected");
-                        let (new_write, new_read) = ws_stream.split();
-                        read_stream = new_read;
-                        *write.lock().await = new_write;
+                        (read_stream, opener) = Self::reconnect(&params, &write).await;
                     }
                     continue;
                 }
@@ -129,15 +241,20 @@ impl WsClient {
                 Ok(msg) => msg,
                 Err(WebsocketDisconnected(e)) => {
                     error!("WsClient::recv_message: {e}");
-                    let ws_stream = Self::connect_loop_forever(&ws_connect_url).await;
-                    debug!("WsClient: WebSocket reconnected");
-                    let (new_write, new_read) = ws_stream.split();
-                    read_stream = new_read;
-                    *write.lock().await = new_write;
+                    (read_stream, opener) = Self::reconnect(&params, &write).await;
                     continue;
                 }
                 Err(e) => break e,
             };
+            let msg = match Self::open_message(msg, &mut opener) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    // The channel is out of sync or tampered with, only a new handshake helps
+                    error!("WsClient::recv_message: {e}");
+                    (read_stream, opener) = Self::reconnect(&params, &write).await;
+                    continue;
+                }
+            };
             if let Err(e) = Self::update_request_id(&mut last_request_id, &mut request_rx).await {
                 break e;
             }
@@ -146,6 +263,7 @@ impl WsClient {
                     last_ping_time = Instant::now();
                     let mut write = write.lock().await;
                     if let Err(e) = write
+                        .sink
                         .send(Message::Pong(b"pong".to_vec()))
                         .await
                         .map_err(Error::from)
@@ -205,7 +323,7 @@ impl WsClient {
 
     async fn recv_one_message(
         read_stream: &mut SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
-    ) -> Result<Bytes, ClientError> {
+    ) -> Result<Message, ClientError> {
         let reply = match read_stream.next().await {
             None => {
                 return Err(WebsocketDisconnected(anyhow!(
@@ -218,11 +336,21 @@ impl WsClient {
                 )))
             }
             Some(Err(WsError::Io(e))) => return Err(WebsocketDisconnected(anyhow!(e))),
-            Some(reply) => Bytes::from(reply.map_err(Error::from)?.into_data()),
+            Some(reply) => reply.map_err(Error::from)?,
         };
         Ok(reply)
     }
 
+    /// Control frames are never sealed, data messages are when we have an encrypted channel
+    fn open_message(msg: Message, opener: &mut Option<Opener>) -> Result<Bytes> {
+        match (msg, opener) {
+            (msg @ (Message::Binary(_) | Message::Text(_)), Some(opener)) => {
+                Ok(Bytes::from(opener.open(&msg.into_data())?))
+            }
+            (msg, _) => Ok(Bytes::from(msg.into_data())),
+        }
+    }
+
     fn parse_received_message(data: Bytes) -> Result<WsReceivedMessage> {
         // The format for server commands is: "server_command" <payload>
         // For request replies, it's: <msg_id> <"ok"|"err"> <payload>
@@ -286,10 +414,7 @@ impl ApiClient for WsClient {
             .map_err(|_| ClientError::WebsocketDisconnected(anyhow!("request channel dropped!")))?;
         {
             let mut write = self.write.lock().await;
-            write
-                .send(Message::Binary(msg))
-                .await
-                .map_err(Error::from)?;
+            write.send_data(msg).await.map_err(Error::from)?;
         }
 
         match self.response_rx.recv().await {
@@ -299,4 +424,8 @@ impl ApiClient for WsClient {
             Some(reply) => reply,
         }
     }
+
+    fn server_protocol(&self) -> Option<&PeerProtocol> {
+        Some(&self.server_protocol)
+    }
 }
@@ -1,3 +1,4 @@
 pub mod admin;
 pub mod device;
 pub mod server;
+pub mod signed;
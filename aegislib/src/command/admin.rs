@@ -1,4 +1,8 @@
-use crate::command::server::PowerCommand;
+use crate::command::device::{CaptureTrigger, DeviceEvent, EventLogLevel, StatusReply};
+use crate::command::server::{
+    CapturePolicy, DeviceProfile, FileFetch, PowerCommand, Script, StatusUpdate,
+};
+use crate::command::signed::{SignedCommand, StatusChange};
 use serde::{Deserialize, Serialize};
 use std::time::SystemTime;
 
@@ -8,6 +12,38 @@ pub struct RegisteredDevice {
     pub created_at: SystemTime,
     pub name: String,
     pub pubkey: String,
+    pub owner: Option<String>,
+    pub contact: Option<String>,
+    pub notes: Option<String>,
+    pub serial_number: Option<String>,
+    pub hardware_model: Option<String>,
+    /// The protection profile the device last reported as active
+    pub active_profile: Option<String>,
+}
+
+/// A device whose key was revoked, its pictures and events are kept until it is purged
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArchivedDevice {
+    pub device: RegisteredDevice,
+    pub archived_at: SystemTime,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RenameDeviceArg {
+    pub dev_name: String,
+    pub new_name: String,
+}
+
+/// Fields left to None are unchanged, an empty string clears the field
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SetDeviceInfoArg {
+    pub dev_name: String,
+    pub owner: Option<String>,
+    pub contact: Option<String>,
+    pub notes: Option<String>,
+    /// Devices report these themselves when they can, but admins may override them
+    pub serial_number: Option<String>,
+    pub hardware_model: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -17,13 +53,18 @@ pub struct PendingDevice {
     pub pubkey: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct SetStatusArg {
     pub dev_name: String,
     pub vt_locked: Option<bool>,
     pub ssh_locked: Option<bool>,
     pub draw_decoy: Option<bool>,
-    // NOTE: update is_no_op if you add a field
+    // NOTE: update is_no_op if you add a status field
+    /// Apply the change later, instead of right away
+    pub schedule: Option<StatusSchedule>,
+    /// Flip every field set by the change back this long after it applies,
+    /// e.g. lock now and unlock after 2 hours
+    pub expires_after_secs: Option<u64>,
 }
 
 impl SetStatusArg {
@@ -31,6 +72,286 @@ impl SetStatusArg {
         // Destructure to cause build error if we add a field
         self.vt_locked.is_none() && self.ssh_locked.is_none() && self.draw_decoy.is_none()
     }
+
+    pub fn change(&self) -> StatusChange {
+        StatusChange {
+            vt_locked: self.vt_locked,
+            ssh_locked: self.ssh_locked,
+            draw_decoy: self.draw_decoy,
+        }
+    }
+}
+
+/// When aegisd applies a scheduled status change, times are UTC
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub enum StatusSchedule {
+    /// Once, at this unix timestamp
+    At { timestamp: u64 },
+    /// Every day, this many minutes after midnight. Only for changes that lock,
+    /// unlocking needs a command the admin signed for that time.
+    Daily { minute_of_day: u16 },
+}
+
+/// A status change waiting for aegisd's scheduler
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduledStatusChange {
+    pub id: i32,
+    /// When it applies next
+    pub apply_at_timestamp: u64,
+    pub repeat_daily: bool,
+    /// Set for the revert of a change with `expires_after_secs`
+    pub is_expiry: bool,
+    pub vt_locked: Option<bool>,
+    pub ssh_locked: Option<bool>,
+    pub draw_decoy: Option<bool>,
+}
+
+/// Selects one [`ScheduledStatusChange`] of a device
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusScheduleIdArg {
+    pub dev_name: String,
+    pub id: i32,
+}
+
+/// Longest protection profile name that aegisd stores
+pub const MAX_PROFILE_NAME_LEN: usize = 64;
+/// Shortest capture or telemetry interval of a protection profile
+pub const MIN_PROFILE_INTERVAL_SECS: u32 = 10;
+
+/// A named set of status flags and device behaviors, e.g. "stolen mode"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProtectionProfile {
+    pub name: String,
+    pub vt_locked: bool,
+    pub ssh_locked: bool,
+    pub draw_decoy: bool,
+    /// Take a webcam picture this often, unset doesn't take any
+    pub capture_interval_secs: Option<u32>,
+    /// Log an event with the device's telemetry this often, unset doesn't log any
+    pub telemetry_interval_secs: Option<u32>,
+}
+
+impl ProtectionProfile {
+    pub fn status(&self) -> StatusUpdate {
+        StatusUpdate {
+            vt_locked: self.vt_locked,
+            ssh_locked: self.ssh_locked,
+            draw_decoy: self.draw_decoy,
+        }
+    }
+
+    pub fn status_change(&self) -> StatusChange {
+        StatusChange {
+            vt_locked: Some(self.vt_locked),
+            ssh_locked: Some(self.ssh_locked),
+            draw_decoy: Some(self.draw_decoy),
+        }
+    }
+
+    pub fn device_profile(&self) -> DeviceProfile {
+        DeviceProfile {
+            name: self.name.clone(),
+            status: self.status(),
+            capture_interval_secs: self.capture_interval_secs,
+            telemetry_interval_secs: self.telemetry_interval_secs,
+        }
+    }
+}
+
+/// Applies a stored profile to a device, or stops the active one if `profile` is None.
+/// Devices that predate profiles only get `status_command`, and none of the behaviors.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApplyProfileArg {
+    pub dev_name: String,
+    pub profile: Option<String>,
+    /// Signed `AdminCommand::SetProfile`
+    pub profile_command: SignedCommand,
+    /// Signed `AdminCommand::SetStatus` with the profile's status, unset when stopping a profile
+    pub status_command: Option<SignedCommand>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetCapturePolicyArg {
+    pub dev_name: String,
+    /// Unset goes back to [`CapturePolicy::default`]
+    pub policy: Option<CapturePolicy>,
+}
+
+pub const MAX_RULE_NAME_LEN: usize = 64;
+
+/// What an [`AutomationRule`] reacts to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum RuleTrigger {
+    /// The device logged an event at least this severe, containing `contains` if set
+    Event {
+        min_level: EventLogLevel,
+        contains: Option<String>,
+    },
+    /// The device uploaded a picture, taken for this reason if set
+    Picture {
+        trigger: Option<CaptureTrigger>,
+    },
+    /// The device connected, only while locked or from an IP it never used before if asked
+    Connected {
+        while_locked: bool,
+        new_address: bool,
+    },
+    Disconnected,
+    /// The device stayed disconnected this long, matches once per disconnection
+    Offline {
+        secs: u32,
+    },
+}
+
+/// What an [`AutomationRule`] does to the device. The server can't sign commands,
+/// so rules can only do what devices accept unsigned.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum RuleAction {
+    /// Sets the flags that are true, never unlocks anything
+    Lock {
+        vt_locked: bool,
+        ssh_locked: bool,
+        draw_decoy: bool,
+    },
+    /// Powers the device off, devices take it unsigned since it never unlocks anything
+    Poweroff,
+    /// Asks the device for a webcam picture, it only takes one while locked
+    Capture,
+    /// Flags the device as stolen, see [`SetStolenArg`]
+    MarkStolen,
+}
+
+impl RuleAction {
+    /// The status change of a `Lock`
+    pub fn status_change(&self) -> Option<StatusChange> {
+        let lock = |flag: bool| flag.then_some(true);
+        match self {
+            RuleAction::Lock {
+                vt_locked,
+                ssh_locked,
+                draw_decoy,
+            } => Some(StatusChange {
+                vt_locked: lock(*vt_locked),
+                ssh_locked: lock(*ssh_locked),
+                draw_decoy: lock(*draw_decoy),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Runs `action` on a device once its `trigger` matched `threshold` times within `window_secs`,
+/// e.g. power off after 3 inputs while locked in 10 minutes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AutomationRule {
+    pub name: String,
+    /// Only applies to this device, or to every device when unset
+    pub dev_name: Option<String>,
+    pub trigger: RuleTrigger,
+    pub threshold: u32,
+    pub window_secs: u32,
+    pub action: RuleAction,
+    pub enabled: bool,
+    /// Logs executions without running the action
+    pub dry_run: bool,
+}
+
+/// A rule that fired, kept even after the rule is deleted
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RuleExecution {
+    pub rule_name: String,
+    pub dev_name: String,
+    pub executed_at_timestamp: u64,
+    pub dry_run: bool,
+    pub action: RuleAction,
+    /// Why the action failed, unset if it ran
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListRuleExecutionsArg {
+    /// Only executions of this rule, or of every rule when unset
+    pub rule_name: Option<String>,
+    /// Most recent first, up to this many
+    pub limit: Option<u32>,
+}
+
+/// Signed as `AdminCommand::RunScript`, see [`SignedArg`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RunScriptArg {
+    pub dev_name: String,
+    pub script: Script,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScriptRun {
+    pub run_id: u64,
+    pub source: String,
+    pub requested_at_timestamp: u64,
+    /// Unset until the device reports a result
+    pub finished_at_timestamp: Option<u64>,
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScriptRunIdArg {
+    pub dev_name: String,
+    pub run_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScriptOutput {
+    pub run: ScriptRun,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Whether the device dropped some output
+    pub truncated: bool,
+}
+
+/// Signed as `AdminCommand::FetchFiles`, see [`SignedArg`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FetchFilesArg {
+    pub dev_name: String,
+    pub fetch: FileFetch,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FetchedFile {
+    pub path: String,
+    /// As announced by the device, before encryption
+    pub size: u64,
+    /// How many chunks the server has, download them in order
+    pub chunks: u32,
+    pub complete: bool,
+    /// Why the device didn't send this path, there are no chunks then
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileFetchInfo {
+    pub fetch_id: u64,
+    pub paths: Vec<String>,
+    /// Whether the chunks are sealed for the admin, see `crypto::sealed`
+    pub encrypted: bool,
+    pub requested_at_timestamp: u64,
+    /// Unset until the device is done sending files
+    pub finished_at_timestamp: Option<u64>,
+    pub files: Vec<FetchedFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileFetchIdArg {
+    pub dev_name: String,
+    pub fetch_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FetchedChunkArg {
+    pub dev_name: String,
+    pub fetch_id: u64,
+    pub path: String,
+    pub seq: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -45,8 +366,314 @@ pub struct StoredCameraPicture {
     pub jpeg_data: Vec<u8>,
 }
 
+/// Selects a page of a device's events, oldest first
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetEventsArg {
+    pub dev_name: String,
+    /// Only events at or after this unix timestamp
+    pub since: Option<u64>,
+    /// Only events before this unix timestamp
+    pub until: Option<u64>,
+    /// Only events at least this severe
+    pub min_level: Option<EventLogLevel>,
+    /// Only events whose message contains this, ignoring case
+    pub search: Option<String>,
+    /// The `next_cursor` of the previous page
+    pub cursor: Option<String>,
+    /// Page size, the server caps it and picks a default if unset
+    pub limit: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EventPage {
+    pub events: Vec<DeviceEvent>,
+    /// Set when there may be more events after this page
+    pub next_cursor: Option<String>,
+}
+
+/// Selects a page of a device's camera pictures, oldest first
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetPicturesArg {
+    pub dev_name: String,
+    /// Only pictures taken at or after this unix timestamp
+    pub since: Option<u64>,
+    /// Only pictures taken before this unix timestamp
+    pub until: Option<u64>,
+    /// The `next_cursor` of the previous page
+    pub cursor: Option<String>,
+    /// Page size, the server caps it and picks a default if unset
+    pub limit: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PicturePage {
+    pub pictures: Vec<StoredCameraPicture>,
+    /// Set when there may be more pictures after this page
+    pub next_cursor: Option<String>,
+}
+
+/// A stored camera picture, without its data
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PictureInfo {
+    pub id: i32,
+    pub created_at_timestamp: u64,
+    /// Size of the JPEG in bytes
+    pub size: u64,
+    /// Unknown if the server could not decode the picture
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub trigger: CaptureTrigger,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PictureInfoPage {
+    pub pictures: Vec<PictureInfo>,
+    /// Set when there may be more pictures after this page
+    pub next_cursor: Option<String>,
+}
+
+/// A stored picture's metadata, and the hash of its data to check it after downloading it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportedPicture {
+    pub info: PictureInfo,
+    /// Hex-encoded SHA-256 of the JPEG
+    pub sha256: String,
+}
+
+/// One websocket connection of a device
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectionRecord {
+    pub connected_at_timestamp: u64,
+    /// None while the device is connected, or if aegisd stopped before it disconnected
+    pub disconnected_at_timestamp: Option<u64>,
+    pub remote_addr: String,
+}
+
+/// A device's status right after it changed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusHistoryEntry {
+    pub changed_at_timestamp: u64,
+    /// "admin", or the scheduled change that applied it
+    pub changed_by: String,
+    pub vt_locked: bool,
+    pub ssh_locked: bool,
+    pub draw_decoy: bool,
+}
+
+/// Everything aegisd keeps about a device, to hand over as evidence.
+/// Pictures are listed without their data, fetch each one with `get_device_camera_picture`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeviceExport {
+    pub device: RegisteredDevice,
+    pub archived_at: Option<SystemTime>,
+    pub exported_at: SystemTime,
+    pub status: StatusReply,
+    pub status_history: Vec<StatusHistoryEntry>,
+    pub connections: Vec<ConnectionRecord>,
+    pub events: Vec<DeviceEvent>,
+    pub pictures: Vec<ExportedPicture>,
+}
+
+/// Selects one picture by its [`PictureInfo::id`]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PictureIdArg {
+    pub dev_name: String,
+    pub id: i32,
+}
+
+/// Limits on what aegisd keeps for a device, unset fields don't limit anything
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Eq, PartialEq)]
+pub struct RetentionPolicy {
+    pub event_max_age_days: Option<u32>,
+    /// Only the most recent events are kept
+    pub event_max_count: Option<u32>,
+    /// Less severe events are pruned regardless of their age
+    pub event_min_level: Option<EventLogLevel>,
+    pub picture_max_age_days: Option<u32>,
+    /// Only the most recent pictures are kept
+    pub picture_max_count: Option<u32>,
+    /// Only the most recent pictures are kept, up to this total size
+    pub picture_max_bytes: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetRetentionArg {
+    pub dev_name: String,
+    /// Replaces the server-wide policy for this device, unset goes back to the server's
+    pub policy: Option<RetentionPolicy>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceRetention {
+    /// Set when the device doesn't use the server-wide policy
+    pub policy: Option<RetentionPolicy>,
+    /// Nothing is pruned for stolen devices, to preserve evidence
+    pub stolen: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetStolenArg {
+    pub dev_name: String,
+    pub stolen: bool,
+}
+
+/// Lets devices register without an open registration, see [`crate::client::register_device`]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreateEnrollmentTokenArg {
+    /// Shown to admins, e.g. the batch of devices the token is for
+    pub label: String,
+    /// Unset for unlimited registrations, 1 for a single-use token
+    pub max_uses: Option<u32>,
+    /// Unix timestamp after which the token is refused
+    pub expires_at_timestamp: Option<u64>,
+    /// Devices registering with the token are confirmed right away,
+    /// otherwise they wait in the token's queue of pending devices
+    pub auto_approve: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreatedEnrollmentToken {
+    pub id: i32,
+    /// Only known to aegisd when it's created, the server keeps a hash
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EnrollmentToken {
+    pub id: i32,
+    pub label: String,
+    pub created_at_timestamp: u64,
+    pub expires_at_timestamp: Option<u64>,
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+    pub auto_approve: bool,
+    /// Devices that registered with the token and wait for confirmation
+    pub pending_devices: u32,
+}
+
+/// A device whose key was generated ahead of time, imported already confirmed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportedDevice {
+    pub name: String,
+    /// URL-safe base64 public key, like [`RegisteredDevice::pubkey`]
+    pub pubkey: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportDevicesReply {
+    pub imported: u32,
+    /// Names of the devices skipped because their name or key is already registered
+    pub conflicts: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SendPowerCommandArg {
     pub dev_name: String,
     pub command: PowerCommand,
 }
+
+/// An admin request carrying the signed command that aegisd must relay to the device
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SignedArg<T> {
+    pub arg: T,
+    pub command: SignedCommand,
+}
+
+/// A [`SetStatusArg`] with the commands aegisd relays to the device.
+/// `revert` undoes the change when it expires, it's signed ahead of time for that moment.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SignedStatusArg {
+    pub arg: SetStatusArg,
+    pub command: SignedCommand,
+    pub revert: Option<SignedCommand>,
+}
+
+/// Which live events an admin wants to receive
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SubscribeArg {
+    /// Only events about this device
+    pub dev_name: Option<String>,
+    /// Only device events at least this severe. Other kinds of events are always sent.
+    pub min_level: Option<EventLogLevel>,
+}
+
+/// Pushed to subscribed admins as it happens
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum LiveEvent {
+    /// The device logged an event
+    Logged {
+        dev_name: String,
+        event: DeviceEvent,
+    },
+    PictureUploaded {
+        dev_name: String,
+        timestamp: u64,
+        size: u64,
+    },
+    Connected {
+        dev_name: String,
+        timestamp: u64,
+    },
+    Disconnected {
+        dev_name: String,
+        timestamp: u64,
+    },
+}
+
+impl LiveEvent {
+    pub fn dev_name(&self) -> &str {
+        match self {
+            LiveEvent::Logged { dev_name, .. }
+            | LiveEvent::PictureUploaded { dev_name, .. }
+            | LiveEvent::Connected { dev_name, .. }
+            | LiveEvent::Disconnected { dev_name, .. } => dev_name,
+        }
+    }
+
+    pub fn matches(&self, filter: &SubscribeArg) -> bool {
+        if let Some(dev_name) = &filter.dev_name {
+            if dev_name != self.dev_name() {
+                return false;
+            }
+        }
+        match (self, filter.min_level) {
+            (LiveEvent::Logged { event, .. }, Some(min_level)) => event.level >= min_level,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn live_event_filter() {
+        let event = |level| LiveEvent::Logged {
+            dev_name: "laptop".into(),
+            event: DeviceEvent {
+                timestamp: 0,
+                level,
+                message: String::new(),
+            },
+        };
+        let filter = SubscribeArg {
+            dev_name: Some("laptop".into()),
+            min_level: Some(EventLogLevel::Warn),
+        };
+        assert!(event(EventLogLevel::Error).matches(&filter));
+        assert!(!event(EventLogLevel::Info).matches(&filter));
+
+        let connected = LiveEvent::Connected {
+            dev_name: "laptop".into(),
+            timestamp: 0,
+        };
+        assert!(connected.matches(&filter));
+        let other = SubscribeArg {
+            dev_name: Some("desktop".into()),
+            min_level: None,
+        };
+        assert!(!connected.matches(&other));
+        assert!(connected.matches(&SubscribeArg::default()));
+    }
+}
@@ -1,5 +1,12 @@
+use crate::crypto::{check_signature, public_key_from_base64, randomized_signature};
+use anyhow::{bail, Result};
+use base64::prelude::*;
+use ed25519_dalek::{SigningKey, VerifyingKey};
 use serde::{Deserialize, Serialize};
 
+/// Signature domain of the proof in [`RotateKeyArg`]
+const KEY_ROTATION_PROOF_ROUTE: &[u8] = b"rotate_key_proof";
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StatusArg {}
 
@@ -20,7 +27,32 @@ pub struct StoreCameraPictureArg {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StoreCameraPictureReply {}
 
+/// Why a device took a camera picture
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum CaptureTrigger {
+    /// Uploaded by a device that predates capture triggers
+    Unknown,
+    /// Input was detected while the device was locked
+    InputWhileLocked,
+    /// Taken on the interval of the active protection profile
+    Periodic,
+    /// Taken on the capture policy's schedule while the device was locked
+    Scheduled,
+    /// A screenshot taken on the capture policy's schedule while the device was locked
+    Screenshot,
+    /// An automation rule asked for it while the device was locked
+    Requested,
+}
+
+/// Like [`StoreCameraPictureArg`], for servers of protocol version 4 and later
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadCameraPictureArg {
+    pub jpeg_data: Vec<u8>,
+    pub trigger: CaptureTrigger,
+}
+
+/// Ordered from least to most severe
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 pub enum EventLogLevel {
     Trace,
     Debug,
@@ -35,3 +67,114 @@ pub struct DeviceEvent {
     pub level: EventLogLevel,
     pub message: String,
 }
+
+/// What a device could find out about its own hardware, None if it couldn't read it
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct HardwareInfo {
+    pub serial_number: Option<String>,
+    pub hardware_model: Option<String>,
+}
+
+/// Replaces the device's key. The request itself is signed with the current key like any other,
+/// `proof` shows that the device also holds the new one.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RotateKeyArg {
+    /// URL-safe base64
+    pub new_pubkey: String,
+    /// Signature of the current public key by the new key
+    pub proof: Vec<u8>,
+}
+
+impl RotateKeyArg {
+    pub fn new(current_pubkey: &VerifyingKey, new_key: &SigningKey) -> Self {
+        Self {
+            new_pubkey: BASE64_URL_SAFE_NO_PAD.encode(new_key.verifying_key()),
+            proof: randomized_signature(new_key, KEY_ROTATION_PROOF_ROUTE, current_pubkey.as_ref()),
+        }
+    }
+
+    /// Returns the new key, if the proof was made with it for `current_pubkey`
+    pub fn verify(&self, current_pubkey: &VerifyingKey) -> Result<VerifyingKey> {
+        let new_pubkey = public_key_from_base64(&self.new_pubkey)?;
+        if new_pubkey == *current_pubkey {
+            bail!("The new key is the current key");
+        }
+        if !check_signature(
+            &new_pubkey,
+            &self.proof,
+            KEY_ROTATION_PROOF_ROUTE,
+            current_pubkey.as_ref(),
+        ) {
+            bail!("Invalid proof of possession of the new key");
+        }
+        Ok(new_pubkey)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RotateKeyReply {}
+
+/// What a [`crate::command::server::Script`] did, each output cut at `Script::MAX_OUTPUT_LEN`
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ScriptResult {
+    pub run_id: u64,
+    /// Unset if the script was killed by a signal, or couldn't be started
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Whether some output was dropped
+    pub truncated: bool,
+}
+
+/// Part of a file sent for a [`crate::command::server::FileFetch`], in order
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct FileChunk {
+    pub fetch_id: u64,
+    pub path: String,
+    /// Starts at 0 for every file
+    pub seq: u32,
+    /// Of the whole file, before encryption
+    pub size: u64,
+    /// At most `FileFetch::CHUNK_LEN` bytes, sealed if the fetch asked for encryption
+    pub data: Vec<u8>,
+    pub last: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct FetchFailure {
+    pub path: String,
+    pub error: String,
+}
+
+/// Sent once a device is done with a fetch, after the chunks of every file it could read
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct FileFetchFinished {
+    pub fetch_id: u64,
+    /// Paths that were refused or couldn't be read
+    pub failures: Vec<FetchFailure>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::random_sign_keypair;
+
+    #[test]
+    fn rotate_key_proof() {
+        let current = random_sign_keypair().verifying_key();
+        let new_key = random_sign_keypair();
+        let arg = RotateKeyArg::new(&current, &new_key);
+        assert_eq!(arg.verify(&current).unwrap(), new_key.verifying_key());
+
+        // The proof is only good for the key it was made for
+        let other = random_sign_keypair().verifying_key();
+        assert!(arg.verify(&other).is_err());
+
+        let stolen_pubkey = RotateKeyArg {
+            new_pubkey: BASE64_URL_SAFE_NO_PAD.encode(other),
+            proof: arg.proof.clone(),
+        };
+        assert!(stolen_pubkey.verify(&current).is_err());
+    }
+}
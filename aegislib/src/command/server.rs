@@ -1,15 +1,26 @@
 use crate::command::device::StatusReply;
+use crate::command::signed::SignedCommand;
+use anyhow::{bail, Result};
 use derive_more::From;
 use serde::{Deserialize, Serialize};
 use strum_macros::IntoStaticStr;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct StatusUpdate {
     pub vt_locked: bool,
     pub ssh_locked: bool,
     pub draw_decoy: bool,
 }
 
+impl StatusUpdate {
+    /// Whether going from `self` to `new` would unlock something
+    pub fn is_loosened_by(&self, new: &StatusUpdate) -> bool {
+        (self.vt_locked && !new.vt_locked)
+            || (self.ssh_locked && !new.ssh_locked)
+            || (self.draw_decoy && !new.draw_decoy)
+    }
+}
+
 impl From<StatusReply> for StatusUpdate {
     fn from(reply: StatusReply) -> Self {
         Self {
@@ -20,7 +31,156 @@ impl From<StatusReply> for StatusUpdate {
     }
 }
 
-#[derive(Copy, Clone, Serialize, Deserialize, Debug)]
+/// What a device runs itself while a protection profile is active
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct DeviceProfile {
+    pub name: String,
+    pub status: StatusUpdate,
+    /// Take a webcam picture this often
+    pub capture_interval_secs: Option<u32>,
+    /// Log an event with the device's telemetry this often
+    pub telemetry_interval_secs: Option<u32>,
+}
+
+/// A script the admin signed for one device, run by `/bin/sh` as root
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Script {
+    /// Chosen by the admin, the device reports its result under this ID
+    pub run_id: u64,
+    pub source: String,
+    /// The script is killed after this long
+    pub timeout_secs: u32,
+    /// The only variables set besides `PATH`
+    pub env: Vec<(String, String)>,
+}
+
+impl Script {
+    pub const MAX_SOURCE_LEN: usize = 64 * 1024;
+    pub const MAX_TIMEOUT_SECS: u32 = 60 * 60;
+    /// Devices keep at most this much of stdout and of stderr
+    pub const MAX_OUTPUT_LEN: usize = 1024 * 1024;
+
+    /// Fails if devices would refuse to run this script
+    pub fn check(&self) -> Result<()> {
+        if self.source.len() > Self::MAX_SOURCE_LEN {
+            bail!("Scripts must be at most {} bytes", Self::MAX_SOURCE_LEN);
+        }
+        if !(1..=Self::MAX_TIMEOUT_SECS).contains(&self.timeout_secs) {
+            bail!(
+                "Script timeouts must be 1 to {} seconds",
+                Self::MAX_TIMEOUT_SECS
+            );
+        }
+        for (name, value) in &self.env {
+            if name.is_empty() || name.contains(['=', '\0']) || value.contains('\0') {
+                bail!("Invalid script environment variable: {name}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Files the admin signed a request for, the device sends those under its allowed prefixes
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct FileFetch {
+    /// Chosen by the admin, the device sends the files under this ID
+    pub fetch_id: u64,
+    /// Absolute paths, directories are sent with everything under them
+    pub paths: Vec<String>,
+    /// The admin's x25519 key, see `crypto::sealed`. Unset sends the files as they are.
+    pub encrypt_to: Option<[u8; 32]>,
+}
+
+impl FileFetch {
+    pub const MAX_PATHS: usize = 64;
+    pub const MAX_PATH_LEN: usize = 4096;
+    /// Files are read and sent this much at a time
+    pub const CHUNK_LEN: usize = 256 * 1024;
+    /// aegisd refuses chunks once a fetch has stored this many bytes
+    pub const MAX_TOTAL_LEN: u64 = 1024 * 1024 * 1024;
+
+    /// Fails if devices would refuse to send these paths
+    pub fn check(&self) -> Result<()> {
+        if !(1..=Self::MAX_PATHS).contains(&self.paths.len()) {
+            bail!("File fetches must ask for 1 to {} paths", Self::MAX_PATHS);
+        }
+        for path in &self.paths {
+            if !path.starts_with('/') || path.contains('\0') || path.len() > Self::MAX_PATH_LEN {
+                bail!("Invalid path to fetch: {path:?}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a device captures whoever uses it while its VT is locked
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct CapturePolicy {
+    /// Take a webcam picture this often while locked
+    pub webcam_interval_secs: Option<u32>,
+    /// Take a screenshot this often while locked
+    pub screenshot_interval_secs: Option<u32>,
+    /// Webcam pictures taken after input while locked
+    pub burst_count: u32,
+    /// Time between the pictures of a burst
+    pub burst_interval_secs: u32,
+    /// Input is ignored for this long after a burst
+    pub input_cooldown_secs: u32,
+    /// Most pictures the device uploads in an hour, aegisd refuses the others
+    pub max_uploads_per_hour: u32,
+}
+
+impl CapturePolicy {
+    /// Shortest interval between scheduled captures
+    pub const MIN_INTERVAL_SECS: u32 = 10;
+    pub const MAX_BURST_COUNT: u32 = 10;
+    pub const MAX_BURST_INTERVAL_SECS: u32 = 60;
+    pub const MAX_UPLOADS_PER_HOUR: u32 = 720;
+
+    /// Fails if a setting is out of the range that devices accept
+    pub fn check(&self) -> Result<()> {
+        if *self != self.clamped() {
+            bail!(
+                "Capture intervals must be at least {}s, bursts 1 to {} pictures at most {}s apart, \
+                 and uploads at most {} an hour",
+                Self::MIN_INTERVAL_SECS,
+                Self::MAX_BURST_COUNT,
+                Self::MAX_BURST_INTERVAL_SECS,
+                Self::MAX_UPLOADS_PER_HOUR
+            );
+        }
+        Ok(())
+    }
+
+    /// Brings every setting within range, devices apply this to whatever the server sends
+    pub fn clamped(&self) -> Self {
+        let interval = |secs: Option<u32>| secs.map(|secs| secs.max(Self::MIN_INTERVAL_SECS));
+        Self {
+            webcam_interval_secs: interval(self.webcam_interval_secs),
+            screenshot_interval_secs: interval(self.screenshot_interval_secs),
+            burst_count: self.burst_count.clamp(1, Self::MAX_BURST_COUNT),
+            burst_interval_secs: self.burst_interval_secs.min(Self::MAX_BURST_INTERVAL_SECS),
+            input_cooldown_secs: self.input_cooldown_secs,
+            max_uploads_per_hour: self.max_uploads_per_hour.min(Self::MAX_UPLOADS_PER_HOUR),
+        }
+    }
+}
+
+/// What devices did before capture policies: one picture per input, then a 5s cooldown
+impl Default for CapturePolicy {
+    fn default() -> Self {
+        Self {
+            webcam_interval_secs: None,
+            screenshot_interval_secs: None,
+            burst_count: 1,
+            burst_interval_secs: 0,
+            input_cooldown_secs: 5,
+            max_uploads_per_hour: 120,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub enum PowerCommand {
     Reboot,
     Poweroff,
@@ -31,4 +191,92 @@ pub enum PowerCommand {
 pub enum ServerCommand {
     StatusUpdate(StatusUpdate),
     PowerCommand(PowerCommand),
+    /// An admin command that the device checks against the root public key before applying
+    Signed(SignedCommand),
+    /// An admin asks the device to replace its key now, see `rotate_key`
+    RotateKey,
+    /// The device's capture policy changed
+    CapturePolicy(CapturePolicy),
+    /// Take a webcam picture now, devices ignore it unless their VT is locked
+    Capture,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn capture_policy_limits() {
+        let default = CapturePolicy::default();
+        assert!(default.check().is_ok());
+        let greedy = CapturePolicy {
+            webcam_interval_secs: Some(1),
+            burst_count: 0,
+            max_uploads_per_hour: u32::MAX,
+            ..default
+        };
+        assert!(greedy.check().is_err());
+        let clamped = greedy.clamped();
+        assert!(clamped.check().is_ok());
+        assert_eq!(
+            clamped.webcam_interval_secs,
+            Some(CapturePolicy::MIN_INTERVAL_SECS)
+        );
+        assert_eq!(clamped.burst_count, 1);
+        assert_eq!(
+            clamped.max_uploads_per_hour,
+            CapturePolicy::MAX_UPLOADS_PER_HOUR
+        );
+    }
+
+    #[test]
+    fn script_limits() {
+        let script = Script {
+            run_id: 1,
+            source: "uptime".into(),
+            timeout_secs: 60,
+            env: vec![("LANG".into(), "C".into())],
+        };
+        assert!(script.check().is_ok());
+        let forever = Script {
+            timeout_secs: 0,
+            ..script.clone()
+        };
+        assert!(forever.check().is_err());
+        let bad_env = Script {
+            env: vec![("A=B".into(), "C".into())],
+            ..script.clone()
+        };
+        assert!(bad_env.check().is_err());
+        let huge = Script {
+            source: "#".repeat(Script::MAX_SOURCE_LEN + 1),
+            ..script
+        };
+        assert!(huge.check().is_err());
+    }
+
+    #[test]
+    fn file_fetch_limits() {
+        let fetch = FileFetch {
+            fetch_id: 1,
+            paths: vec!["/home/user/project".into()],
+            encrypt_to: None,
+        };
+        assert!(fetch.check().is_ok());
+        let relative = FileFetch {
+            paths: vec!["project".into()],
+            ..fetch.clone()
+        };
+        assert!(relative.check().is_err());
+        let nothing = FileFetch {
+            paths: vec![],
+            ..fetch.clone()
+        };
+        assert!(nothing.check().is_err());
+        let too_many = FileFetch {
+            paths: vec!["/etc".into(); FileFetch::MAX_PATHS + 1],
+            ..fetch
+        };
+        assert!(too_many.check().is_err());
+    }
 }
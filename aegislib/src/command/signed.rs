@@ -0,0 +1,264 @@
+//! Commands signed by the admin, that devices verify before acting on them.
+//!
+//! aegisd only relays a `SignedCommand`, it can't forge or alter one, so a compromised server
+//! can't reboot or unlock devices on its own.
+
+use crate::command::server::{DeviceProfile, FileFetch, PowerCommand, Script, StatusUpdate};
+use crate::crypto::{check_signature, randomized_signature};
+use anyhow::{bail, Result};
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Signature domain, so a command signature can't be confused with a REST request signature
+const SIGNED_COMMAND_ROUTE: &[u8] = b"signed_command";
+/// How long after it was issued a device still accepts a `Validity::Once` command
+pub const MAX_COMMAND_AGE: Duration = Duration::from_secs(10 * 60);
+/// How far in the future `issued_at` may be, devices clocks aren't perfect
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(2 * 60);
+
+/// Changes to a device's status, unset fields are left as they are
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct StatusChange {
+    pub vt_locked: Option<bool>,
+    pub ssh_locked: Option<bool>,
+    pub draw_decoy: Option<bool>,
+}
+
+impl StatusChange {
+    pub fn apply_to(&self, status: StatusUpdate) -> StatusUpdate {
+        StatusUpdate {
+            vt_locked: self.vt_locked.unwrap_or(status.vt_locked),
+            ssh_locked: self.ssh_locked.unwrap_or(status.ssh_locked),
+            draw_decoy: self.draw_decoy.unwrap_or(status.draw_decoy),
+        }
+    }
+
+    /// Flips every field that is set, to undo this change
+    pub fn inverted(&self) -> StatusChange {
+        StatusChange {
+            vt_locked: self.vt_locked.map(|v| !v),
+            ssh_locked: self.ssh_locked.map(|v| !v),
+            draw_decoy: self.draw_decoy.map(|v| !v),
+        }
+    }
+
+    /// Whether this change can't unlock anything, so devices accept it unsigned
+    pub fn only_locks(&self) -> bool {
+        [self.vt_locked, self.ssh_locked, self.draw_decoy]
+            .iter()
+            .all(|v| *v != Some(false))
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub enum AdminCommand {
+    SetStatus(StatusChange),
+    Power(PowerCommand),
+    /// Applies the profile's status and starts its behaviors, None stops the active profile's
+    SetProfile(Option<DeviceProfile>),
+    /// Devices only run scripts when they have a root public key to verify them
+    RunScript(Script),
+    /// Like scripts, only with a root public key, and devices also limit which paths they send
+    FetchFiles(FileFetch),
+}
+
+/// When a device should accept a command
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub enum Validity {
+    /// Must be received shortly after `issued_at` (unix seconds), and only once
+    Once { issued_at: u64 },
+}
+
+/// The canonical payload covered by the admin's signature
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct CommandPayload {
+    /// Base64 public key of the target device, so a command can't be replayed to another one
+    pub device_pubkey: String,
+    pub validity: Validity,
+    pub command: AdminCommand,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct SignedCommand {
+    /// Bincode `CommandPayload`, kept as bytes so everyone checks exactly what was signed
+    pub payload: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl SignedCommand {
+    pub fn sign(
+        root_key: &ed25519_dalek::SigningKey,
+        device_pubkey: String,
+        command: AdminCommand,
+    ) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Self::sign_at(root_key, device_pubkey, command, now)
+    }
+
+    /// Signs a command ahead of time, for aegisd to relay at `issued_at`.
+    /// Devices reject it before then, and [`MAX_COMMAND_AGE`] after.
+    pub fn sign_at(
+        root_key: &ed25519_dalek::SigningKey,
+        device_pubkey: String,
+        command: AdminCommand,
+        issued_at: u64,
+    ) -> Self {
+        let payload = CommandPayload {
+            device_pubkey,
+            validity: Validity::Once { issued_at },
+            command,
+        };
+        let payload = bincode::serialize(&payload).unwrap();
+        let signature = randomized_signature(root_key, SIGNED_COMMAND_ROUTE, &payload);
+        Self { payload, signature }
+    }
+
+    /// Reads the payload without checking the signature, only the device can trust it
+    pub fn unverified_payload(&self) -> Result<CommandPayload> {
+        Ok(bincode::deserialize(&self.payload)?)
+    }
+
+    /// Checks that the admin signed this command for this device, and that it's still valid.
+    /// Replays within the validity window must be caught by the caller.
+    pub fn verify(
+        &self,
+        root_pk: &ed25519_dalek::VerifyingKey,
+        device_pk: &ed25519_dalek::VerifyingKey,
+        now: SystemTime,
+    ) -> Result<CommandPayload> {
+        if !check_signature(
+            root_pk,
+            &self.signature,
+            SIGNED_COMMAND_ROUTE,
+            &self.payload,
+        ) {
+            bail!("Invalid command signature");
+        }
+        let payload = self.unverified_payload()?;
+        if payload.device_pubkey != BASE64_URL_SAFE_NO_PAD.encode(device_pk) {
+            bail!("Command was signed for another device");
+        }
+        let now = now.duration_since(UNIX_EPOCH)?.as_secs();
+        match payload.validity {
+            Validity::Once { issued_at } => {
+                if issued_at > now + MAX_CLOCK_SKEW.as_secs() {
+                    bail!("Command was issued in the future");
+                }
+                if now.saturating_sub(issued_at) > MAX_COMMAND_AGE.as_secs() {
+                    bail!("Command has expired");
+                }
+            }
+        }
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::random_sign_keypair;
+
+    const POWEROFF: AdminCommand = AdminCommand::Power(PowerCommand::Poweroff);
+
+    #[test]
+    fn verify() {
+        let root_key = random_sign_keypair();
+        let device_pk = random_sign_keypair().verifying_key();
+        let device_pubkey = BASE64_URL_SAFE_NO_PAD.encode(device_pk);
+        let cmd = SignedCommand::sign(&root_key, device_pubkey, POWEROFF);
+        let payload = cmd
+            .verify(&root_key.verifying_key(), &device_pk, SystemTime::now())
+            .unwrap();
+        assert_eq!(payload.command, AdminCommand::Power(PowerCommand::Poweroff));
+
+        let other_device = random_sign_keypair().verifying_key();
+        assert!(cmd
+            .verify(&root_key.verifying_key(), &other_device, SystemTime::now())
+            .is_err());
+
+        let forger = random_sign_keypair();
+        assert!(cmd
+            .verify(&forger.verifying_key(), &device_pk, SystemTime::now())
+            .is_err());
+    }
+
+    #[test]
+    fn tampered_payload() {
+        let root_key = random_sign_keypair();
+        let device_pk = random_sign_keypair().verifying_key();
+        let device_pubkey = BASE64_URL_SAFE_NO_PAD.encode(device_pk);
+        let mut cmd = SignedCommand::sign(&root_key, device_pubkey, POWEROFF);
+        let mut payload = cmd.unverified_payload().unwrap();
+        payload.command = AdminCommand::Power(PowerCommand::Reboot);
+        cmd.payload = bincode::serialize(&payload).unwrap();
+        assert!(cmd
+            .verify(&root_key.verifying_key(), &device_pk, SystemTime::now())
+            .is_err());
+    }
+
+    #[test]
+    fn expired() {
+        let root_key = random_sign_keypair();
+        let device_pk = random_sign_keypair().verifying_key();
+        let device_pubkey = BASE64_URL_SAFE_NO_PAD.encode(device_pk);
+        // Timestamps are in whole seconds, so measure from either side of signing
+        let before = SystemTime::now();
+        let cmd = SignedCommand::sign(&root_key, device_pubkey, POWEROFF);
+        let after = SystemTime::now();
+        let later = after + MAX_COMMAND_AGE + Duration::from_secs(1);
+        assert!(cmd
+            .verify(&root_key.verifying_key(), &device_pk, later)
+            .is_err());
+        let earlier = before - MAX_CLOCK_SKEW - Duration::from_secs(1);
+        assert!(cmd
+            .verify(&root_key.verifying_key(), &device_pk, earlier)
+            .is_err());
+    }
+
+    #[test]
+    fn status_change() {
+        let status = StatusUpdate {
+            vt_locked: true,
+            ssh_locked: false,
+            draw_decoy: true,
+        };
+        let change = StatusChange {
+            vt_locked: None,
+            ssh_locked: Some(true),
+            draw_decoy: Some(false),
+        };
+        assert_eq!(
+            change.apply_to(status),
+            StatusUpdate {
+                vt_locked: true,
+                ssh_locked: true,
+                draw_decoy: false,
+            }
+        );
+        assert!(!change.only_locks());
+        let inverted = change.inverted();
+        assert_eq!(inverted.vt_locked, None);
+        assert_eq!(inverted.ssh_locked, Some(false));
+        assert_eq!(inverted.draw_decoy, Some(true));
+    }
+
+    #[test]
+    fn signed_ahead_of_time() {
+        let root_key = random_sign_keypair();
+        let device_pk = random_sign_keypair().verifying_key();
+        let device_pubkey = BASE64_URL_SAFE_NO_PAD.encode(device_pk);
+        let due = SystemTime::now() + Duration::from_secs(2 * 3600);
+        let issued_at = due.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let cmd = SignedCommand::sign_at(&root_key, device_pubkey, POWEROFF, issued_at);
+        assert!(cmd
+            .verify(&root_key.verifying_key(), &device_pk, SystemTime::now())
+            .is_err());
+        assert!(cmd
+            .verify(&root_key.verifying_key(), &device_pk, due)
+            .is_ok());
+    }
+}
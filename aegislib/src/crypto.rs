@@ -1,10 +1,14 @@
+pub mod channel;
+pub mod evidence;
+pub mod sealed;
+
 use anyhow::{bail, Result};
 use ed25519_dalek::Digest;
 use serde::{Deserialize, Serialize};
-use signature::Signature;
+use std::fmt::Write;
 use std::path::Path;
 
-pub use ed25519_dalek::SigningKey;
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
 
 // Random buffer prepended to the signature. Doesn't actually prevent any kind of replay!
 // Mostly this serves to inject random *somewhere* (hey, we get websocket message IDs for free!)
@@ -55,12 +59,37 @@ pub fn check_signature(
         .is_ok()
 }
 
+/// Lowercase hex, as used for the SHA-256 hashes we store and show
+pub fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, b| {
+            let _ = write!(hex, "{b:02x}");
+            hex
+        })
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(&sha2::Sha256::digest(data))
+}
+
 pub fn random_sign_keypair() -> ed25519_dalek::SigningKey {
     let sk = &mut [0u8; ed25519_dalek::SECRET_KEY_LENGTH];
     getrandom::getrandom(sk).unwrap();
     SigningKey::from_bytes(sk)
 }
 
+/// Parses a public key in the URL-safe base64 form used in config files and device lists
+pub fn public_key_from_base64(key: &str) -> Result<VerifyingKey> {
+    use base64::prelude::*;
+
+    let bytes = BASE64_URL_SAFE_NO_PAD.decode(key.trim())?;
+    match bytes.as_slice().try_into() {
+        Ok(bytes) => Ok(VerifyingKey::from_bytes(bytes)?),
+        Err(_) => bail!("Invalid public key length: {} bytes", bytes.len()),
+    }
+}
+
 pub fn sign_keypair_from_file(path: impl AsRef<Path>) -> Result<ed25519_dalek::SigningKey> {
     let key = &std::fs::read(path.as_ref())?.try_into();
     match key {
@@ -126,7 +155,7 @@ impl RootKeys {
     pub fn matches_serializes_pubkey(&self, pubkey: &str) -> bool {
         use base64::prelude::*;
 
-        let our_pubkey = self.sig.public;
+        let our_pubkey = self.sig.verifying_key();
         let our_pubkey = BASE64_URL_SAFE_NO_PAD.encode(our_pubkey.as_ref());
         our_pubkey == pubkey
     }
@@ -0,0 +1,353 @@
+//! Encrypted device websocket, for deployments where TLS is off or terminated by a middlebox
+//! we don't trust.
+//!
+//! The key exchange rides on the websocket upgrade. The device puts an ephemeral x25519 key,
+//! signed with its identity key, in the `aegis-channel` request header. The server answers in
+//! the same response header with its own ephemeral key, signing the whole exchange with the
+//! server key that devices have in their config. Each direction then gets its own
+//! ChaCha20Poly1305 key (HKDF-SHA256 of the shared secret), and every websocket data message is
+//! sealed with a counter nonce, so dropped, reordered or replayed messages fail to open.
+
+use crate::crypto::{check_signature, randomized_signature};
+use anyhow::{anyhow, bail, Result};
+use base64::prelude::*;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+
+/// Carries the client hello in the upgrade request, and the server hello in the response
+pub const CHANNEL_HEADER: &str = "aegis-channel";
+/// A hello is sent right after it's made, anything older was captured and replayed
+const MAX_HELLO_AGE: Duration = Duration::from_secs(5 * 60);
+
+const CLIENT_HELLO_ROUTE: &[u8] = b"channel_client_hello";
+const SERVER_HELLO_ROUTE: &[u8] = b"channel_server_hello";
+const CLIENT_TO_SERVER_INFO: &[u8] = b"aegis channel v1 client to server";
+const SERVER_TO_CLIENT_INFO: &[u8] = b"aegis channel v1 server to client";
+
+#[derive(Serialize, Deserialize)]
+struct ClientHello {
+    /// Unix seconds
+    timestamp: u64,
+    ephemeral: [u8; 32],
+    /// By the device key, over the timestamp and ephemeral key
+    signature: Vec<u8>,
+}
+
+impl ClientHello {
+    fn signed_payload(timestamp: u64, ephemeral: &[u8; 32]) -> Vec<u8> {
+        let mut payload = timestamp.to_le_bytes().to_vec();
+        payload.extend_from_slice(ephemeral);
+        payload
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ServerHello {
+    ephemeral: [u8; 32],
+    /// By the server key, over the device key, the client hello and our ephemeral key
+    signature: Vec<u8>,
+}
+
+impl ServerHello {
+    fn signed_payload(
+        device_pk: &VerifyingKey,
+        client: &ClientHello,
+        ephemeral: &[u8; 32],
+    ) -> Vec<u8> {
+        let mut payload = device_pk.as_bytes().to_vec();
+        payload.extend(ClientHello::signed_payload(
+            client.timestamp,
+            &client.ephemeral,
+        ));
+        payload.extend_from_slice(ephemeral);
+        payload
+    }
+}
+
+fn encode_header(hello: &impl Serialize) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(bincode::serialize(hello).unwrap())
+}
+
+fn decode_header<T: DeserializeOwned>(header_value: &str) -> Result<T> {
+    let bytes = BASE64_URL_SAFE_NO_PAD
+        .decode(header_value.trim())
+        .map_err(|_| anyhow!("Invalid {CHANNEL_HEADER} header encoding"))?;
+    bincode::deserialize(&bytes).map_err(|_| anyhow!("Invalid {CHANNEL_HEADER} header"))
+}
+
+/// The device side of the key exchange
+pub struct ClientHandshake {
+    secret: EphemeralSecret,
+    device_pk: VerifyingKey,
+    hello: ClientHello,
+}
+
+impl ClientHandshake {
+    pub fn start(device_key: &SigningKey) -> Self {
+        let secret = EphemeralSecret::random();
+        let ephemeral = PublicKey::from(&secret).to_bytes();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let payload = ClientHello::signed_payload(timestamp, &ephemeral);
+        let signature = randomized_signature(device_key, CLIENT_HELLO_ROUTE, &payload);
+        Self {
+            secret,
+            device_pk: device_key.verifying_key(),
+            hello: ClientHello {
+                timestamp,
+                ephemeral,
+                signature,
+            },
+        }
+    }
+
+    /// Value of the `aegis-channel` request header
+    pub fn header_value(&self) -> String {
+        encode_header(&self.hello)
+    }
+
+    /// Checks the server's answer, which must be signed by `server_pk`
+    pub fn finish(self, server_pk: &VerifyingKey, header_value: &str) -> Result<(Sealer, Opener)> {
+        let server_hello: ServerHello = decode_header(header_value)?;
+        let payload =
+            ServerHello::signed_payload(&self.device_pk, &self.hello, &server_hello.ephemeral);
+        if !check_signature(
+            server_pk,
+            &server_hello.signature,
+            SERVER_HELLO_ROUTE,
+            &payload,
+        ) {
+            bail!("Invalid server channel signature, is server_public_key right?");
+        }
+        let shared = self
+            .secret
+            .diffie_hellman(&PublicKey::from(server_hello.ephemeral));
+        let (client_to_server, server_to_client) =
+            derive_keys(&shared, &self.hello.ephemeral, &server_hello.ephemeral)?;
+        Ok((
+            Sealer::new(&client_to_server),
+            Opener::new(&server_to_client),
+        ))
+    }
+}
+
+/// The server side of the key exchange.
+/// Returns our ends of the channel, and the value of the `aegis-channel` response header.
+pub fn accept_client(
+    server_key: &SigningKey,
+    device_pk: &VerifyingKey,
+    header_value: &str,
+    now: SystemTime,
+) -> Result<(Sealer, Opener, String)> {
+    let client_hello: ClientHello = decode_header(header_value)?;
+    let payload = ClientHello::signed_payload(client_hello.timestamp, &client_hello.ephemeral);
+    if !check_signature(
+        device_pk,
+        &client_hello.signature,
+        CLIENT_HELLO_ROUTE,
+        &payload,
+    ) {
+        bail!("Invalid device channel signature");
+    }
+    let now = now.duration_since(UNIX_EPOCH)?.as_secs();
+    if client_hello.timestamp.abs_diff(now) > MAX_HELLO_AGE.as_secs() {
+        bail!("Channel hello is too old, or the device clock is off");
+    }
+
+    let secret = EphemeralSecret::random();
+    let ephemeral = PublicKey::from(&secret).to_bytes();
+    let payload = ServerHello::signed_payload(device_pk, &client_hello, &ephemeral);
+    let signature = randomized_signature(server_key, SERVER_HELLO_ROUTE, &payload);
+    let shared = secret.diffie_hellman(&PublicKey::from(client_hello.ephemeral));
+    let (client_to_server, server_to_client) =
+        derive_keys(&shared, &client_hello.ephemeral, &ephemeral)?;
+    let header = encode_header(&ServerHello {
+        ephemeral,
+        signature,
+    });
+    Ok((
+        Sealer::new(&server_to_client),
+        Opener::new(&client_to_server),
+        header,
+    ))
+}
+
+/// The (client to server, server to client) keys
+fn derive_keys(
+    shared: &SharedSecret,
+    client_ephemeral: &[u8; 32],
+    server_ephemeral: &[u8; 32],
+) -> Result<(Key, Key)> {
+    if !shared.was_contributory() {
+        bail!("Invalid channel ephemeral key");
+    }
+    let mut salt = client_ephemeral.to_vec();
+    salt.extend_from_slice(server_ephemeral);
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+    let mut client_to_server = Key::default();
+    let mut server_to_client = Key::default();
+    hkdf.expand(CLIENT_TO_SERVER_INFO, &mut client_to_server)
+        .and_then(|_| hkdf.expand(SERVER_TO_CLIENT_INFO, &mut server_to_client))
+        .map_err(|_| anyhow!("Failed to derive channel keys"))?;
+    Ok((client_to_server, server_to_client))
+}
+
+/// The nth message of a direction uses nonce n, both sides count the messages they see
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[..8].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// Encrypts the messages we send
+pub struct Sealer {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl Sealer {
+    fn new(key: &Key) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(key),
+            counter: 0,
+        }
+    }
+
+    pub fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce(self.counter), plaintext)
+            .expect("Failed to encrypt channel message");
+        self.counter += 1;
+        ciphertext
+    }
+}
+
+/// Decrypts the messages we receive
+pub struct Opener {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl Opener {
+    fn new(key: &Key) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(key),
+            counter: 0,
+        }
+    }
+
+    pub fn open(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let plaintext = self
+            .cipher
+            .decrypt(&nonce(self.counter), ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt channel message"))?;
+        self.counter += 1;
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::random_sign_keypair;
+
+    #[test]
+    fn handshake() {
+        let device_key = random_sign_keypair();
+        let server_key = random_sign_keypair();
+        let client = ClientHandshake::start(&device_key);
+        let (mut server_sealer, mut server_opener, header) = accept_client(
+            &server_key,
+            &device_key.verifying_key(),
+            &client.header_value(),
+            SystemTime::now(),
+        )
+        .unwrap();
+        let (mut client_sealer, mut client_opener) =
+            client.finish(&server_key.verifying_key(), &header).unwrap();
+
+        for msg in [&b"first"[..], b"second"] {
+            let sealed = client_sealer.seal(msg);
+            assert_ne!(sealed, msg);
+            assert_eq!(server_opener.open(&sealed).unwrap(), msg);
+        }
+        let sealed = server_sealer.seal(b"reply");
+        assert_eq!(client_opener.open(&sealed).unwrap(), b"reply");
+    }
+
+    #[test]
+    fn wrong_server_key() {
+        let device_key = random_sign_keypair();
+        let server_key = random_sign_keypair();
+        let client = ClientHandshake::start(&device_key);
+        let (_, _, header) = accept_client(
+            &server_key,
+            &device_key.verifying_key(),
+            &client.header_value(),
+            SystemTime::now(),
+        )
+        .unwrap();
+        let expected_server = random_sign_keypair().verifying_key();
+        assert!(client.finish(&expected_server, &header).is_err());
+    }
+
+    #[test]
+    fn wrong_device_key() {
+        let client = ClientHandshake::start(&random_sign_keypair());
+        let other_device = random_sign_keypair().verifying_key();
+        let server_key = random_sign_keypair();
+        let accepted = accept_client(
+            &server_key,
+            &other_device,
+            &client.header_value(),
+            SystemTime::now(),
+        );
+        assert!(accepted.is_err());
+    }
+
+    #[test]
+    fn old_hello() {
+        let device_key = random_sign_keypair();
+        let client = ClientHandshake::start(&device_key);
+        let later = SystemTime::now() + MAX_HELLO_AGE + Duration::from_secs(1);
+        let accepted = accept_client(
+            &random_sign_keypair(),
+            &device_key.verifying_key(),
+            &client.header_value(),
+            later,
+        );
+        assert!(accepted.is_err());
+    }
+
+    #[test]
+    fn replayed_and_tampered_messages() {
+        let device_key = random_sign_keypair();
+        let server_key = random_sign_keypair();
+        let client = ClientHandshake::start(&device_key);
+        let (_, mut server_opener, header) = accept_client(
+            &server_key,
+            &device_key.verifying_key(),
+            &client.header_value(),
+            SystemTime::now(),
+        )
+        .unwrap();
+        let (mut client_sealer, _) = client.finish(&server_key.verifying_key(), &header).unwrap();
+
+        let first = client_sealer.seal(b"first");
+        let mut second = client_sealer.seal(b"second");
+        assert!(server_opener.open(&first).is_ok());
+        assert!(server_opener.open(&first).is_err());
+        second[0] ^= 1;
+        assert!(server_opener.open(&second).is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//! Evidence bundles: the files exported for a device, with a manifest of their SHA-256 hashes
+//! signed by the admin key, so a third party can check that nothing was added, removed or altered.
+//!
+//! The manifest uses the `sha256sum` format, so `sha256sum -c MANIFEST.sha256` also checks
+//! the files, but only the signature proves who exported them.
+
+use crate::crypto::{check_signature, public_key_from_base64, randomized_signature, sha256_hex};
+use anyhow::{bail, Context, Result};
+use base64::prelude::*;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+pub const MANIFEST_PATH: &str = "MANIFEST.sha256";
+pub const SIGNATURE_PATH: &str = "MANIFEST.sha256.sig";
+/// The admin public key that signed the manifest, check it against a copy you trust
+pub const SIGNER_PATH: &str = "SIGNER.pub";
+const MANIFEST_SIGNATURE_ROUTE: &[u8] = b"evidence_manifest";
+
+fn check_path(path: &str) -> Result<()> {
+    let valid = !path.is_empty()
+        && !path.starts_with('/')
+        && !path.contains('\\')
+        && !path.chars().any(|c| c.is_control())
+        && path.split('/').all(|part| !matches!(part, "" | "." | ".."));
+    if !valid {
+        bail!("Invalid path in evidence bundle: {path:?}");
+    }
+    Ok(())
+}
+
+/// Files collected for an evidence bundle, before they are signed
+#[derive(Default)]
+pub struct EvidenceBundle {
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+impl EvidenceBundle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `path` is relative to the root of the bundle, with `/` separators
+    pub fn add(&mut self, path: impl Into<String>, data: Vec<u8>) -> Result<()> {
+        let path = path.into();
+        check_path(&path)?;
+        if [MANIFEST_PATH, SIGNATURE_PATH, SIGNER_PATH].contains(&path.as_str()) {
+            bail!("{path} is reserved for the bundle's signature");
+        }
+        if self.files.insert(path.clone(), data).is_some() {
+            bail!("Duplicate file in evidence bundle: {path}");
+        }
+        Ok(())
+    }
+
+    fn manifest(&self) -> String {
+        self.files
+            .iter()
+            .fold(String::new(), |mut manifest, (path, data)| {
+                let _ = writeln!(manifest, "{}  {path}", sha256_hex(data));
+                manifest
+            })
+    }
+
+    /// Returns every file of the bundle, including the manifest and its signature
+    pub fn sign(mut self, key: &SigningKey) -> BTreeMap<String, Vec<u8>> {
+        let manifest = self.manifest().into_bytes();
+        let signature = randomized_signature(key, MANIFEST_SIGNATURE_ROUTE, &manifest);
+        let signer = BASE64_URL_SAFE_NO_PAD.encode(key.verifying_key().as_ref());
+        let signature = BASE64_URL_SAFE_NO_PAD.encode(signature);
+        self.files.insert(MANIFEST_PATH.to_owned(), manifest);
+        self.files.insert(
+            SIGNATURE_PATH.to_owned(),
+            format!("{signature}\n").into_bytes(),
+        );
+        self.files
+            .insert(SIGNER_PATH.to_owned(), format!("{signer}\n").into_bytes());
+        self.files
+    }
+}
+
+fn read_text<'a>(files: &'a BTreeMap<String, Vec<u8>>, path: &str) -> Result<&'a str> {
+    let data = files
+        .get(path)
+        .with_context(|| format!("Missing {path} in evidence bundle"))?;
+    std::str::from_utf8(data).with_context(|| format!("{path} is not valid UTF-8"))
+}
+
+/// Checks the signature and every hash of a bundle, and that no file is missing or unlisted.
+/// Returns the key that signed it, which is only meaningful if it matches a key you trust.
+pub fn verify(files: &BTreeMap<String, Vec<u8>>) -> Result<VerifyingKey> {
+    let manifest = read_text(files, MANIFEST_PATH)?;
+    let signer = public_key_from_base64(read_text(files, SIGNER_PATH)?)
+        .context("Invalid signer public key")?;
+    let signature = BASE64_URL_SAFE_NO_PAD
+        .decode(read_text(files, SIGNATURE_PATH)?.trim())
+        .context("Invalid manifest signature encoding")?;
+    if !check_signature(
+        &signer,
+        &signature,
+        MANIFEST_SIGNATURE_ROUTE,
+        manifest.as_bytes(),
+    ) {
+        bail!("The manifest signature does not match");
+    }
+
+    let mut listed = BTreeMap::new();
+    for line in manifest.lines() {
+        let Some((hash, path)) = line.split_once("  ") else {
+            bail!("Invalid manifest line: {line:?}");
+        };
+        check_path(path)?;
+        if listed.insert(path, hash).is_some() {
+            bail!("{path} is listed twice in the manifest");
+        }
+    }
+    for (path, hash) in &listed {
+        let data = files
+            .get(*path)
+            .with_context(|| format!("{path} is listed in the manifest but missing"))?;
+        if sha256_hex(data) != *hash {
+            bail!("{path} was modified");
+        }
+    }
+    for path in files.keys() {
+        let is_signature = [MANIFEST_PATH, SIGNATURE_PATH, SIGNER_PATH].contains(&path.as_str());
+        if !is_signature && !listed.contains_key(path.as_str()) {
+            bail!("{path} is not listed in the manifest");
+        }
+    }
+    Ok(signer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::random_sign_keypair;
+
+    fn signed_bundle(key: &SigningKey) -> BTreeMap<String, Vec<u8>> {
+        let mut bundle = EvidenceBundle::new();
+        bundle.add("events.json", b"[]".to_vec()).unwrap();
+        bundle.add("pictures/1.jpg", vec![0xff, 0xd8]).unwrap();
+        bundle.sign(key)
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let key = random_sign_keypair();
+        let files = signed_bundle(&key);
+        assert_eq!(verify(&files).unwrap(), key.verifying_key());
+        let manifest = std::str::from_utf8(&files[MANIFEST_PATH]).unwrap();
+        assert!(manifest.ends_with(&format!("{}  pictures/1.jpg\n", sha256_hex(&[0xff, 0xd8]))));
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let key = random_sign_keypair();
+
+        let mut modified = signed_bundle(&key);
+        modified.insert("events.json".into(), b"[{}]".to_vec());
+        assert!(verify(&modified).is_err());
+
+        let mut removed = signed_bundle(&key);
+        removed.remove("pictures/1.jpg");
+        assert!(verify(&removed).is_err());
+
+        let mut added = signed_bundle(&key);
+        added.insert("pictures/2.jpg".into(), vec![0xff, 0xd8]);
+        assert!(verify(&added).is_err());
+
+        // Swapping in a manifest signed by someone else doesn't match the listed signer
+        let mut resigned = signed_bundle(&key);
+        let other = random_sign_keypair();
+        let forged = signed_bundle(&other);
+        resigned.insert(MANIFEST_PATH.into(), forged[MANIFEST_PATH].clone());
+        resigned.insert(SIGNATURE_PATH.into(), forged[SIGNATURE_PATH].clone());
+        assert!(verify(&resigned).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_paths() {
+        let mut bundle = EvidenceBundle::new();
+        for path in ["", "/etc/passwd", "../x", "a//b", "a/./b", MANIFEST_PATH] {
+            assert!(bundle.add(path, Vec::new()).is_err(), "{path}");
+        }
+        bundle.add("a", Vec::new()).unwrap();
+        assert!(bundle.add("a", Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+//! Files sealed for the admin, so aegisd stores data it can't read.
+//!
+//! The admin's x25519 key is derived from the root encryption key, only its public half is sent
+//! to devices. A device seals every file to it with a new ephemeral key: the ephemeral public key
+//! comes first, then each chunk of the file sealed with ChaCha20Poly1305 under a counter nonce.
+//! The nonce of the last chunk is marked, so a file cut short at a chunk boundary fails to open.
+//! Every chunk is bound to the fetch ID and path as associated data, so aegisd can't pass a
+//! sealed file off as another one.
+
+use anyhow::{anyhow, bail, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret, StaticSecret};
+
+/// The ephemeral public key that goes before the first chunk
+pub const HEADER_LEN: usize = 32;
+/// What sealing adds to every chunk
+pub const TAG_LEN: usize = 16;
+
+const RECIPIENT_INFO: &[u8] = b"aegis sealed files v1 recipient";
+const FILE_KEY_INFO: &[u8] = b"aegis sealed files v1 file key";
+
+fn recipient_secret(root_enc: &Key) -> StaticSecret {
+    let hkdf = Hkdf::<Sha256>::new(None, root_enc);
+    let mut secret = [0u8; 32];
+    hkdf.expand(RECIPIENT_INFO, &mut secret)
+        .expect("32 bytes is a valid HKDF output length");
+    StaticSecret::from(secret)
+}
+
+/// What devices seal files to, for the admin with this root encryption key
+pub fn recipient_public_key(root_enc: &Key) -> [u8; 32] {
+    PublicKey::from(&recipient_secret(root_enc)).to_bytes()
+}
+
+fn file_key(shared: &SharedSecret, ephemeral: &[u8; 32], recipient: &[u8; 32]) -> Result<Key> {
+    if !shared.was_contributory() {
+        bail!("Invalid sealed file key");
+    }
+    let mut salt = ephemeral.to_vec();
+    salt.extend_from_slice(recipient);
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+    let mut key = Key::default();
+    hkdf.expand(FILE_KEY_INFO, &mut key)
+        .map_err(|_| anyhow!("Failed to derive sealed file key"))?;
+    Ok(key)
+}
+
+/// The nth chunk uses nonce n, with the last byte set on the last chunk
+fn nonce(counter: u64, last: bool) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[..8].copy_from_slice(&counter.to_le_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+/// The associated data of every chunk of the file at `path` sent for fetch `fetch_id`
+fn associated_data(fetch_id: u64, path: &str) -> Vec<u8> {
+    let mut aad = fetch_id.to_le_bytes().to_vec();
+    aad.extend_from_slice(path.as_bytes());
+    aad
+}
+
+/// Seals the chunks of one file, in order
+pub struct FileSealer {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    aad: Vec<u8>,
+}
+
+impl FileSealer {
+    /// Returns the sealer, and the header that goes before the first chunk
+    pub fn new(
+        recipient: &[u8; 32],
+        fetch_id: u64,
+        path: &str,
+    ) -> Result<(Self, [u8; HEADER_LEN])> {
+        let secret = EphemeralSecret::random();
+        let ephemeral = PublicKey::from(&secret).to_bytes();
+        let shared = secret.diffie_hellman(&PublicKey::from(*recipient));
+        let key = file_key(&shared, &ephemeral, recipient)?;
+        let sealer = Self {
+            cipher: ChaCha20Poly1305::new(&key),
+            counter: 0,
+            aad: associated_data(fetch_id, path),
+        };
+        Ok((sealer, ephemeral))
+    }
+
+    pub fn seal(&mut self, chunk: &[u8], last: bool) -> Vec<u8> {
+        let payload = Payload {
+            msg: chunk,
+            aad: &self.aad,
+        };
+        let sealed = self
+            .cipher
+            .encrypt(&nonce(self.counter, last), payload)
+            .expect("Failed to seal file chunk");
+        self.counter += 1;
+        sealed
+    }
+}
+
+/// Opens the chunks of one file, in order
+pub struct FileOpener {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    aad: Vec<u8>,
+}
+
+impl FileOpener {
+    pub fn new(root_enc: &Key, header: &[u8], fetch_id: u64, path: &str) -> Result<Self> {
+        let ephemeral: [u8; HEADER_LEN] = header
+            .try_into()
+            .map_err(|_| anyhow!("Invalid sealed file header"))?;
+        let secret = recipient_secret(root_enc);
+        let recipient = PublicKey::from(&secret).to_bytes();
+        let shared = secret.diffie_hellman(&PublicKey::from(ephemeral));
+        let key = file_key(&shared, &ephemeral, &recipient)?;
+        Ok(Self {
+            cipher: ChaCha20Poly1305::new(&key),
+            counter: 0,
+            aad: associated_data(fetch_id, path),
+        })
+    }
+
+    pub fn open(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>> {
+        let payload = Payload {
+            msg: chunk,
+            aad: &self.aad,
+        };
+        let plaintext = self
+            .cipher
+            .decrypt(&nonce(self.counter, last), payload)
+            .map_err(|_| anyhow!("Failed to open sealed file chunk, wrong key or altered file"))?;
+        self.counter += 1;
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seal_and_open() {
+        let root_enc = Key::from([7; 32]);
+        let recipient = recipient_public_key(&root_enc);
+        let (mut sealer, header) = FileSealer::new(&recipient, 1, "/etc/hosts").unwrap();
+        let first = sealer.seal(b"first chunk", false);
+        let last = sealer.seal(b"last", true);
+        assert_eq!(first.len(), b"first chunk".len() + TAG_LEN);
+
+        let mut opener = FileOpener::new(&root_enc, &header, 1, "/etc/hosts").unwrap();
+        assert_eq!(opener.open(&first, false).unwrap(), b"first chunk");
+        assert_eq!(opener.open(&last, true).unwrap(), b"last");
+
+        // A file cut after its first chunk doesn't pass for a complete one
+        let mut opener = FileOpener::new(&root_enc, &header, 1, "/etc/hosts").unwrap();
+        assert!(opener.open(&first, true).is_err());
+
+        // Nor does it pass for another file, or the same file from another fetch
+        let mut opener = FileOpener::new(&root_enc, &header, 1, "/etc/passwd").unwrap();
+        assert!(opener.open(&first, false).is_err());
+        let mut opener = FileOpener::new(&root_enc, &header, 2, "/etc/hosts").unwrap();
+        assert!(opener.open(&first, false).is_err());
+
+        let other_key = Key::from([8; 32]);
+        let mut opener = FileOpener::new(&other_key, &header, 1, "/etc/hosts").unwrap();
+        assert!(opener.open(&first, false).is_err());
+    }
+}
@@ -1,5 +1,7 @@
 mod admin_client_ffi;
 use admin_client_ffi::AdminClientFfi;
+mod live_events_ffi;
+use live_events_ffi::{LiveEventListener, LiveSubscription};
 mod error;
 pub(crate) use error::FfiError;
 
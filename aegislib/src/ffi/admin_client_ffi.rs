@@ -1,14 +1,20 @@
+use super::live_events_ffi::{LiveEventListener, LiveSubscription};
 use super::FfiError;
 use crate::client::{AdminClient, ClientConfig};
 use crate::command::admin::{
-    PendingDevice, RegisteredDevice, SendPowerCommandArg, SetStatusArg, StoredCameraPicture,
+    ArchivedDevice, AutomationRule, CreateEnrollmentTokenArg, CreatedEnrollmentToken,
+    DeviceRetention, EnrollmentToken, EventPage, GetEventsArg, GetPicturesArg, ImportDevicesReply,
+    ImportedDevice, ListRuleExecutionsArg, PendingDevice, PictureIdArg, PictureInfoPage,
+    PicturePage, ProtectionProfile, RegisteredDevice, RenameDeviceArg, RuleExecution,
+    ScheduledStatusChange, SetCapturePolicyArg, SetDeviceInfoArg, SetRetentionArg, SetStatusArg,
+    SetStolenArg, StatusHistoryEntry, StatusScheduleIdArg, StoredCameraPicture, SubscribeArg,
 };
 use crate::command::device::{DeviceEvent, StatusReply};
-use crate::command::server::PowerCommand;
+use crate::command::server::{CapturePolicy, PowerCommand};
 use crate::crypto::RootKeys;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use tokio::runtime::Runtime;
 
 pub struct AdminClientFfi {
@@ -46,22 +52,147 @@ impl AdminClientFfi {
         self.do_request("confirm_pending_device", name)
     }
 
+    pub fn create_enrollment_token(
+        &self,
+        arg: CreateEnrollmentTokenArg,
+    ) -> Result<CreatedEnrollmentToken, FfiError> {
+        self.do_request("create_enrollment_token", arg)
+    }
+
+    pub fn list_enrollment_tokens(&self) -> Result<Vec<EnrollmentToken>, FfiError> {
+        self.do_request("list_enrollment_tokens", ())
+    }
+
+    pub fn delete_enrollment_token(&self, id: i32) -> Result<(), FfiError> {
+        self.do_request("delete_enrollment_token", id)
+    }
+
+    pub fn list_token_pending(&self, id: i32) -> Result<Vec<PendingDevice>, FfiError> {
+        self.do_request("list_token_pending_devices", id)
+    }
+
+    pub fn import_devices(
+        &self,
+        devices: Vec<ImportedDevice>,
+    ) -> Result<ImportDevicesReply, FfiError> {
+        self.do_request("import_devices", devices)
+    }
+
     pub fn list_registered(&self) -> Result<Vec<RegisteredDevice>, FfiError> {
         self.do_request("list_registered_devices", ())
     }
 
-    pub fn delete_registered(&self, name: String) -> Result<(), FfiError> {
-        self.do_request("delete_registered_device", name)
+    pub fn list_archived(&self) -> Result<Vec<ArchivedDevice>, FfiError> {
+        self.do_request("list_archived_devices", ())
+    }
+
+    pub fn archive_device(&self, name: String) -> Result<(), FfiError> {
+        self.do_request("archive_device", name)
+    }
+
+    pub fn restore_device(&self, name: String) -> Result<(), FfiError> {
+        self.do_request("restore_device", name)
+    }
+
+    pub fn purge_device(&self, name: String) -> Result<(), FfiError> {
+        self.do_request("purge_device", name)
+    }
+
+    pub fn rename_device(&self, dev_name: String, new_name: String) -> Result<(), FfiError> {
+        self.do_request("rename_device", RenameDeviceArg { dev_name, new_name })
+    }
+
+    pub fn set_device_info(&self, arg: SetDeviceInfoArg) -> Result<(), FfiError> {
+        self.do_request("set_device_info", arg)
     }
 
     pub fn set_status(&self, arg: SetStatusArg) -> Result<StatusReply, FfiError> {
-        self.do_request("set_status", arg)
+        let mut client = self.client.lock().expect("Poisoned lock");
+        self.rt
+            .block_on(client.set_status(arg))
+            .map_err(FfiError::Error)
+    }
+
+    pub fn status_history(&self, dev_name: String) -> Result<Vec<StatusHistoryEntry>, FfiError> {
+        self.do_request("status_history", dev_name)
+    }
+
+    pub fn list_status_schedules(
+        &self,
+        dev_name: String,
+    ) -> Result<Vec<ScheduledStatusChange>, FfiError> {
+        self.do_request("list_status_schedules", dev_name)
+    }
+
+    pub fn cancel_status_schedule(&self, dev_name: String, id: i32) -> Result<(), FfiError> {
+        self.do_request(
+            "cancel_status_schedule",
+            StatusScheduleIdArg { dev_name, id },
+        )
+    }
+
+    pub fn list_profiles(&self) -> Result<Vec<ProtectionProfile>, FfiError> {
+        self.do_request("list_profiles", ())
+    }
+
+    pub fn set_profile(&self, profile: ProtectionProfile) -> Result<(), FfiError> {
+        self.do_request("set_profile", profile)
+    }
+
+    pub fn delete_profile(&self, name: String) -> Result<(), FfiError> {
+        self.do_request("delete_profile", name)
+    }
+
+    pub fn apply_profile(&self, dev_name: String, name: String) -> Result<StatusReply, FfiError> {
+        let mut client = self.client.lock().expect("Poisoned lock");
+        self.rt
+            .block_on(client.apply_profile(dev_name, name))
+            .map_err(FfiError::Error)
+    }
+
+    pub fn clear_profile(&self, dev_name: String) -> Result<StatusReply, FfiError> {
+        let mut client = self.client.lock().expect("Poisoned lock");
+        self.rt
+            .block_on(client.clear_profile(dev_name))
+            .map_err(FfiError::Error)
     }
 
     pub fn delete_device_camera_pictures(&self, dev_name: String) -> Result<(), FfiError> {
         self.do_request("delete_device_camera_pictures", dev_name)
     }
 
+    pub fn list_device_camera_pictures(
+        &self,
+        arg: GetPicturesArg,
+    ) -> Result<PicturePage, FfiError> {
+        self.do_request("list_device_camera_pictures", arg)
+    }
+
+    pub fn list_device_picture_info(
+        &self,
+        arg: GetPicturesArg,
+    ) -> Result<PictureInfoPage, FfiError> {
+        self.do_request("list_device_picture_info", arg)
+    }
+
+    pub fn get_device_camera_picture(
+        &self,
+        arg: PictureIdArg,
+    ) -> Result<StoredCameraPicture, FfiError> {
+        self.do_request("get_device_camera_picture", arg)
+    }
+
+    pub fn get_device_picture_thumbnail(
+        &self,
+        arg: PictureIdArg,
+    ) -> Result<StoredCameraPicture, FfiError> {
+        self.do_request("get_device_picture_thumbnail", arg)
+    }
+
+    pub fn delete_device_camera_picture(&self, arg: PictureIdArg) -> Result<(), FfiError> {
+        self.do_request("delete_device_camera_picture", arg)
+    }
+
     pub fn get_device_camera_pictures(
         &self,
         dev_name: String,
@@ -69,23 +200,82 @@ impl AdminClientFfi {
         self.do_request("get_device_camera_pictures", dev_name)
     }
 
+    pub fn get_device_retention(&self, dev_name: String) -> Result<DeviceRetention, FfiError> {
+        self.do_request("get_device_retention", dev_name)
+    }
+
+    pub fn set_device_retention(&self, arg: SetRetentionArg) -> Result<(), FfiError> {
+        self.do_request("set_device_retention", arg)
+    }
+
+    pub fn set_device_stolen(&self, arg: SetStolenArg) -> Result<(), FfiError> {
+        self.do_request("set_device_stolen", arg)
+    }
+
+    pub fn get_capture_policy(&self, dev_name: String) -> Result<CapturePolicy, FfiError> {
+        self.do_request("get_capture_policy", dev_name)
+    }
+
+    pub fn set_capture_policy(&self, arg: SetCapturePolicyArg) -> Result<(), FfiError> {
+        self.do_request("set_capture_policy", arg)
+    }
+
+    pub fn list_rules(&self) -> Result<Vec<AutomationRule>, FfiError> {
+        self.do_request("list_rules", ())
+    }
+
+    pub fn set_rule(&self, rule: AutomationRule) -> Result<(), FfiError> {
+        self.do_request("set_rule", rule)
+    }
+
+    pub fn delete_rule(&self, name: String) -> Result<(), FfiError> {
+        self.do_request("delete_rule", name)
+    }
+
+    pub fn list_rule_executions(
+        &self,
+        arg: ListRuleExecutionsArg,
+    ) -> Result<Vec<RuleExecution>, FfiError> {
+        self.do_request("list_rule_executions", arg)
+    }
+
     pub fn send_power_command(&self, dev_name: String, cmd: PowerCommand) -> Result<(), FfiError> {
-        self.do_request(
-            "send_power_command",
-            SendPowerCommandArg {
-                dev_name,
-                command: cmd,
-            },
-        )
+        let mut client = self.client.lock().expect("Poisoned lock");
+        self.rt
+            .block_on(client.send_power_command(dev_name, cmd))
+            .map_err(FfiError::Error)
+    }
+
+    pub fn request_key_rotation(&self, dev_name: String) -> Result<(), FfiError> {
+        self.do_request("request_key_rotation", dev_name)
     }
 
     pub fn delete_device_events(&self, dev_name: String) -> Result<(), FfiError> {
         self.do_request("delete_device_events", dev_name)
     }
 
+    pub fn list_device_events(&self, arg: GetEventsArg) -> Result<EventPage, FfiError> {
+        self.do_request("list_device_events", arg)
+    }
+
     pub fn get_device_events(&self, dev_name: String) -> Result<Vec<DeviceEvent>, FfiError> {
         self.do_request("get_device_events", dev_name)
     }
+
+    /// Calls `listener` from a background thread for each live event, until cancelled
+    pub fn subscribe(
+        &self,
+        filter: SubscribeArg,
+        listener: Box<dyn LiveEventListener>,
+    ) -> Result<Arc<LiveSubscription>, FfiError> {
+        let mut client = self.client.lock().expect("Poisoned lock");
+        let stream = self
+            .rt
+            .block_on(client.subscribe(filter))
+            .map_err(FfiError::Error)?;
+        let task = self.rt.spawn(LiveSubscription::forward(stream, listener));
+        Ok(Arc::new(LiveSubscription::new(task)))
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,41 @@
+use crate::client::LiveEventStream;
+use crate::command::admin::LiveEvent;
+use tokio::task::JoinHandle;
+
+/// Implemented by the foreign app to receive live events
+pub trait LiveEventListener: Send + Sync + std::fmt::Debug {
+    fn on_event(&self, event: LiveEvent);
+    /// The stream ended, with an error unless the server closed it cleanly
+    fn on_closed(&self, error: Option<String>);
+}
+
+/// Stops delivering events when cancelled or dropped
+pub struct LiveSubscription {
+    task: JoinHandle<()>,
+}
+
+impl LiveSubscription {
+    pub(super) fn new(task: JoinHandle<()>) -> Self {
+        Self { task }
+    }
+
+    pub(super) async fn forward(mut stream: LiveEventStream, listener: Box<dyn LiveEventListener>) {
+        loop {
+            match stream.next().await {
+                Ok(Some(event)) => listener.on_event(event),
+                Ok(None) => return listener.on_closed(None),
+                Err(e) => return listener.on_closed(Some(e.to_string())),
+            }
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.task.abort();
+    }
+}
+
+impl Drop for LiveSubscription {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -1,5 +1,6 @@
 pub mod command;
 pub mod crypto;
+pub mod protocol;
 
 #[cfg(feature = "client")]
 pub mod client;
@@ -0,0 +1,301 @@
+//! Protocol version and capability negotiation between devices, admins and aegisd.
+//!
+//! Payloads are positional bincode, so a peer that doesn't know about a field or a
+//! `ServerCommand` variant fails to deserialize it. Every REST request and websocket upgrade
+//! carries our version and capabilities in headers, and the server answers with its own.
+//! The server only sends what a device says it understands, and each side rejects peers older
+//! than the minimum version it still speaks.
+
+use crate::command::device::CaptureTrigger;
+use crate::command::server::ServerCommand;
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use strum_macros::IntoStaticStr;
+
+/// Version of the protocol spoken by this build
+pub const PROTOCOL_VERSION: u32 = 12;
+/// Oldest device protocol that aegisd still talks to
+pub const MIN_DEVICE_PROTOCOL_VERSION: u32 = 1;
+/// Oldest admin client protocol that aegisd still talks to.
+/// Version 3 admins sign status and power commands, older ones can't be relayed to devices.
+/// aegisd sends later admins the argument and reply shapes of their own version.
+pub const MIN_ADMIN_PROTOCOL_VERSION: u32 = 3;
+/// Oldest server protocol that clients still talk to
+pub const MIN_SERVER_PROTOCOL_VERSION: u32 = 1;
+/// Oldest server protocol that accepts `upload_camera_picture`, older ones only take
+/// `store_camera_picture` and don't record why a picture was taken
+pub const UPLOAD_CAMERA_PICTURE_VERSION: u32 = 4;
+/// Oldest server protocol that lets devices replace their key with `rotate_key`
+pub const KEY_ROTATION_VERSION: u32 = 5;
+/// Oldest server protocol that accepts `report_hardware`, and oldest admin protocol with device
+/// metadata in `RegisteredDevice`
+pub const HARDWARE_INFO_VERSION: u32 = 6;
+/// Oldest admin protocol with scheduled and expiring status changes, and `changed_by` in
+/// `StatusHistoryEntry`
+pub const STATUS_SCHEDULE_VERSION: u32 = 7;
+/// Oldest server protocol that accepts `report_profile` and `CaptureTrigger::Periodic`
+pub const PROTECTION_PROFILE_VERSION: u32 = 8;
+/// Oldest server protocol that has `capture_policy`, and accepts `CaptureTrigger::Scheduled`
+/// and `CaptureTrigger::Screenshot`
+pub const CAPTURE_POLICY_VERSION: u32 = 9;
+/// Oldest server protocol that accepts `CaptureTrigger::Requested`
+pub const REQUESTED_CAPTURE_VERSION: u32 = 10;
+/// Oldest server protocol that accepts `script_result`
+pub const SCRIPT_RESULT_VERSION: u32 = 11;
+/// Oldest server protocol that accepts `file_chunk` and `file_fetch_finished`
+pub const FILE_FETCH_VERSION: u32 = 12;
+
+/// Peers that predate negotiation don't send headers, they all speak this version
+pub const LEGACY_PROTOCOL_VERSION: u32 = 1;
+
+pub const PROTOCOL_VERSION_HEADER: &str = "aegis-protocol-version";
+pub const CAPABILITIES_HEADER: &str = "aegis-capabilities";
+/// Carries an enrollment token when registering a device
+pub const ENROLLMENT_TOKEN_HEADER: &str = "aegis-enrollment-token";
+
+/// Optional features that a peer understands.
+/// Capability names are sent over the wire, so never rename a variant.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum Capability {
+    /// Understands `ServerCommand::StatusUpdate`
+    StatusUpdate,
+    /// Understands `ServerCommand::PowerCommand`
+    PowerCommand,
+    /// Understands `ServerCommand::Signed`
+    SignedCommands,
+    /// Understands `ServerCommand::RotateKey`
+    KeyRotation,
+    /// Understands `AdminCommand::SetProfile` in signed commands
+    Profiles,
+    /// Understands `ServerCommand::CapturePolicy`
+    CapturePolicy,
+    /// Understands `ServerCommand::Capture`
+    RemoteCapture,
+    /// Understands `AdminCommand::RunScript` in signed commands
+    Scripts,
+    /// Understands `AdminCommand::FetchFiles` in signed commands
+    FileFetch,
+}
+
+impl Capability {
+    pub const ALL: &'static [Capability] = &[
+        Capability::StatusUpdate,
+        Capability::PowerCommand,
+        Capability::SignedCommands,
+        Capability::KeyRotation,
+        Capability::Profiles,
+        Capability::CapturePolicy,
+        Capability::RemoteCapture,
+        Capability::Scripts,
+        Capability::FileFetch,
+    ];
+
+    /// What every client spoke before capabilities were negotiated
+    pub const LEGACY: &'static [Capability] = &[Capability::StatusUpdate, Capability::PowerCommand];
+
+    pub fn name(self) -> &'static str {
+        self.into()
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|c| c.name() == name)
+    }
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl ServerCommand {
+    /// The capability a device must have advertised before we can send it this command
+    pub fn required_capability(&self) -> Capability {
+        match self {
+            ServerCommand::StatusUpdate(_) => Capability::StatusUpdate,
+            ServerCommand::PowerCommand(_) => Capability::PowerCommand,
+            ServerCommand::Signed(_) => Capability::SignedCommands,
+            ServerCommand::RotateKey => Capability::KeyRotation,
+            ServerCommand::CapturePolicy(_) => Capability::CapturePolicy,
+            ServerCommand::Capture => Capability::RemoteCapture,
+        }
+    }
+}
+
+impl CaptureTrigger {
+    /// Oldest protocol that has this trigger
+    pub fn min_version(self) -> u32 {
+        match self {
+            CaptureTrigger::Unknown | CaptureTrigger::InputWhileLocked => 0,
+            CaptureTrigger::Periodic => PROTECTION_PROFILE_VERSION,
+            CaptureTrigger::Scheduled | CaptureTrigger::Screenshot => CAPTURE_POLICY_VERSION,
+            CaptureTrigger::Requested => REQUESTED_CAPTURE_VERSION,
+        }
+    }
+
+    /// The trigger as a peer of `version` knows it, newer ones are `Unknown` to it
+    pub fn for_version(self, version: u32) -> Self {
+        match version < self.min_version() {
+            true => CaptureTrigger::Unknown,
+            false => self,
+        }
+    }
+}
+
+/// What a peer told us it speaks
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PeerProtocol {
+    pub version: u32,
+    pub capabilities: BTreeSet<Capability>,
+}
+
+impl PeerProtocol {
+    /// The protocol spoken by this build
+    pub fn current() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities: Capability::ALL.iter().copied().collect(),
+        }
+    }
+
+    /// The protocol spoken by peers that don't send any negotiation headers
+    pub fn legacy() -> Self {
+        Self {
+            version: LEGACY_PROTOCOL_VERSION,
+            capabilities: Capability::LEGACY.iter().copied().collect(),
+        }
+    }
+
+    /// Parses the raw values of the negotiation headers.
+    /// Unknown capabilities are ignored, they come from peers newer than us.
+    pub fn from_header_values(
+        version: Option<&[u8]>,
+        capabilities: Option<&[u8]>,
+    ) -> Result<Self, ProtocolError> {
+        let version = match version {
+            None => return Ok(Self::legacy()),
+            Some(v) => std::str::from_utf8(v)
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+                .ok_or_else(|| {
+                    ProtocolError::Invalid(format!(
+                        "Invalid {PROTOCOL_VERSION_HEADER} header: {}",
+                        String::from_utf8_lossy(v)
+                    ))
+                })?,
+        };
+        let capabilities = match capabilities {
+            None => BTreeSet::new(),
+            Some(caps) => std::str::from_utf8(caps)
+                .map_err(|_| {
+                    ProtocolError::Invalid(format!("Invalid {CAPABILITIES_HEADER} header"))
+                })?
+                .split(',')
+                .filter_map(|c| Capability::from_name(c.trim()))
+                .collect(),
+        };
+        Ok(Self {
+            version,
+            capabilities,
+        })
+    }
+
+    pub fn version_header_value(&self) -> String {
+        self.version.to_string()
+    }
+
+    pub fn capabilities_header_value(&self) -> String {
+        self.capabilities
+            .iter()
+            .map(|c| c.name())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// The (name, value) pairs of the negotiation headers describing this protocol
+    pub fn header_values(&self) -> [(&'static str, String); 2] {
+        [
+            (PROTOCOL_VERSION_HEADER, self.version_header_value()),
+            (CAPABILITIES_HEADER, self.capabilities_header_value()),
+        ]
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Fails if the peer is older than the oldest version we still speak
+    pub fn check_min_version(&self, min_version: u32) -> Result<(), ProtocolError> {
+        if self.version < min_version {
+            return Err(ProtocolError::Incompatible {
+                peer_version: self.version,
+                min_version,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, thiserror::Error)]
+pub enum ProtocolError {
+    #[error("{0}")]
+    Invalid(String),
+    #[error(
+        "Incompatible protocol version {peer_version}, at least version {min_version} is required"
+    )]
+    Incompatible { peer_version: u32, min_version: u32 },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn missing_headers_is_legacy() {
+        let proto = PeerProtocol::from_header_values(None, None).unwrap();
+        assert_eq!(proto, PeerProtocol::legacy());
+        assert!(proto.supports(Capability::StatusUpdate));
+    }
+
+    #[test]
+    fn header_values_roundtrip() {
+        let ours = PeerProtocol::current();
+        let version = ours.version_header_value();
+        let caps = ours.capabilities_header_value();
+        let parsed =
+            PeerProtocol::from_header_values(Some(version.as_bytes()), Some(caps.as_bytes()))
+                .unwrap();
+        assert_eq!(parsed, ours);
+    }
+
+    #[test]
+    fn unknown_capabilities_are_ignored() {
+        let parsed =
+            PeerProtocol::from_header_values(Some(b"42"), Some(b"power_command, from_the_future"))
+                .unwrap();
+        assert_eq!(parsed.version, 42);
+        assert_eq!(
+            parsed.capabilities.into_iter().collect::<Vec<_>>(),
+            vec![Capability::PowerCommand]
+        );
+    }
+
+    #[test]
+    fn invalid_version() {
+        assert!(PeerProtocol::from_header_values(Some(b"two"), None).is_err());
+    }
+
+    #[test]
+    fn min_version() {
+        let legacy = PeerProtocol::legacy();
+        assert!(legacy.check_min_version(LEGACY_PROTOCOL_VERSION).is_ok());
+        assert_eq!(
+            legacy.check_min_version(LEGACY_PROTOCOL_VERSION + 1),
+            Err(ProtocolError::Incompatible {
+                peer_version: LEGACY_PROTOCOL_VERSION,
+                min_version: LEGACY_PROTOCOL_VERSION + 1
+            })
+        );
+    }
+}